@@ -0,0 +1,53 @@
+use criterion::{black_box, criterion_group, criterion_main, BatchSize, Criterion};
+
+use accountmerge::comment::Comment;
+
+/// A comment as typically found on an imported posting.
+const IMPORTED_COMMENT: &str = ":import-self:unknown-account:\n\
+    :fp-nwcsv6.1.current-B9g4cu5vC0RBfKOa7xLeNTcBxrw:\n\
+    :fp-current-4ABLhdAwR1T9mjf5w6KJXtc3yQk:\n\
+    account: Current\n\
+    bank: Nationwide\n\
+    seq: current-1\n\
+    trn_type: Visa purchase";
+
+/// A comment mixing plain text with tags.
+const TEXT_COMMENT: &str = "Bought some things :tag1:tag2: for the house\n\
+    second line of text\n\
+    key: value";
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("comment");
+    group.bench_function("parse imported", |b| {
+        b.iter(|| Comment::from_opt_comment(black_box(Some(IMPORTED_COMMENT))))
+    });
+    group.bench_function("parse text", |b| {
+        b.iter(|| Comment::from_opt_comment(black_box(Some(TEXT_COMMENT))))
+    });
+    group.finish();
+}
+
+fn format(c: &mut Criterion) {
+    let imported = Comment::from_opt_comment(Some(IMPORTED_COMMENT));
+    let text = Comment::from_opt_comment(Some(TEXT_COMMENT));
+
+    let mut group = c.benchmark_group("comment");
+    group.bench_function("format imported", |b| {
+        b.iter_batched(
+            || imported.clone(),
+            Comment::into_opt_comment,
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("format text", |b| {
+        b.iter_batched(
+            || text.clone(),
+            Comment::into_opt_comment,
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse, format);
+criterion_main!(benches);
@@ -0,0 +1,51 @@
+//! Synthetic journal data shared between the benchmarks.
+
+use std::fmt::Write;
+use std::ops::Range;
+
+use accountmerge::internal::{JournalTransactions, TransactionPostings};
+use accountmerge::ledgerutil;
+
+/// Number of transactions generated per day.
+const TRANSACTIONS_PER_DAY: usize = 10;
+
+/// Generates Ledger journal text containing a transaction with two postings
+/// for each index in `indices`.
+///
+/// The date, description, amount and fingerprints of each transaction are
+/// derived from its index, so journals generated with the same `fp_ns` and
+/// overlapping indices contain matching postings.
+pub fn journal_text(fp_ns: &str, indices: Range<usize>) -> String {
+    let mut s = String::new();
+    for i in indices {
+        let date = chrono::NaiveDate::from_ymd_opt(2000, 1, 1).unwrap()
+            + chrono::Duration::days((i / TRANSACTIONS_PER_DAY) as i64);
+        let pence = 100 + i % 10_000;
+        writeln!(
+            s,
+            "{} Shop {} purchase\n  \
+             assets:checking  GBP -{}.{:02}  ; :fp-{}-{}s:import-self:\n  \
+             ; account: Current\n  \
+             ; bank: Nationwide\n  \
+             expenses:unknown  GBP {}.{:02}  ; :fp-{}-{}p:import-peer:unknown-account:\n",
+            date.format("%Y/%m/%d"),
+            i % 1000,
+            pence / 100,
+            pence % 100,
+            fp_ns,
+            i,
+            pence / 100,
+            pence % 100,
+            fp_ns,
+            i,
+        )
+        .unwrap();
+    }
+    s
+}
+
+/// Generates transactions as per `journal_text`.
+pub fn transactions(fp_ns: &str, indices: Range<usize>) -> Vec<TransactionPostings> {
+    let journal = ledgerutil::parse(&journal_text(fp_ns, indices)).expect("parse journal");
+    JournalTransactions::from(journal).trns
+}
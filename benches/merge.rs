@@ -0,0 +1,76 @@
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use accountmerge::merge::merger::Merger;
+
+mod common;
+
+/// Merges 100k postings into a destination of 500k postings. Half of the
+/// incoming postings match existing postings by fingerprint, the other half
+/// are new.
+fn merge_into_large_destination(c: &mut Criterion) {
+    let dest = common::transactions("dest", 0..250_000);
+    let mut src = common::transactions("dest", 200_000..225_000);
+    src.extend(common::transactions("src", 250_000..275_000));
+
+    let mut group = c.benchmark_group("merge");
+    group.sample_size(10);
+    group.bench_function("100k postings into 500k", |b| {
+        b.iter_batched(
+            || {
+                let mut merger = Merger::new();
+                merger.merge(dest.clone()).expect("merge destination");
+                (merger, src.clone())
+            },
+            |(mut merger, src)| {
+                let unmerged = merger.merge(src).expect("merge source");
+                assert!(unmerged.0.is_empty());
+                merger
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+/// Merges a large journal into an empty `Merger` in small batches (as when
+/// merging many statements), with and without sizing it up front, to show the
+/// cost of growing the indexes as postings are added.
+fn merge_presized(c: &mut Criterion) {
+    let src = common::transactions("src", 0..250_000);
+    let num_trns = src.len();
+    let num_posts = src.iter().map(|trn| trn.posts.len()).sum();
+    let batches: Vec<Vec<_>> = src.chunks(1000).map(<[_]>::to_vec).collect();
+
+    let mut group = c.benchmark_group("merge");
+    group.sample_size(10);
+    group.bench_function("500k postings in batches into new", |b| {
+        b.iter_batched(
+            || batches.clone(),
+            |batches| {
+                let mut merger = Merger::new();
+                for batch in batches {
+                    merger.merge(batch).expect("merge source");
+                }
+                merger
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.bench_function("500k postings in batches into presized", |b| {
+        b.iter_batched(
+            || batches.clone(),
+            |batches| {
+                let mut merger = Merger::with_capacity(num_trns, num_posts);
+                for batch in batches {
+                    merger.merge(batch).expect("merge source");
+                }
+                merger
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, merge_into_large_destination, merge_presized);
+criterion_main!(benches);
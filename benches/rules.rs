@@ -0,0 +1,56 @@
+use std::fmt::Write as _;
+use std::io::Write as _;
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion};
+
+use accountmerge::rules::table;
+
+mod common;
+
+const NUM_RULES: usize = 1000;
+
+/// Generates a rules table with `NUM_RULES` rules in its start chain, one for
+/// each shop generated by `common::journal_text`.
+fn rules_text() -> String {
+    let mut s = String::from("[Chain(\"start\", [\n");
+    for i in 0..NUM_RULES {
+        writeln!(
+            s,
+            "Rule(\
+             predicate: All([\
+             Account(Eq(\"expenses:unknown\")), \
+             TransactionDescription(Matches(\"^Shop {} \")),\
+             ]), \
+             action: All([SetAccount(\"expenses:shop{}\"), RemovePostingFlagTag(\"unknown-account\")]), \
+             result: Return),",
+            i, i,
+        )
+        .unwrap();
+    }
+    s.push_str("])]\n");
+    s
+}
+
+/// Applies a 1000 rule table to 100k postings.
+fn apply_large_table(c: &mut Criterion) {
+    let mut rules_file = tempfile::NamedTempFile::new().expect("create rules file");
+    rules_file
+        .write_all(rules_text().as_bytes())
+        .expect("write rules file");
+    let table = table::load_from_path(rules_file.path()).expect("load rules");
+    let trns = common::transactions("bench", 0..50_000);
+
+    let mut group = c.benchmark_group("rules");
+    group.sample_size(10);
+    group.bench_function("1000 rules over 100k postings", |b| {
+        b.iter_batched(
+            || trns.clone(),
+            |trns| table.update_transactions(trns).expect("apply rules"),
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, apply_large_table);
+criterion_main!(benches);
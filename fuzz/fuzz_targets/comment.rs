@@ -0,0 +1,20 @@
+//! Parses arbitrary text as a comment, as importers do with text from bank
+//! statements, and checks that formatting it is stable.
+
+#![no_main]
+
+use accountmerge::comment::{Comment, MAX_LINES};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let once = Comment::from_opt_comment(Some(text));
+    let twice = Comment::from_opt_string(&once.into_opt_comment());
+    if text.lines().count() >= MAX_LINES {
+        // Formatting may take the comment over the line limit.
+        return;
+    }
+    // The first formatting may turn text that looks like a tag into one, but
+    // after that, formatting must round trip.
+    let thrice = Comment::from_opt_string(&twice.clone().into_opt_comment());
+    assert_eq!(twice, thrice);
+});
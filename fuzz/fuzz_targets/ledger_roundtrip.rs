@@ -0,0 +1,26 @@
+//! Reads arbitrary text as a journal and checks that the journal written from
+//! it can be read back.
+
+#![no_main]
+
+use accountmerge::internal::JournalTransactions;
+use accountmerge::ledgerutil;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|text: &str| {
+    let journal = match ledgerutil::parse(text) {
+        Ok(journal) => journal,
+        Err(_) => return,
+    };
+    let contents = JournalTransactions::from(journal);
+    let count = (contents.trns.len(), contents.prices.len());
+    let formatted = contents.into_journal().to_string();
+    let journal = ledgerutil::parse(&formatted).expect("formatted journal should parse");
+    let contents = JournalTransactions::from(journal);
+    assert_eq!(
+        (contents.trns.len(), contents.prices.len()),
+        count,
+        "in formatted journal:\n{}",
+        formatted
+    );
+});
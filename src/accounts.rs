@@ -1,3 +1,311 @@
+//! Ledger account names.
+
+use std::borrow::Cow;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Error, Result};
+use clap::Args;
+use ledger_parser::Transaction;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
+
 pub const ASSETS_UNKNOWN: &str = "assets:unknown";
 pub const EXPENSES_UNKNOWN: &str = "expenses:unknown";
 pub const INCOME_UNKNOWN: &str = "income:unknown";
+
+/// The root accounts that the user's accounts are organized under, read from
+/// a RON file such as `(expenses: "spending", income: "earnings")`. Roots
+/// that are not given have their usual names.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Taxonomy {
+    pub assets: Account,
+    pub liabilities: Account,
+    pub income: Account,
+    pub expenses: Account,
+    pub equity: Account,
+}
+
+impl Default for Taxonomy {
+    fn default() -> Self {
+        Self {
+            assets: Account::new("assets"),
+            liabilities: Account::new("liabilities"),
+            income: Account::new("income"),
+            expenses: Account::new("expenses"),
+            equity: Account::new("equity"),
+        }
+    }
+}
+
+impl Taxonomy {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let reader = std::fs::File::open(path)
+            .with_context(|| format!("opening taxonomy file {:?}", path))?;
+        ron::de::from_reader(reader).with_context(|| format!("parsing taxonomy file {:?}", path))
+    }
+
+    pub fn roots(&self) -> [&Account; 5] {
+        [
+            &self.assets,
+            &self.liabilities,
+            &self.income,
+            &self.expenses,
+            &self.equity,
+        ]
+    }
+
+    /// Returns true if `account` is one of the roots or under one of them.
+    pub fn contains(&self, account: &Account) -> bool {
+        self.roots()
+            .into_iter()
+            .any(|root| account.starts_with_segments(root))
+    }
+
+    /// Moves the postings that importers put in the unknown accounts (e.g.
+    /// `expenses:unknown`) under the corresponding roots of the taxonomy.
+    pub fn rename_unknown_accounts(&self, trns: &mut [Transaction]) {
+        let unknowns = [
+            (ASSETS_UNKNOWN, &self.assets),
+            (EXPENSES_UNKNOWN, &self.expenses),
+            (INCOME_UNKNOWN, &self.income),
+        ];
+        for post in trns.iter_mut().flat_map(|trn| trn.postings.iter_mut()) {
+            if let Some((_, root)) = unknowns.iter().find(|(name, _)| post.account == *name) {
+                post.account = root.join("unknown").to_string();
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct TaxonomyOpts {
+    /// RON file naming the root accounts (assets, liabilities, income,
+    /// expenses and equity), e.g. `(expenses: "spending")`. Imported unknown
+    /// accounts are put under these roots, and `apply-rules table` warns
+    /// about rules that set accounts under any other root.
+    #[arg(long = "taxonomy", value_name = "FILE")]
+    taxonomy: Option<PathBuf>,
+}
+
+impl TaxonomyOpts {
+    /// Returns the taxonomy from --taxonomy, if given.
+    pub fn load(&self) -> Result<Option<Taxonomy>> {
+        self.taxonomy
+            .as_deref()
+            .map(Taxonomy::from_path)
+            .transpose()
+    }
+
+    /// Returns the path of --taxonomy, if given.
+    pub fn path(&self) -> Option<&Path> {
+        self.taxonomy.as_deref()
+    }
+}
+
+/// A Ledger account name, e.g. `expenses:food:groceries`, normalized so that
+/// names that Ledger would treat as the same account compare equal. Each
+/// colon separated segment is trimmed and has runs of whitespace collapsed
+/// to a single space, and empty segments (from repeated colons) are removed.
+#[derive(Clone, Debug, Default, Eq, Hash, Ord, PartialEq, PartialOrd, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Account(String);
+
+impl Account {
+    /// Creates an account from `name`, normalizing it.
+    pub fn new(name: &str) -> Self {
+        Self(normalize(name).into_owned())
+    }
+
+    /// Returns the colon separated segments of the account name.
+    pub fn segments(&self) -> impl Iterator<Item = &str> {
+        self.0.split(':').filter(|segment| !segment.is_empty())
+    }
+
+    /// Returns true if `prefix` is this account or one of its parents. Unlike
+    /// `str::starts_with`, `assets:bank` is not a prefix of `assets:bankcard`.
+    pub fn starts_with_segments(&self, prefix: &Account) -> bool {
+        let mut segments = self.segments();
+        prefix
+            .segments()
+            .all(|prefix_segment| segments.next() == Some(prefix_segment))
+    }
+
+    /// Returns the subaccount of this account named by `sub`, which may
+    /// itself have several segments.
+    pub fn join(&self, sub: &str) -> Self {
+        Self::new(&format!("{}:{}", self.0, sub))
+    }
+}
+
+/// Normalizes an account name as for `Account`, without allocating if it is
+/// already normalized.
+pub fn normalize(name: &str) -> Cow<'_, str> {
+    let is_normalized = name
+        .split(':')
+        .all(|segment| !segment.is_empty() && normalize_segment(segment) == segment);
+    if is_normalized {
+        return Cow::Borrowed(name);
+    }
+    let segments: Vec<String> = name
+        .split(':')
+        .map(normalize_segment)
+        .filter(|segment| !segment.is_empty())
+        .collect();
+    Cow::Owned(segments.join(":"))
+}
+
+/// Converts `value`, e.g. a value tag, into a single account segment by
+/// replacing characters that cannot be part of one with spaces, and
+/// collapsing whitespace.
+pub fn sanitize_segment(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .map(|c| match c {
+            ':' | ';' | '(' | ')' | '[' | ']' => ' ',
+            c => c,
+        })
+        .collect();
+    normalize_segment(&value)
+}
+
+fn normalize_segment(segment: &str) -> String {
+    segment.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+impl FromStr for Account {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let account = Self::new(s);
+        if account.0.is_empty() {
+            bail!("invalid account {:?}: has no name", s);
+        }
+        if let Some(c) = account
+            .0
+            .chars()
+            .find(|c| matches!(c, ';' | '(' | ')' | '[' | ']'))
+        {
+            bail!("invalid account {:?}: contains {:?}", s, c);
+        }
+        Ok(account)
+    }
+}
+
+impl TryFrom<String> for Account {
+    type Error = Error;
+
+    fn try_from(s: String) -> Result<Self> {
+        s.parse()
+    }
+}
+
+impl From<Account> for String {
+    fn from(account: Account) -> Self {
+        account.0
+    }
+}
+
+impl fmt::Display for Account {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl JsonSchema for Account {
+    fn schema_name() -> String {
+        "Account".to_string()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    #[test_case("expenses:food" => "expenses:food"; "already_normalized")]
+    #[test_case(" expenses : food " => "expenses:food"; "trims_segments")]
+    #[test_case("expenses::food:" => "expenses:food"; "removes_empty_segments")]
+    #[test_case("expenses:eating  \tout" => "expenses:eating out"; "collapses_whitespace")]
+    fn new(name: &str) -> String {
+        Account::new(name).into()
+    }
+
+    #[test_case("expenses:food"; "ok")]
+    #[test_case(" : "; "empty")]
+    #[test_case("expenses;food"; "comment")]
+    #[test_case("(expenses:food)"; "virtual_account")]
+    fn from_str(name: &str) {
+        let got: Result<Account> = name.parse();
+        assert_eq!(got.is_ok(), name == "expenses:food", "{:?}", got);
+    }
+
+    #[test_case("assets:bank", "assets:bank" => true; "same")]
+    #[test_case("assets:bank:current", "assets:bank" => true; "parent")]
+    #[test_case("assets:bankcard", "assets:bank" => false; "partial_segment")]
+    #[test_case("assets", "assets:bank" => false; "child")]
+    fn starts_with_segments(account: &str, prefix: &str) -> bool {
+        Account::new(account).starts_with_segments(&Account::new(prefix))
+    }
+
+    #[test_case("assets:bank" => true; "default_root")]
+    #[test_case("income" => true; "root_itself")]
+    #[test_case("spending:food" => true; "configured_root")]
+    #[test_case("expenses:food" => false; "replaced_root")]
+    #[test_case("spendng:food" => false; "typo")]
+    fn taxonomy_contains(account: &str) -> bool {
+        let taxonomy = Taxonomy {
+            expenses: Account::new("spending"),
+            ..Default::default()
+        };
+        taxonomy.contains(&Account::new(account))
+    }
+
+    #[test]
+    fn rename_unknown_accounts() {
+        let taxonomy = Taxonomy {
+            assets: Account::new("Assets"),
+            expenses: Account::new("Expenses"),
+            ..Default::default()
+        };
+        let mut trns = crate::ledgerutil::parse(
+            "2001/01/01 description
+                assets:unknown  GBP-2.00
+                expenses:unknown  GBP1.00
+                income:unknown  GBP1.00\n",
+        )
+        .expect("parse")
+        .transactions()
+        .cloned()
+        .collect::<Vec<_>>();
+        taxonomy.rename_unknown_accounts(&mut trns);
+        let got: Vec<&str> = trns[0]
+            .postings
+            .iter()
+            .map(|post| post.account.as_str())
+            .collect();
+        assert_eq!(
+            got,
+            vec!["Assets:unknown", "Expenses:unknown", "income:unknown"]
+        );
+    }
+
+    #[test]
+    fn join() {
+        assert_eq!(
+            Account::new("expenses").join(" food : groceries"),
+            Account::new("expenses:food:groceries")
+        );
+    }
+}
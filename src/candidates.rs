@@ -0,0 +1,492 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Result};
+use clap::Args;
+
+use crate::datefilter::DateFilter;
+use crate::filespec::{self, FileSpec};
+use crate::internal::{JournalTransactions, PostingInternal, TransactionPostings};
+use crate::tags;
+
+#[derive(Debug, Args)]
+pub struct Cmd {
+    /// The Ledger journals to update.
+    journals: Vec<FileSpec>,
+    /// Only clear candidate tags from transactions in this date range.
+    #[command(flatten)]
+    date_filter: DateFilter,
+    /// Only clear candidate tags from postings added or modified by merges
+    /// before the one labelled LABEL, i.e. whose `merged` value tag (see
+    /// `merge --merged-tag`) sorts before LABEL. Postings without a `merged`
+    /// tag are taken to be from before any labelled merge.
+    #[arg(long = "before-merged", value_name = "LABEL")]
+    before_merged: Option<String>,
+}
+
+impl Cmd {
+    pub fn run(&self) -> Result<()> {
+        filespec::check_single_stdin(&self.journals)?;
+        for ledger_file in &self.journals {
+            let mut contents = JournalTransactions::from(filespec::read_ledger_file(ledger_file)?);
+            let count = clear_candidates(
+                &mut contents.trns,
+                &self.date_filter,
+                self.before_merged.as_deref(),
+            );
+            eprintln!("{}: removed {} candidate tags", ledger_file, count);
+            filespec::write_ledger_file(ledger_file, &contents.into_journal())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Removes `candidate-*` tags, and `candidate-hint` value tags, from the
+/// postings of transactions within the date filter, and that were merged
+/// before the `before_merged` merge label if given. Returns the number of
+/// candidate tags removed.
+fn clear_candidates(
+    trns: &mut [TransactionPostings],
+    date_filter: &DateFilter,
+    before_merged: Option<&str>,
+) -> usize {
+    let mut count = 0;
+    for trn in trns.iter_mut().filter(|trn| date_filter.contains(trn)) {
+        for post in &mut trn.posts {
+            let merged_before = before_merged.is_none_or(|before| {
+                post.comment
+                    .value_tags
+                    .get(tags::MERGED)
+                    .is_none_or(|label| label.as_str() < before)
+            });
+            if merged_before {
+                count += remove_candidates(post);
+            }
+        }
+    }
+    count
+}
+
+#[derive(Debug, Args)]
+pub struct ResolveCmd {
+    /// The unmerged transactions written by `merge --unmerged`.
+    unmerged: FileSpec,
+    /// Where to write the resolved transactions. Defaults to updating the
+    /// unmerged file.
+    #[arg(short = 'o', long = "output")]
+    output: Option<FileSpec>,
+    /// Merges the posting with the fingerprint NEW into its candidate with
+    /// the fingerprint CANDIDATE (with or without the "candidate-" prefix).
+    #[arg(long = "accept", value_name = "NEW=CANDIDATE")]
+    accept: Vec<Accept>,
+    /// Rejects all candidates of the posting with the fingerprint NEW, so
+    /// that its transaction merges as a new transaction.
+    #[arg(long = "reject", value_name = "NEW")]
+    reject: Vec<String>,
+    /// Prompts for a decision on each posting with candidates.
+    #[arg(short = 'i', long = "interactive", conflicts_with_all = ["accept", "reject"])]
+    interactive: bool,
+}
+
+impl ResolveCmd {
+    pub fn run(&self) -> Result<()> {
+        if self.interactive && matches!(self.unmerged, FileSpec::Stdio) {
+            bail!("--interactive cannot read the unmerged transactions from stdin");
+        }
+        let mut contents = JournalTransactions::from(filespec::read_ledger_file(&self.unmerged)?);
+        let trns = &mut contents.trns;
+
+        let resolved = if self.interactive {
+            let stdin = std::io::stdin();
+            resolve_interactively(trns, &mut stdin.lock(), &mut std::io::stderr())?
+        } else {
+            let mut directives = HashMap::new();
+            let accepts = self
+                .accept
+                .iter()
+                .map(|accept| (&accept.new, Decision::Accept(accept.candidate.clone())));
+            let rejects = self.reject.iter().map(|new| (new, Decision::Reject));
+            for (new, decision) in accepts.chain(rejects) {
+                if directives.insert(new.clone(), decision).is_some() {
+                    bail!("more than one decision for {:?}", new);
+                }
+            }
+            resolve_with(trns, directives)?
+        };
+
+        let remaining = count_with_candidates(trns);
+        eprintln!(
+            "{}: resolved {} postings, {} still have candidates",
+            self.unmerged, resolved, remaining
+        );
+        filespec::write_ledger_file(
+            self.output.as_ref().unwrap_or(&self.unmerged),
+            &contents.into_journal(),
+        )
+    }
+}
+
+/// An `--accept` directive.
+#[derive(Clone, Debug)]
+struct Accept {
+    new: String,
+    candidate: String,
+}
+
+impl FromStr for Accept {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (new, candidate) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected NEW=CANDIDATE, got {:?}", s))?;
+        Ok(Self {
+            new: new.to_string(),
+            candidate: candidate.to_string(),
+        })
+    }
+}
+
+/// What to do with the candidates of a posting.
+#[derive(Debug)]
+enum Decision {
+    /// Merge into the candidate with this fingerprint.
+    Accept(String),
+    /// Merge as a new posting.
+    Reject,
+}
+
+impl Decision {
+    /// Applies the decision to the posting at `idx` in `trn`.
+    fn apply(&self, trn: &mut TransactionPostings, idx: usize) -> Result<()> {
+        match self {
+            Decision::Accept(fp) => pick_candidate(&mut trn.posts[idx], fp),
+            Decision::Reject => {
+                reject_candidates(trn, idx);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Picks the candidate with fingerprint `fp` (with or without the
+/// "candidate-" prefix) of `post`, so that the posting merges into it when
+/// next merged. This removes the other candidate tags.
+pub fn pick_candidate(post: &mut PostingInternal, fp: &str) -> Result<()> {
+    let fp = fp.strip_prefix(tags::CANDIDATE_FP_PREFIX).unwrap_or(fp);
+    let candidate = format!("{}{}", tags::CANDIDATE_FP_PREFIX, fp);
+    if !post.comment.tags.contains(candidate.as_str()) {
+        bail!("{:?} is not one of its candidates", fp);
+    }
+    remove_candidates(post);
+    post.comment.tags.insert(fp.into());
+    Ok(())
+}
+
+/// Rejects all candidates of the posting at `idx` in `trn`, tagging every
+/// posting of the transaction to merge as new. Tagging only the rejected
+/// posting would let its siblings soft match an existing transaction, and
+/// the rejected posting would then be added to that transaction.
+fn reject_candidates(trn: &mut TransactionPostings, idx: usize) {
+    remove_candidates(&mut trn.posts[idx]);
+    for post in &mut trn.posts {
+        post.comment.tags.insert(tags::MERGE_AS_NEW.into());
+    }
+}
+
+fn remove_candidates(post: &mut PostingInternal) -> usize {
+    let count = post
+        .comment
+        .remove_tags_with_prefix(tags::CANDIDATE_FP_PREFIX);
+    post.comment.value_tags.remove(tags::CANDIDATE_HINT);
+    count
+}
+
+fn has_candidates(post: &PostingInternal) -> bool {
+    post.comment
+        .tags_with_prefix(tags::CANDIDATE_FP_PREFIX)
+        .next()
+        .is_some()
+}
+
+fn count_with_candidates(trns: &[TransactionPostings]) -> usize {
+    trns.iter()
+        .flat_map(|trn| &trn.posts)
+        .filter(|post| has_candidates(post))
+        .count()
+}
+
+/// Applies `directives`, keyed by the fingerprints of the postings that they
+/// apply to, to the postings with candidates. Returns the number of postings
+/// resolved.
+fn resolve_with(
+    trns: &mut [TransactionPostings],
+    mut directives: HashMap<String, Decision>,
+) -> Result<usize> {
+    let mut resolved = 0;
+    for trn in trns.iter_mut() {
+        for idx in 0..trn.posts.len() {
+            let post = &trn.posts[idx];
+            if !has_candidates(post) {
+                continue;
+            }
+            let mut fps: Vec<String> = post
+                .comment
+                .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+                .map(str::to_string)
+                .collect();
+            fps.sort();
+            let mut decisions = fps
+                .iter()
+                .filter_map(|fp| directives.remove_entry(fp))
+                .collect::<Vec<_>>();
+            match decisions.len() {
+                0 => {}
+                1 => {
+                    let (fp, decision) = decisions.remove(0);
+                    decision
+                        .apply(trn, idx)
+                        .map_err(|e| anyhow!("cannot resolve {}: {}", fp, e))?;
+                    resolved += 1;
+                }
+                _ => bail!(
+                    "more than one decision for the same posting: {}",
+                    decisions
+                        .iter()
+                        .map(|(fp, _)| fp.as_str())
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+            }
+        }
+    }
+    if !directives.is_empty() {
+        let mut unknown: Vec<&str> = directives.keys().map(String::as_str).collect();
+        unknown.sort();
+        bail!(
+            "no posting with candidates has the fingerprint(s): {}",
+            unknown.join(", ")
+        );
+    }
+    Ok(resolved)
+}
+
+/// Prompts on `output` for a decision on each posting with candidates,
+/// reading the answers from `input`. Returns the number of postings
+/// resolved.
+fn resolve_interactively<R: BufRead, W: Write>(
+    trns: &mut [TransactionPostings],
+    input: &mut R,
+    output: &mut W,
+) -> Result<usize> {
+    let mut resolved = 0;
+    for trn in trns.iter_mut() {
+        for idx in 0..trn.posts.len() {
+            let post = &trn.posts[idx];
+            if !has_candidates(post) {
+                continue;
+            }
+            let mut candidates: Vec<String> = post
+                .comment
+                .tags_with_prefix(tags::CANDIDATE_FP_PREFIX)
+                .map(|tag| tag[tags::CANDIDATE_FP_PREFIX.len()..].to_string())
+                .collect();
+            candidates.sort();
+
+            write!(
+                output,
+                "\n{} {}\n  {}",
+                trn.trn.raw.date, trn.trn.raw.description, post.raw.account
+            )?;
+            if let Some(amount) = &post.raw.amount {
+                write!(output, "  {}", amount.amount)?;
+            }
+            writeln!(output)?;
+            if let Some(hint) = post.comment.value_tags.get(tags::CANDIDATE_HINT) {
+                writeln!(output, "best candidate: {}", hint)?;
+            }
+            for (i, fp) in candidates.iter().enumerate() {
+                writeln!(output, "  {}) {}", i + 1, fp)?;
+            }
+
+            loop {
+                write!(
+                    output,
+                    "accept [1-{}], n(ew), s(kip) or q(uit)? ",
+                    candidates.len()
+                )?;
+                output.flush()?;
+                let mut line = String::new();
+                if input.read_line(&mut line)? == 0 {
+                    return Ok(resolved);
+                }
+                match line.trim() {
+                    "n" => {
+                        reject_candidates(trn, idx);
+                        resolved += 1;
+                    }
+                    "s" => {}
+                    "q" => return Ok(resolved),
+                    answer => match answer.parse::<usize>() {
+                        Ok(i) if (1..=candidates.len()).contains(&i) => {
+                            pick_candidate(&mut trn.posts[idx], &candidates[i - 1])?;
+                            resolved += 1;
+                        }
+                        _ => {
+                            writeln!(output, "unrecognised answer {:?}", answer)?;
+                            continue;
+                        }
+                    },
+                }
+                break;
+            }
+        }
+    }
+    Ok(resolved)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test]
+    fn clears_candidates_in_range() {
+        let mut trns = parse_transaction_postings(
+            "
+            2001/01/01 old
+                assets:bank  GBP-1.00  ; :candidate-fp-a:candidate-fp-b:fp-c:
+                ; candidate-hint: 2001-01-01 other GBP-1.00 (1 of 2)
+                expenses:unknown  GBP1.00
+
+            2001/01/02 new
+                assets:bank  GBP-1.00  ; :candidate-fp-d:
+                expenses:unknown  GBP1.00",
+        );
+        let date_filter = DateFilter {
+            until: "2001-01-01".parse().ok(),
+            ..Default::default()
+        };
+        assert_eq!(clear_candidates(&mut trns, &date_filter, None), 2);
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/01 old
+                    assets:bank  GBP-1.00  ; :fp-c:
+                    expenses:unknown  GBP1.00
+
+                2001/01/02 new
+                    assets:bank  GBP-1.00  ; :candidate-fp-d:
+                    expenses:unknown  GBP1.00",
+            )
+        );
+    }
+
+    #[test]
+    fn clears_candidates_before_merge() {
+        let mut trns = parse_transaction_postings(
+            "
+            2001/01/01 shop
+                assets:bank  GBP-1.00  ; :candidate-fp-a:
+                ; merged: 2001-01-05T10:00:00
+                expenses:unknown  GBP1.00  ; :candidate-fp-b:
+                ; merged: 2001-01-06T10:00:00
+                income:unknown  GBP0.00  ; :candidate-fp-c:",
+        );
+        assert_eq!(
+            clear_candidates(&mut trns, &DateFilter::default(), Some("2001-01-06")),
+            2
+        );
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/01 shop
+                    assets:bank  GBP-1.00
+                    ; merged: 2001-01-05T10:00:00
+                    expenses:unknown  GBP1.00  ; :candidate-fp-b:
+                    ; merged: 2001-01-06T10:00:00
+                    income:unknown  GBP0.00",
+            )
+        );
+    }
+
+    fn unmerged() -> Vec<TransactionPostings> {
+        parse_transaction_postings(
+            "
+            2001/01/01 shop
+                assets:bank  GBP-1.00  ; :fp-a:candidate-fp-x:candidate-fp-y:
+                ; candidate-hint: 2001-01-01 shop GBP-1.00 (1 of 2)
+                expenses:unknown  GBP1.00  ; :fp-b:
+
+            2001/01/02 other
+                assets:bank  GBP-2.00  ; :fp-c:candidate-fp-z:candidate-fp-w:
+                expenses:unknown  GBP2.00  ; :fp-d:",
+        )
+    }
+
+    #[test]
+    fn resolves_with_directives() {
+        let mut trns = unmerged();
+        let directives = HashMap::from([
+            ("fp-a".to_string(), Decision::Accept("fp-y".to_string())),
+            ("fp-c".to_string(), Decision::Reject),
+        ]);
+        assert_eq!(resolve_with(&mut trns, directives).expect("resolve"), 2);
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/01 shop
+                    assets:bank  GBP-1.00  ; :fp-a:fp-y:
+                    expenses:unknown  GBP1.00  ; :fp-b:
+
+                2001/01/02 other
+                    assets:bank  GBP-2.00  ; :fp-c:merge-as-new:
+                    expenses:unknown  GBP2.00  ; :fp-d:merge-as-new:",
+            )
+        );
+    }
+
+    #[test]
+    fn resolve_with_bad_directives_fails() {
+        let unknown_fp = HashMap::from([("fp-b".to_string(), Decision::Reject)]);
+        assert!(resolve_with(&mut unmerged(), unknown_fp).is_err());
+        let not_a_candidate =
+            HashMap::from([("fp-a".to_string(), Decision::Accept("fp-z".to_string()))]);
+        assert!(resolve_with(&mut unmerged(), not_a_candidate).is_err());
+    }
+
+    #[test]
+    fn resolves_interactively() {
+        let mut trns = unmerged();
+        let mut output = Vec::new();
+        let resolved = resolve_interactively(&mut trns, &mut "3\n1\ns\n".as_bytes(), &mut output)
+            .expect("resolve");
+        assert_eq!(resolved, 1);
+        let output = String::from_utf8(output).expect("utf-8");
+        assert!(output.contains("unrecognised answer \"3\""), "{}", output);
+        assert!(
+            output.contains("best candidate: 2001-01-01 shop"),
+            "{}",
+            output
+        );
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/01 shop
+                    assets:bank  GBP-1.00  ; :fp-a:fp-x:
+                    expenses:unknown  GBP1.00  ; :fp-b:
+
+                2001/01/02 other
+                    assets:bank  GBP-2.00  ; :fp-c:candidate-fp-z:candidate-fp-w:
+                    expenses:unknown  GBP2.00  ; :fp-d:",
+            )
+        );
+    }
+}
@@ -0,0 +1,110 @@
+//! Commands that describe the command line interface itself.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use clap::Args;
+use clap_complete::Shell;
+
+use crate::filespec::{self, LineEnding};
+use crate::rules::table::FORMAT_MAN_PAGE;
+
+/// Options that apply to all subcommands.
+#[derive(Debug, Args)]
+pub struct GlobalOpts {
+    /// The line endings to write journals and other text files with: "lf",
+    /// "crlf" or "native" (crlf on Windows, otherwise lf). Input files may
+    /// use either.
+    #[arg(long = "line-endings", global = true, default_value = "lf")]
+    line_endings: LineEnding,
+}
+
+impl GlobalOpts {
+    /// Applies the options for the rest of the process.
+    pub fn apply(&self) {
+        filespec::set_line_ending(self.line_endings);
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Completions {
+    /// The shell to generate completions for.
+    shell: Shell,
+}
+
+impl Completions {
+    /// Writes completions for `cmd` to stdout.
+    pub fn run(&self, mut cmd: clap::Command) -> Result<()> {
+        let name = cmd.get_name().to_string();
+        clap_complete::generate(self.shell, &mut cmd, name, &mut std::io::stdout());
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct GenMan {
+    /// The directory to write the man pages to.
+    out_dir: PathBuf,
+}
+
+impl GenMan {
+    /// Writes man pages for `cmd` and all of its subcommands, and for the
+    /// rules file format.
+    pub fn run(&self, mut cmd: clap::Command) -> Result<()> {
+        std::fs::create_dir_all(&self.out_dir)
+            .with_context(|| format!("creating directory {:?}", self.out_dir))?;
+        cmd.build();
+        write_man_pages(&self.out_dir, &cmd)?;
+
+        let rules_path = self.out_dir.join(format!("{}-rules.5", cmd.get_name()));
+        std::fs::write(&rules_path, FORMAT_MAN_PAGE)
+            .with_context(|| format!("writing {:?}", rules_path))
+    }
+}
+
+fn write_man_pages(out_dir: &Path, cmd: &clap::Command) -> Result<()> {
+    let path = out_dir.join(format!("{}.1", cmd.get_name()));
+    let mut content = Vec::new();
+    clap_mangen::Man::new(cmd.clone()).render(&mut content)?;
+    std::fs::write(&path, content).with_context(|| format!("writing {:?}", path))?;
+
+    for sub in cmd.get_subcommands() {
+        if sub.is_hide_set() || sub.get_name() == "help" {
+            continue;
+        }
+        let name = format!("{}-{}", cmd.get_name(), sub.get_name());
+        write_man_pages(out_dir, &sub.clone().name(name))?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn man_pages_for_subcommands() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let mut cmd = clap::Command::new("tool")
+            .subcommand(clap::Command::new("visible").subcommand(clap::Command::new("nested")))
+            .subcommand(clap::Command::new("hidden").hide(true));
+        cmd.build();
+        write_man_pages(dir.path(), &cmd).expect("write man pages");
+
+        let mut names: Vec<String> = std::fs::read_dir(dir.path())
+            .expect("read dir")
+            .map(|entry| {
+                entry
+                    .expect("entry")
+                    .file_name()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        names.sort();
+        assert_eq!(
+            names,
+            vec!["tool-visible-nested.1", "tool-visible.1", "tool.1"]
+        );
+    }
+}
@@ -1,21 +1,102 @@
 use std::collections::{HashMap, HashSet};
 
+use chrono::NaiveDate;
 use lazy_static::lazy_static;
 use regex::Regex;
+use smol_str::SmolStr;
 
 /// Maximum length of a tag before it gets put onto a line on its own.
 const MAX_INLINE_TAG_LEN: usize = 12;
 
+/// Maximum length of a flag tag or value tag name. Longer ones, which only
+/// come from text that was never meant as tags, are kept as text.
+pub const MAX_TAG_LEN: usize = 256;
+
+/// Maximum number of comment lines that are parsed. Any further lines are
+/// kept as a single line of text.
+pub const MAX_LINES: usize = 1000;
+
+lazy_static! {
+    static ref VALUE_TAG_RX: Regex = Regex::new(r"^\s*([^:\s]+):(?:\s+(.*?))?\s*$").unwrap();
+    static ref FLAG_TAG_RX: Regex = Regex::new(r":((?:[^:\s]+:)+)").unwrap();
+    static ref DATES_RX: Regex =
+        Regex::new(r"\[(\d{4}[/.-]\d{1,2}[/.-]\d{1,2})?(?:=(\d{4}[/.-]\d{1,2}[/.-]\d{1,2}))?\]")
+            .unwrap();
+}
+
+/// Format of dates in `[DATE=AUX_DATE]`, as Ledger itself writes them.
+const DATE_FORMAT: &str = "%Y/%m/%d";
+
+fn parse_date(s: &str) -> Option<NaiveDate> {
+    let s = s.replace(['.', '-'], "/");
+    NaiveDate::parse_from_str(&s, DATE_FORMAT).ok()
+}
+
+/// Describes suspicious constructs in a comment that parsing as a `Comment`
+/// would silently treat as plain text, or lose. Returns an empty `Vec` if
+/// there are none.
+pub fn lint(comment: &str) -> Vec<String> {
+    lazy_static! {
+        // A key followed by a colon, but without the single space required
+        // to separate it from a value (excluding URLs).
+        static ref NEAR_VALUE_TAG_RX: Regex = Regex::new(r"^\s*[^:\s]+(?:\s+:|:[^\s:/])").unwrap();
+        static ref VALID_TAG_RX: Regex = Regex::new(r"^[A-Za-z0-9_.+/=-]+$").unwrap();
+    }
+
+    let mut problems = Vec::new();
+    let mut value_keys = HashSet::new();
+    for line in comment.split('\n') {
+        if let Some(kv_parts) = VALUE_TAG_RX.captures(line) {
+            let key = kv_parts
+                .get(1)
+                .expect("should always have group 1")
+                .as_str();
+            if !VALID_TAG_RX.is_match(key) {
+                problems.push(format!("value tag {:?} has invalid characters", key));
+            }
+            if !value_keys.insert(key) {
+                problems.push(format!("duplicate value tag {:?}", key));
+            }
+            continue;
+        }
+        for flag_group in FLAG_TAG_RX.captures_iter(line) {
+            let flags = flag_group.get(1).expect("should always have group 1");
+            for flag in flags.as_str().trim_end_matches(':').split(':') {
+                if !VALID_TAG_RX.is_match(flag) {
+                    problems.push(format!("tag {:?} has invalid characters", flag));
+                }
+            }
+        }
+        if !FLAG_TAG_RX.is_match(line) && NEAR_VALUE_TAG_RX.is_match(line) {
+            problems.push(format!(
+                "{:?} looks like a value tag, but is not of the form \"key: value\"",
+                line.trim()
+            ));
+        }
+    }
+    problems
+}
+
 /// Parsed contents of a Ledger comment, suitable for manipulation before being
 /// (re)output.
+///
+/// Tag names are held as `SmolStr`, which stores short names inline and makes
+/// clones of longer names (such as fingerprints) cheap. Comments are parsed,
+/// cloned and formatted for every posting handled, so this reduces allocation
+/// churn on large journals.
 #[derive(Clone, Debug, Default, Eq, PartialEq)]
 pub struct Comment {
     /// Plain text lines in the comment.
     pub lines: Vec<String>,
     /// Tags that are present or not, e.g: `":TAG:"`.
-    pub tags: HashSet<String>,
+    pub tags: HashSet<SmolStr>,
     /// Tags that have a string value, e.g: `"TAG: value"`.
-    pub value_tags: HashMap<String, String>,
+    pub value_tags: HashMap<SmolStr, String>,
+    /// The date given as `[DATE]` or `[DATE=AUX_DATE]`. On a posting, this
+    /// is the date of the posting, instead of the date of its transaction.
+    pub date: Option<NaiveDate>,
+    /// The auxiliary date given as `[=AUX_DATE]` or `[DATE=AUX_DATE]`.
+    pub aux_date: Option<NaiveDate>,
 }
 
 impl Comment {
@@ -25,6 +106,8 @@ impl Comment {
             lines: Default::default(),
             tags: Default::default(),
             value_tags: Default::default(),
+            date: None,
+            aux_date: None,
         }
     }
 
@@ -39,13 +122,6 @@ impl Comment {
 
     /// Parses the given string into a `Comment`.
     pub fn from_opt_comment(comment: Option<&str>) -> Self {
-        lazy_static! {
-            static ref VALUE_TAG_RX: Regex = Regex::new(r"^[ ]*([^: ]+):(?:[ ]+(.+))?$").unwrap();
-        }
-        lazy_static! {
-            static ref FLAG_TAG_RX: Regex = Regex::new(r":((?:[^: ]+:)+)").unwrap();
-        }
-
         let mut result = Comment::new();
 
         let comment: &str = match comment {
@@ -53,44 +129,66 @@ impl Comment {
             None => return result,
         };
 
-        for line in comment.split('\n') {
+        let mut lines = comment.splitn(MAX_LINES + 1, '\n');
+        for line in lines.by_ref().take(MAX_LINES) {
             // Value tags comprise an entire comment line.
-            if let Some(kv_parts) = VALUE_TAG_RX.captures(line) {
-                let key = kv_parts
-                    .get(1)
-                    .expect("should always have group 1")
-                    .as_str();
-                let value = kv_parts.get(2).map(|c| c.as_str()).unwrap_or("");
-                result.value_tags.insert(key.to_string(), value.to_string());
+            if let Some((key, value)) = parse_value_tag(line) {
+                result.value_tags.insert(key.into(), value.to_string());
             } else {
+                // Dates can also be mixed into a line with comment text. Only
+                // the first dates in the comment are used.
+                let mut line = std::borrow::Cow::Borrowed(line);
+                if result.date.is_none() && result.aux_date.is_none() {
+                    for dates in DATES_RX.captures_iter(line.as_ref()) {
+                        let date = dates.get(1).and_then(|d| parse_date(d.as_str()));
+                        let aux_date = dates.get(2).and_then(|d| parse_date(d.as_str()));
+                        if date.is_none() && aux_date.is_none() {
+                            continue;
+                        }
+                        result.date = date;
+                        result.aux_date = aux_date;
+                        let all = dates.get(0).expect("should always have group 0");
+                        line = format!(
+                            "{} {}",
+                            line[..all.start()].trim_end(),
+                            line[all.end()..].trim_start()
+                        )
+                        .into();
+                        break;
+                    }
+                }
+                let line = line.as_ref();
+
                 // Flag tag groups can be mixed into a line with comment text.
+                let mut texts = Vec::<String>::new();
                 let mut leading_start: usize = 0;
                 for flag_group in FLAG_TAG_RX.captures_iter(line) {
                     // Found flags (maybe with text before them).
 
                     let all = flag_group.get(0).expect("should always have group 0");
                     let flags = flag_group.get(1).expect("should always have group 1");
-                    let leading_end = all.start();
-                    if leading_start < leading_end {
-                        // Found text prior to flags.
-                        let text = line[leading_start..leading_end].trim();
-                        if !text.is_empty() {
-                            result.lines.push(text.to_string());
-                        }
+                    let flags: Vec<&str> =
+                        flags.as_str().trim_end_matches(':').split(':').collect();
+                    if flags.iter().any(|flag| flag.len() > MAX_TAG_LEN) {
+                        // Leave the group to be kept as text.
+                        continue;
                     }
+                    push_text(&mut texts, line[leading_start..all.start()].trim());
                     leading_start = all.end();
 
                     // Flags.
-                    for flag in flags.as_str().trim_end_matches(':').split(':') {
-                        result.tags.insert(flag.to_string());
-                    }
-                }
-                if leading_start < line.len() {
-                    let text = line[leading_start..].trim();
-                    if !text.is_empty() {
-                        result.lines.push(text.to_string());
+                    for flag in flags {
+                        result.tags.insert(flag.into());
                     }
                 }
+                push_text(&mut texts, line[leading_start..].trim());
+                result.lines.extend(texts);
+            }
+        }
+        if let Some(rest) = lines.next() {
+            let rest = rest.split_whitespace().collect::<Vec<_>>().join(" ");
+            if !rest.is_empty() {
+                result.lines.push(rest);
             }
         }
         result
@@ -101,7 +199,7 @@ impl Comment {
         let mut out_lines = Vec::<String>::new();
 
         if !self.tags.is_empty() {
-            let (mut short_tags, mut long_tags): (Vec<String>, Vec<String>) = self
+            let (mut short_tags, mut long_tags): (Vec<SmolStr>, Vec<SmolStr>) = self
                 .tags
                 .into_iter()
                 .partition(|tag| tag.len() <= MAX_INLINE_TAG_LEN);
@@ -126,10 +224,32 @@ impl Comment {
             }
         }
 
-        let mut sorted_entries: Vec<(String, String)> = self.value_tags.into_iter().collect();
+        if self.date.is_some() || self.aux_date.is_some() {
+            let mut dates = String::from("[");
+            if let Some(date) = self.date {
+                dates.push_str(&date.format(DATE_FORMAT).to_string());
+            }
+            if let Some(aux_date) = self.aux_date {
+                dates.push('=');
+                dates.push_str(&aux_date.format(DATE_FORMAT).to_string());
+            }
+            dates.push(']');
+            match out_lines.first_mut() {
+                Some(first) => first.insert_str(0, &format!("{} ", dates)),
+                None => out_lines.push(dates),
+            }
+        }
+
+        let mut sorted_entries: Vec<(SmolStr, String)> = self.value_tags.into_iter().collect();
         sorted_entries.sort();
         for (k, v) in sorted_entries.into_iter() {
-            out_lines.push(format!("{}: {}", k.trim(), v.trim()));
+            let v = v.trim();
+            if v.is_empty() {
+                // A trailing space would stop the line parsing as a value tag.
+                out_lines.push(format!("{}:", k.trim()));
+            } else {
+                out_lines.push(format!("{}: {}", k.trim(), v));
+            }
         }
 
         if !out_lines.is_empty() {
@@ -139,9 +259,35 @@ impl Comment {
         }
     }
 
+    /// Returns the flag tags that start with `prefix`.
+    pub fn tags_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> {
+        self.tags
+            .iter()
+            .map(SmolStr::as_str)
+            .filter(move |tag| tag.starts_with(prefix))
+    }
+
+    /// Removes the flag tags that start with `prefix`. Returns the number of
+    /// tags removed.
+    pub fn remove_tags_with_prefix(&mut self, prefix: &str) -> usize {
+        let before = self.tags.len();
+        self.tags.retain(|tag| !tag.starts_with(prefix));
+        before - self.tags.len()
+    }
+
+    /// Sets the value of a value tag. Returns its previous value, if any.
+    pub fn replace_value_tag<K: Into<SmolStr>, V: Into<String>>(
+        &mut self,
+        k: K,
+        v: V,
+    ) -> Option<String> {
+        self.value_tags.insert(k.into(), v.into())
+    }
+
     /// Merges tags and lines from `other` into `self`. Values from
     /// `other.value_tags` will overwrite values in `self.value_tags` where
-    /// they share a key. It avoids adding duplicate lines from `other.lines`
+    /// they share a key, and the dates from `other` overwrite those in
+    /// `self` if it has any. It avoids adding duplicate lines from `other.lines`
     /// if an exact match already exists in `self.lines`.
     pub fn merge_from(&mut self, other: Self) {
         for other_line in other.lines.into_iter() {
@@ -151,9 +297,43 @@ impl Comment {
         }
         self.tags.extend(other.tags);
         self.value_tags.extend(other.value_tags);
+        if other.date.is_some() || other.aux_date.is_some() {
+            self.date = other.date;
+            self.aux_date = other.aux_date;
+        }
     }
 }
 
+/// Adds a fragment of text from around flag tags in a comment line to
+/// `texts`. A fragment that would be read as a value tag on a line of its own
+/// is instead appended to the previous fragment of the line, if any.
+fn push_text(texts: &mut Vec<String>, text: &str) {
+    if text.is_empty() {
+        return;
+    }
+    match texts.last_mut() {
+        Some(last) if parse_value_tag(text).is_some() => {
+            last.push(' ');
+            last.push_str(text);
+        }
+        _ => texts.push(text.to_string()),
+    }
+}
+
+/// Returns the key and value of a comment line that is a value tag.
+fn parse_value_tag(line: &str) -> Option<(&str, &str)> {
+    let kv_parts = VALUE_TAG_RX.captures(line)?;
+    let key = kv_parts
+        .get(1)
+        .expect("should always have group 1")
+        .as_str();
+    if key.len() > MAX_TAG_LEN {
+        return None;
+    }
+    let value = kv_parts.get(2).map(|c| c.as_str()).unwrap_or("");
+    Some((key, value))
+}
+
 fn trim_string(s: String) -> String {
     if s.trim().len() == s.len() {
         s
@@ -186,19 +366,19 @@ impl CommentBuilder {
         self
     }
 
-    pub fn with_option_tag<K: Into<String>>(mut self, k: Option<K>) -> Self {
+    pub fn with_option_tag<K: Into<SmolStr>>(mut self, k: Option<K>) -> Self {
         if let Some(k) = k {
             self.comment.tags.insert(k.into());
         }
         self
     }
 
-    pub fn with_tag<K: Into<String>>(mut self, k: K) -> Self {
+    pub fn with_tag<K: Into<SmolStr>>(mut self, k: K) -> Self {
         self.comment.tags.insert(k.into());
         self
     }
 
-    pub fn with_option_value_tag<K: Into<String>, V: Into<String>>(
+    pub fn with_option_value_tag<K: Into<SmolStr>, V: Into<String>>(
         mut self,
         k: K,
         v: Option<V>,
@@ -209,10 +389,17 @@ impl CommentBuilder {
         self
     }
 
-    pub fn with_value_tag<K: Into<String>, V: Into<String>>(mut self, k: K, v: V) -> Self {
+    pub fn with_value_tag<K: Into<SmolStr>, V: Into<String>>(mut self, k: K, v: V) -> Self {
         self.comment.value_tags.insert(k.into(), v.into());
         self
     }
+
+    #[cfg(test)] // Currently only used in tests.
+    pub fn with_dates(mut self, date: Option<NaiveDate>, aux_date: Option<NaiveDate>) -> Self {
+        self.comment.date = date;
+        self.comment.aux_date = aux_date;
+        self
+    }
 }
 
 #[cfg(test)]
@@ -221,6 +408,10 @@ mod tests {
 
     use test_case::test_case;
 
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
     #[test_case(
         ""
         => Comment::new();
@@ -278,6 +469,49 @@ mod tests {
             .build();
         "key_without_value"
     )]
+    #[test_case(
+        "[2001/01/05] text :tag:"
+        => CommentBuilder::new()
+            .with_dates(Some(date(2001, 1, 5)), None)
+            .with_line("text")
+            .with_tag("tag")
+            .build();
+        "posting_date"
+    )]
+    #[test_case(
+        "text [2001-01-05=2001.01.07] more"
+        => CommentBuilder::new()
+            .with_dates(Some(date(2001, 1, 5)), Some(date(2001, 1, 7)))
+            .with_line("text more")
+            .build();
+        "posting_date_and_aux_date"
+    )]
+    #[test_case(
+        "[ref] [=2001/01/07]\n[2001/01/08]"
+        => CommentBuilder::new()
+            .with_dates(None, Some(date(2001, 1, 7)))
+            .with_line("[ref]")
+            .with_line("[2001/01/08]")
+            .build();
+        "only_first_dates"
+    )]
+    #[test_case(
+        "text\r\n:tag:\r\nkey:\tvalue\r\n"
+        => CommentBuilder::new()
+            .with_line("text")
+            .with_tag("tag")
+            .with_value_tag("key", "value")
+            .build();
+        "crlf_and_tab"
+    )]
+    #[test_case(
+        "text :tag: key:"
+        => CommentBuilder::new()
+            .with_line("text key:")
+            .with_tag("tag")
+            .build();
+        "text_like_value_tag_kept_with_line"
+    )]
     fn test_parse_comment(text: &str) -> Comment {
         Comment::from_opt_comment(Some(text))
     }
@@ -339,10 +573,169 @@ mod tests {
         => Some(":a_tag:z_tag:\n:really_long_tag_name:\nname1: value1".to_string());
         "long_tags_go_on_own_line"
     )]
+    #[test_case(
+        CommentBuilder::new()
+            .with_dates(Some(date(2001, 1, 5)), Some(date(2001, 1, 7)))
+            .with_tag("tag")
+            .with_value_tag("name", "value")
+            .build()
+        => Some("[2001/01/05=2001/01/07] :tag:\nname: value".to_string());
+        "dates_before_tags"
+    )]
+    #[test_case(
+        CommentBuilder::new()
+            .with_dates(None, Some(date(2001, 1, 7)))
+            .with_value_tag("name", "value")
+            .build()
+        => Some("[=2001/01/07]\nname: value".to_string());
+        "aux_date_on_own_line"
+    )]
     fn test_format_comment(comment: Comment) -> Option<String> {
         comment.into_opt_comment()
     }
 
+    /// Comments built from every combination of a few lines, tags and value
+    /// tags.
+    fn combinations() -> Vec<Comment> {
+        // Text that looks like a value tag would not round trip.
+        const LINES: &[&str] = &["text", "more text"];
+        const TAGS: &[&str] = &["a", "tag2", "really_long_tag_name"];
+        const VALUE_TAGS: &[(&str, &str)] = &[("key", "value"), ("empty", "")];
+        let dates = [
+            (None, None),
+            (Some(date(2001, 1, 5)), None),
+            (None, Some(date(2001, 1, 7))),
+            (Some(date(2001, 1, 5)), Some(date(2001, 1, 7))),
+        ];
+        let mut comments = Vec::new();
+        for lines in 0..(1 << LINES.len()) {
+            for tags in 0..(1 << TAGS.len()) {
+                for value_tags in 0..(1 << VALUE_TAGS.len()) {
+                    for (date, aux_date) in dates {
+                        let mut comment = Comment::new();
+                        comment.date = date;
+                        comment.aux_date = aux_date;
+                        for (i, line) in LINES.iter().enumerate() {
+                            if lines & (1 << i) != 0 {
+                                comment.lines.push(line.to_string());
+                            }
+                        }
+                        for (i, tag) in TAGS.iter().enumerate() {
+                            if tags & (1 << i) != 0 {
+                                comment.tags.insert((*tag).into());
+                            }
+                        }
+                        for (i, (k, v)) in VALUE_TAGS.iter().enumerate() {
+                            if value_tags & (1 << i) != 0 {
+                                comment.replace_value_tag(*k, *v);
+                            }
+                        }
+                        comments.push(comment);
+                    }
+                }
+            }
+        }
+        comments
+    }
+
+    #[test]
+    fn format_then_parse_round_trips() {
+        for want in combinations() {
+            let formatted = want.clone().into_opt_comment();
+            let got = Comment::from_opt_string(&formatted);
+            assert_eq!(got, want, "formatted as {:?}", formatted);
+        }
+    }
+
+    #[test]
+    fn parse_then_format_is_stable() {
+        const FRAGMENTS: &[&str] = &[
+            "text",
+            ":tag:",
+            ":a:b:",
+            "key: value",
+            "key:",
+            " : ",
+            "::",
+            "text :t: text",
+            "[2001/01/05]",
+            "[=2001/01/07]",
+            "key:\t",
+            "text:\r",
+        ];
+        for a in FRAGMENTS {
+            for b in FRAGMENTS {
+                for sep in [" ", "\n", "\r\n"] {
+                    let text = format!("{}{}{}", a, sep, b);
+                    let once = Comment::from_opt_comment(Some(&text));
+                    let twice = Comment::from_opt_string(&once.clone().into_opt_comment());
+                    assert_eq!(once, twice, "for {:?}", text);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn long_tags_are_text() {
+        let long = "x".repeat(MAX_TAG_LEN + 1);
+        let comment =
+            Comment::from_opt_comment(Some(&format!("a :{}: b\n{}: value\n:ok:", long, long)));
+        assert_eq!(
+            comment,
+            CommentBuilder::new()
+                .with_line(format!("a :{}: b", long))
+                .with_line(format!("{}: value", long))
+                .with_tag("ok")
+                .build()
+        );
+    }
+
+    #[test]
+    fn lines_beyond_limit_are_one_line() {
+        let text = format!("{}:tag: more\n  text", "line\n".repeat(MAX_LINES));
+        let comment = Comment::from_opt_comment(Some(&text));
+        assert_eq!(comment.lines.len(), MAX_LINES + 1);
+        assert_eq!(comment.lines.last().unwrap(), ":tag: more text");
+        assert!(comment.tags.is_empty());
+    }
+
+    #[test]
+    fn tags_with_prefix() {
+        let mut comment = CommentBuilder::new()
+            .with_tag("fp-1")
+            .with_tag("fp-2")
+            .with_tag("candidate-fp-3")
+            .build();
+
+        let mut got: Vec<&str> = comment.tags_with_prefix("fp-").collect();
+        got.sort();
+        assert_eq!(got, vec!["fp-1", "fp-2"]);
+
+        assert_eq!(comment.remove_tags_with_prefix("fp-"), 2);
+        assert_eq!(comment.remove_tags_with_prefix("fp-"), 0);
+        assert_eq!(
+            comment,
+            CommentBuilder::new().with_tag("candidate-fp-3").build()
+        );
+    }
+
+    #[test]
+    fn replace_value_tag() {
+        let mut comment = CommentBuilder::new().with_value_tag("key", "old").build();
+        assert_eq!(
+            comment.replace_value_tag("key", "new"),
+            Some("old".to_string())
+        );
+        assert_eq!(comment.replace_value_tag("other", "value"), None);
+        assert_eq!(
+            comment,
+            CommentBuilder::new()
+                .with_value_tag("key", "new")
+                .with_value_tag("other", "value")
+                .build()
+        );
+    }
+
     #[test]
     fn test_merge_comment() {
         let mut orig = CommentBuilder::new()
@@ -372,4 +765,18 @@ mod tests {
             orig,
         );
     }
+
+    #[test_case("comment text :tag: more\nkey: value\nother:" => Vec::<String>::new(); "valid")]
+    #[test_case("see https://example.com" => Vec::<String>::new(); "url")]
+    #[test_case("key:value" => vec![
+        r#""key:value" looks like a value tag, but is not of the form "key: value""#,
+    ]; "missing space")]
+    #[test_case("key : value" => vec![
+        r#""key : value" looks like a value tag, but is not of the form "key: value""#,
+    ]; "space before colon")]
+    #[test_case(":ta*g:" => vec![r#"tag "ta*g" has invalid characters"#]; "invalid tag")]
+    #[test_case("key: a\nkey: b" => vec![r#"duplicate value tag "key""#]; "duplicate value tag")]
+    fn lint(comment: &str) -> Vec<String> {
+        super::lint(comment)
+    }
 }
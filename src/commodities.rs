@@ -0,0 +1,247 @@
+//! Aliases of commodity names, so that amounts written with different names
+//! for the same commodity (e.g. `£` and `GBP`) compare equal when merging.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use clap::Args;
+use ledger_parser::{Amount, Balance, CommodityPrice, LedgerItem, Posting, Price, Transaction};
+
+use crate::filespec::{self, FileSpec};
+use crate::internal::TransactionPostings;
+use crate::ledgerutil::{Journal, JournalItem};
+
+/// An alternative name for a commodity.
+#[derive(Clone, Debug)]
+pub struct CommodityAlias {
+    alias: String,
+    commodity: String,
+}
+
+impl FromStr for CommodityAlias {
+    type Err = Error;
+
+    /// Parses from the form `ALIAS=COMMODITY`, e.g. `£=GBP`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (alias, commodity) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected ALIAS=COMMODITY, got {:?}", s))?;
+        let (alias, commodity) = (alias.trim(), commodity.trim());
+        if alias.is_empty() || commodity.is_empty() {
+            bail!("expected ALIAS=COMMODITY, got {:?}", s);
+        }
+        Ok(Self {
+            alias: alias.to_string(),
+            commodity: commodity.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct AliasOpts {
+    /// Renames commodity ALIAS to COMMODITY in amounts, prices and balance
+    /// assertions, given as ALIAS=COMMODITY (e.g. £=GBP). May be given
+    /// multiple times.
+    #[arg(long = "commodity-alias", value_name = "ALIAS=COMMODITY")]
+    aliases: Vec<CommodityAlias>,
+}
+
+impl AliasOpts {
+    /// Returns the commodity that each alias stands for.
+    fn aliases(&self) -> HashMap<&str, &str> {
+        self.aliases
+            .iter()
+            .map(|a| (a.alias.as_str(), a.commodity.as_str()))
+            .collect()
+    }
+
+    pub fn normalize_transactions(&self, trns: &mut [Transaction]) {
+        if self.aliases.is_empty() {
+            return;
+        }
+        let aliases = self.aliases();
+        for post in trns.iter_mut().flat_map(|trn| &mut trn.postings) {
+            normalize_posting(post, &aliases);
+        }
+    }
+
+    /// As `normalize_transactions`, but for transactions being merged.
+    pub fn normalize_transaction_postings(&self, trns: &mut [TransactionPostings]) {
+        if self.aliases.is_empty() {
+            return;
+        }
+        let aliases = self.aliases();
+        for post in trns.iter_mut().flat_map(|trn| &mut trn.posts) {
+            normalize_posting(&mut post.raw, &aliases);
+        }
+    }
+
+    pub fn normalize_prices(&self, prices: &mut [CommodityPrice]) {
+        let aliases = self.aliases();
+        for price in prices {
+            normalize_price(price, &aliases);
+        }
+    }
+
+    pub fn normalize_amount(&self, amount: &mut Amount) {
+        normalize_amount(amount, &self.aliases());
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Cmd {
+    /// The Ledger journals to update.
+    journals: Vec<FileSpec>,
+    #[command(flatten)]
+    aliases: AliasOpts,
+}
+
+impl Cmd {
+    pub fn run(&self) -> Result<()> {
+        if self.aliases.aliases.is_empty() {
+            bail!("no aliases given, see --commodity-alias");
+        }
+        filespec::check_single_stdin(&self.journals)?;
+        let aliases = self.aliases.aliases();
+        for ledger_file in &self.journals {
+            let mut ledger = filespec::read_ledger_file(ledger_file)?;
+            let count = normalize_ledger(&mut ledger, &aliases);
+            eprintln!("{}: renamed {} commodities", ledger_file, count);
+            filespec::write_ledger_file(ledger_file, &ledger)?;
+        }
+        Ok(())
+    }
+}
+
+/// Renames the aliased commodities in the transactions and commodity prices
+/// of `ledger`. Returns the number of amounts and prices renamed.
+fn normalize_ledger(ledger: &mut Journal, aliases: &HashMap<&str, &str>) -> usize {
+    let mut count = 0;
+    for item in &mut ledger.items {
+        let item = match item {
+            JournalItem::Ledger(item) => item,
+            JournalItem::Directive(_) => continue,
+        };
+        match item {
+            LedgerItem::Transaction(trn) => {
+                for post in &mut trn.postings {
+                    count += normalize_posting(post, aliases);
+                }
+            }
+            LedgerItem::CommodityPrice(price) => {
+                count += normalize_price(price, aliases);
+            }
+            _ => {}
+        }
+    }
+    count
+}
+
+/// Returns the number of amounts renamed.
+fn normalize_posting(post: &mut Posting, aliases: &HashMap<&str, &str>) -> usize {
+    let mut count = 0;
+    if let Some(amount) = &mut post.amount {
+        count += normalize_amount(&mut amount.amount, aliases) as usize;
+        for price in amount.price.iter_mut().chain(&mut amount.lot_price) {
+            match price {
+                Price::Unit(amount) | Price::Total(amount) => {
+                    count += normalize_amount(amount, aliases) as usize;
+                }
+            }
+        }
+    }
+    if let Some(Balance::Amount(balance)) = &mut post.balance {
+        count += normalize_amount(balance, aliases) as usize;
+    }
+    count
+}
+
+/// Returns 1 if the price directive was changed, otherwise 0.
+fn normalize_price(price: &mut CommodityPrice, aliases: &HashMap<&str, &str>) -> usize {
+    let mut changed = normalize_amount(&mut price.amount, aliases);
+    if let Some(commodity) = aliases.get(price.commodity_name.as_str()) {
+        price.commodity_name = commodity.to_string();
+        changed = true;
+    }
+    changed as usize
+}
+
+/// Returns true if the commodity was renamed.
+fn normalize_amount(amount: &mut Amount, aliases: &HashMap<&str, &str>) -> bool {
+    match aliases.get(amount.commodity.name.as_str()) {
+        Some(commodity) => {
+            amount.commodity.name = commodity.to_string();
+            true
+        }
+        None => false,
+    }
+}
+
+/// Returns true if the amounts are of the same quantity of the same
+/// commodity, regardless of whether the commodity is written before or after
+/// the quantity.
+pub fn amounts_equal(a: &Amount, b: &Amount) -> bool {
+    a.quantity == b.quantity && a.commodity.name == b.commodity.name
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test_case("£=GBP" => Some(("£".to_string(), "GBP".to_string())); "ok")]
+    #[test_case(" $ = USD " => Some(("$".to_string(), "USD".to_string())); "trimmed")]
+    #[test_case("GBP" => None; "no_equals")]
+    #[test_case("=GBP" => None; "empty_alias")]
+    fn parse_alias(s: &str) -> Option<(String, String)> {
+        s.parse::<CommodityAlias>()
+            .ok()
+            .map(|a| (a.alias, a.commodity))
+    }
+
+    #[test]
+    fn normalizes_amounts_prices_and_balances() {
+        let opts = AliasOpts {
+            aliases: vec!["£=GBP".parse().unwrap(), "€=EUR".parse().unwrap()],
+        };
+        let mut trns = parse_transaction_postings(
+            "
+            2001/01/02 description
+                assets:bank  £10.00 = £20.00
+                assets:euros  €11.00 @@ £10.00
+                assets:usd  USD1.00
+                expenses",
+        );
+        opts.normalize_transaction_postings(&mut trns);
+        let got = format!("{}", TransactionPostings::into_journal(trns));
+        for want in [
+            "assets:bank  GBP10.00 = GBP20.00",
+            "assets:euros  EUR11.00 @@ GBP10.00",
+            "assets:usd  USD1.00",
+        ] {
+            assert!(got.contains(want), "missing {:?} in:\n{}", want, got);
+        }
+    }
+
+    #[test]
+    fn normalize_ledger_counts_renames() {
+        let mut ledger = crate::ledgerutil::parse(
+            "P 2001/01/01 00:00:00 US$ £0.80\n\n2001/01/02 t\n  a  £10.00 = £20.00\n  b  US$5.00 @ £0.80\n  c  EUR1.00\n  d\n",
+        )
+        .expect("parse");
+        let aliases = HashMap::from([("£", "GBP"), ("US$", "USD")]);
+        assert_eq!(normalize_ledger(&mut ledger, &aliases), 5);
+        let got = format!("{}", ledger);
+        for want in [
+            "P 2001-01-01 00:00:00 USD GBP0.80",
+            "a  GBP10.00 = GBP20.00",
+            "b  USD5.00 @ GBP0.80",
+            "c  EUR1.00",
+        ] {
+            assert!(got.contains(want), "missing {:?} in:\n{}", want, got);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+//! Restricting commands to transactions within a range of dates.
+
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
+use clap::Args;
+
+use crate::internal::TransactionPostings;
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct DateFilter {
+    /// Only consider transactions dated on or after this date (YYYY-MM-DD).
+    #[arg(long = "since")]
+    pub(crate) since: Option<NaiveDate>,
+    /// Only consider transactions dated on or before this date (YYYY-MM-DD).
+    #[arg(long = "until")]
+    pub(crate) until: Option<NaiveDate>,
+}
+
+impl DateFilter {
+    pub fn contains(&self, trn: &TransactionPostings) -> bool {
+        let date = trn.trn.raw.date;
+        self.since.is_none_or(|since| date >= since) && self.until.is_none_or(|until| date <= until)
+    }
+
+    /// Calls `update` with only the transactions that the filter contains,
+    /// and restores the other transactions to their original positions
+    /// among the updated transactions, so that the order of a journal that
+    /// is not sorted by date is kept.
+    pub fn update<F>(
+        &self,
+        trns: Vec<TransactionPostings>,
+        update: F,
+    ) -> Result<Vec<TransactionPostings>>
+    where
+        F: FnOnce(Vec<TransactionPostings>) -> Result<Vec<TransactionPostings>>,
+    {
+        if self.since.is_none() && self.until.is_none() {
+            return update(trns);
+        }
+
+        let (included, excluded): (Vec<_>, Vec<_>) = trns
+            .into_iter()
+            .enumerate()
+            .partition(|(_, trn)| self.contains(trn));
+        let included_len = included.len();
+        let updated = update(included.into_iter().map(|(_, trn)| trn).collect())?;
+        if updated.len() != included_len {
+            bail!(
+                "--since and --until require the rules to keep the transactions, but {} became {}",
+                included_len,
+                updated.len()
+            );
+        }
+
+        let mut trns = updated;
+        for (i, trn) in excluded {
+            trns.insert(i, trn);
+        }
+        Ok(trns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    use test_case::test_case;
+
+    fn date(s: &str) -> Option<NaiveDate> {
+        Some(s.parse().unwrap())
+    }
+
+    #[test_case(None, None => vec!["a", "b", "c"]; "unbounded")]
+    #[test_case(date("2001-01-02"), None => vec!["b", "c"]; "since")]
+    #[test_case(None, date("2001-01-02") => vec!["a", "b"]; "until")]
+    #[test_case(date("2001-01-02"), date("2001-01-02") => vec!["b"]; "since and until")]
+    fn contains(since: Option<NaiveDate>, until: Option<NaiveDate>) -> Vec<String> {
+        let filter = DateFilter { since, until };
+        let trns = parse_transaction_postings(
+            "
+            2001/01/01 a
+                x  GBP1.00
+                y  GBP-1.00
+
+            2001/01/02 b
+                x  GBP1.00
+                y  GBP-1.00
+
+            2001/01/03 c
+                x  GBP1.00
+                y  GBP-1.00",
+        );
+        trns.into_iter()
+            .filter(|trn| filter.contains(trn))
+            .map(|trn| trn.trn.raw.description)
+            .collect()
+    }
+
+    #[test]
+    fn update_keeps_order() {
+        let filter = DateFilter {
+            since: date("2001-01-02"),
+            until: date("2001-01-02"),
+        };
+        let trns = parse_transaction_postings(
+            "
+            2001/01/03 c
+                x  GBP1.00
+                y  GBP-1.00
+
+            2001/01/02 b
+                x  GBP1.00
+                y  GBP-1.00
+
+            2001/01/01 a
+                x  GBP1.00
+                y  GBP-1.00
+
+            2001/01/02 d
+                x  GBP1.00
+                y  GBP-1.00",
+        );
+        let got: Vec<String> = filter
+            .update(trns, |mut trns| {
+                for trn in &mut trns {
+                    trn.trn.raw.description = trn.trn.raw.description.to_uppercase();
+                }
+                Ok(trns)
+            })
+            .expect("update")
+            .into_iter()
+            .map(|trn| trn.trn.raw.description)
+            .collect();
+        assert_eq!(got, vec!["c", "B", "a", "D"]);
+    }
+}
@@ -0,0 +1,164 @@
+//! Exporting journals to other formats, for analysis outside of Ledger.
+
+use std::io::Write;
+
+use anyhow::Result;
+use clap::{Args, Subcommand};
+use rust_decimal::Decimal;
+
+use crate::filespec::{self, FileSpec};
+use crate::internal::{JournalTransactions, PostingInternal, TransactionPostings};
+
+/// Subcommands for exporting journals.
+#[derive(Debug, Subcommand)]
+pub enum ExportCommand {
+    #[command(name = "csv")]
+    /// Writes a journal as CSV with one row per posting, with columns date,
+    /// description, account, amount, commodity and tags.
+    Csv(CsvCmd),
+}
+
+impl ExportCommand {
+    pub fn run(&self) -> Result<()> {
+        use ExportCommand::*;
+        match self {
+            Csv(cmd) => cmd.run(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct CsvCmd {
+    /// The Ledger journal to read.
+    journal: FileSpec,
+    /// The CSV file to write to (overwrites any existing file). "-" writes to
+    /// stdout.
+    #[arg(short = 'o', long = "output", default_value = "-")]
+    output: FileSpec,
+}
+
+impl CsvCmd {
+    pub fn run(&self) -> Result<()> {
+        // Commodity prices have no postings, so have no rows.
+        let trns = JournalTransactions::from(filespec::read_ledger_file(&self.journal)?).trns;
+        write_csv(&trns, self.output.writer()?)
+    }
+}
+
+/// Writes a row for each posting in `trns`. A posting without an amount is
+/// given the amount that balances its transaction, if the other postings are
+/// all in one commodity. The tags column lists the posting's flag tags and
+/// then its value tags (as `key: value`), separated by `; `.
+fn write_csv<W: Write>(trns: &[TransactionPostings], w: W) -> Result<()> {
+    let mut writer = csv::Writer::from_writer(w);
+    writer.write_record([
+        "date",
+        "description",
+        "account",
+        "amount",
+        "commodity",
+        "tags",
+    ])?;
+    for trn in trns {
+        let balancing = balancing_amount(&trn.posts);
+        for post in &trn.posts {
+            let (quantity, commodity) = match &post.raw.amount {
+                Some(amount) => (
+                    amount.amount.quantity.to_string(),
+                    amount.amount.commodity.name.clone(),
+                ),
+                None => match &balancing {
+                    Some((quantity, commodity)) => (quantity.to_string(), commodity.clone()),
+                    None => (String::new(), String::new()),
+                },
+            };
+            writer.write_record([
+                trn.trn.raw.date.to_string(),
+                trn.trn.raw.description.clone(),
+                post.raw.account.clone(),
+                quantity,
+                commodity,
+                format_tags(post),
+            ])?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Returns the amount that a single posting without an amount must have to
+/// balance the other postings, if they are all in one commodity.
+fn balancing_amount(posts: &[PostingInternal]) -> Option<(Decimal, String)> {
+    let (with_amount, without_amount): (Vec<_>, Vec<_>) =
+        posts.iter().partition(|post| post.raw.amount.is_some());
+    if without_amount.len() != 1 {
+        return None;
+    }
+    let mut commodity: Option<&str> = None;
+    let mut total = Decimal::ZERO;
+    for post in with_amount {
+        let amount = post.raw.amount.as_ref()?;
+        if amount.price.is_some() || amount.lot_price.is_some() {
+            return None;
+        }
+        let name = amount.amount.commodity.name.as_str();
+        if commodity.is_some_and(|c| c != name) {
+            return None;
+        }
+        commodity = Some(name);
+        total += amount.amount.quantity;
+    }
+    Some((-total, commodity?.to_string()))
+}
+
+fn format_tags(post: &PostingInternal) -> String {
+    let mut flag_tags: Vec<&str> = post.comment.tags.iter().map(|tag| tag.as_str()).collect();
+    flag_tags.sort();
+    let mut value_tags: Vec<String> = post
+        .comment
+        .value_tags
+        .iter()
+        .map(|(k, v)| format!("{}: {}", k, v))
+        .collect();
+    value_tags.sort();
+    flag_tags
+        .into_iter()
+        .map(str::to_string)
+        .chain(value_tags)
+        .collect::<Vec<_>>()
+        .join("; ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test]
+    fn writes_row_per_posting() {
+        let trns = parse_transaction_postings(
+            "
+            2001/01/02 Shop, Ltd
+                assets:bank  GBP-10.50  ; :fp-2:fp-1:
+                ; bank-ref: 123
+                expenses:food
+
+            2001/01/03 Exchange
+                assets:bank  GBP-10.00
+                assets:wallet  EUR11.00
+                equity:conversion",
+        );
+        let mut out = Vec::new();
+        write_csv(&trns, &mut out).expect("write_csv");
+
+        assert_eq!(
+            String::from_utf8(out).expect("utf-8"),
+            "date,description,account,amount,commodity,tags\n\
+             2001-01-02,\"Shop, Ltd\",assets:bank,-10.50,GBP,fp-1; fp-2; bank-ref: 123\n\
+             2001-01-02,\"Shop, Ltd\",expenses:food,10.50,GBP,\n\
+             2001-01-03,Exchange,assets:bank,-10.00,GBP,\n\
+             2001-01-03,Exchange,assets:wallet,11.00,EUR,\n\
+             2001-01-03,Exchange,equity:conversion,,,\n"
+        );
+    }
+}
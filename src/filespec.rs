@@ -1,14 +1,19 @@
 //! Functions to read and write text files. Allows use of "-" as a way to
 //! specify stdin or stdout.
 
+use std::borrow::Cow;
 use std::fmt;
 use std::fs::File;
 use std::io::{stdin, stdout, Read, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-use anyhow::{Context, Error, Result};
-use ledger_parser::Ledger;
+use anyhow::{bail, Context, Error, Result};
+use ledger_parser::{LedgerItem, ParseError};
+
+use crate::comment;
+use crate::ledgerutil::{self, Journal, JournalItem};
 
 /// Specifies a file to read from to write to (depending on context).
 #[derive(Clone, Debug)]
@@ -30,6 +35,14 @@ impl fmt::Display for FileSpec {
 }
 
 impl FileSpec {
+    /// Returns the path of the file, or `None` for stdin/stdout.
+    pub fn path(&self) -> Option<&std::path::Path> {
+        match self {
+            FileSpec::Stdio => None,
+            FileSpec::Path(path) => Some(path),
+        }
+    }
+
     pub fn reader(&self) -> Result<Box<dyn Read>> {
         use FileSpec::*;
         Ok(match self {
@@ -40,6 +53,17 @@ impl FileSpec {
         })
     }
 
+    /// Returns a reader of the file with `\r\n` line endings converted to
+    /// `\n`. The `csv` crate accepts either, but miscounts the line numbers of
+    /// records after `\r\n`.
+    pub fn lf_reader(&self) -> Result<Box<dyn Read>> {
+        let mut content = Vec::new();
+        self.reader()?
+            .read_to_end(&mut content)
+            .with_context(|| format!("reading {}", self))?;
+        Ok(Box::new(std::io::Cursor::new(crlf_to_lf(content))))
+    }
+
     pub fn writer(&self) -> Result<Box<dyn Write>> {
         use FileSpec::*;
         Ok(match self {
@@ -64,6 +88,54 @@ impl FromStr for FileSpec {
     }
 }
 
+fn crlf_to_lf(mut content: Vec<u8>) -> Vec<u8> {
+    let mut len = 0;
+    for i in 0..content.len() {
+        if content[i] == b'\r' && content.get(i + 1) == Some(&b'\n') {
+            continue;
+        }
+        content[len] = content[i];
+        len += 1;
+    }
+    content.truncate(len);
+    content
+}
+
+/// Converts a relative path written in a journal or rules file to one for
+/// this platform. Both `/` and `\` are accepted as separators, so that files
+/// written on Windows can be used elsewhere.
+pub fn portable_path(path: &Path) -> Cow<'_, Path> {
+    match path.to_str() {
+        Some(s) if !cfg!(windows) && s.contains('\\') => {
+            Cow::Owned(PathBuf::from(s.replace('\\', "/")))
+        }
+        _ => Cow::Borrowed(path),
+    }
+}
+
+/// Returns the canonical form of `path`. Unlike `std::fs::canonicalize`, on
+/// Windows this avoids `\\?\` paths where possible, as relative paths cannot
+/// be joined onto them.
+pub fn canonicalize(path: &Path) -> std::io::Result<PathBuf> {
+    dunce::canonicalize(path)
+}
+
+/// Errors if stdin is given more than once in `file_specs`, as it can only be
+/// read once.
+pub fn check_single_stdin<'a>(file_specs: impl IntoIterator<Item = &'a FileSpec>) -> Result<()> {
+    let count = file_specs
+        .into_iter()
+        .filter(|spec| matches!(spec, FileSpec::Stdio))
+        .count();
+    if count > 1 {
+        bail!(
+            "\"-\" (stdin) can only be given as an input once, got {}",
+            count
+        );
+    }
+    Ok(())
+}
+
 pub fn read_file(file_spec: &FileSpec) -> Result<String> {
     let mut f = file_spec.reader()?;
     let mut content = String::new();
@@ -71,18 +143,766 @@ pub fn read_file(file_spec: &FileSpec) -> Result<String> {
     Ok(content)
 }
 
-pub fn read_ledger_file(file_spec: &FileSpec) -> Result<Ledger> {
+/// An error parsing an entry (e.g. a transaction) of a Ledger file, with the
+/// location and text of the entry.
+#[derive(Debug)]
+pub struct LedgerParseError {
+    file: String,
+    /// Line number of the start of the entry, starting from 1.
+    line: usize,
+    /// The text of the entry.
+    entry: String,
+    source: ParseError,
+}
+
+impl fmt::Display for LedgerParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}: could not parse entry:", self.file, self.line)?;
+        for (i, line) in self.entry.trim_end().lines().enumerate() {
+            write!(f, "\n{:>5} | {}", self.line + i, line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for LedgerParseError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.source)
+    }
+}
+
+/// Options for reading Ledger files.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReadOptions {
+    /// Report and skip entries that fail to parse, instead of failing.
+    pub skip_bad: bool,
+    /// Fail on suspicious constructs in comments (see `comment::lint`), rather
+    /// than silently treating them as plain text.
+    pub strict: bool,
+}
+
+/// Reads a Ledger file according to `options`.
+pub fn read_ledger_file_with(file_spec: &FileSpec, options: ReadOptions) -> Result<Journal> {
     let content: String = read_file(file_spec)?;
-    ledger_parser::parse(&content).map_err(Into::into)
+    if options.strict {
+        let problems = lint_entries(file_spec, &content);
+        if !problems.is_empty() {
+            bail!(
+                "problems found in comments in {}:\n{}",
+                file_spec,
+                problems.join("\n")
+            );
+        }
+    }
+    if options.skip_bad {
+        parse_ledger_skipping_bad(file_spec, &content)
+    } else {
+        parse_ledger(file_spec, &content)
+    }
+}
+
+pub fn read_ledger_file(file_spec: &FileSpec) -> Result<Journal> {
+    read_ledger_file_with(file_spec, ReadOptions::default())
+}
+
+fn parse_ledger(file_spec: &FileSpec, content: &str) -> Result<Journal> {
+    match ledgerutil::parse(content) {
+        Ok(ledger) => Ok(ledger),
+        Err(e) => {
+            let err = parse_entries(file_spec, content)
+                .find_map(Result::err)
+                .map(Error::from)
+                .unwrap_or_else(|| Error::from(e).context(format!("parsing {}", file_spec)));
+            Err(err)
+        }
+    }
+}
+
+/// Parses a Ledger file, skipping any entries that fail to parse after
+/// reporting them as warnings.
+fn parse_ledger_skipping_bad(file_spec: &FileSpec, content: &str) -> Result<Journal> {
+    if let Ok(ledger) = ledgerutil::parse(content) {
+        return Ok(ledger);
+    }
+    let mut items = Vec::new();
+    for entry in parse_entries(file_spec, content) {
+        match entry {
+            Ok(journal) => items.extend(journal.items),
+            Err(e) => eprintln!("warning: skipping {}", e),
+        }
+    }
+    Ok(Journal { items })
+}
+
+/// Parses each entry of a Ledger file separately. An entry starts on each line
+/// that does not begin with whitespace.
+fn parse_entries<'a>(
+    file_spec: &'a FileSpec,
+    content: &'a str,
+) -> impl Iterator<Item = std::result::Result<Journal, LedgerParseError>> + 'a {
+    split_entries(content)
+        .into_iter()
+        .map(move |(line, entry)| {
+            ledgerutil::parse(entry).map_err(|source| LedgerParseError {
+                file: file_spec.to_string(),
+                line,
+                entry: entry.to_string(),
+                source,
+            })
+        })
+}
+
+/// Describes suspicious constructs in the transaction and posting comments of
+/// each entry, prefixed with the location of the entry.
+fn lint_entries(file_spec: &FileSpec, content: &str) -> Vec<String> {
+    let mut problems = Vec::new();
+    for (line, entry) in split_entries(content) {
+        let journal = match ledgerutil::parse(entry) {
+            Ok(journal) => journal,
+            // Reported when parsing the whole file.
+            Err(_) => continue,
+        };
+        for trn in journal.transactions() {
+            let location = format!("{}:{}: transaction {:?}", file_spec, line, trn.description);
+            for problem in trn
+                .comment
+                .as_deref()
+                .map(comment::lint)
+                .unwrap_or_default()
+            {
+                problems.push(format!("{}: {}", location, problem));
+            }
+            for post in &trn.postings {
+                for problem in post
+                    .comment
+                    .as_deref()
+                    .map(comment::lint)
+                    .unwrap_or_default()
+                {
+                    problems.push(format!(
+                        "{}: posting {:?}: {}",
+                        location, post.account, problem
+                    ));
+                }
+            }
+        }
+    }
+    problems
+}
+
+/// Splits Ledger file content into entries, along with the line number that
+/// each starts on.
+fn split_entries(content: &str) -> Vec<(usize, &str)> {
+    let mut entries = Vec::new();
+    let mut start = (1, 0);
+    let mut offset = 0;
+    for (i, line) in content.split_inclusive('\n').enumerate() {
+        let starts_entry = line.starts_with(|c: char| !c.is_whitespace());
+        if starts_entry && offset > start.1 {
+            entries.push((start.0, &content[start.1..offset]));
+            start = (i + 1, offset);
+        }
+        offset += line.len();
+    }
+    if offset > start.1 {
+        entries.push((start.0, &content[start.1..offset]));
+    }
+    entries
+}
+
+/// The line endings that text files are written with. Files are always
+/// read with either.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub enum LineEnding {
+    /// `\n`, as on Unix.
+    #[default]
+    Lf,
+    /// `\r\n`, as on Windows.
+    Crlf,
+    /// `Crlf` on Windows, otherwise `Lf`.
+    Native,
+}
+
+impl LineEnding {
+    fn is_crlf(self) -> bool {
+        match self {
+            LineEnding::Lf => false,
+            LineEnding::Crlf => true,
+            LineEnding::Native => cfg!(windows),
+        }
+    }
+}
+
+impl FromStr for LineEnding {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        use LineEnding::*;
+        match s {
+            "lf" => Ok(Lf),
+            "crlf" => Ok(Crlf),
+            "native" => Ok(Native),
+            _ => bail!("unknown line ending {:?}, want one of: lf, crlf, native", s),
+        }
+    }
+}
+
+static WRITE_CRLF: AtomicBool = AtomicBool::new(false);
+
+/// Sets the line endings used by `write_file`, `write_ledger_file` and
+/// `WriteBatch` for the rest of the process.
+pub fn set_line_ending(line_ending: LineEnding) {
+    WRITE_CRLF.store(line_ending.is_crlf(), Ordering::Relaxed);
+}
+
+/// Converts the line endings of `content` to `\r\n` if `crlf`, otherwise to
+/// `\n`.
+fn convert_line_endings(content: &str, crlf: bool) -> Cow<'_, str> {
+    let content = if content.contains("\r\n") {
+        Cow::Owned(content.replace("\r\n", "\n"))
+    } else {
+        Cow::Borrowed(content)
+    };
+    if crlf {
+        Cow::Owned(content.replace('\n', "\r\n"))
+    } else {
+        content
+    }
+}
+
+fn with_line_endings(content: &str) -> Cow<'_, str> {
+    convert_line_endings(content, WRITE_CRLF.load(Ordering::Relaxed))
 }
 
 pub fn write_file(file_spec: &FileSpec, content: &str) -> Result<()> {
     let mut f = file_spec.writer()?;
-    f.write_all(content.as_bytes())?;
+    f.write_all(with_line_endings(content).as_bytes())?;
     Ok(())
 }
 
-pub fn write_ledger_file(file_spec: &FileSpec, ledger: &Ledger) -> Result<()> {
-    let content: String = format!("{}", ledger);
-    write_file(file_spec, &content)
+pub fn write_ledger_file(file_spec: &FileSpec, journal: &Journal) -> Result<()> {
+    write_file(file_spec, &journal.to_string())
+}
+
+/// Checks that `content`, the formatting of `journal`, parses back into the
+/// same journal. This guards against formatting quirks silently changing
+/// amounts or comments. Comments are compared by their parsed tags and text,
+/// and blank lines are ignored.
+pub fn verify_roundtrip(journal: &Journal, content: &str) -> Result<()> {
+    let parsed = ledgerutil::parse(content)
+        .map_err(Error::from)
+        .context("formatted ledger does not parse")?;
+    let want = comparable_items(journal.items.iter().cloned());
+    let got = comparable_items(parsed.items);
+    for (i, (want, got)) in want.iter().zip(&got).enumerate() {
+        if want != got {
+            bail!(
+                "formatted ledger parses differently at item #{}:\nwant: {:?}\n got: {:?}",
+                i + 1,
+                want,
+                got
+            );
+        }
+    }
+    if want.len() != got.len() {
+        bail!(
+            "formatted ledger parses into {} items, want {}",
+            got.len(),
+            want.len()
+        );
+    }
+    Ok(())
+}
+
+/// Returns the items other than blank lines, with comments normalized.
+fn comparable_items(items: impl IntoIterator<Item = JournalItem>) -> Vec<JournalItem> {
+    let normalize = |text: &mut Option<String>| {
+        *text = comment::Comment::from_opt_string(text).into_opt_comment();
+    };
+    items
+        .into_iter()
+        .filter(|item| !matches!(item, JournalItem::Ledger(LedgerItem::EmptyLine)))
+        .map(|mut item| {
+            if let JournalItem::Ledger(LedgerItem::Transaction(trn)) = &mut item {
+                normalize(&mut trn.comment);
+                for post in &mut trn.postings {
+                    normalize(&mut post.comment);
+                }
+            }
+            item
+        })
+        .collect()
+}
+
+/// Files to be written together, so that a failure part way through leaves
+/// none of them changed rather than only some of them.
+///
+/// `commit` first writes each file's content to a temporary file next to it,
+/// and backs up any existing file, before renaming the temporary files into
+/// place. If a rename fails, the files already replaced are restored from
+/// their backups. Output to stdout is written last, as it cannot be undone.
+///
+/// A symlink is followed, so that the file it points to is replaced rather
+/// than the link. Files with several hard links are refused, as the rename
+/// would leave the other links with the old content.
+#[derive(Default)]
+pub struct WriteBatch {
+    files: Vec<(FileSpec, String)>,
+    verify_roundtrip: bool,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add(&mut self, file_spec: &FileSpec, content: String) {
+        self.files.push((file_spec.clone(), content));
+    }
+
+    /// Checks that each ledger added parses back to the same ledger, as for
+    /// `verify_roundtrip`.
+    pub fn with_roundtrip_verification(mut self, verify_roundtrip: bool) -> Self {
+        self.verify_roundtrip = verify_roundtrip;
+        self
+    }
+
+    pub fn add_ledger(&mut self, file_spec: &FileSpec, journal: &Journal) -> Result<()> {
+        self.add_formatted_ledger(file_spec, journal, journal.to_string())
+    }
+
+    /// Adds `content`, which is `journal` already formatted, e.g. because the
+    /// caller also needs the content.
+    pub fn add_formatted_ledger(
+        &mut self,
+        file_spec: &FileSpec,
+        journal: &Journal,
+        content: String,
+    ) -> Result<()> {
+        if self.verify_roundtrip {
+            verify_roundtrip(journal, &content)
+                .with_context(|| format!("verifying output to {}", file_spec))?;
+        }
+        self.add(file_spec, content);
+        Ok(())
+    }
+
+    pub fn commit(self) -> Result<()> {
+        let mut staged = Vec::new();
+        let mut stdout_content = Vec::new();
+        for (file_spec, content) in self.files {
+            match file_spec {
+                FileSpec::Stdio => stdout_content.push(content),
+                FileSpec::Path(path) => staged.push(StagedFile::new(path, &content)?),
+            }
+        }
+
+        replace_all(&mut staged)?;
+
+        for content in stdout_content {
+            write_file(&FileSpec::Stdio, &content)?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the file that `path` links to, if it is a symlink. Fails if the
+/// file has other hard links.
+fn resolve_link(path: PathBuf) -> Result<PathBuf> {
+    let metadata = match std::fs::symlink_metadata(&path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(path),
+        Err(e) => return Err(e).with_context(|| format!("reading metadata of {:?}", path)),
+    };
+    let path = if metadata.file_type().is_symlink() {
+        std::fs::canonicalize(&path)
+            .with_context(|| format!("following symlink {:?} to write it", path))?
+    } else {
+        path
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let links = std::fs::metadata(&path)
+            .with_context(|| format!("reading metadata of {:?}", path))?
+            .nlink();
+        if links > 1 {
+            bail!(
+                "refusing to replace {:?}, which has {} hard links that would keep the old content",
+                path,
+                links
+            );
+        }
+    }
+    Ok(path)
+}
+
+/// Moves the new content of each file into place, restoring those already
+/// replaced if one fails.
+fn replace_all(staged: &mut [StagedFile]) -> Result<()> {
+    for i in 0..staged.len() {
+        if let Err(err) = staged[i].replace() {
+            for file in staged[..i].iter().rev() {
+                if let Err(restore_err) = file.restore() {
+                    eprintln!("error: {:#}", restore_err);
+                }
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// New content for a file, written to a temporary file in the same directory,
+/// with a backup of the existing file (if any).
+struct StagedFile {
+    path: PathBuf,
+    temp: Option<tempfile::TempPath>,
+    backup: Option<tempfile::TempPath>,
+}
+
+impl StagedFile {
+    fn new(path: PathBuf, content: &str) -> Result<Self> {
+        let path = resolve_link(path)?;
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        let mut temp = tempfile::NamedTempFile::new_in(dir)
+            .with_context(|| format!("creating temporary file to write {:?}", path))?;
+        temp.write_all(with_line_endings(content).as_bytes())
+            .and_then(|_| temp.as_file().sync_all())
+            .with_context(|| format!("writing temporary file for {:?}", path))?;
+        let backup = if path.exists() {
+            let backup = tempfile::NamedTempFile::new_in(dir)
+                .with_context(|| format!("creating backup of {:?}", path))?
+                .into_temp_path();
+            // Copying also copies the permissions, which the new content
+            // should keep too.
+            std::fs::copy(&path, &backup).with_context(|| format!("backing up {:?}", path))?;
+            let permissions = std::fs::metadata(&path)?.permissions();
+            temp.as_file().set_permissions(permissions)?;
+            Some(backup)
+        } else {
+            // Temporary files are only readable by their owner, unlike files
+            // that are normally created.
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt;
+                temp.as_file()
+                    .set_permissions(std::fs::Permissions::from_mode(0o644))?;
+            }
+            None
+        };
+        Ok(Self {
+            path,
+            temp: Some(temp.into_temp_path()),
+            backup,
+        })
+    }
+
+    /// Moves the new content into place.
+    fn replace(&mut self) -> Result<()> {
+        if let Some(temp) = self.temp.take() {
+            temp.persist(&self.path)
+                .with_context(|| format!("replacing {:?}", self.path))?;
+        }
+        Ok(())
+    }
+
+    /// Restores the file as it was before `replace`.
+    fn restore(&self) -> Result<()> {
+        match &self.backup {
+            Some(backup) => std::fs::copy(backup, &self.path).map(|_| ()),
+            None => std::fs::remove_file(&self.path),
+        }
+        .with_context(|| format!("restoring {:?} after a failed write", self.path))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+
+    const CONTENT: &str = "2001/01/01 good
+  a  GBP1.00
+  b
+
+2001/01/02 bad
+  a  GBP1.00 GBP
+  b
+
+2001/01/03 good
+  a  GBP1.00
+  b
+";
+
+    fn write_temp(content: &str) -> (tempfile::TempDir, FileSpec) {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let path = dir.path().join("test.journal");
+        std::fs::write(&path, content).expect("write file");
+        (dir, FileSpec::Path(path))
+    }
+
+    #[test]
+    fn split_entries_by_line() {
+        let lines: Vec<usize> = split_entries(CONTENT).iter().map(|e| e.0).collect();
+        assert_eq!(lines, vec![1, 5, 9]);
+    }
+
+    #[test]
+    fn parse_error_has_location() {
+        let (_dir, file_spec) = write_temp(CONTENT);
+        let err = read_ledger_file(&file_spec).expect_err("should fail");
+        let parse_err = err
+            .downcast_ref::<LedgerParseError>()
+            .expect("LedgerParseError");
+        assert_eq!(parse_err.line, 5);
+        assert!(
+            parse_err.to_string().contains("    6 |   a  GBP1.00 GBP"),
+            "{}",
+            parse_err
+        );
+    }
+
+    #[test]
+    fn parser_panic_is_error() {
+        // ledger-parser panics rather than failing on this entry.
+        let (_dir, file_spec) = write_temp("2001/01/01 ! \rdescription\n  a  GBP1.00\n  b\n");
+        let err = read_ledger_file(&file_spec).expect_err("should fail");
+        assert!(
+            format!("{:#}", err).contains("malformed input"),
+            "{:#}",
+            err
+        );
+    }
+
+    #[test]
+    fn skip_bad_entries() {
+        let (_dir, file_spec) = write_temp(CONTENT);
+        let options = ReadOptions {
+            skip_bad: true,
+            ..Default::default()
+        };
+        let journal = read_ledger_file_with(&file_spec, options).expect("read");
+        let descriptions: Vec<&str> = journal
+            .transactions()
+            .map(|trn| trn.description.as_str())
+            .collect();
+        assert_eq!(descriptions, vec!["good", "good"]);
+    }
+
+    #[test]
+    fn strict_reports_comment_problems() {
+        let (_dir, file_spec) = write_temp(
+            "2001/01/01 first
+  a  GBP1.00
+  b
+
+2001/01/02 second
+  a  GBP1.00
+  ; key:value
+  b
+",
+        );
+        let options = ReadOptions {
+            strict: true,
+            ..Default::default()
+        };
+        let err = read_ledger_file_with(&file_spec, options).expect_err("should fail");
+        assert!(
+            err.to_string().contains(&format!(
+                r#"{}:5: transaction "second": posting "a": "key:value" looks like a value tag"#,
+                file_spec
+            )),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn reads_crlf_ledger() {
+        let content = "2001/01/01 good ; :trn-tag:\n  ; key: value\n  a  GBP1.00  ; :tag:\n  b\n";
+        let (_dir, lf) = write_temp(content);
+        let (_dir, crlf) = write_temp(&content.replace('\n', "\r\n"));
+        let want = read_ledger_file(&lf).expect("read lf");
+        let got = read_ledger_file(&crlf).expect("read crlf");
+        verify_roundtrip(&want, &format!("{}", got)).expect("same ledger");
+    }
+
+    #[test_case("a\nb\n", false => "a\nb\n"; "lf_to_lf")]
+    #[test_case("a\r\nb\n", false => "a\nb\n"; "mixed_to_lf")]
+    #[test_case("a\nb\n", true => "a\r\nb\r\n"; "lf_to_crlf")]
+    #[test_case("a\r\nb\n", true => "a\r\nb\r\n"; "mixed_to_crlf")]
+    fn convert_line_endings(content: &str, crlf: bool) -> String {
+        super::convert_line_endings(content, crlf).into_owned()
+    }
+
+    #[test_case(b"a\r\nb\rc\n\r" => b"a\nb\rc\n\r".to_vec(); "only_crlf")]
+    fn crlf_to_lf(content: &[u8]) -> Vec<u8> {
+        super::crlf_to_lf(content.to_vec())
+    }
+
+    #[test_case("lf" => Some(LineEnding::Lf); "lf")]
+    #[test_case("crlf" => Some(LineEnding::Crlf); "crlf")]
+    #[test_case("native" => Some(LineEnding::Native); "native")]
+    #[test_case("CR" => None; "unknown")]
+    fn parse_line_ending(s: &str) -> Option<LineEnding> {
+        s.parse().ok()
+    }
+
+    #[cfg(not(windows))]
+    #[test_case("sub/file.ron" => "sub/file.ron"; "unix")]
+    #[test_case("sub\\file.ron" => "sub/file.ron"; "windows")]
+    #[test_case("..\\lib/file.ron" => "../lib/file.ron"; "mixed")]
+    fn portable_path(path: &str) -> String {
+        super::portable_path(Path::new(path))
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    /// Relative paths should be joinable onto canonical paths, which is not
+    /// the case for Windows `\\?\` paths.
+    #[test]
+    fn canonicalize_then_join() {
+        let (dir, file_spec) = write_temp("");
+        std::fs::create_dir(dir.path().join("sub")).expect("create dir");
+        let canonical = canonicalize(file_spec.path().unwrap()).expect("canonicalize");
+        let joined = canonical
+            .parent()
+            .unwrap()
+            .join(super::portable_path(Path::new("sub\\..\\test.journal")));
+        assert_eq!(
+            canonicalize(&joined).expect("canonicalize joined"),
+            canonical
+        );
+    }
+
+    #[test]
+    fn check_single_stdin_rejects_repeated_stdin() {
+        let specs: Vec<FileSpec> = ["a.journal", "-", "b.journal"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        check_single_stdin(&specs).expect("single stdin");
+        let specs = vec![FileSpec::Stdio, FileSpec::Stdio];
+        assert!(check_single_stdin(&specs).is_err());
+    }
+
+    #[test]
+    fn verify_roundtrip_accepts_formatted_ledger() {
+        let journal = ledgerutil::parse(
+            "2001/01/01 good  ; :tag:
+  a  GBP1.00  ; key: value
+  b
+",
+        )
+        .expect("parse");
+        verify_roundtrip(&journal, &journal.to_string()).expect("verify");
+    }
+
+    #[test]
+    fn verify_roundtrip_rejects_lossy_formatting() {
+        let mut journal = ledgerutil::parse(
+            "2001/01/01 good
+  a  GBP1.00
+  b
+",
+        )
+        .expect("parse");
+        // A description containing a comment marker is formatted as a
+        // description followed by a comment.
+        let trn = journal.transactions_mut().next().expect("transaction");
+        trn.description = "good  ; not a comment".to_string();
+        let err = verify_roundtrip(&journal, &journal.to_string()).expect_err("should fail");
+        assert!(
+            err.to_string().contains("parses differently at item #1"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn write_batch_writes_all_files() {
+        let (dir, existing) = write_temp("old");
+        let new = FileSpec::Path(dir.path().join("new.journal"));
+        let mut batch = WriteBatch::new();
+        batch.add(&existing, "first".to_string());
+        batch.add(&new, "second".to_string());
+        batch.commit().expect("commit");
+
+        assert_eq!(read_file(&existing).expect("read"), "first");
+        assert_eq!(read_file(&new).expect("read"), "second");
+        let mut names: Vec<_> = std::fs::read_dir(dir.path())
+            .expect("read_dir")
+            .map(|entry| entry.expect("entry").file_name())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["new.journal", "test.journal"]);
+    }
+
+    #[test]
+    fn write_batch_restores_replaced_files() {
+        let (dir, existing) = write_temp("old");
+        let later = dir.path().join("later.journal");
+        let mut staged = vec![
+            StagedFile::new(existing.path().expect("path").to_owned(), "first").expect("stage"),
+            StagedFile::new(later.clone(), "second").expect("stage"),
+        ];
+        // Renaming over a non-empty directory fails.
+        std::fs::create_dir(&later).expect("create dir");
+        std::fs::write(later.join("file"), "").expect("write file");
+
+        replace_all(&mut staged).expect_err("should fail");
+        assert_eq!(read_file(&existing).expect("read"), "old");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_batch_follows_symlinks() {
+        let (dir, existing) = write_temp("old");
+        let link = dir.path().join("link.journal");
+        std::os::unix::fs::symlink(existing.path().expect("path"), &link).expect("symlink");
+        let mut batch = WriteBatch::new();
+        batch.add(&FileSpec::Path(link.clone()), "new".to_string());
+        batch.commit().expect("commit");
+
+        assert!(std::fs::symlink_metadata(&link)
+            .expect("metadata")
+            .file_type()
+            .is_symlink());
+        assert_eq!(read_file(&existing).expect("read"), "new");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_batch_refuses_hard_links() {
+        let (dir, existing) = write_temp("old");
+        let link = dir.path().join("link.journal");
+        std::fs::hard_link(existing.path().expect("path"), &link).expect("hard link");
+        let mut batch = WriteBatch::new();
+        batch.add(&FileSpec::Path(link), "new".to_string());
+        let err = batch.commit().expect_err("should fail");
+        assert!(err.to_string().contains("hard links"), "{}", err);
+        assert_eq!(read_file(&existing).expect("read"), "old");
+    }
+
+    #[test]
+    fn write_batch_failure_changes_nothing() {
+        let (dir, existing) = write_temp("old");
+        let unwritable = FileSpec::Path(dir.path().join("missing").join("new.journal"));
+        let mut batch = WriteBatch::new();
+        batch.add(&existing, "first".to_string());
+        batch.add(&unwritable, "second".to_string());
+        batch.commit().expect_err("should fail");
+
+        assert_eq!(read_file(&existing).expect("read"), "old");
+        assert_eq!(
+            std::fs::read_dir(dir.path()).expect("read_dir").count(),
+            1,
+            "temporary files should be removed"
+        );
+    }
 }
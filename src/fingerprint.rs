@@ -1,16 +1,255 @@
-use anyhow::{bail, Result};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
 use byteorder::{BigEndian, ByteOrder};
 use chrono::{Datelike, NaiveDate, NaiveTime, Timelike};
 use lazy_static::lazy_static;
 use ledger_parser::Amount;
 use regex::Regex;
 use sha1::{Digest, Sha1};
+use sha2::Sha256;
+use smol_str::SmolStr;
 
 use crate::tags;
 
-/// Returns `true` if the tag is a fingerprint.
-pub fn is_fingerprint(tag: &str) -> bool {
-    tag.starts_with(tags::FINGERPRINT_PREFIX)
+/// Returns the user namespace component of a fingerprint tag, or `None` if the
+/// tag is not a fingerprint.
+///
+/// This handles the current
+/// `fp-<algorithm>.<version>[.<hash spec>].<namespace>-<value>` format and the
+/// legacy `fp-<namespace>-<value>` format.
+pub fn user_namespace(tag: &str) -> Option<&str> {
+    if let Some(parts) = parse_tag(tag) {
+        return Some(parts.user_namespace);
+    }
+    let rest = tag.strip_prefix(tags::FINGERPRINT_PREFIX)?;
+    let (legacy_ns, _value) = rest.split_once('-')?;
+    Some(legacy_ns)
+}
+
+/// The components of a fingerprint tag in the current format.
+#[derive(Debug, PartialEq, Eq)]
+pub struct TagParts<'a> {
+    pub algorithm_name: &'a str,
+    pub algorithm_version: i64,
+    pub hash_spec: HashSpec,
+    pub user_namespace: &'a str,
+    pub value: &'a str,
+}
+
+/// Parses a fingerprint tag in the current format, as written by
+/// `Fingerprint::tag`. Returns `None` for other tags, including legacy
+/// fingerprint tags.
+pub fn parse_tag(tag: &str) -> Option<TagParts<'_>> {
+    let rest = tag.strip_prefix(tags::FINGERPRINT_PREFIX)?;
+    let (qualified_ns, value) = rest.split_once('-')?;
+    let mut parts = qualified_ns.split('.');
+    let algorithm_name = parts.next()?;
+    let algorithm_version = parts.next()?.parse().ok()?;
+    let (hash_spec, user_namespace) = match (parts.next()?, parts.next(), parts.next()) {
+        (user_namespace, None, _) => (HashSpec::default(), user_namespace),
+        (spec, Some(user_namespace), None) => (spec.parse().ok()?, user_namespace),
+        (_, Some(_), Some(_)) => return None,
+    };
+    Some(TagParts {
+        algorithm_name,
+        algorithm_version,
+        hash_spec,
+        user_namespace,
+        value,
+    })
+}
+
+/// Limits the fingerprint tags of each algorithm and user namespace in `tags`
+/// to `max`, keeping those of the newest algorithm versions. Tags that are not
+/// fingerprints in the current format are left unchanged. Returns the number
+/// of tags removed.
+pub fn limit_fingerprints(tags: &mut HashSet<SmolStr>, max: usize) -> usize {
+    let mut groups = HashMap::<(&str, &str), Vec<(i64, &SmolStr)>>::new();
+    for tag in tags.iter() {
+        if let Some(parts) = parse_tag(tag) {
+            groups
+                .entry((parts.algorithm_name, parts.user_namespace))
+                .or_default()
+                .push((parts.algorithm_version, tag));
+        }
+    }
+    let mut remove = Vec::new();
+    for group in groups.values_mut() {
+        if group.len() <= max {
+            continue;
+        }
+        // Newest version first, then by tag so that the result is stable.
+        group.sort_by(|(a_version, a_tag), (b_version, b_tag)| {
+            b_version.cmp(a_version).then_with(|| a_tag.cmp(b_tag))
+        });
+        remove.extend(group.drain(max..).map(|(_, tag)| tag.clone()));
+    }
+    for tag in &remove {
+        tags.remove(tag);
+    }
+    remove.len()
+}
+
+/// The hash function that fingerprint values are digests of.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// SHA-1, as used by all fingerprints before hash specs existed.
+    #[default]
+    Sha1,
+    /// SHA-256.
+    Sha256,
+}
+
+impl HashAlgorithm {
+    fn name(self) -> &'static str {
+        use HashAlgorithm::*;
+        match self {
+            Sha1 => "sha1",
+            Sha256 => "sha256",
+        }
+    }
+}
+
+/// How the hash value of a fingerprint is written in its tag.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// Standard base64 without padding.
+    #[default]
+    Base64,
+    /// Lowercase RFC 4648 base32 without padding.
+    Base32,
+    /// Lowercase hexadecimal.
+    Hex,
+}
+
+impl Encoding {
+    fn name(self) -> &'static str {
+        use Encoding::*;
+        match self {
+            Base64 => "b64",
+            Base32 => "b32",
+            Hex => "hex",
+        }
+    }
+
+    fn encode(self, bytes: &[u8]) -> String {
+        use Encoding::*;
+        match self {
+            Base64 => base64::display::Base64Display::new(
+                bytes,
+                &base64::engine::general_purpose::STANDARD_NO_PAD,
+            )
+            .to_string(),
+            Base32 => encode_base32(bytes),
+            Hex => bytes.iter().map(|b| format!("{:02x}", b)).collect(),
+        }
+    }
+}
+
+fn encode_base32(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+    let mut out = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in bytes {
+        buffer = (buffer << 8) | u32::from(b);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+/// The hash algorithm of fingerprint values, their encoding, and the number
+/// of characters of the encoded hash to keep. Shorter fingerprints are less
+/// cluttered, at the cost of a greater chance of collisions.
+///
+/// Written as the encoding name, optionally preceded by the algorithm and
+/// `_`, and optionally followed by `_` and the length, e.g. `b64` (the
+/// default, of SHA-1 hashes), `b32_16`, `hex_12` or `sha256_b32_16`. Any hash
+/// spec other than the default is included in fingerprint tags, so that
+/// fingerprints generated with different specs are distinct.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HashSpec {
+    algorithm: HashAlgorithm,
+    encoding: Encoding,
+    length: Option<usize>,
+}
+
+impl HashSpec {
+    fn encode(self, bytes: &[u8]) -> String {
+        let mut value = self.encoding.encode(bytes);
+        if let Some(length) = self.length {
+            value.truncate(length);
+        }
+        value
+    }
+}
+
+impl fmt::Display for HashSpec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.algorithm != HashAlgorithm::default() {
+            write!(f, "{}_", self.algorithm.name())?;
+        }
+        f.write_str(self.encoding.name())?;
+        if let Some(length) = self.length {
+            write!(f, "_{}", length)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for HashSpec {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut parts = s.split('_').peekable();
+        let algorithm = [HashAlgorithm::Sha1, HashAlgorithm::Sha256]
+            .into_iter()
+            .find(|algorithm| parts.peek() == Some(&algorithm.name()));
+        if algorithm.is_some() {
+            parts.next();
+        }
+        let name = parts.next().unwrap_or_default();
+        let length = parts.next();
+        if parts.next().is_some() {
+            bail!("invalid fingerprint hash spec {:?}", s);
+        }
+        use Encoding::*;
+        let encoding = [Base64, Base32, Hex]
+            .into_iter()
+            .find(|encoding| encoding.name() == name)
+            .ok_or_else(|| {
+                anyhow!(
+                    "unknown fingerprint encoding {:?} in {:?}, expected one of b64, b32 or hex, optionally preceded by sha1_ or sha256_",
+                    name,
+                    s
+                )
+            })?;
+        let length = length
+            .map(|length| match length.parse::<usize>() {
+                Ok(length) if length > 0 => Ok(length),
+                _ => Err(anyhow!(
+                    "invalid fingerprint length {:?} in {:?}, expected a positive integer",
+                    length,
+                    s
+                )),
+            })
+            .transpose()?;
+        Ok(Self {
+            algorithm: algorithm.unwrap_or_default(),
+            encoding,
+            length,
+        })
+    }
 }
 
 pub trait Fingerprintable {
@@ -20,28 +259,41 @@ pub trait Fingerprintable {
 pub struct Fingerprint {
     algorithm_name: &'static str,
     algorithm_version: i64,
+    hash_spec: HashSpec,
     user_namespace: String,
-    value: String,
+    digest: Vec<u8>,
+    /// The SHA-1 digest, for the legacy tag.
+    legacy_digest: Vec<u8>,
 }
 
 impl Fingerprint {
+    /// Returns the tag in the legacy format, which always uses the default
+    /// hash spec so that it matches tags generated before hash specs existed.
     pub fn legacy_tag(&self) -> String {
         format!(
             "{}{}-{}",
             tags::FINGERPRINT_PREFIX,
             self.user_namespace,
-            self.value,
+            HashSpec::default().encode(&self.legacy_digest),
         )
     }
 
     pub fn tag(&self) -> String {
+        // The default hash spec is left out, so that tags are unchanged from
+        // before hash specs existed.
+        let hash_spec = if self.hash_spec == HashSpec::default() {
+            String::new()
+        } else {
+            format!("{}.", self.hash_spec)
+        };
         format!(
-            "{}{}.{}.{}-{}",
+            "{}{}.{}.{}{}-{}",
             tags::FINGERPRINT_PREFIX,
             self.algorithm_name,
             self.algorithm_version,
+            hash_spec,
             self.user_namespace,
-            self.value,
+            self.hash_spec.encode(&self.digest),
         )
     }
 }
@@ -52,13 +304,17 @@ pub struct FingerprintBuilder {
     acc: Accumulator,
     algorithm_name: &'static str,
     algorithm_version: i64,
+    hash_spec: HashSpec,
     user_namespace: String,
 }
 
 impl FingerprintBuilder {
+    /// Creates a builder for fingerprints of the given algorithm, hashed and
+    /// encoded as given by `hash_spec`.
     pub fn new(
         algorithm_name: &'static str,
         algorithm_version: i64,
+        hash_spec: HashSpec,
         user_namespace: &str,
     ) -> Result<Self> {
         lazy_static! {
@@ -68,7 +324,7 @@ impl FingerprintBuilder {
             static ref VALID_FP_PART_RX: Regex = Regex::new(r#"^[a-zA-Z0-9_+/]*$"#).unwrap();
         }
 
-        if !VALID_FP_PART_RX.is_match(&algorithm_name) {
+        if !VALID_FP_PART_RX.is_match(algorithm_name) {
             bail!(
                 "fingerprint algorithm name {:?} must match regex {:?}",
                 algorithm_name,
@@ -84,19 +340,23 @@ impl FingerprintBuilder {
         }
 
         Ok(Self {
-            acc: Accumulator::new(),
+            acc: Accumulator::with_algorithm(hash_spec.algorithm),
             algorithm_name,
             algorithm_version,
+            hash_spec,
             user_namespace: user_namespace.to_string(),
         })
     }
 
     pub fn build(self) -> Fingerprint {
+        let (digest, legacy_digest) = self.acc.into_digests();
         Fingerprint {
             algorithm_name: self.algorithm_name,
             algorithm_version: self.algorithm_version,
+            hash_spec: self.hash_spec,
             user_namespace: self.user_namespace,
-            value: self.acc.into_base64(),
+            digest,
+            legacy_digest,
         }
     }
 
@@ -108,6 +368,7 @@ impl FingerprintBuilder {
             acc: v.fingerprint(self.acc),
             algorithm_name: self.algorithm_name,
             algorithm_version: self.algorithm_version,
+            hash_spec: self.hash_spec,
             user_namespace: self.user_namespace,
         }
     }
@@ -117,28 +378,50 @@ impl FingerprintBuilder {
 ///
 /// This does *not* write length prefixes, unlike `FingerprintBuilder`, but is
 /// used *by* `FingerprintBuilder`.
+///
+/// A SHA-1 hash is always accumulated, as legacy fingerprint tags use it
+/// whatever the hash algorithm of the fingerprint.
 #[derive(Debug, Clone)]
 pub struct Accumulator {
-    hasher: Sha1,
+    sha1: Sha1,
+    sha256: Option<Sha256>,
 }
 
 impl Accumulator {
+    /// Creates an accumulator of a SHA-1 hash.
     pub fn new() -> Self {
+        Self::with_algorithm(HashAlgorithm::Sha1)
+    }
+
+    pub fn with_algorithm(algorithm: HashAlgorithm) -> Self {
         Self {
-            hasher: Sha1::new(),
+            sha1: Sha1::new(),
+            sha256: match algorithm {
+                HashAlgorithm::Sha1 => None,
+                HashAlgorithm::Sha256 => Some(Sha256::new()),
+            },
         }
     }
 
     pub fn into_base64(self) -> String {
-        base64::display::Base64Display::new(
-            &self.hasher.finalize(),
-            &base64::engine::general_purpose::STANDARD_NO_PAD,
-        )
-        .to_string()
+        Encoding::Base64.encode(&self.into_digests().0)
+    }
+
+    /// Returns the digest of the accumulator's algorithm, and the SHA-1
+    /// digest.
+    fn into_digests(self) -> (Vec<u8>, Vec<u8>) {
+        let sha1 = self.sha1.finalize().to_vec();
+        match self.sha256 {
+            Some(sha256) => (sha256.finalize().to_vec(), sha1),
+            None => (sha1.clone(), sha1),
+        }
     }
 
     fn add_bytes(&mut self, v: &[u8]) {
-        self.hasher.update(v);
+        self.sha1.update(v);
+        if let Some(sha256) = &mut self.sha256 {
+            sha256.update(v);
+        }
     }
 
     pub fn with<T>(self, v: T) -> Self
@@ -275,3 +558,86 @@ impl Fingerprintable for NaiveTime {
             .with(self.nanosecond())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    #[test_case("b64"; "base64")]
+    #[test_case("b32_16"; "base32 truncated")]
+    #[test_case("hex"; "hex")]
+    #[test_case("hex_12"; "hex truncated")]
+    #[test_case("sha256_b64"; "sha256")]
+    #[test_case("sha256_hex_12"; "sha256 hex truncated")]
+    fn hash_spec_round_trip(s: &str) {
+        let spec: HashSpec = s.parse().expect("parse");
+        assert_eq!(spec.to_string(), s);
+    }
+
+    #[test_case("b16"; "unknown encoding")]
+    #[test_case("hex_0"; "zero length")]
+    #[test_case("hex_x"; "bad length")]
+    #[test_case("md5_hex"; "unknown algorithm")]
+    #[test_case("sha256"; "algorithm only")]
+    #[test_case("sha256_hex_12_3"; "trailing part")]
+    fn hash_spec_invalid(s: &str) {
+        assert!(s.parse::<HashSpec>().is_err());
+    }
+
+    #[test_case(b"" => ""; "empty")]
+    #[test_case(b"f" => "my"; "one byte")]
+    #[test_case(b"foobar" => "mzxw6ytboi"; "rfc 4648")]
+    fn base32(bytes: &[u8]) -> String {
+        encode_base32(bytes)
+    }
+
+    #[test_case("b64" => "fp-alg.1.ns-zmb3e98gNaO2u5kA+wkYpUBzfyE"; "default")]
+    #[test_case("b32_16" => "fp-alg.1.b32_16.ns-zztpo667ea22hnv3"; "base32 truncated")]
+    #[test_case("hex_12" => "fp-alg.1.hex_12.ns-ce66f77bdf20"; "hex truncated")]
+    #[test_case("sha1_hex_12" => "fp-alg.1.hex_12.ns-ce66f77bdf20"; "explicit sha1")]
+    #[test_case("sha256_hex_12" => "fp-alg.1.sha256_hex_12.ns-635137b596c8"; "sha256 hex truncated")]
+    fn tag_round_trip(spec: &str) -> String {
+        let spec: HashSpec = spec.parse().expect("parse");
+        let tag = FingerprintBuilder::new("alg", 1, spec, "ns")
+            .expect("new")
+            .with("value")
+            .build()
+            .tag();
+        let parts = parse_tag(&tag).expect("parse_tag");
+        assert_eq!(parts.algorithm_name, "alg");
+        assert_eq!(parts.algorithm_version, 1);
+        assert_eq!(parts.hash_spec, spec);
+        assert_eq!(parts.user_namespace, "ns");
+        assert_eq!(user_namespace(&tag), Some("ns"));
+        tag
+    }
+
+    #[test_case(&["fp-a.1.ns-x", "fp-a.2.ns-y", "fp-a.3.ns-z"], 1 => vec!["fp-a.3.ns-z"]; "keeps newest")]
+    #[test_case(&["fp-a.1.ns-x", "fp-a.1.hex_4.ns-y", "fp-a.2.ns-z"], 2 => vec!["fp-a.1.hex_4.ns-y", "fp-a.2.ns-z"]; "hash specs")]
+    #[test_case(&["fp-a.1.ns-x", "fp-a.2.ns-y", "fp-b.1.ns-z", "fp-a.1.other-w"], 1 => vec!["fp-a.1.other-w", "fp-a.2.ns-y", "fp-b.1.ns-z"]; "per algorithm and namespace")]
+    #[test_case(&["fp-ns-x", "fp-ns-y", "fp-a.1.ns-z", "other"], 1 => vec!["fp-a.1.ns-z", "fp-ns-x", "fp-ns-y", "other"]; "ignores legacy and other tags")]
+    fn limit_fingerprints(tags: &[&str], max: usize) -> Vec<String> {
+        let mut tags: HashSet<SmolStr> = tags.iter().map(|&tag| tag.into()).collect();
+        let before = tags.len();
+        let removed = super::limit_fingerprints(&mut tags, max);
+        assert_eq!(removed, before - tags.len());
+        let mut got: Vec<String> = tags.into_iter().map(String::from).collect();
+        got.sort();
+        got
+    }
+
+    #[test]
+    fn legacy_tag_ignores_hash_spec() {
+        let build = |spec: &str| {
+            FingerprintBuilder::new("alg", 1, spec.parse().expect("parse"), "ns")
+                .expect("new")
+                .with("value")
+                .build()
+                .legacy_tag()
+        };
+        assert_eq!(build("hex_12"), build("b64"));
+        assert_eq!(build("sha256_hex_12"), build("b64"));
+    }
+}
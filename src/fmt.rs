@@ -1,21 +1,27 @@
 use anyhow::Result;
 use clap::Args;
 
-use crate::filespec::{self, FileSpec};
+use crate::filespec::{self, FileSpec, WriteBatch};
 
 #[derive(Debug, Args)]
 pub struct Cmd {
     /// The Ledger journals to format.
     journals: Vec<FileSpec>,
+    /// Check that each formatted journal parses back into the same
+    /// transactions, and fail without writing any journal if not.
+    #[arg(long = "verify-roundtrip")]
+    verify_roundtrip: bool,
 }
 
 impl Cmd {
     pub fn run(&self) -> Result<()> {
+        filespec::check_single_stdin(&self.journals)?;
+        let mut batch = WriteBatch::new().with_roundtrip_verification(self.verify_roundtrip);
         for ledger_file in &self.journals {
             let ledger = filespec::read_ledger_file(ledger_file)?;
-            filespec::write_ledger_file(ledger_file, &ledger)?;
+            batch.add_ledger(ledger_file, &ledger)?;
         }
 
-        Ok(())
+        batch.commit()
     }
 }
@@ -1,50 +1,270 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
 use anyhow::Result;
 
 use clap::Args;
 
 use crate::filespec::{self, FileSpec};
-use crate::fingerprint;
-use crate::internal::TransactionPostings;
+use crate::fingerprint::Accumulator;
+use crate::internal::{JournalTransactions, TransactionPostings};
 use crate::tags;
 
 #[derive(Debug, Args)]
 pub struct Cmd {
     /// The Ledger journals to update.
     journals: Vec<FileSpec>,
+
+    /// Report what would change, but write nothing.
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+
+    /// Write the report of what changed to this file, instead of to stderr.
+    #[arg(long = "report")]
+    report: Option<FileSpec>,
+
+    /// Also add a fingerprint to each transaction without one, which is a
+    /// hash of its date, description and postings. Merging matches
+    /// transactions by it when none of their postings match, e.g. when
+    /// merging two copies of a hand-written journal.
+    #[arg(long = "transaction-fingerprints")]
+    transaction_fingerprints: bool,
 }
 
 impl Cmd {
     pub fn run(&self) -> Result<()> {
+        filespec::check_single_stdin(&self.journals)?;
+        let mut reports = String::new();
         for ledger_file in &self.journals {
-            let ledger = filespec::read_ledger_file(ledger_file)?;
-            let mut trns = TransactionPostings::from_ledger(ledger)?;
-            update_transactions(&mut trns);
-            let ledger = TransactionPostings::into_ledger(trns);
-            filespec::write_ledger_file(ledger_file, &ledger)?;
+            let mut contents = JournalTransactions::from(filespec::read_ledger_file(ledger_file)?);
+            let report = update_transactions(&mut contents.trns, self.transaction_fingerprints);
+            reports.push_str(&format!("{}: {}", ledger_file, report));
+            if !self.dry_run {
+                filespec::write_ledger_file(ledger_file, &contents.into_journal())?;
+            }
         }
 
+        match &self.report {
+            Some(report_file) => filespec::write_file(report_file, &reports)?,
+            None => eprint!("{}", reports),
+        }
         Ok(())
     }
 }
 
-fn update_transactions(trns: &mut Vec<TransactionPostings>) {
+/// Counts of postings by whether they were already fingerprinted.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Counts {
+    /// Postings that already had a fingerprint.
+    existing: usize,
+    /// Postings that had a fingerprint added.
+    added: usize,
+}
+
+impl Counts {
+    fn record(&mut self, added: bool) {
+        if added {
+            self.added += 1;
+        } else {
+            self.existing += 1;
+        }
+    }
+}
+
+impl fmt::Display for Counts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} added, {} already fingerprinted",
+            self.added, self.existing
+        )
+    }
+}
+
+/// What `update_transactions` changed in a journal.
+#[derive(Debug, Default)]
+struct Report {
+    total: Counts,
+    by_account: BTreeMap<String, Counts>,
+    /// Transactions, if transaction fingerprints were requested.
+    transactions: Option<Counts>,
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "{}", self.total)?;
+        for (account, counts) in &self.by_account {
+            writeln!(f, "  {}: {}", account, counts)?;
+        }
+        if let Some(counts) = &self.transactions {
+            writeln!(f, "  transactions: {}", counts)?;
+        }
+        Ok(())
+    }
+}
+
+fn update_transactions(trns: &mut Vec<TransactionPostings>, transaction_fps: bool) -> Report {
+    let mut report = Report::default();
+    if transaction_fps {
+        report.transactions = Some(Counts::default());
+    }
     for trn in trns {
+        if let Some(counts) = &mut report.transactions {
+            let add = trn
+                .trn
+                .comment
+                .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+                .next()
+                .is_none();
+            if add {
+                let fp = transaction_fingerprint(trn);
+                trn.trn.comment.tags.insert(fp.into());
+            }
+            counts.record(add);
+        }
         for post in &mut trn.posts {
-            if !post
+            let add = post
                 .comment
-                .tags
-                .iter()
-                .map(String::as_str)
-                .any(fingerprint::is_fingerprint)
-            {
+                .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+                .next()
+                .is_none();
+            if add {
                 // The post has no existing fingerprint tag. Add a
                 // randomly generated one as requested.
-                post.comment.tags.insert(format!(
-                    "{}uuidb64-{}",
-                    tags::FINGERPRINT_PREFIX,
-                    uuid_b64::UuidB64::new().to_istring()
-                ));
+                post.comment.tags.insert(
+                    format!(
+                        "{}uuidb64-{}",
+                        tags::FINGERPRINT_PREFIX,
+                        uuid_b64::UuidB64::new().to_istring()
+                    )
+                    .into(),
+                );
             }
+            report.total.record(add);
+            report
+                .by_account
+                .entry(post.raw.account.clone())
+                .or_default()
+                .record(add);
         }
     }
+    report
+}
+
+/// Returns a transaction fingerprint tag for the transaction, which does not
+/// depend on the order of its postings.
+fn transaction_fingerprint(trn: &TransactionPostings) -> String {
+    let mut posts: Vec<String> = trn
+        .posts
+        .iter()
+        .map(|post| match &post.raw.amount {
+            Some(amount) => format!("{} {}", post.raw.account, amount.amount),
+            None => post.raw.account.clone(),
+        })
+        .collect();
+    posts.sort();
+    let mut acc = Accumulator::new()
+        .with(trn.trn.raw.date)
+        .with(trn.trn.raw.description.as_str())
+        .with(posts.len());
+    for post in &posts {
+        acc = acc.with(post.as_str());
+    }
+    format!(
+        "{}{}",
+        tags::TRANSACTION_FINGERPRINT_PREFIX,
+        acc.into_base64()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test]
+    fn reports_added_fingerprints() {
+        let mut trns = parse_transaction_postings(
+            "
+            2001/01/01 foo
+                assets:bank  GBP-10.00  ; :fp-1:
+                expenses:food  GBP10.00
+
+            2001/01/02 bar
+                assets:bank  GBP-5.00
+                expenses:food  GBP5.00",
+        );
+        let report = update_transactions(&mut trns, false);
+
+        assert_eq!(
+            report.total,
+            Counts {
+                existing: 1,
+                added: 3
+            }
+        );
+        assert_eq!(
+            report.by_account["assets:bank"],
+            Counts {
+                existing: 1,
+                added: 1
+            }
+        );
+        assert_eq!(
+            report.to_string(),
+            "3 added, 1 already fingerprinted\n  \
+             assets:bank: 1 added, 1 already fingerprinted\n  \
+             expenses:food: 2 added, 0 already fingerprinted\n"
+        );
+        assert!(trns.iter().flat_map(|trn| &trn.posts).all(|post| post
+            .comment
+            .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+            .next()
+            .is_some()));
+    }
+
+    #[test]
+    fn adds_transaction_fingerprints() {
+        let mut trns = parse_transaction_postings(
+            "
+            2001/01/01 foo
+                assets:bank  GBP-10.00
+                expenses:food  GBP10.00
+
+            2001/01/01 foo
+                expenses:food  GBP10.00
+                assets:bank  GBP-10.00
+
+            2001/01/01 foo
+                assets:bank  GBP-11.00
+                expenses:food  GBP11.00
+
+            2001/01/02 bar
+                ; :trn-fp-existing:
+                assets:bank  GBP-5.00
+                expenses:food  GBP5.00",
+        );
+        let report = update_transactions(&mut trns, true);
+        assert_eq!(
+            report.transactions,
+            Some(Counts {
+                existing: 1,
+                added: 3
+            })
+        );
+        let fps: Vec<Vec<&str>> = trns
+            .iter()
+            .map(|trn| {
+                trn.trn
+                    .comment
+                    .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+                    .collect()
+            })
+            .collect();
+        assert_eq!(fps[0].len(), 1);
+        // The order of the postings does not matter, but their amounts do.
+        assert_eq!(fps[0], fps[1]);
+        assert_ne!(fps[0], fps[2]);
+        assert_eq!(fps[3], vec!["trn-fp-existing"]);
+    }
 }
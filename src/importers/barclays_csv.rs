@@ -0,0 +1,292 @@
+use std::path::Path;
+
+use anyhow::{bail, Context, Result};
+use chrono::NaiveDate;
+use clap::Args;
+use ledger_parser::{Amount, Posting, Reality, Transaction};
+
+use crate::accounts::ASSETS_UNKNOWN;
+use crate::comment::Comment;
+use crate::filespec::FileSpec;
+use crate::fingerprint::{FingerprintBuilder, HashSpec};
+use crate::importers::importer::TransactionImporter;
+use crate::importers::registry::Registry;
+use crate::importers::util::date::DateFormatOpts;
+use crate::importers::util::{self_and_peer_account_amount, self_and_peer_fingerprints};
+use crate::ledgerutil::simple_posting_amount;
+use crate::money::parse_gbp;
+use crate::tags;
+
+use super::importer::{Import, Metadata};
+
+const BANK_NAME: &str = "Barclays";
+
+/// Subcategory field, provided by the bank (e.g. "PAYMENT", "DIRECTDEBIT").
+const SUBCATEGORY_TAG: &str = "subcategory";
+
+#[derive(Debug, Args)]
+/// Converts from Barclays CSV format to Ledger transactions.
+pub struct BarclaysCsv {
+    /// Barclays CSV file to read from. "-" reads from stdin.
+    input: FileSpec,
+    /// User namespace of the fingerprints to generate. Defaults to the sort
+    /// code and account number in the CSV file, e.g. "203206_12345678".
+    #[arg(long = "fingerprint-namespace")]
+    fp_ns: Option<String>,
+    /// Hash algorithm, encoding and length of fingerprint hashes, e.g. "b64"
+    /// (the default), "b32_16", "hex_12" or "sha256_b64".
+    #[arg(long = "fingerprint-hash", default_value = "b64")]
+    fp_hash: HashSpec,
+    #[command(flatten)]
+    date_format: DateFormatOpts,
+}
+
+impl TransactionImporter for BarclaysCsv {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
+        let mut csv_rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(self.input.lf_reader()?);
+        let headers = csv_rdr.headers()?.clone();
+
+        let mut records = Vec::new();
+        for row in csv_rdr.records() {
+            let row = row?;
+            let line = row.position().map(|pos| pos.line());
+            let record: de::Record = row
+                .deserialize(Some(&headers))
+                .with_context(|| format!("parsing CSV line {}", line.unwrap_or_default()))?;
+            records.push(Record::from_csv_record(record, line, &self.date_format)?);
+        }
+
+        let account_id = records.first().map(|first| first.account.clone());
+        if let Some(account_id) = &account_id {
+            if let Some(other) = records.iter().find(|r| &r.account != account_id) {
+                bail!(
+                    "statement contains transactions for more than one account: {:?} and {:?}",
+                    account_id,
+                    other.account
+                );
+            }
+        }
+
+        let user_fp_namespace = match account_id
+            .as_deref()
+            .and_then(|id| accounts.fp_namespace(id))
+        {
+            Some(ns) => ns.to_string(),
+            None => match (&self.fp_ns, &account_id) {
+                (Some(fp_ns), _) => fp_ns.clone(),
+                (None, Some(account_id)) => default_namespace(account_id),
+                (None, None) => String::new(),
+            },
+        };
+
+        // Keep the per-date counters stable regardless of the order that the
+        // statement was exported in.
+        if let (Some(first), Some(last)) = (records.first(), records.last()) {
+            if first.date > last.date {
+                records.reverse();
+            }
+        }
+
+        let transactions = form_transactions(records, self.fp_hash, &user_fp_namespace)?;
+
+        let mut metadata = Metadata::new(BANK_NAME).with_period_from(&transactions);
+        metadata.account = account_id;
+
+        Ok(Import {
+            user_fp_namespace,
+            transactions,
+            prices: Vec::new(),
+            metadata,
+        })
+    }
+}
+
+fn form_transactions(
+    records: Vec<Record>,
+    fp_hash: HashSpec,
+    fp_ns: &str,
+) -> Result<Vec<Transaction>> {
+    let mut transactions = Vec::new();
+
+    let mut prev_date: Option<NaiveDate> = None;
+    let mut date_counter: i32 = 0;
+
+    for record in records {
+        // Maintain the per-date counter. Include a sequence number to each
+        // transaction in a given day for use in the fingerprint.
+        if Some(record.date) != prev_date {
+            prev_date = Some(record.date);
+            date_counter = 0;
+        } else {
+            date_counter += 1;
+        }
+
+        let date = record.date;
+        // The memo pads its parts with runs of spaces.
+        let description = record.memo.split_whitespace().collect::<Vec<_>>().join(" ");
+        let code = record.number.clone();
+        let line = record.line.map(|line| line.to_string());
+        let (post1, post2) = form_postings(record, fp_hash, fp_ns, date_counter)?;
+
+        transactions.push(Transaction {
+            date,
+            description,
+            comment: Comment::builder()
+                .with_option_value_tag(tags::SOURCE_LINE, line)
+                .build()
+                .into_opt_comment(),
+            status: None,
+            code,
+            effective_date: None,
+            postings: vec![post1, post2],
+        });
+    }
+
+    Ok(transactions)
+}
+
+fn form_postings(
+    record: Record,
+    fp_hash: HashSpec,
+    fp_ns: &str,
+    date_counter: i32,
+) -> Result<(Posting, Posting)> {
+    let fp = self_and_peer_fingerprints(
+        FingerprintBuilder::new("bcsv", 1, fp_hash, fp_ns)?
+            .with(record.date)
+            .with(date_counter)
+            .with(record.number.as_deref())
+            .with(&record.amount)
+            .with(record.subcategory.as_str())
+            .with(record.memo.as_str()),
+    );
+    let halves = self_and_peer_account_amount(record.amount, ASSETS_UNKNOWN.to_string());
+
+    let mut self_comment = Comment::builder()
+        .with_tag(tags::UNKNOWN_ACCOUNT)
+        .with_value_tag(tags::ACCOUNT, record.account)
+        .with_value_tag(tags::BANK, BANK_NAME)
+        .with_value_tag(SUBCATEGORY_TAG, record.subcategory);
+    let mut peer_comment = self_comment.clone();
+    self_comment = self_comment
+        .with_tag(fp.self_.tag())
+        .with_value_tag(tags::SEQ, format!("{}-{}", fp_ns, date_counter + 1))
+        .with_tag(tags::IMPORT_SELF);
+    peer_comment = peer_comment
+        .with_tag(fp.peer.tag())
+        .with_tag(tags::IMPORT_PEER);
+
+    Ok((
+        Posting {
+            account: halves.self_.account,
+            reality: Reality::Real,
+            amount: Some(simple_posting_amount(halves.self_.amount)),
+            balance: None,
+            comment: self_comment.build().into_opt_comment(),
+            status: None,
+        },
+        Posting {
+            account: halves.peer.account,
+            reality: Reality::Real,
+            amount: Some(simple_posting_amount(halves.peer.amount)),
+            balance: None,
+            comment: peer_comment.build().into_opt_comment(),
+            status: None,
+        },
+    ))
+}
+
+/// Returns the fingerprint namespace derived from the account, e.g.
+/// "203206_12345678" for "20-32-06 12345678".
+fn default_namespace(account_id: &str) -> String {
+    account_id
+        .split_whitespace()
+        .map(|part| {
+            part.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .collect::<String>()
+        })
+        .collect::<Vec<_>>()
+        .join("_")
+}
+
+struct Record {
+    number: Option<String>,
+    date: NaiveDate,
+    /// Sort code and account number, e.g. "20-32-06 12345678".
+    account: String,
+    amount: Amount,
+    subcategory: String,
+    memo: String,
+    /// Line number of the row in the CSV file.
+    line: Option<u64>,
+}
+
+impl Record {
+    fn from_csv_record(
+        v: de::Record,
+        line: Option<u64>,
+        date_format: &DateFormatOpts,
+    ) -> Result<Self> {
+        Ok(Self {
+            number: v.number,
+            date: date_format.parse(&v.date)?,
+            account: v.account,
+            amount: parse_gbp(&v.amount)?,
+            subcategory: v.subcategory,
+            memo: v.memo,
+            line,
+        })
+    }
+}
+
+mod de {
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Record {
+        #[serde(rename = "Number")]
+        pub number: Option<String>,
+        #[serde(rename = "Date")]
+        pub date: String,
+        #[serde(rename = "Account")]
+        pub account: String,
+        #[serde(rename = "Amount")]
+        pub amount: String,
+        #[serde(rename = "Subcategory")]
+        pub subcategory: String,
+        #[serde(rename = "Memo")]
+        pub memo: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use crate::importers::testutil::golden_test;
+
+    use super::*;
+
+    #[test_case("barclays_csv.csv"; "lf line endings")]
+    #[test_case("barclays_csv_crlf.csv"; "crlf line endings")]
+    fn golden(csv: &str) {
+        golden_test(
+            &BarclaysCsv {
+                input: FileSpec::Path(["testdata/importers", csv].iter().collect()),
+                fp_ns: None,
+                fp_hash: Default::default(),
+                date_format: Default::default(),
+            },
+            "barclays_csv.golden.journal",
+        );
+    }
+}
@@ -1,15 +1,32 @@
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+
 use anyhow::{anyhow, bail, Result};
-use clap::{Args, Subcommand};
+use clap::{Args, Parser, Subcommand};
+use ledger_parser::CommodityPrice;
 
+use crate::accounts::TaxonomyOpts;
+use crate::commodities::AliasOpts;
 use crate::filespec::{self, FileSpec};
 use crate::importers;
+use crate::importers::history::HistoryCheck;
 use crate::importers::importer::TransactionImporter;
-use crate::ledgerutil::ledger_from_transactions;
+use crate::importers::registry::{AccountConfig, Registry};
+use crate::internal::TransactionPostings;
+use crate::ledgerutil::{ledger_from_transactions, ledger_with_prices};
+use crate::precision::PrecisionOpts;
 
 use super::importer::Import;
 
 #[derive(Debug, Subcommand)]
 pub enum Importer {
+    /// Converts from Barclays CSV format to Ledger transactions.
+    #[command(name = "barclays-csv")]
+    BarclaysCsv(importers::barclays_csv::BarclaysCsv),
+    /// Converts from the Halifax and Lloyds Bank CSV format to Ledger
+    /// transactions.
+    #[command(name = "halifax-csv")]
+    HalifaxCsv(importers::halifax_csv::HalifaxCsv),
     /// Converts from Nationwide (nationwide.co.uk) CSV format to Ledger
     /// transactions.
     #[command(name = "nationwide-csv")]
@@ -21,23 +38,108 @@ pub enum Importer {
     /// Converts from PayPal CSV format to Ledger transactions.
     #[command(name = "paypal-csv")]
     PaypalCsv(importers::paypal_csv::PaypalCsv),
+    /// Converts from Revolut CSV format to Ledger transactions.
+    #[command(name = "revolut-csv")]
+    RevolutCsv(importers::revolut_csv::RevolutCsv),
+    /// Imports statements attached to emails in a maildir or mbox, using the
+    /// importers configured for each type of attachment. Writes a journal per
+    /// new attachment, ignoring --output.
+    #[command(name = "email")]
+    Email(importers::email::Email),
 }
 
 impl Importer {
-    pub fn do_import(&self) -> Result<Import> {
-        self.get_importer().get_transactions()
+    pub fn do_import(&self, accounts: &Registry) -> Result<Import> {
+        let mut import = self.get_importer().get_transactions(accounts)?;
+        accounts
+            .taxonomy()
+            .rename_unknown_accounts(&mut import.transactions);
+        Ok(import)
+    }
+
+    /// Returns the path of the statement being imported, if it is a file.
+    pub fn input_path(&self) -> Option<&Path> {
+        match self {
+            Importer::Email(_) => None,
+            _ => self.get_importer().input_path(),
+        }
     }
 
     fn get_importer(&self) -> &dyn TransactionImporter {
         use Importer::*;
         match self {
+            BarclaysCsv(imp) => imp,
+            HalifaxCsv(imp) => imp,
             NationwideCsv(imp) => imp,
             NationwidePdf(imp) => imp,
             PaypalCsv(imp) => imp,
+            RevolutCsv(imp) => imp,
+            Email(_) => unreachable!("email imports are handled by Command::run"),
         }
     }
 }
 
+/// Used to parse importer arguments given in configuration files.
+#[derive(Debug, Parser)]
+#[command(no_binary_name = true)]
+struct ImporterArgs {
+    #[command(subcommand)]
+    importer: Importer,
+}
+
+/// Parses an importer subcommand and its arguments, as they would be given on
+/// the command line. The email importer is not allowed, as this is used to
+/// configure importers for individual files.
+pub fn parse_importer<I, T>(args: I) -> Result<Importer>
+where
+    I: IntoIterator<Item = T>,
+    T: Into<OsString> + Clone,
+{
+    let importer = ImporterArgs::try_parse_from(args)?.importer;
+    if let Importer::Email(_) = importer {
+        bail!("the email importer cannot be used to import individual files");
+    }
+    Ok(importer)
+}
+
+/// Parses an importer subcommand and its arguments other than the statement
+/// to import, as `parse_importer`, with the statement at `path`. The
+/// statement is given as the importer's first positional argument, so that
+/// importers may take further positional arguments.
+pub fn parse_importer_with_input(args: &[String], path: &Path) -> Result<Importer> {
+    let (name, args) = args
+        .split_first()
+        .ok_or_else(|| anyhow!("no importer given"))?;
+    parse_importer(
+        std::iter::once(OsString::from(name))
+            .chain(std::iter::once(path.as_os_str().to_owned()))
+            .chain(args.iter().map(OsString::from)),
+    )
+}
+
+/// Imports the statement at `path` using the importer described by `args`
+/// (see `parse_importer_with_input`), applying any account configuration from
+/// the registry. Returns the transactions and any commodity prices.
+pub fn import_file(
+    args: &[String],
+    path: &Path,
+    accounts: &Registry,
+) -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)> {
+    import_statement(&parse_importer_with_input(args, path)?, accounts)
+}
+
+/// Imports a statement with `importer`, applying any account configuration
+/// from the registry. Returns the transactions and any commodity prices.
+pub fn import_statement(
+    importer: &Importer,
+    accounts: &Registry,
+) -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)> {
+    let mut import = importer.do_import(accounts)?;
+    import.check_duplicate_rows(false)?;
+    let prices = std::mem::take(&mut import.prices);
+    Ok((apply_account_config(accounts, import)?, prices))
+}
+
 #[derive(Debug, Args)]
 pub struct Command {
     /// The ledger file to write to (overwrites any existing file). "-" writes
@@ -53,6 +155,45 @@ pub struct Command {
     /// exist).
     #[arg(long = "make-parent-dirs", default_value_t = false)]
     make_parent_dirs: bool,
+    /// If given, also write the statement metadata (bank, account, statement
+    /// period and balances, where known) as JSON to this file. The same
+    /// substitutions and directory creation as for --output apply.
+    #[arg(long = "metadata-output")]
+    metadata_output: Option<FileSpec>,
+    /// RON file mapping account identifiers found in statements to the
+    /// ledger account, fingerprint namespace and rules file to use for them.
+    #[arg(long = "accounts-file")]
+    accounts_file: Option<PathBuf>,
+    #[command(flatten)]
+    taxonomy: TaxonomyOpts,
+    /// Remove exact duplicate rows within the statement (with a warning),
+    /// rather than failing. Duplicate rows are detected by generating the
+    /// same fingerprints.
+    #[arg(long = "dedupe-rows")]
+    dedupe_rows: bool,
+    /// Tag each imported transaction with a `source` value tag containing the
+    /// file name of the statement, so that it can be traced after merging.
+    #[arg(long = "source-tag")]
+    source_tag: bool,
+    /// Add a balance assertion of the statement's closing balance (where the
+    /// importer finds one) to the last transaction, and warn if the
+    /// transactions do not add up to it.
+    #[arg(long = "assert-closing-balance")]
+    assert_closing_balance: bool,
+    /// JSON file recording the hash of each statement file imported, which
+    /// is created if it does not exist. Importing a statement with the same
+    /// contents as one already recorded gives a warning, even if it has a
+    /// different name or is imported to a different --output.
+    #[arg(long = "import-history", value_name = "FILE")]
+    import_history: Option<PathBuf>,
+    /// Fail instead of warning when --import-history shows that the
+    /// statement was already imported.
+    #[arg(long = "reject-reimport", requires = "import_history")]
+    reject_reimport: bool,
+    #[command(flatten)]
+    commodity_aliases: AliasOpts,
+    #[command(flatten)]
+    precision: PrecisionOpts,
     /// The importer type to use to read transactions.
     #[command(subcommand)]
     importer: Importer,
@@ -60,11 +201,100 @@ pub struct Command {
 
 impl Command {
     pub fn run(&self) -> Result<()> {
-        let import = self.importer.do_import()?;
+        let mut accounts = match &self.accounts_file {
+            Some(path) => Registry::from_path(path)?,
+            None => Registry::default(),
+        };
+        if let Some(taxonomy) = self.taxonomy.load()? {
+            accounts = accounts.with_taxonomy(taxonomy);
+        }
+        if let Importer::Email(email) = &self.importer {
+            if self.metadata_output.is_some() {
+                bail!("--metadata-output is not supported by the email importer");
+            }
+            if self.import_history.is_some() {
+                bail!("--import-history is not supported by the email importer");
+            }
+            return email.run(&accounts, |mut import, name, output| {
+                import.check_duplicate_rows(self.dedupe_rows)?;
+                if self.source_tag {
+                    import.tag_source(name);
+                }
+                self.normalize(&mut import);
+                let output = self.prepare_output(output, &import.user_fp_namespace)?;
+                write_import(&accounts, import, &output)
+            });
+        }
+
+        let input_path = self.importer.input_path();
+        let history_check = match &self.import_history {
+            Some(history_path) => {
+                let input_path = input_path
+                    .ok_or_else(|| anyhow!("--import-history requires the input to be a file"))?;
+                Some(HistoryCheck::new(
+                    history_path,
+                    input_path,
+                    self.reject_reimport,
+                )?)
+            }
+            None => None,
+        };
+
+        let mut import = self.importer.do_import(&accounts)?;
+        import.check_duplicate_rows(self.dedupe_rows)?;
+        if self.source_tag {
+            let name = input_path
+                .and_then(Path::file_name)
+                .ok_or_else(|| anyhow!("--source-tag requires the input to be a file"))?;
+            import.tag_source(&name.to_string_lossy());
+        }
+        self.normalize(&mut import);
+        let output = self.prepare_output(&self.output, &import.user_fp_namespace)?;
+
+        if let Some(metadata_output) = &self.metadata_output {
+            let metadata_output =
+                self.prepare_output(metadata_output, &import.user_fp_namespace)?;
+            let mut content = serde_json::to_string_pretty(&import.metadata)?;
+            content.push('\n');
+            filespec::write_file(&metadata_output, &content)?;
+        }
+
+        write_import(&accounts, import, &output)?;
+
+        match (history_check, input_path) {
+            (Some(history_check), Some(input_path)) => history_check.record(input_path, &output),
+            _ => Ok(()),
+        }
+    }
+
+    /// Applies --commodity-alias, --assert-closing-balance and --precision to
+    /// an import, in that order.
+    fn normalize(&self, import: &mut Import) {
+        let aliases = &self.commodity_aliases;
+        aliases.normalize_transactions(&mut import.transactions);
+        aliases.normalize_prices(&mut import.prices);
+        let metadata = &mut import.metadata;
+        for balance in [&mut metadata.opening_balance, &mut metadata.closing_balance]
+            .into_iter()
+            .flatten()
+        {
+            aliases.normalize_amount(balance);
+        }
+        if self.assert_closing_balance {
+            for drift in import.assert_closing_balance() {
+                eprintln!("warning: {}", drift);
+            }
+        }
+        self.precision
+            .normalize_transactions(&mut import.transactions);
+    }
+
+    /// Applies --sub-output-path and --make-parent-dirs to an output file.
+    fn prepare_output(&self, output: &FileSpec, user_fp_namespace: &str) -> Result<FileSpec> {
         let output = if !self.substitute_output_path {
-            self.output.clone()
+            output.clone()
         } else {
-            let p = match self.output {
+            let p = match output {
                 FileSpec::Stdio => {
                     bail!("--sub-output-path only works with file paths, not stdout")
                 }
@@ -73,7 +303,7 @@ impl Command {
             let p_str = p.to_str().ok_or_else(|| {
                 anyhow!("--sub-output-path only works if --output is a UTF-8 path")
             })?;
-            let new_p = p_str.replace("%FP_NS%", &import.user_fp_namespace);
+            let new_p = p_str.replace("%FP_NS%", user_fp_namespace);
             FileSpec::Path(new_p.into())
         };
 
@@ -90,7 +320,34 @@ impl Command {
             }
         }
 
-        let ledger = ledger_from_transactions(import.transactions);
-        filespec::write_ledger_file(&output, &ledger)
+        Ok(output)
+    }
+}
+
+/// Writes the imported transactions to `output`, first applying any account
+/// configuration from the registry.
+fn write_import(accounts: &Registry, mut import: Import, output: &FileSpec) -> Result<()> {
+    let prices = std::mem::take(&mut import.prices);
+    let ledger = match account_config(accounts, &import) {
+        Some(_) => TransactionPostings::into_journal(apply_account_config(accounts, import)?),
+        None => ledger_from_transactions(import.transactions),
+    };
+    filespec::write_ledger_file(output, &ledger_with_prices(prices, ledger))
+}
+
+fn account_config<'a>(accounts: &'a Registry, import: &Import) -> Option<&'a AccountConfig> {
+    import
+        .metadata
+        .account
+        .as_deref()
+        .and_then(|id| accounts.get(id))
+}
+
+fn apply_account_config(accounts: &Registry, import: Import) -> Result<Vec<TransactionPostings>> {
+    let config = account_config(accounts, &import);
+    let trns = import.transactions.into_iter().map(Into::into).collect();
+    match config {
+        Some(config) => config.apply(trns),
+        None => Ok(trns),
     }
 }
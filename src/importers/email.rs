@@ -0,0 +1,303 @@
+//! Importing statements that arrive as email attachments.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args;
+use glob::{MatchOptions, Pattern};
+use mail_parser::decoders::base64::base64_decode;
+use mail_parser::decoders::quoted_printable::quoted_printable_decode;
+use mail_parser::{Encoding, MessageParser, MessagePart, MimeHeaders, PartType};
+use serde_derive::Deserialize;
+use sha1::{Digest, Sha1};
+
+use crate::filespec::FileSpec;
+use crate::importers::cmd::parse_importer_with_input;
+use crate::importers::importer::Import;
+use crate::importers::registry::Registry;
+
+#[derive(Debug, Args)]
+pub struct Email {
+    /// Maildir directory or mbox file to scan for statement attachments.
+    source: PathBuf,
+    /// RON file describing which attachments to import, and the importer to
+    /// use for each.
+    #[arg(long = "config")]
+    config: PathBuf,
+    /// Directory to write the imported journals to. Each journal is named
+    /// after the hash of the attachment that it was imported from, and
+    /// attachments that already have a journal in the directory are skipped.
+    #[arg(long = "output-dir")]
+    output_dir: PathBuf,
+}
+
+/// Configuration for `Email`, as read from its config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Attachment patterns, checked in order. The first matching pattern
+    /// determines the importer used for an attachment. Attachments that match
+    /// no pattern are ignored.
+    attachments: Vec<AttachmentPattern>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct AttachmentPattern {
+    /// Glob pattern matched against the attachment filename.
+    #[serde(default)]
+    filename: Option<String>,
+    /// Glob pattern matched against the attachment MIME type (e.g.
+    /// "text/csv" or "application/*").
+    #[serde(default)]
+    mime: Option<String>,
+    /// Importer subcommand and its arguments, as they would be given on the
+    /// command line (e.g. `["nationwide-csv", "--fp-namespace=fixed:current"]`).
+    /// The path to the attachment is given as the importer's first positional
+    /// argument.
+    importer: Vec<String>,
+}
+
+const MATCH_OPTIONS: MatchOptions = MatchOptions {
+    case_sensitive: false,
+    require_literal_separator: false,
+    require_literal_leading_dot: false,
+};
+
+impl AttachmentPattern {
+    fn matches(&self, name: &str, mime: &str) -> Result<bool> {
+        let matches = |pattern: &Option<String>, value: &str| -> Result<bool> {
+            Ok(match pattern {
+                Some(pattern) => Pattern::new(pattern)
+                    .with_context(|| format!("parsing pattern {:?}", pattern))?
+                    .matches_with(value, MATCH_OPTIONS),
+                None => true,
+            })
+        };
+        Ok(matches(&self.filename, name)? && matches(&self.mime, mime)?)
+    }
+}
+
+/// An attachment matched by the configuration.
+#[derive(Debug)]
+struct Attachment<'a> {
+    name: String,
+    /// Hex encoded SHA-1 hash of the contents.
+    hash: String,
+    contents: Vec<u8>,
+    pattern: &'a AttachmentPattern,
+}
+
+impl Email {
+    /// Imports each new matching attachment, passing each import to `write`
+    /// along with the attachment name and the journal path to write it to.
+    pub fn run<F>(&self, accounts: &Registry, mut write: F) -> Result<()>
+    where
+        F: FnMut(Import, &str, &FileSpec) -> Result<()>,
+    {
+        let config: Config = ron::de::from_reader(
+            std::fs::File::open(&self.config)
+                .with_context(|| format!("opening {:?} for reading", self.config))?,
+        )
+        .with_context(|| format!("parsing {:?}", self.config))?;
+
+        let attachments = find_attachments(&self.source, &config)?;
+        let tmp_dir = tempfile::tempdir()?;
+        for attachment in attachments {
+            let output = self.output_dir.join(format!("{}.journal", attachment.hash));
+            if output.exists() {
+                continue;
+            }
+
+            let path = tmp_dir.path().join(safe_filename(&attachment.name));
+            std::fs::write(&path, &attachment.contents)?;
+            let import = import_attachment(&attachment, &path, accounts)
+                .with_context(|| format!("importing attachment {:?}", attachment.name))?;
+            std::fs::remove_file(&path)?;
+
+            eprintln!(
+                "{}: imported {} transactions from {:?}",
+                attachment.hash,
+                import.transactions.len(),
+                attachment.name
+            );
+            write(import, &attachment.name, &FileSpec::Path(output))?;
+        }
+        Ok(())
+    }
+}
+
+fn import_attachment(attachment: &Attachment, path: &Path, accounts: &Registry) -> Result<Import> {
+    parse_importer_with_input(&attachment.pattern.importer, path)?.do_import(accounts)
+}
+
+/// Finds the attachments in the maildir or mbox at `source` that match the
+/// configuration. Attachments with identical contents are only returned once.
+fn find_attachments<'a>(source: &Path, config: &'a Config) -> Result<Vec<Attachment<'a>>> {
+    let messages: Vec<Vec<u8>> = if source.is_dir() {
+        mail_parser::mailbox::maildir::MessageIterator::new(source)
+            .with_context(|| format!("opening maildir {:?}", source))?
+            .map(|msg| msg.map(|msg| msg.unwrap_contents()))
+            .collect::<std::io::Result<_>>()
+            .with_context(|| format!("reading maildir {:?}", source))?
+    } else {
+        let file = std::fs::File::open(source)
+            .with_context(|| format!("opening {:?} for reading", source))?;
+        mail_parser::mailbox::mbox::MessageIterator::new(std::io::BufReader::new(file))
+            .map(|msg| msg.map(|msg| msg.unwrap_contents()))
+            .collect::<Result<_, _>>()
+            .map_err(|_| anyhow!("parsing mbox {:?}", source))?
+    };
+
+    let parser = MessageParser::default();
+    let mut seen_hashes = HashSet::new();
+    let mut attachments = Vec::new();
+    for raw in &messages {
+        let message = match parser.parse(raw.as_slice()) {
+            Some(message) => message,
+            None => {
+                eprintln!("warning: skipping unparseable message in {:?}", source);
+                continue;
+            }
+        };
+        for part in message.attachments() {
+            let name = part.attachment_name().unwrap_or("").to_string();
+            let mime = part
+                .content_type()
+                .map(|ct| match ct.subtype() {
+                    Some(subtype) => format!("{}/{}", ct.ctype(), subtype),
+                    None => ct.ctype().to_string(),
+                })
+                .unwrap_or_default();
+
+            let mut pattern = None;
+            for candidate in &config.attachments {
+                if candidate.matches(&name, &mime)? {
+                    pattern = Some(candidate);
+                    break;
+                }
+            }
+            let pattern = match pattern {
+                Some(pattern) => pattern,
+                None => continue,
+            };
+
+            let contents = attachment_contents(raw, part)
+                .with_context(|| format!("decoding attachment {:?}", name))?;
+            let hash = format!("{:x}", Sha1::digest(&contents));
+            if !seen_hashes.insert(hash.clone()) {
+                continue;
+            }
+            attachments.push(Attachment {
+                name,
+                hash,
+                contents,
+                pattern,
+            });
+        }
+    }
+    Ok(attachments)
+}
+
+/// Returns the contents of an attachment. Text parts are decoded from the raw
+/// message, as the parser's lossy conversion to UTF-8 would otherwise corrupt
+/// statements in other character sets.
+fn attachment_contents(raw: &[u8], part: &MessagePart) -> Result<Vec<u8>> {
+    match part.body {
+        PartType::Text(_) | PartType::Html(_) => {
+            let body = raw
+                .get(part.offset_body..part.offset_end)
+                .ok_or_else(|| anyhow!("attachment outside of message"))?;
+            let decoded = match part.encoding {
+                Encoding::None => Some(body.to_vec()),
+                Encoding::QuotedPrintable => quoted_printable_decode(body),
+                Encoding::Base64 => base64_decode(body),
+            };
+            decoded.ok_or_else(|| anyhow!("bad transfer encoding"))
+        }
+        _ => Ok(part.contents().to_vec()),
+    }
+}
+
+/// Returns a filename based on the attachment name that is safe to create in
+/// a temporary directory, keeping any extension.
+fn safe_filename(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '.' || c == '-' || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    match name.trim_start_matches('.') {
+        "" => "attachment".to_string(),
+        name => name.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(attachment_name: &str, mime: &str, contents: &str) -> String {
+        format!(
+            "From statements@example.com Mon Jan  1 00:00:00 2001\n\
+             From: statements@example.com\n\
+             Subject: Statement\n\
+             MIME-Version: 1.0\n\
+             Content-Type: multipart/mixed; boundary=\"BOUNDARY\"\n\
+             \n\
+             --BOUNDARY\n\
+             Content-Type: text/plain\n\
+             \n\
+             Your statement is attached.\n\
+             --BOUNDARY\n\
+             Content-Type: {mime}; name=\"{attachment_name}\"\n\
+             Content-Disposition: attachment; filename=\"{attachment_name}\"\n\
+             \n\
+             {contents}\n\
+             --BOUNDARY--\n\
+             \n",
+        )
+    }
+
+    #[test]
+    fn find_attachments_matches_and_dedups() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let mbox = dir.path().join("mbox");
+        let content = [
+            message("statement.csv", "text/csv", "a,b,c"),
+            message("STATEMENT.CSV", "text/csv", "a,b,c"),
+            message("other.csv", "text/csv", "d,e,f"),
+            message("picture.png", "image/png", "not a statement"),
+        ]
+        .concat();
+        std::fs::write(&mbox, content).expect("write mbox");
+
+        let config: Config = ron::de::from_str(
+            r#"Config(attachments: [
+                AttachmentPattern(
+                    filename: Some("*.csv"),
+                    mime: Some("text/*"),
+                    importer: ["nationwide-csv"],
+                ),
+            ])"#,
+        )
+        .expect("parse config");
+
+        let attachments = find_attachments(&mbox, &config).expect("find attachments");
+        let names: Vec<&str> = attachments.iter().map(|a| a.name.as_str()).collect();
+        assert_eq!(names, vec!["statement.csv", "other.csv"]);
+        assert_eq!(attachments[0].contents, b"a,b,c");
+    }
+
+    #[test]
+    fn safe_filename_strips_path() {
+        assert_eq!(safe_filename("../x/statement.pdf"), "_x_statement.pdf");
+        assert_eq!(safe_filename(""), "attachment");
+    }
+}
@@ -0,0 +1,329 @@
+use std::path::Path;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveDate;
+use clap::Args;
+use ledger_parser::{Amount, Balance, Posting, Reality, Transaction};
+
+use crate::accounts::ASSETS_UNKNOWN;
+use crate::comment::Comment;
+use crate::filespec::FileSpec;
+use crate::fingerprint::{FingerprintBuilder, HashSpec};
+use crate::importers::importer::TransactionImporter;
+use crate::importers::registry::Registry;
+use crate::importers::util::date::DateFormatOpts;
+use crate::importers::util::{self_and_peer_account_amount, self_and_peer_fingerprints};
+use crate::ledgerutil::simple_posting_amount;
+use crate::money::{self, parse_gbp};
+use crate::tags;
+
+use super::importer::{Import, Metadata};
+
+/// Transaction type field, provided by the bank (e.g. "DEB", "FPI").
+const TRANSACTION_TYPE_TAG: &str = "trn_type";
+
+#[derive(Debug, Args)]
+/// Converts from the Halifax and Lloyds Bank CSV format to Ledger
+/// transactions.
+pub struct HalifaxCsv {
+    /// Halifax or Lloyds CSV file to read from. "-" reads from stdin.
+    input: FileSpec,
+    /// User namespace of the fingerprints to generate. Defaults to the sort
+    /// code and account number in the CSV file, e.g. "112233_12345678".
+    #[arg(long = "fingerprint-namespace")]
+    fp_ns: Option<String>,
+    /// Hash algorithm, encoding and length of fingerprint hashes, e.g. "b64"
+    /// (the default), "b32_16", "hex_12" or "sha256_b64".
+    #[arg(long = "fingerprint-hash", default_value = "b64")]
+    fp_hash: HashSpec,
+    #[command(flatten)]
+    date_format: DateFormatOpts,
+    /// Name of the bank, as tagged on the postings. Both banks use the same
+    /// format.
+    #[arg(long = "bank", default_value = "Halifax")]
+    bank: String,
+}
+
+impl TransactionImporter for HalifaxCsv {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
+        let mut csv_rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(self.input.lf_reader()?);
+        let headers = csv_rdr.headers()?.clone();
+
+        let mut records = Vec::new();
+        for row in csv_rdr.records() {
+            let row = row?;
+            let line = row.position().map(|pos| pos.line());
+            let record: de::Record = row
+                .deserialize(Some(&headers))
+                .with_context(|| format!("parsing CSV line {}", line.unwrap_or_default()))?;
+            records.push(Record::from_csv_record(record, line, &self.date_format)?);
+        }
+
+        let account_id = records.first().map(Record::account_id);
+        if let Some(account_id) = &account_id {
+            if let Some(other) = records.iter().find(|r| &r.account_id() != account_id) {
+                bail!(
+                    "statement contains transactions for more than one account: {:?} and {:?}",
+                    account_id,
+                    other.account_id()
+                );
+            }
+        }
+
+        let user_fp_namespace = match account_id
+            .as_deref()
+            .and_then(|id| accounts.fp_namespace(id))
+        {
+            Some(ns) => ns.to_string(),
+            None => match (&self.fp_ns, records.first()) {
+                (Some(fp_ns), _) => fp_ns.clone(),
+                (None, Some(first)) => first.default_namespace(),
+                (None, None) => String::new(),
+            },
+        };
+
+        // Statements are exported newest first, but balance assertions must be
+        // in date order.
+        if let (Some(first), Some(last)) = (records.first(), records.last()) {
+            if first.date > last.date {
+                records.reverse();
+            }
+        }
+
+        let transactions = self.form_transactions(
+            records,
+            &user_fp_namespace,
+            account_id.as_deref().unwrap_or_default(),
+        )?;
+
+        let mut metadata = Metadata::new(self.bank.as_str())
+            .with_period_from(&transactions)
+            .with_balances_from(&transactions);
+        metadata.account = account_id;
+
+        Ok(Import {
+            user_fp_namespace,
+            transactions,
+            prices: Vec::new(),
+            metadata,
+        })
+    }
+}
+
+impl HalifaxCsv {
+    fn form_transactions(
+        &self,
+        records: Vec<Record>,
+        fp_ns: &str,
+        account_id: &str,
+    ) -> Result<Vec<Transaction>> {
+        let mut transactions = Vec::new();
+
+        let mut prev_date: Option<NaiveDate> = None;
+        let mut date_counter: i32 = 0;
+
+        for record in records {
+            // Maintain the per-date counter. Include a sequence number to each
+            // transaction in a given day for use in the fingerprint.
+            if Some(record.date) != prev_date {
+                prev_date = Some(record.date);
+                date_counter = 0;
+            } else {
+                date_counter += 1;
+            }
+
+            let date = record.date;
+            let description = record.description.clone();
+            let line = record.line.map(|line| line.to_string());
+            let (post1, post2) = self.form_postings(record, fp_ns, account_id, date_counter)?;
+
+            transactions.push(Transaction {
+                date,
+                description,
+                comment: Comment::builder()
+                    .with_option_value_tag(tags::SOURCE_LINE, line)
+                    .build()
+                    .into_opt_comment(),
+                status: None,
+                code: None,
+                effective_date: None,
+                postings: vec![post1, post2],
+            });
+        }
+
+        Ok(transactions)
+    }
+
+    fn form_postings(
+        &self,
+        record: Record,
+        fp_ns: &str,
+        account_id: &str,
+        date_counter: i32,
+    ) -> Result<(Posting, Posting)> {
+        let self_amount: Amount = match (&record.credit, &record.debit) {
+            // Credit only.
+            (Some(amt), None) => amt.clone(),
+            // Debit only.
+            (None, Some(amt)) => money::negate(amt.clone()),
+            // Credit and debit or neither - both are errors.
+            _ => bail!(
+                "expected *either* debit or credit amount on line {}",
+                record.line.unwrap_or_default()
+            ),
+        };
+        let fp = self_and_peer_fingerprints(
+            FingerprintBuilder::new("hlxcsv", 1, self.fp_hash, fp_ns)?
+                .with(record.type_.as_str())
+                .with(record.date)
+                .with(date_counter)
+                .with(record.description.as_str())
+                .with(record.debit.as_ref())
+                .with(record.credit.as_ref())
+                .with(&record.balance),
+        );
+        let halves = self_and_peer_account_amount(self_amount, ASSETS_UNKNOWN.to_string());
+
+        let mut self_comment = Comment::builder()
+            .with_tag(tags::UNKNOWN_ACCOUNT)
+            .with_value_tag(tags::ACCOUNT, account_id)
+            .with_value_tag(tags::BANK, self.bank.as_str())
+            .with_value_tag(TRANSACTION_TYPE_TAG, record.type_);
+        let mut peer_comment = self_comment.clone();
+        self_comment = self_comment
+            .with_tag(fp.self_.tag())
+            .with_value_tag(tags::SEQ, format!("{}-{}", fp_ns, date_counter + 1))
+            .with_tag(tags::IMPORT_SELF);
+        peer_comment = peer_comment
+            .with_tag(fp.peer.tag())
+            .with_tag(tags::IMPORT_PEER);
+
+        Ok((
+            Posting {
+                account: halves.self_.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(halves.self_.amount)),
+                balance: Some(Balance::Amount(record.balance)),
+                comment: self_comment.build().into_opt_comment(),
+                status: None,
+            },
+            Posting {
+                account: halves.peer.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(halves.peer.amount)),
+                balance: None,
+                comment: peer_comment.build().into_opt_comment(),
+                status: None,
+            },
+        ))
+    }
+}
+
+struct Record {
+    date: NaiveDate,
+    type_: String,
+    sort_code: String,
+    account_number: String,
+    description: String,
+    debit: Option<Amount>,
+    credit: Option<Amount>,
+    balance: Amount,
+    /// Line number of the row in the CSV file.
+    line: Option<u64>,
+}
+
+impl Record {
+    fn from_csv_record(
+        v: de::Record,
+        line: Option<u64>,
+        date_format: &DateFormatOpts,
+    ) -> Result<Self> {
+        let parse_opt = |s: Option<String>| s.as_deref().map(parse_gbp).transpose();
+        Ok(Self {
+            date: date_format.parse(&v.date)?,
+            type_: v.type_,
+            // Spreadsheet exports may prefix the sort code with a quote to
+            // keep it as text.
+            sort_code: v.sort_code.trim_start_matches('\'').to_string(),
+            account_number: v.account_number,
+            description: v.description,
+            debit: parse_opt(v.debit)?,
+            credit: parse_opt(v.credit)?,
+            balance: parse_gbp(&v.balance)
+                .map_err(|e| anyhow!("bad balance on line {}: {}", line.unwrap_or_default(), e))?,
+            line,
+        })
+    }
+
+    /// Identifies the account in the statement and the accounts registry, e.g.
+    /// "11-22-33 12345678".
+    fn account_id(&self) -> String {
+        format!("{} {}", self.sort_code, self.account_number)
+    }
+
+    /// Returns the fingerprint namespace derived from the account, e.g.
+    /// "112233_12345678".
+    fn default_namespace(&self) -> String {
+        let digits =
+            |s: &str| -> String { s.chars().filter(char::is_ascii_alphanumeric).collect() };
+        format!(
+            "{}_{}",
+            digits(&self.sort_code),
+            digits(&self.account_number)
+        )
+    }
+}
+
+mod de {
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Record {
+        #[serde(rename = "Transaction Date")]
+        pub date: String,
+        #[serde(rename = "Transaction Type")]
+        pub type_: String,
+        #[serde(rename = "Sort Code")]
+        pub sort_code: String,
+        #[serde(rename = "Account Number")]
+        pub account_number: String,
+        #[serde(rename = "Transaction Description")]
+        pub description: String,
+        #[serde(rename = "Debit Amount")]
+        pub debit: Option<String>,
+        #[serde(rename = "Credit Amount")]
+        pub credit: Option<String>,
+        #[serde(rename = "Balance")]
+        pub balance: String,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importers::testutil::golden_test;
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn golden() {
+        golden_test(
+            &HalifaxCsv {
+                input: FileSpec::from_str("testdata/importers/halifax_csv.csv").unwrap(),
+                fp_ns: None,
+                fp_hash: Default::default(),
+                date_format: Default::default(),
+                bank: "Halifax".to_string(),
+            },
+            "halifax_csv.golden.journal",
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! A sidecar record of the statement files that have been imported, keyed by
+//! the hash of their contents, so that feeding the same statement into the
+//! pipeline twice (e.g. under a different name or output path) is noticed.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::filespec::{FileSpec, WriteBatch};
+
+/// Increment when the contents of the history change meaning.
+const FORMAT_VERSION: u32 = 1;
+
+#[derive(Debug, Deserialize, Serialize)]
+pub struct ImportHistory {
+    version: u32,
+    /// Previous imports, keyed by the hex encoded SHA-1 hash of the
+    /// statement file.
+    imports: BTreeMap<String, ImportRecord>,
+}
+
+impl Default for ImportHistory {
+    fn default() -> Self {
+        Self {
+            version: FORMAT_VERSION,
+            imports: BTreeMap::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct ImportRecord {
+    /// The statement file that was imported.
+    pub statement: String,
+    /// Where the imported transactions were written, or "-" for stdout.
+    pub output: String,
+}
+
+impl ImportRecord {
+    pub fn new(statement: &Path, output: &FileSpec) -> Self {
+        Self {
+            statement: statement.display().to_string(),
+            output: match output {
+                FileSpec::Stdio => "-".to_string(),
+                FileSpec::Path(path) => path.display().to_string(),
+            },
+        }
+    }
+}
+
+impl ImportHistory {
+    /// Loads the history at `path`, or returns an empty history if it does
+    /// not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(e) => return Err(e).with_context(|| format!("reading {:?}", path)),
+        };
+        let history: Self =
+            serde_json::from_str(&content).with_context(|| format!("parsing {:?}", path))?;
+        if history.version != FORMAT_VERSION {
+            bail!(
+                "{:?} has unsupported version {}, expected {}",
+                path,
+                history.version,
+                FORMAT_VERSION
+            );
+        }
+        Ok(history)
+    }
+
+    pub fn save(&self, path: &Path, batch: &mut WriteBatch) -> Result<()> {
+        let mut content = serde_json::to_string_pretty(self)?;
+        content.push('\n');
+        batch.add(&FileSpec::Path(path.to_owned()), content);
+        Ok(())
+    }
+
+    /// Checks whether the statement at `statement`, whose contents have the
+    /// given hash, has been imported before. A previous import is reported
+    /// as a warning, or as an error if `reject` is true.
+    pub fn check(&self, statement: &Path, hash: &str, reject: bool) -> Result<()> {
+        if let Some(previous) = self.get(hash) {
+            let msg = format!(
+                "{:?} has the same contents as {:?}, which was already imported to {:?}",
+                statement, previous.statement, previous.output
+            );
+            if reject {
+                bail!("{}", msg);
+            }
+            eprintln!("warning: {}", msg);
+        }
+        Ok(())
+    }
+
+    /// Returns the previous import of a statement with the given hash.
+    pub fn get(&self, hash: &str) -> Option<&ImportRecord> {
+        self.imports.get(hash)
+    }
+
+    /// Records an import of a statement with the given hash, replacing any
+    /// previous record of it.
+    pub fn record(&mut self, hash: String, record: ImportRecord) {
+        self.imports.insert(hash, record);
+    }
+}
+
+/// Returns the hex encoded SHA-1 hash of the file at `path`.
+pub fn hash_file(path: &Path) -> Result<String> {
+    let content = std::fs::read(path).with_context(|| format!("reading {:?}", path))?;
+    Ok(format!("{:x}", Sha1::digest(content)))
+}
+
+/// Checks statements against an import history, and records them once
+/// imported.
+pub struct HistoryCheck {
+    path: PathBuf,
+    history: ImportHistory,
+    hash: String,
+}
+
+impl HistoryCheck {
+    /// Checks whether the statement at `statement` has been imported before,
+    /// according to the history at `history_path`. A previous import is
+    /// reported as a warning, or as an error if `reject` is true.
+    pub fn new(history_path: &Path, statement: &Path, reject: bool) -> Result<Self> {
+        let history = ImportHistory::load(history_path)?;
+        let hash = hash_file(statement)?;
+        history.check(statement, &hash, reject)?;
+        Ok(Self {
+            path: history_path.to_owned(),
+            history,
+            hash,
+        })
+    }
+
+    /// Records that the statement has been imported to `output`, and saves
+    /// the history.
+    pub fn record(mut self, statement: &Path, output: &FileSpec) -> Result<()> {
+        self.history
+            .record(self.hash, ImportRecord::new(statement, output));
+        let mut batch = WriteBatch::new();
+        self.history.save(&self.path, &mut batch)?;
+        batch.commit()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_reimport_by_content() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let history_path = dir.path().join("history.json");
+        let statement = dir.path().join("statement.csv");
+        let copy = dir.path().join("copy.csv");
+        let other = dir.path().join("other.csv");
+        std::fs::write(&statement, "a,b,c\n").unwrap();
+        std::fs::write(&copy, "a,b,c\n").unwrap();
+        std::fs::write(&other, "d,e,f\n").unwrap();
+        let output = FileSpec::Path(dir.path().join("out.journal"));
+
+        HistoryCheck::new(&history_path, &statement, true)
+            .expect("first import")
+            .record(&statement, &output)
+            .expect("record");
+
+        let err = HistoryCheck::new(&history_path, &copy, true)
+            .err()
+            .expect("copy should be rejected");
+        assert!(err.to_string().contains("already imported"), "{}", err);
+        HistoryCheck::new(&history_path, &copy, false).expect("copy is only a warning");
+        HistoryCheck::new(&history_path, &other, true).expect("other statement");
+
+        let history = ImportHistory::load(&history_path).expect("load");
+        assert_eq!(
+            history.get(&hash_file(&statement).unwrap()),
+            Some(&ImportRecord {
+                statement: statement.display().to_string(),
+                output: dir.path().join("out.journal").display().to_string(),
+            })
+        );
+    }
+
+    #[test]
+    fn missing_history_is_empty() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let history = ImportHistory::load(&dir.path().join("missing.json")).expect("load");
+        assert!(history.imports.is_empty());
+    }
+}
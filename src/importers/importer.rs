@@ -1,13 +1,556 @@
-use anyhow::Result;
-use ledger_parser::Transaction;
+use std::collections::HashMap;
+use std::path::Path;
+
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
+use ledger_parser::{Amount, Balance, CommodityPrice, Transaction};
+use rust_decimal::Decimal;
+use serde::Serializer;
+use serde_derive::Serialize;
+
+use crate::comment::Comment;
+use crate::commodities;
+use crate::importers::registry::Registry;
+use crate::tags;
 
 pub struct Import {
     /// User namespace for fingerprints.
     pub user_fp_namespace: String,
     /// Imported transactions.
     pub transactions: Vec<Transaction>,
+    /// Commodity prices found in the statement, e.g. from currency
+    /// conversions.
+    pub prices: Vec<CommodityPrice>,
+    /// Information about the imported statement as a whole.
+    pub metadata: Metadata,
+}
+
+impl Import {
+    /// Tags each transaction with the name of the statement that it was
+    /// imported from.
+    pub fn tag_source(&mut self, source: &str) {
+        for trn in &mut self.transactions {
+            let mut comment = Comment::from_opt_string(&trn.comment);
+            comment.replace_value_tag(tags::SOURCE, source);
+            trn.comment = comment.into_opt_comment();
+        }
+    }
+
+    /// Checks for transactions that share a fingerprint with an earlier
+    /// transaction in the same statement, which happens when a statement
+    /// contains exact duplicate rows. If `dedupe` is true, then the duplicates
+    /// are removed with a warning, otherwise an error identifying the rows is
+    /// returned.
+    ///
+    /// Left unchecked, such duplicates only fail later at merge time.
+    pub fn check_duplicate_rows(&mut self, dedupe: bool) -> Result<()> {
+        // Maps each fingerprint to the index of the first transaction that
+        // has it.
+        let mut seen = HashMap::<String, usize>::new();
+        let mut duplicates = Vec::<(usize, usize)>::new();
+        for (i, trn) in self.transactions.iter().enumerate() {
+            let fps = transaction_fingerprints(trn);
+            match fps.iter().find_map(|fp| seen.get(fp)) {
+                Some(&first) => duplicates.push((first, i)),
+                None => seen.extend(fps.into_iter().map(|fp| (fp, i))),
+            }
+        }
+
+        if duplicates.is_empty() {
+            return Ok(());
+        }
+        let descriptions: Vec<String> = duplicates
+            .iter()
+            .map(|&(first, i)| {
+                let trn = &self.transactions[i];
+                format!(
+                    "{} duplicates {} ({} {})",
+                    self.locate(i),
+                    self.locate(first),
+                    trn.date,
+                    trn.description
+                )
+            })
+            .collect();
+        if !dedupe {
+            bail!(
+                "statement contains duplicate rows, which would generate duplicate fingerprints:\n{}",
+                descriptions.join("\n")
+            );
+        }
+        for description in &descriptions {
+            eprintln!("warning: removing duplicate: {}", description);
+        }
+        let mut i = 0;
+        self.transactions.retain(|_| {
+            let keep = !duplicates.iter().any(|&(_, dup)| dup == i);
+            i += 1;
+            keep
+        });
+        Ok(())
+    }
+
+    /// Describes where the transaction at `index` came from: its line in the
+    /// statement if the importer recorded it, otherwise its (1-based)
+    /// position among the imported transactions.
+    fn locate(&self, index: usize) -> String {
+        let comment = Comment::from_opt_string(&self.transactions[index].comment);
+        match comment.value_tags.get(tags::SOURCE_LINE) {
+            Some(lines) if lines.contains(',') => format!("lines {}", lines),
+            Some(line) => format!("line {}", line),
+            None => format!("transaction {}", index + 1),
+        }
+    }
+
+    /// Reconciles the transactions with the statement's closing balance, if
+    /// known, returning a description of each discrepancy found. The own
+    /// (`import-self`) posting of the last transaction is given a balance
+    /// assertion of the closing balance, unless it already has one, so that
+    /// Ledger keeps checking it after merging.
+    pub fn assert_closing_balance(&mut self) -> Vec<String> {
+        let closing = match &self.metadata.closing_balance {
+            Some(closing) => closing.clone(),
+            None => return Vec::new(),
+        };
+        let mut drift = Vec::new();
+        if let Some(opening) = &self.metadata.opening_balance {
+            if let Some(total) = self_total(&self.transactions, &closing.commodity.name) {
+                let computed = Amount {
+                    quantity: opening.quantity + total,
+                    commodity: closing.commodity.clone(),
+                };
+                if opening.commodity.name == closing.commodity.name
+                    && !commodities::amounts_equal(&computed, &closing)
+                {
+                    drift.push(format!(
+                        "opening balance {} plus transactions comes to {}, but the closing balance is {}",
+                        opening, computed, closing
+                    ));
+                }
+            }
+        }
+
+        let last = match self.transactions.iter_mut().max_by_key(|trn| trn.date) {
+            Some(last) => last,
+            None => return drift,
+        };
+        let description = format!("{} {}", last.date, last.description);
+        let self_post = last.postings.iter_mut().rev().find(|post| {
+            Comment::from_opt_string(&post.comment)
+                .tags
+                .contains(tags::IMPORT_SELF)
+        });
+        match self_post {
+            None => drift.push(format!(
+                "last transaction ({}) has no {} posting to assert the closing balance {} on",
+                description,
+                tags::IMPORT_SELF,
+                closing
+            )),
+            Some(post) => match &post.balance {
+                Some(Balance::Amount(asserted)) => {
+                    if !commodities::amounts_equal(asserted, &closing) {
+                        drift.push(format!(
+                            "last transaction ({}) asserts a balance of {}, but the closing balance is {}",
+                            description, asserted, closing
+                        ));
+                    }
+                }
+                Some(Balance::Zero) => {
+                    if !closing.quantity.is_zero() {
+                        drift.push(format!(
+                            "last transaction ({}) asserts a zero balance, but the closing balance is {}",
+                            description, closing
+                        ));
+                    }
+                }
+                None => post.balance = Some(Balance::Amount(closing)),
+            },
+        }
+        drift
+    }
+}
+
+/// Returns the total of the amounts of the `import-self` postings, or `None`
+/// if any of them has no amount or is not in `commodity`.
+fn self_total(transactions: &[Transaction], commodity: &str) -> Option<Decimal> {
+    let mut total = Decimal::ZERO;
+    for post in transactions.iter().flat_map(|trn| &trn.postings) {
+        if !Comment::from_opt_string(&post.comment)
+            .tags
+            .contains(tags::IMPORT_SELF)
+        {
+            continue;
+        }
+        let amount = &post.amount.as_ref()?.amount;
+        if amount.commodity.name != commodity {
+            return None;
+        }
+        total += amount.quantity;
+    }
+    Some(total)
+}
+
+fn transaction_fingerprints(trn: &Transaction) -> Vec<String> {
+    trn.postings
+        .iter()
+        .flat_map(|post| {
+            Comment::from_opt_string(&post.comment)
+                .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+                .map(str::to_string)
+                .collect::<Vec<_>>()
+        })
+        .collect()
+}
+
+/// Statement-level information, where the importer is able to determine it.
+#[derive(Debug, Default, Serialize)]
+pub struct Metadata {
+    /// Name of the bank or other institution that produced the statement.
+    pub bank: Option<String>,
+    /// Account identifier as given in the statement.
+    pub account: Option<String>,
+    /// First date covered by the statement.
+    pub period_start: Option<NaiveDate>,
+    /// Last date covered by the statement.
+    pub period_end: Option<NaiveDate>,
+    /// Balance of the account before the first transaction.
+    #[serde(serialize_with = "serialize_opt_amount")]
+    pub opening_balance: Option<Amount>,
+    /// Balance of the account after the last transaction.
+    #[serde(serialize_with = "serialize_opt_amount")]
+    pub closing_balance: Option<Amount>,
+}
+
+impl Metadata {
+    pub fn new<B: Into<String>>(bank: B) -> Self {
+        Self {
+            bank: Some(bank.into()),
+            ..Default::default()
+        }
+    }
+
+    pub fn with_account<A: Into<String>>(mut self, account: A) -> Self {
+        self.account = Some(account.into());
+        self
+    }
+
+    /// Fills in the statement period from the transaction dates, unless
+    /// already set.
+    pub fn with_period_from(mut self, transactions: &[Transaction]) -> Self {
+        if let Some((first, last)) = first_and_last(transactions) {
+            self.period_start.get_or_insert(first.date);
+            self.period_end.get_or_insert(last.date);
+        }
+        self
+    }
+
+    /// Fills in the opening and closing balances from balance assertions on
+    /// the first and last transactions, unless already set.
+    pub fn with_balances_from(mut self, transactions: &[Transaction]) -> Self {
+        if let Some((first, last)) = first_and_last(transactions) {
+            if self.opening_balance.is_none() {
+                self.opening_balance = opening_balance(first);
+            }
+            if self.closing_balance.is_none() {
+                self.closing_balance = asserted_balance(last).map(|(balance, _)| balance.clone());
+            }
+        }
+        self
+    }
+}
+
+/// Returns the earliest and latest transactions by date.
+fn first_and_last(transactions: &[Transaction]) -> Option<(&Transaction, &Transaction)> {
+    let mut by_date: Vec<&Transaction> = transactions.iter().collect();
+    by_date.sort_by_key(|trn| trn.date);
+    Some((by_date.first()?, by_date.last()?))
+}
+
+/// Returns the first balance assertion in the transaction, along with the
+/// amount of the posting that it is on.
+fn asserted_balance(trn: &Transaction) -> Option<(&Amount, Option<&Amount>)> {
+    trn.postings.iter().find_map(|post| match &post.balance {
+        Some(Balance::Amount(balance)) => {
+            Some((balance, post.amount.as_ref().map(|amt| &amt.amount)))
+        }
+        _ => None,
+    })
+}
+
+fn opening_balance(trn: &Transaction) -> Option<Amount> {
+    let (balance, amount) = asserted_balance(trn)?;
+    let amount = amount?;
+    if amount.commodity.name != balance.commodity.name {
+        return None;
+    }
+    Some(Amount {
+        quantity: balance.quantity - amount.quantity,
+        commodity: balance.commodity.clone(),
+    })
+}
+
+fn serialize_opt_amount<S: Serializer>(amount: &Option<Amount>, s: S) -> Result<S::Ok, S::Error> {
+    match amount {
+        Some(amount) => s.serialize_some(&amount.to_string()),
+        None => s.serialize_none(),
+    }
 }
 
 pub trait TransactionImporter {
-    fn get_transactions(&self) -> Result<Import>;
+    /// Returns the path of the statement being imported, if it is a file.
+    fn input_path(&self) -> Option<&Path>;
+
+    /// Reads the transactions. `accounts` may override the fingerprint
+    /// namespace for the account being imported.
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::money::parse_gbp;
+    use crate::testutil::parse_transaction_postings;
+
+    fn parse_transactions(s: &str) -> Vec<Transaction> {
+        parse_transaction_postings(s)
+            .into_iter()
+            .map(Into::into)
+            .collect()
+    }
+
+    #[test]
+    fn metadata_from_transactions() {
+        let trns = parse_transactions(
+            r#"
+            2001/01/05 second
+                assets:bank  GBP 5.00 = GBP 25.00
+                income:unknown  GBP -5.00
+
+            2001/01/02 first
+                assets:bank  GBP 10.00 = GBP 20.00
+                income:unknown  GBP -10.00
+            "#,
+        );
+        let got = Metadata::new("Bank")
+            .with_period_from(&trns)
+            .with_balances_from(&trns);
+        assert_eq!(got.period_start, NaiveDate::from_ymd_opt(2001, 1, 2));
+        assert_eq!(got.period_end, NaiveDate::from_ymd_opt(2001, 1, 5));
+        assert_eq!(
+            got.opening_balance.map(|amt| amt.to_string()),
+            Some("GBP10.00".to_string())
+        );
+        assert_eq!(
+            got.closing_balance.map(|amt| amt.to_string()),
+            Some("GBP25.00".to_string())
+        );
+    }
+
+    #[test]
+    fn metadata_keeps_explicit_values() {
+        let trns = parse_transactions(
+            r#"
+            2001/01/02 first
+                assets:bank  GBP 10.00
+                income:unknown  GBP -10.00
+            "#,
+        );
+        let mut metadata = Metadata::new("Bank");
+        metadata.period_start = NaiveDate::from_ymd_opt(2001, 1, 1);
+        let got = metadata.with_period_from(&trns).with_balances_from(&trns);
+        assert_eq!(got.period_start, NaiveDate::from_ymd_opt(2001, 1, 1));
+        assert_eq!(got.period_end, NaiveDate::from_ymd_opt(2001, 1, 2));
+        assert!(got.opening_balance.is_none());
+        assert!(got.closing_balance.is_none());
+    }
+
+    fn import_with_duplicates() -> Import {
+        Import {
+            user_fp_namespace: "ns".to_string(),
+            transactions: parse_transactions(
+                r#"
+                2001/01/01 shop
+                    assets:bank  GBP -5.00
+                        ; :fp-a:
+                    expenses:unknown  GBP 5.00
+                        ; :fp-b:
+
+                2001/01/02 other
+                    assets:bank  GBP -3.00
+                        ; :fp-c:
+                    expenses:unknown  GBP 3.00
+
+                2001/01/01 shop
+                    assets:bank  GBP -5.00
+                        ; :fp-a:
+                    expenses:unknown  GBP 5.00
+                        ; :fp-b:
+                "#,
+            ),
+            prices: Vec::new(),
+            metadata: Metadata::default(),
+        }
+    }
+
+    #[test]
+    fn duplicate_rows_error() {
+        let err = import_with_duplicates()
+            .check_duplicate_rows(false)
+            .unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("transaction 3 duplicates transaction 1 (2001-01-01 shop)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn duplicate_rows_error_reports_source_lines() {
+        let mut import = import_with_duplicates();
+        for (trn, line) in import.transactions.iter_mut().zip(["2", "3", "5,6"]) {
+            let mut comment = Comment::from_opt_string(&trn.comment);
+            comment.replace_value_tag(tags::SOURCE_LINE, line);
+            trn.comment = comment.into_opt_comment();
+        }
+        let err = import.check_duplicate_rows(false).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("lines 5,6 duplicates line 2 (2001-01-01 shop)"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn duplicate_rows_dedupe() {
+        let mut import = import_with_duplicates();
+        import.check_duplicate_rows(true).unwrap();
+        let descriptions: Vec<&str> = import
+            .transactions
+            .iter()
+            .map(|trn| trn.description.as_str())
+            .collect();
+        assert_eq!(descriptions, vec!["shop", "other"]);
+    }
+
+    #[test]
+    fn tag_source() {
+        let mut import = import_with_duplicates();
+        import.tag_source("statement.csv");
+        for trn in &import.transactions {
+            let comment = Comment::from_opt_string(&trn.comment);
+            assert_eq!(
+                comment.value_tags.get(tags::SOURCE).map(String::as_str),
+                Some("statement.csv")
+            );
+        }
+    }
+
+    /// Returns an import of `trns`, with the metadata taken from them and
+    /// then the closing balance replaced by `closing` (in GBP).
+    fn import_with_closing_balance(trns: &str, closing: Option<&str>) -> Import {
+        let transactions = parse_transactions(trns);
+        let mut metadata = Metadata::new("Bank").with_balances_from(&transactions);
+        metadata.closing_balance = closing.map(|closing| parse_gbp(closing).expect("parse_gbp"));
+        Import {
+            user_fp_namespace: "ns".to_string(),
+            transactions,
+            prices: Vec::new(),
+            metadata,
+        }
+    }
+
+    const RECONCILE_TRNS: &str = r#"
+        2001/01/02 first
+            assets:bank  GBP 10.00 = GBP 20.00
+                ; :import-self:
+            income:unknown  GBP -10.00
+
+        2001/01/05 last
+            assets:bank  GBP 5.00
+                ; :import-self:
+            income:unknown  GBP -5.00
+
+        2001/01/03 middle
+            assets:bank  GBP -1.00
+                ; :import-self:
+            expenses:unknown  GBP 1.00
+        "#;
+
+    #[test]
+    fn assert_closing_balance_adds_assertion() {
+        let mut import = import_with_closing_balance(RECONCILE_TRNS, Some("24.00"));
+        assert_eq!(import.assert_closing_balance(), Vec::<String>::new());
+        let balances: Vec<Option<String>> = import
+            .transactions
+            .iter()
+            .map(|trn| trn.postings[0].balance.as_ref().map(|b| b.to_string()))
+            .collect();
+        assert_eq!(
+            balances,
+            vec![
+                Some("GBP20.00".to_string()),
+                Some("GBP24.00".to_string()),
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn assert_closing_balance_reports_drift() {
+        let mut import = import_with_closing_balance(RECONCILE_TRNS, Some("25.00"));
+        let drift = import.assert_closing_balance();
+        assert_eq!(drift.len(), 1, "{:?}", drift);
+        assert!(
+            drift[0].contains("comes to GBP24.00, but the closing balance is GBP25.00"),
+            "{}",
+            drift[0]
+        );
+    }
+
+    #[test]
+    fn assert_closing_balance_keeps_existing_assertion() {
+        let mut import = import_with_closing_balance(
+            r#"
+            2001/01/05 last
+                assets:bank  GBP 5.00 = GBP 30.00
+                    ; :import-self:
+                income:unknown  GBP -5.00
+            "#,
+            Some("25.00"),
+        );
+        let drift = import.assert_closing_balance();
+        // The opening balance is derived from the same assertion, so only the
+        // assertion itself disagrees.
+        assert_eq!(drift.len(), 2, "{:?}", drift);
+        assert!(
+            drift[1].contains("asserts a balance of GBP30.00"),
+            "{}",
+            drift[1]
+        );
+        assert_eq!(
+            import.transactions[0].postings[0]
+                .balance
+                .as_ref()
+                .map(|b| b.to_string()),
+            Some("GBP30.00".to_string())
+        );
+    }
+
+    #[test]
+    fn assert_closing_balance_without_closing_balance() {
+        let mut import = import_with_closing_balance(
+            r#"
+            2001/01/05 last
+                assets:bank  GBP 5.00
+                    ; :import-self:
+                income:unknown  GBP -5.00
+            "#,
+            None,
+        );
+        assert!(import.assert_closing_balance().is_empty());
+        assert!(import.transactions[0].postings[0].balance.is_none());
+    }
 }
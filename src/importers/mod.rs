@@ -1,9 +1,15 @@
+mod barclays_csv;
 pub mod cmd;
+mod email;
+mod halifax_csv;
+pub mod history;
 mod importer;
 mod nationwide;
 mod nationwide_csv;
 mod nationwide_pdf;
 mod paypal_csv;
+pub mod registry;
+mod revolut_csv;
 mod tesseract;
 mod util;
 
@@ -5,7 +5,8 @@ use anyhow::{anyhow, bail, Error, Result};
 use clap::Args;
 
 use crate::filespec::FileSpec;
-use crate::fingerprint::Accumulator;
+use crate::fingerprint::{Accumulator, HashSpec};
+use crate::importers::registry::Registry;
 
 pub const BANK_NAME: &str = "Nationwide";
 
@@ -27,6 +28,24 @@ pub struct CommonOpts {
     /// in the CSV file to the fingerprint namespace.
     #[arg(long = "fp-namespace", default_value = "generated")]
     pub fp_ns: FpNamespace,
+
+    /// Hash algorithm, encoding and length of fingerprint hashes, e.g. "b64"
+    /// (the default), "b32_16", "hex_12" or "sha256_b64". Fingerprints with
+    /// any other than the default are tagged with the hash spec, so they never
+    /// match fingerprints generated differently.
+    #[arg(long = "fp-hash", default_value = "b64")]
+    pub fp_hash: HashSpec,
+}
+
+impl CommonOpts {
+    /// Returns the fingerprint namespace for the account, preferring any
+    /// configured for it in the accounts registry.
+    pub fn make_namespace(&self, accounts: &Registry, account_name: &str) -> Result<String> {
+        match accounts.fp_namespace(account_name) {
+            Some(ns) => Ok(ns.to_string()),
+            None => self.fp_ns.make_namespace(account_name),
+        }
+    }
 }
 
 #[derive(Clone, Debug)]
@@ -1,3 +1,5 @@
+use std::path::Path;
+
 use anyhow::{anyhow, bail, Result};
 use chrono::NaiveDate;
 use clap::Args;
@@ -8,14 +10,18 @@ use serde_derive::Deserialize;
 use crate::accounts::ASSETS_UNKNOWN;
 use crate::comment::Comment;
 use crate::filespec::FileSpec;
+use crate::fingerprint::HashSpec;
 use crate::importers::importer::TransactionImporter;
 use crate::importers::nationwide::{CommonOpts, BANK_NAME};
 use crate::importers::nationwide_csv::de::*;
-use crate::importers::util::{negate_amount, self_and_peer_account_amount};
+use crate::importers::registry::Registry;
+use crate::importers::util::date::DateFormatOpts;
+use crate::importers::util::self_and_peer_account_amount;
 use crate::ledgerutil::simple_posting_amount;
+use crate::money::{self, Gbp, Money};
 use crate::tags;
 
-use super::importer::Import;
+use super::importer::{Import, Metadata};
 
 /// Transaction type field, provided by the bank.
 pub const TRANSACTION_TYPE_TAG: &str = "trn_type";
@@ -33,9 +39,7 @@ struct AccountName {
 #[derive(Debug, Deserialize)]
 struct AccountQuantity {
     header: String,
-    // Current only uUsed for deserialization testing.
-    #[allow(dead_code)]
-    amount: GbpValue,
+    amount: Money<Gbp>,
 }
 
 #[derive(Debug, Args)]
@@ -49,15 +53,28 @@ pub struct NationwideCsv {
     #[arg(long = "include-legacy-fingerprint")]
     include_legacy_fingerprint: bool,
 
+    /// Skip up to N malformed transaction rows, reporting each to stderr,
+    /// instead of failing the import. Either way, all malformed rows are
+    /// reported, with their line numbers and text.
+    #[arg(long = "max-errors", value_name = "N", default_value = "0")]
+    max_errors: usize,
+
+    #[command(flatten)]
+    date_format: DateFormatOpts,
+
     #[command(flatten)]
     commonopts: CommonOpts,
 }
 
 impl TransactionImporter for NationwideCsv {
-    fn get_transactions(&self) -> Result<Import> {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
         let reader = encoding_rs_io::DecodeReaderBytesBuilder::new()
             .encoding(Some(encoding_rs::WINDOWS_1252))
-            .build(self.input.reader()?);
+            .build(self.input.lf_reader()?);
         let mut csv_rdr = csv::ReaderBuilder::new()
             .has_headers(false)
             .flexible(true)
@@ -77,17 +94,24 @@ impl TransactionImporter for NationwideCsv {
 
         let user_fp_namespace = self
             .commonopts
-            .fp_ns
-            .make_namespace(&acct_name.account_name)?;
+            .make_namespace(accounts, &acct_name.account_name)?;
         let transactions = self.process_file(
             &mut csv_records,
             &user_fp_namespace,
             &acct_name.account_name,
         )?;
 
+        let mut metadata = Metadata::new(BANK_NAME).with_account(&acct_name.account_name);
+        metadata.closing_balance = Some(balance.amount.into_amount());
+        let metadata = metadata
+            .with_period_from(&transactions)
+            .with_balances_from(&transactions);
+
         Ok(Import {
             user_fp_namespace,
             transactions,
+            prices: Vec::new(),
+            metadata,
         })
     }
 }
@@ -110,6 +134,9 @@ impl NationwideCsv {
             ["Date", "Transaction type", "Description", "Paid out", "Paid in", "Balance"] => {
                 self.process_rows::<R, RecordSix>(csv_records, fp_prefix, account_name)
             }
+            ["Date", "Transaction type", "Description", "Paid out", "Paid in"] => {
+                self.process_rows::<R, RecordMini>(csv_records, fp_prefix, account_name)
+            }
             _ => {
                 bail!(
                     "bad file format: unexpected transaction headers: {}",
@@ -126,37 +153,75 @@ impl NationwideCsv {
         account_name: &str,
     ) -> Result<Vec<Transaction>> {
         let mut transactions = Vec::new();
+        let mut errors = Vec::new();
 
         let mut prev_date: Option<NaiveDate> = None;
         let mut date_counter: i32 = 0;
-
-        for result in csv_records {
-            let str_record = result?;
-            let record: T = str_record.deserialize(None)?;
-
-            // Maintain the per-date counter. Include a sequence number to each
-            // transaction in a given day for use in the fingerprint.
-            let date = record.date();
+        // Maintain the per-date counter. Include a sequence number to each
+        // transaction in a given day for use in the fingerprint.
+        let mut count_date = |date: NaiveDate| {
             if Some(date) != prev_date {
                 prev_date = Some(date);
                 date_counter = 0;
             } else {
                 date_counter += 1;
             }
+            date_counter
+        };
+
+        for result in csv_records {
+            let str_record = result?;
+            let record: T = match str_record.deserialize(None) {
+                Ok(record) => record,
+                Err(e) => {
+                    // Count the row if its date is readable, so that the
+                    // fingerprints of the rows after it on the same day do
+                    // not change when it is fixed.
+                    if let Some(date) = str_record
+                        .get(0)
+                        .and_then(|s| self.date_format.parse(s).ok())
+                    {
+                        count_date(date);
+                    }
+                    errors.push(row_error(&str_record, e.into()));
+                    continue;
+                }
+            };
+            let line = str_record.position().map(|pos| pos.line().to_string());
+
+            let date = match self.date_format.parse(record.raw_date()) {
+                Ok(date) => date,
+                Err(e) => {
+                    errors.push(row_error(&str_record, e));
+                    continue;
+                }
+            };
+            let date_counter = count_date(date);
 
             let description = record.description();
 
-            let (post1, post2) = record.form_postings(
+            let (post1, post2) = match record.form_postings(
+                self.commonopts.fp_hash,
                 fp_prefix,
                 account_name,
+                date,
                 date_counter,
                 self.include_legacy_fingerprint,
-            )?;
+            ) {
+                Ok(postings) => postings,
+                Err(e) => {
+                    errors.push(row_error(&str_record, e));
+                    continue;
+                }
+            };
 
             transactions.push(Transaction {
                 date,
                 description,
-                comment: None,
+                comment: Comment::builder()
+                    .with_option_value_tag(tags::SOURCE_LINE, line)
+                    .build()
+                    .into_opt_comment(),
                 status: None,
                 code: None,
                 effective_date: None,
@@ -164,25 +229,53 @@ impl NationwideCsv {
             });
         }
 
+        if errors.len() > self.max_errors {
+            bail!(
+                "{} malformed rows, more than --max-errors {}:\n{}",
+                errors.len(),
+                self.max_errors,
+                errors.join("\n")
+            );
+        }
+        for error in errors {
+            eprintln!("warning: skipped malformed row at {}", error);
+        }
+
         Ok(transactions)
     }
 }
 
+/// Describes an error in a transaction row, with its line number and text.
+fn row_error(record: &csv::StringRecord, err: anyhow::Error) -> String {
+    let line = match record.position() {
+        Some(pos) => format!("line {}", pos.line()),
+        None => "unknown line".to_string(),
+    };
+    let text: Vec<String> = record
+        .iter()
+        .map(|field| format!("\"{}\"", field.replace('"', "\"\"")))
+        .collect();
+    format!("{}: {:#}: {}", line, err, text.join(","))
+}
+
 pub trait PostingFormer {
-    fn date(&self) -> NaiveDate;
+    /// The date as written in the record, to be parsed with --date-format.
+    fn raw_date(&self) -> &str;
     fn description(&self) -> String;
     fn form_postings(
         self,
+        fp_hash: HashSpec,
         fp_namespace: &str,
         account_name: &str,
+        date: NaiveDate,
         date_counter: i32,
         include_legacy_fingerprint: bool,
     ) -> Result<(Posting, Posting)>;
 }
 
 impl PostingFormer for RecordFive {
-    fn date(&self) -> NaiveDate {
-        self.date.0
+    fn raw_date(&self) -> &str {
+        &self.date
     }
     fn description(&self) -> String {
         if self.location.is_empty() {
@@ -193,8 +286,10 @@ impl PostingFormer for RecordFive {
     }
     fn form_postings(
         self,
+        fp_hash: HashSpec,
         fp_namespace: &str,
         account_name: &str,
+        date: NaiveDate,
         date_counter: i32,
         include_legacy_fingerprint: bool,
     ) -> Result<(Posting, Posting)> {
@@ -203,14 +298,14 @@ impl PostingFormer for RecordFive {
 
         let self_amount: Amount = match (self.paid_in.clone(), self.paid_out.clone()) {
             // Paid in only.
-            (Some(GbpValue(amt)), None) => amt,
+            (Some(amt), None) => amt.into_amount(),
             // Paid out only.
-            (None, Some(GbpValue(amt))) => negate_amount(amt),
+            (None, Some(amt)) => money::negate(amt.into_amount()),
             // Paid in and out or neither - both are errors.
             _ => bail!("expected *either* paid in or paid out"),
         };
         let halves = self_and_peer_account_amount(self_amount, ASSETS_UNKNOWN.to_string());
-        let fp_v1 = self.fingerprint_v1(fp_namespace, date_counter)?;
+        let fp_v1 = self.fingerprint_v1(fp_hash, fp_namespace, date, date_counter)?;
         let mut self_comment = Comment::builder()
             .with_tag(tags::UNKNOWN_ACCOUNT)
             .with_value_tag(tags::ACCOUNT, account_name)
@@ -254,24 +349,26 @@ impl PostingFormer for RecordFive {
 }
 
 impl PostingFormer for RecordSix {
-    fn date(&self) -> NaiveDate {
-        self.date.0
+    fn raw_date(&self) -> &str {
+        &self.date
     }
     fn description(&self) -> String {
         self.description.clone()
     }
     fn form_postings(
         self,
+        fp_hash: HashSpec,
         fp_namespace: &str,
         account_name: &str,
+        date: NaiveDate,
         date_counter: i32,
         include_legacy_fingerprint: bool,
     ) -> Result<(Posting, Posting)> {
         let self_amount: Amount = match (self.paid_in.clone(), self.paid_out.clone()) {
             // Paid in only.
-            (Some(GbpValue(amt)), None) => amt,
+            (Some(amt), None) => amt.into_amount(),
             // Paid out only.
-            (None, Some(GbpValue(amt))) => negate_amount(amt),
+            (None, Some(amt)) => money::negate(amt.into_amount()),
             // Paid in and out or neither - both are errors.
             _ => bail!("expected *either* paid in or paid out"),
         };
@@ -282,7 +379,7 @@ impl PostingFormer for RecordSix {
             .with_value_tag(tags::BANK, BANK_NAME)
             .with_value_tag(TRANSACTION_TYPE_TAG, self.type_.clone());
         let mut peer_comment = self_comment.clone();
-        let fp_v1 = self.fingerprint_v1(fp_namespace, date_counter)?;
+        let fp_v1 = self.fingerprint_v1(fp_hash, fp_namespace, date, date_counter)?;
         self_comment = self_comment
             .with_tag(fp_v1.self_.tag())
             .with_value_tag(tags::SEQ, format!("{}-{}", fp_namespace, date_counter + 1))
@@ -291,7 +388,7 @@ impl PostingFormer for RecordSix {
             .with_tag(fp_v1.peer.tag())
             .with_tag(tags::IMPORT_PEER.to_string());
         if include_legacy_fingerprint {
-            let fp_legacy = self.fingerprint_legacy(fp_namespace, date_counter, &halves)?;
+            let fp_legacy = self.fingerprint_legacy(fp_namespace, date, date_counter, &halves)?;
             self_comment = self_comment.with_tag(fp_legacy.self_.legacy_tag());
             peer_comment = peer_comment.with_tag(fp_legacy.peer.legacy_tag());
         }
@@ -300,7 +397,7 @@ impl PostingFormer for RecordSix {
                 account: halves.self_.account,
                 reality: Reality::Real,
                 amount: Some(simple_posting_amount(halves.self_.amount)),
-                balance: Some(Balance::Amount(self.balance.0)),
+                balance: Some(Balance::Amount(self.balance.into_amount())),
                 comment: self_comment.build().into_opt_comment(),
                 status: None,
             },
@@ -316,46 +413,104 @@ impl PostingFormer for RecordSix {
     }
 }
 
-mod de {
-    use std::fmt;
-    use std::str::FromStr;
+impl PostingFormer for RecordMini {
+    fn raw_date(&self) -> &str {
+        &self.date
+    }
+    fn description(&self) -> String {
+        self.description.clone()
+    }
+    fn form_postings(
+        self,
+        fp_hash: HashSpec,
+        fp_namespace: &str,
+        account_name: &str,
+        date: NaiveDate,
+        date_counter: i32,
+        include_legacy_fingerprint: bool,
+    ) -> Result<(Posting, Posting)> {
+        // No legacy fingerprint existed for RecordMini.
+        let _ = include_legacy_fingerprint;
+
+        let self_amount: Amount = match (self.paid_in.clone(), self.paid_out.clone()) {
+            // Paid in only.
+            (Some(amt), None) => amt.into_amount(),
+            // Paid out only.
+            (None, Some(amt)) => money::negate(amt.into_amount()),
+            // Paid in and out or neither - both are errors.
+            _ => bail!("expected *either* paid in or paid out"),
+        };
+        let halves = self_and_peer_account_amount(self_amount, ASSETS_UNKNOWN.to_string());
+        let mut self_comment = Comment::builder()
+            .with_tag(tags::UNKNOWN_ACCOUNT)
+            .with_value_tag(tags::ACCOUNT, account_name)
+            .with_value_tag(tags::BANK, BANK_NAME)
+            .with_value_tag(TRANSACTION_TYPE_TAG, self.type_.clone());
+        let mut peer_comment = self_comment.clone();
+        let fp_v1 = self.fingerprint_v1(fp_hash, fp_namespace, date, date_counter)?;
+        self_comment = self_comment
+            .with_tag(fp_v1.self_.tag())
+            .with_value_tag(tags::SEQ, format!("{}-{}", fp_namespace, date_counter + 1))
+            .with_tag(tags::IMPORT_SELF.to_string());
+        peer_comment = peer_comment
+            .with_tag(fp_v1.peer.tag())
+            .with_tag(tags::IMPORT_PEER.to_string());
+        Ok((
+            Posting {
+                account: halves.self_.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(halves.self_.amount)),
+                balance: None,
+                comment: self_comment.build().into_opt_comment(),
+                status: None,
+            },
+            Posting {
+                account: halves.peer.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(halves.peer.amount)),
+                balance: None,
+                comment: peer_comment.build().into_opt_comment(),
+                status: None,
+            },
+        ))
+    }
+}
 
+mod de {
     use anyhow::{bail, Context, Result};
     use chrono::NaiveDate;
-    use lazy_static::lazy_static;
-    use ledger_parser::{Amount, Commodity, CommodityPosition};
-    use regex::Regex;
-    use rust_decimal::Decimal;
-    use serde::de::{self, DeserializeOwned, Deserializer};
-    use serde::Deserialize;
+    use serde::de::DeserializeOwned;
     use serde_derive::Deserialize;
 
-    use crate::fingerprint::{Accumulator, FingerprintBuilder, Fingerprintable};
+    use crate::fingerprint::{FingerprintBuilder, HashSpec};
     use crate::importers::util::{
         self_and_peer_fingerprints, FingerprintHalves, TransactionHalves,
     };
+    use crate::money::{Gbp, Money};
 
     /// Contains the directly deserialized values from the five-column
     /// transaction format.
     #[derive(Debug, Deserialize)]
     pub struct RecordFive {
-        pub date: Date,
+        pub date: String,
         pub transactions: String,
         pub location: String,
-        pub paid_out: Option<GbpValue>,
-        pub paid_in: Option<GbpValue>,
+        pub paid_out: Option<Money<Gbp>>,
+        pub paid_in: Option<Money<Gbp>>,
     }
 
     impl RecordFive {
         pub fn fingerprint_v1(
             &self,
+            fp_hash: HashSpec,
             fp_namespace: &str,
+            date: NaiveDate,
             date_counter: i32,
         ) -> Result<FingerprintHalves> {
             Ok(self_and_peer_fingerprints(
-                FingerprintBuilder::new("nwcsv5", 1, fp_namespace)
+                FingerprintBuilder::new("nwcsv5", 1, fp_hash, fp_namespace)
                     .with_context(|| "building v1 fingerprint")?
-                    .with(self.date.0)
+                    .with(date)
                     .with(date_counter)
                     .with(self.transactions.as_str())
                     .with(self.location.as_str())
@@ -369,12 +524,12 @@ mod de {
     /// transaction format.
     #[derive(Debug, Deserialize)]
     pub struct RecordSix {
-        pub date: Date,
+        pub date: String,
         pub type_: String,
         pub description: String,
-        pub paid_out: Option<GbpValue>,
-        pub paid_in: Option<GbpValue>,
-        pub balance: GbpValue,
+        pub paid_out: Option<Money<Gbp>>,
+        pub paid_in: Option<Money<Gbp>>,
+        pub balance: Money<Gbp>,
     }
 
     impl RecordSix {
@@ -383,13 +538,14 @@ mod de {
         pub fn fingerprint_legacy(
             &self,
             fp_namespace: &str,
+            date: NaiveDate,
             date_counter: i32,
             halves: &TransactionHalves,
         ) -> Result<FingerprintHalves> {
-            let fpb_legacy = FingerprintBuilder::new("", 0, fp_namespace)
+            let fpb_legacy = FingerprintBuilder::new("", 0, HashSpec::default(), fp_namespace)
                 .with_context(|| "building legacy fingerprint")?
                 .with(self.type_.as_str())
-                .with(self.date.0)
+                .with(date)
                 // Description should have been included in the legacy fingerprint, but a
                 // bug left it blank.
                 .with("")
@@ -412,14 +568,16 @@ mod de {
 
         pub fn fingerprint_v1(
             &self,
+            fp_hash: HashSpec,
             fp_namespace: &str,
+            date: NaiveDate,
             date_counter: i32,
         ) -> Result<FingerprintHalves> {
             Ok(self_and_peer_fingerprints(
-                FingerprintBuilder::new("nwcsv6", 1, fp_namespace)
+                FingerprintBuilder::new("nwcsv6", 1, fp_hash, fp_namespace)
                     .with_context(|| "building v1 fingerprint")?
                     .with(self.type_.as_str())
-                    .with(self.date.0)
+                    .with(date)
                     .with(date_counter)
                     .with(self.description.as_str())
                     .with(self.paid_out.as_ref())
@@ -429,72 +587,36 @@ mod de {
         }
     }
 
-    #[derive(Debug)]
-    pub struct Date(pub NaiveDate);
-
-    impl<'de> Deserialize<'de> for Date {
-        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-            d.deserialize_str(DateVisitor)
-        }
-    }
-
-    struct DateVisitor;
-    impl<'de> de::Visitor<'de> for DateVisitor {
-        type Value = Date;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a date string in \"DD Jan YYYY\" format")
-        }
-
-        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
-            NaiveDate::parse_from_str(s, "%d %b %Y")
-                .map(Date)
-                .map_err(de::Error::custom)
-        }
-    }
-
-    #[derive(Clone, Debug)]
-    pub struct GbpValue(pub Amount);
-
-    impl Fingerprintable for &GbpValue {
-        fn fingerprint(self, acc: Accumulator) -> Accumulator {
-            acc.with(&self.0)
-        }
-    }
-
-    impl<'de> Deserialize<'de> for GbpValue {
-        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-            d.deserialize_str(GbpValueVisitor)
-        }
+    /// Contains the directly deserialized values from the five-column
+    /// "mini-statement" transaction format exported for savings accounts,
+    /// which has no balance column.
+    #[derive(Debug, Deserialize)]
+    pub struct RecordMini {
+        pub date: String,
+        pub type_: String,
+        pub description: String,
+        pub paid_out: Option<Money<Gbp>>,
+        pub paid_in: Option<Money<Gbp>>,
     }
 
-    struct GbpValueVisitor;
-    impl<'de> de::Visitor<'de> for GbpValueVisitor {
-        type Value = GbpValue;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a monetary value string £NNN.NN format")
-        }
-
-        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
-            lazy_static! {
-                static ref RE: Regex = Regex::new(r"£(-?)(\d+)\.(\d+)").unwrap();
-            }
-            let captures = RE
-                .captures(s)
-                .ok_or_else(|| de::Error::custom("incorrect monetary format"))?;
-            let is_negative: bool = captures.get(1).unwrap().as_str() == "-";
-            let pounds: i64 = deserialize_captured_number(&captures, 2)?;
-            let pence: i64 = deserialize_captured_number(&captures, 3)?;
-            let mut quantity = Decimal::new(pounds * 100 + pence, 2);
-            quantity.set_sign_negative(is_negative);
-            Ok(GbpValue(Amount {
-                commodity: Commodity {
-                    name: "GBP".to_string(),
-                    position: CommodityPosition::Left,
-                },
-                quantity,
-            }))
+    impl RecordMini {
+        pub fn fingerprint_v1(
+            &self,
+            fp_hash: HashSpec,
+            fp_namespace: &str,
+            date: NaiveDate,
+            date_counter: i32,
+        ) -> Result<FingerprintHalves> {
+            Ok(self_and_peer_fingerprints(
+                FingerprintBuilder::new("nwcsvmini", 1, fp_hash, fp_namespace)
+                    .with_context(|| "building v1 fingerprint")?
+                    .with(self.type_.as_str())
+                    .with(date)
+                    .with(date_counter)
+                    .with(self.description.as_str())
+                    .with(self.paid_out.as_ref())
+                    .with(self.paid_in.as_ref()),
+            ))
         }
     }
 
@@ -505,19 +627,6 @@ mod de {
         Ok(())
     }
 
-    fn deserialize_captured_number<T, E>(c: &regex::Captures, i: usize) -> Result<T, E>
-    where
-        T: FromStr,
-        E: de::Error,
-        <T as FromStr>::Err: fmt::Display,
-    {
-        c.get(i)
-            .unwrap()
-            .as_str()
-            .parse()
-            .map_err(de::Error::custom)
-    }
-
     pub fn deserialize_required_record<T, R>(
         csv_records: &mut csv::StringRecordsIter<R>,
     ) -> Result<Option<T>>
@@ -545,17 +654,79 @@ mod tests {
 
     #[test_case("nationwide_csv_5.csv", "nationwide_csv_5.golden.journal"; "five column format")]
     #[test_case("nationwide_csv_6.csv", "nationwide_csv_6.golden.journal"; "six column format")]
+    #[test_case("nationwide_csv_6_crlf.csv", "nationwide_csv_6.golden.journal"; "crlf line endings")]
+    #[test_case("nationwide_csv_mini.csv", "nationwide_csv_mini.golden.journal"; "mini-statement format")]
     fn golden(csv: &str, golden: &str) {
         let input: PathBuf = ["testdata/importers", csv].iter().collect();
         golden_test(
             &NationwideCsv {
                 input: FileSpec::Path(input),
                 include_legacy_fingerprint: true,
+                max_errors: 0,
+                date_format: Default::default(),
                 commonopts: CommonOpts {
                     fp_ns: FpNamespace::Generated,
+                    fp_hash: Default::default(),
                 },
             },
             golden,
         );
     }
+
+    fn importer(csv: &str, max_errors: usize) -> NationwideCsv {
+        NationwideCsv {
+            input: FileSpec::Path(["testdata/importers", csv].iter().collect()),
+            include_legacy_fingerprint: false,
+            max_errors,
+            date_format: Default::default(),
+            commonopts: CommonOpts {
+                fp_ns: FpNamespace::Generated,
+                fp_hash: Default::default(),
+            },
+        }
+    }
+
+    #[test]
+    fn reports_malformed_rows() {
+        let err =
+            match importer("nationwide_csv_6_bad.csv", 1).get_transactions(&Registry::default()) {
+                Ok(_) => panic!("should fail"),
+                Err(err) => format!("{:#}", err),
+            };
+        assert!(
+            err.starts_with("2 malformed rows, more than --max-errors 1:\n"),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("line 8: ")
+                && err.contains(r#""02 Jan 2019","Transfer","Bad amount","","#),
+            "{}",
+            err
+        );
+        assert!(
+            err.contains("line 9: ") && err.contains(r#""2019-13-02","Transfer","Bad date","#),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn skips_malformed_rows() {
+        let got = importer("nationwide_csv_6_bad.csv", 2)
+            .get_transactions(&Registry::default())
+            .expect("import");
+        let want = importer("nationwide_csv_6.csv", 0)
+            .get_transactions(&Registry::default())
+            .expect("import");
+        // The rows are on different lines, but otherwise import the same.
+        let postings = |import: Import| -> Vec<Vec<Posting>> {
+            import
+                .transactions
+                .into_iter()
+                .map(|trn| trn.postings)
+                .collect()
+        };
+        assert_eq!(postings(got), postings(want));
+    }
 }
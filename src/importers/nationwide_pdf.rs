@@ -1,57 +1,75 @@
 use std::ffi::OsStr;
-use std::path::PathBuf;
-use std::str::FromStr;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
 
 use anyhow::{anyhow, bail, Context, Result};
 use chrono::NaiveDate;
 use clap::Args;
 use lazy_static::lazy_static;
 use ledger_parser::{Amount, Posting, Reality, Transaction};
+use rayon::prelude::*;
 use regex::Regex;
-use rust_decimal::Decimal;
 
 use crate::accounts;
 use crate::comment::Comment;
-use crate::fingerprint::FingerprintBuilder;
+use crate::filespec::FileSpec;
+use crate::fingerprint::{FingerprintBuilder, HashSpec};
 use crate::importers::importer::TransactionImporter;
 use crate::importers::nationwide::{CommonOpts, BANK_NAME};
+use crate::importers::registry::Registry;
 use crate::importers::tesseract;
 use crate::importers::util;
 use crate::ledgerutil::simple_posting_amount;
+use crate::money;
 use crate::tags;
 
-use super::importer::Import;
+use super::importer::{Import, Metadata};
 
 #[derive(Debug, Args)]
 /// Converts from Nationwide (nationwide.co.uk) PDF statements to Ledger
 /// transactions. It assumes that Graphics Magick and Tesseract v4 executables
 /// are installed.
 pub struct NationwidePdf {
-    /// PDF file to read.
-    input: PathBuf,
+    /// PDF file to read, or "-" to read from stdin.
+    input: FileSpec,
     /// Path to Graphics Magick binary to run.
     #[arg(default_value = "gm")]
     graphics_magic_binary: PathBuf,
     /// Path to Tesseract v4 binary to run.
     #[arg(default_value = "tesseract")]
     tesseract_binary: PathBuf,
+    /// Keep the temporary directory containing the intermediate PNG and TSV
+    /// files, and print its path, for debugging.
+    #[arg(long = "keep-temp")]
+    keep_temp: bool,
+    /// Number of pages to convert and scan at once. 0 uses one per CPU.
+    /// The output is the same regardless of this value.
+    #[arg(short = 'j', long = "jobs", default_value = "0")]
+    jobs: usize,
 
     #[command(flatten)]
     commonopts: CommonOpts,
 }
 
 impl TransactionImporter for NationwidePdf {
-    fn get_transactions(&self) -> Result<Import> {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
         let doc = self.ocr_document().context("OCR scanning PDF")?;
 
         let account_name = find_account_name(&doc)
             .ok_or_else(|| anyhow!("bad input structure: account name not found"))?;
 
-        let user_fp_namespace = self.commonopts.fp_ns.make_namespace(&account_name)?;
+        let user_fp_namespace = self.commonopts.make_namespace(accounts, &account_name)?;
 
-        let mut acc = TransactionsAccumulator::new(user_fp_namespace.clone());
+        let mut acc =
+            TransactionsAccumulator::new(self.commonopts.fp_hash, user_fp_namespace.clone());
+        let mut found_table = false;
         for page in &doc.pages {
             for table in table::Table::find_in_page(page) {
+                found_table = true;
                 let trn_lines = table.read_lines().with_context(|| {
                     format!(
                         "failed to read transaction lines from table on page #{}",
@@ -65,10 +83,22 @@ impl TransactionImporter for NationwidePdf {
             }
         }
 
-        let transactions = acc.build()?;
+        if !found_table {
+            bail!("bad input structure: no transaction table headings found, the statement layout may not be supported");
+        }
+
+        let (transactions, statement_balances) = acc.build()?;
+        let mut metadata = Metadata::new(BANK_NAME).with_account(account_name);
+        metadata.opening_balance = statement_balances.opening.map(|(_, amount)| amount);
+        metadata.closing_balance = statement_balances.closing;
+        let metadata = metadata
+            .with_period_from(&transactions)
+            .with_balances_from(&transactions);
         Ok(Import {
             user_fp_namespace,
             transactions,
+            prices: Vec::new(),
+            metadata,
         })
     }
 }
@@ -76,79 +106,172 @@ impl TransactionImporter for NationwidePdf {
 impl NationwidePdf {
     /// Performs OCR on the PDF file, extracting a `Document`.
     fn ocr_document(&self) -> Result<tesseract::Document> {
+        let tmpdir = tempfile::tempdir().context("creating temporary directory")?;
+        let result = self.ocr_document_in(tmpdir.path());
+        if self.keep_temp {
+            let path = tmpdir.into_path();
+            eprintln!("keeping temporary files in {:?}", path);
+        }
+        result
+    }
+
+    /// Checks that the external binaries are available, and that Tesseract is
+    /// a supported version.
+    fn check_binaries(&self) -> Result<()> {
+        run_command(
+            Command::new(&self.graphics_magic_binary).arg("version"),
+            "GraphicsMagick",
+            &self.graphics_magic_binary,
+        )?;
+        let output = run_command(
+            Command::new(&self.tesseract_binary).arg("--version"),
+            "Tesseract",
+            &self.tesseract_binary,
+        )?;
+        // Older versions write the version to stderr.
+        let version_text = format!(
+            "{}{}",
+            String::from_utf8_lossy(&output.stdout),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        match tesseract_major_version(&version_text) {
+            Some(major) if major >= 4 => Ok(()),
+            Some(major) => bail!(
+                "Tesseract version 4 or later is required, but {:?} is version {}",
+                self.tesseract_binary,
+                major
+            ),
+            None => bail!(
+                "could not determine the version of Tesseract {:?} from: {:?}",
+                self.tesseract_binary,
+                version_text.lines().next().unwrap_or_default()
+            ),
+        }
+    }
+
+    fn ocr_document_in(&self, tmpdir: &Path) -> Result<tesseract::Document> {
         use std::fs::File;
-        use std::process::Command;
 
-        let tmpdir = tempfile::tempdir().context("creating temporary directory")?;
-        let png_pattern = tmpdir.path().join("page-*.png");
-        let png_pattern_str = png_pattern
-            .to_str()
-            .ok_or_else(|| anyhow!("converting glob path to utf-8 string"))?;
+        self.check_binaries()?;
+
+        let input_path = match &self.input {
+            FileSpec::Path(path) => path.clone(),
+            FileSpec::Stdio => {
+                // GraphicsMagick needs to read the PDF from a file.
+                let path = tmpdir.join("input.pdf");
+                let mut file = File::create(&path).context("creating file for PDF from stdin")?;
+                std::io::copy(&mut std::io::stdin(), &mut file)
+                    .context("copying PDF from stdin")?;
+                path
+            }
+        };
+
+        let num_pages = self.count_pages(&input_path)?;
+        if num_pages == 0 {
+            bail!("PDF has no pages");
+        }
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs)
+            .build()?;
+        // Tesseract uses several threads of its own, which only slow each
+        // other down when several pages are scanned at once.
+        let single_threaded = pool.current_num_threads() > 1;
+        let tsv_paths = pool.install(|| {
+            (0..num_pages)
+                .into_par_iter()
+                .map(|idx| self.ocr_page(tmpdir, &input_path, idx, single_threaded))
+                .collect::<Result<Vec<PathBuf>>>()
+        })?;
+
+        let tsv_files = tsv_paths
+            .iter()
+            .map(|path| {
+                File::open(path).with_context(|| format!("opening TSV output file {:?}", path))
+            })
+            .collect::<Result<Vec<File>>>()?;
+        tesseract::Document::from_page_tsv_readers(tsv_files)
+    }
+
+    /// Returns the number of pages in the PDF file.
+    fn count_pages(&self, input_path: &Path) -> Result<usize> {
+        // Identify prints a line for each page. `-ping` stops it from
+        // rendering the pages just to report on them.
+        let output = run_command(
+            Command::new(&self.graphics_magic_binary)
+                .arg("identify")
+                .arg("-ping")
+                .args(["-format", "%p\n"])
+                .arg(input_path),
+            "GraphicsMagick",
+            &self.graphics_magic_binary,
+        )
+        .context("counting pages in PDF")?;
+        Ok(String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .count())
+    }
+
+    /// Converts the page at (zero based) `idx` in the PDF file into a PNG
+    /// file, and performs OCR on it, returning the path of the TSV output
+    /// file.
+    fn ocr_page(
+        &self,
+        tmpdir: &Path,
+        input_path: &Path,
+        idx: usize,
+        single_threaded: bool,
+    ) -> Result<PathBuf> {
+        let page_num = idx + 1;
+        let output_base = tmpdir.join(format!("page-{:03}", page_num));
+        let png_path = output_base.with_extension("png");
 
         {
-            let png_fmt = tmpdir.path().join("page-%02d.png");
-            let gm_args: [&OsStr; 6] = [
+            // Selects the single page from the PDF.
+            let mut page_input = input_path.as_os_str().to_owned();
+            page_input.push(format!("[{}]", idx));
+            let gm_args: [&OsStr; 5] = [
                 "convert".as_ref(),
-                // DPI of the PNG files.
+                // DPI of the PNG file.
                 "-density".as_ref(),
                 "300".as_ref(),
-                self.input.as_os_str(),
-                // Output a PNG file per page in the PDF, according to png_fmt.
-                "+adjoin".as_ref(),
-                png_fmt.as_os_str(),
+                &page_input,
+                png_path.as_os_str(),
             ];
 
-            Command::new(self.graphics_magic_binary.as_os_str())
-                .args(gm_args)
-                .status()
-                .context("converting PDF into PNG files")?;
-        }
-
-        let png_list_file_path = tmpdir.path().join("png-files.txt");
-        {
-            use std::io::Write;
-            let mut png_list_file =
-                File::create(&png_list_file_path).context("creating file to list PNG files")?;
-            let png_glob = glob::glob(png_pattern_str).context("globbing for PNG files")?;
-            for png_path_result in png_glob {
-                let png_path = png_path_result?;
-                let png_path_str = png_path
-                    .to_str()
-                    .ok_or_else(|| anyhow!("converting PNG file path to utf-8 string"))?;
-                png_list_file.write_all(png_path_str.as_bytes())?;
-                png_list_file.write_all(b"\n")?;
-            }
+            run_command(
+                Command::new(&self.graphics_magic_binary).args(gm_args),
+                "GraphicsMagick",
+                &self.graphics_magic_binary,
+            )
+            .with_context(|| format!("converting page {} of PDF into PNG file", page_num))?;
         }
 
-        let output_base = tmpdir.path().join("ocr");
         {
             let tess_args: [&OsStr; 7] = [
                 // Language model to use (English).
                 "-l".as_ref(),
                 "eng".as_ref(),
-                // DPI of the PNG files.
+                // DPI of the PNG file.
                 "--dpi".as_ref(),
                 "300".as_ref(),
-                // Text file containing PNG filenames, which treats them each as
-                // a page of input in the OCR output.
-                png_list_file_path.as_os_str(),
+                png_path.as_os_str(),
                 // Base filename for the TSV output file.
                 output_base.as_os_str(),
                 // Configuration to use (i.e output format).
                 "tsv".as_ref(),
             ];
-            Command::new(self.tesseract_binary.as_os_str())
-                .args(tess_args)
-                .status()
-                .context("performing OCR on PNG files")?;
+            let mut command = Command::new(&self.tesseract_binary);
+            command.args(tess_args);
+            if single_threaded {
+                command.env("OMP_THREAD_LIMIT", "1");
+            }
+            run_command(&mut command, "Tesseract", &self.tesseract_binary)
+                .with_context(|| format!("performing OCR on page {}", page_num))?;
         }
 
-        {
-            let output_path = output_base.with_extension("tsv");
-            let tsv_file = File::open(&output_path)
-                .with_context(|| format!("opening TSV output file {:?}", output_path))?;
-            tesseract::Document::from_tsv_reader(tsv_file)
-        }
+        Ok(output_base.with_extension("tsv"))
     }
 
     fn lines_to_transactions(
@@ -177,26 +300,98 @@ impl NationwidePdf {
     }
 }
 
+/// Runs a command to completion, returning an error that includes its stderr
+/// if it fails. `name` and `binary` identify the program in errors.
+fn run_command(command: &mut Command, name: &str, binary: &Path) -> Result<Output> {
+    let output = command.output().map_err(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            anyhow!(
+                "{} binary {:?} not found; install {} or give the path to its binary",
+                name,
+                binary,
+                name
+            )
+        } else {
+            anyhow!("running {} binary {:?}: {}", name, binary, e)
+        }
+    })?;
+    if !output.status.success() {
+        bail!(
+            "{} binary {:?} failed ({}):\n{}",
+            name,
+            binary,
+            output.status,
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        );
+    }
+    Ok(output)
+}
+
+/// Parses the major version from the output of `tesseract --version`, e.g.
+/// "tesseract 4.1.1" or "tesseract v5.3.0".
+fn tesseract_major_version(version_text: &str) -> Option<u32> {
+    let version = version_text
+        .lines()
+        .next()?
+        .trim()
+        .strip_prefix("tesseract ")?
+        .trim_start_matches('v');
+    version.split('.').next()?.parse().ok()
+}
+
 struct TransactionsAccumulator {
+    fp_hash: HashSpec,
     fp_ns: String,
     cur_trn_opt: Option<TransactionBuilder>,
     prev_date: Option<NaiveDate>,
     date_counter: i32,
     trns: Vec<Transaction>,
+    balances: StatementBalances,
+}
+
+/// Balances from the "brought forward" and "carried forward" rows of a
+/// statement.
+#[derive(Default)]
+struct StatementBalances {
+    /// The first balance brought forward, and the date of its row if known.
+    opening: Option<(Option<NaiveDate>, Amount)>,
+    /// The last balance carried forward.
+    closing: Option<Amount>,
 }
 
 impl TransactionsAccumulator {
-    fn new(fp_ns: String) -> Self {
+    fn new(fp_hash: HashSpec, fp_ns: String) -> Self {
         Self {
+            fp_hash,
             fp_ns,
             cur_trn_opt: None,
             prev_date: None,
             date_counter: 0,
             trns: Vec::new(),
+            balances: StatementBalances::default(),
         }
     }
 
     fn feed_line(&mut self, trn_line: &table::TransactionLine) -> Result<()> {
+        if let Some(kind) = trn_line.balance_kind() {
+            // Balance rows are not part of any transaction.
+            let balance = match &trn_line.balance {
+                Some(balance) => money::parse_gbp(balance)?,
+                None => return Ok(()),
+            };
+            match kind {
+                table::BalanceKind::BroughtForward => {
+                    if self.balances.opening.is_none() {
+                        self.balances.opening = Some((trn_line.implied_date, balance));
+                    }
+                }
+                table::BalanceKind::CarriedForward => {
+                    self.balances.closing = Some(balance);
+                }
+            }
+            return Ok(());
+        }
+
         match (&trn_line.payment, &trn_line.receipt) {
             (Some(payment), Some(receipt)) => {
                 // Should not happen.
@@ -217,9 +412,10 @@ impl TransactionsAccumulator {
                 self.cur_trn_opt = Some(TransactionBuilder::new(
                     trn_line.implied_date,
                     self.date_counter,
-                    parse_amount(payment)?,
+                    money::parse_gbp(payment)?,
                     TransactionType::Payment,
                     trn_line.detail.clone(),
+                    trn_line.source_line(),
                 )?);
             }
             (None, Some(receipt)) => {
@@ -233,9 +429,10 @@ impl TransactionsAccumulator {
                 self.cur_trn_opt = Some(TransactionBuilder::new(
                     trn_line.implied_date,
                     self.date_counter,
-                    parse_amount(receipt)?,
+                    money::parse_gbp(receipt)?,
                     TransactionType::Receipt,
                     trn_line.detail.clone(),
+                    trn_line.source_line(),
                 )?);
             }
             (None, None) => {
@@ -270,7 +467,7 @@ impl TransactionsAccumulator {
         };
 
         if let Some(balance) = &trn_line.balance {
-            cur_trn.balance = Some(parse_amount(balance)?);
+            cur_trn.balance = Some(money::parse_gbp(balance)?);
         }
 
         Ok(())
@@ -278,17 +475,67 @@ impl TransactionsAccumulator {
 
     fn flush_transaction(&mut self) -> Result<()> {
         if let Some(pending) = self.cur_trn_opt.take() {
-            self.trns.push(pending.build(&self.fp_ns)?);
+            self.trns.push(pending.build(self.fp_hash, &self.fp_ns)?);
         }
         Ok(())
     }
 
-    fn build(mut self) -> Result<Vec<Transaction>> {
+    /// Returns the transactions, starting with a balance assertion for the
+    /// opening balance if the statement has one.
+    fn build(mut self) -> Result<(Vec<Transaction>, StatementBalances)> {
         self.flush_transaction()?;
-        Ok(self.trns)
+        let opening_date = self
+            .balances
+            .opening
+            .as_ref()
+            .and_then(|(date, _)| *date)
+            .or_else(|| self.trns.first().map(|trn| trn.date));
+        if let (Some((_, balance)), Some(date)) = (&self.balances.opening, opening_date) {
+            let trn =
+                opening_balance_transaction(self.fp_hash, &self.fp_ns, date, balance.clone())?;
+            self.trns.insert(0, trn);
+        }
+        Ok((self.trns, self.balances))
     }
 }
 
+/// Returns a transaction asserting the balance brought forward at the start of
+/// the statement.
+fn opening_balance_transaction(
+    fp_hash: HashSpec,
+    fp_ns: &str,
+    date: NaiveDate,
+    balance: Amount,
+) -> Result<Transaction> {
+    let fp = FingerprintBuilder::new("nwpdf", 1, fp_hash, fp_ns)?
+        .with(date)
+        .with(table::BROUGHT_FORWARD)
+        .with(&balance);
+    let zero = money::zero_like(&balance);
+    Ok(Transaction {
+        date,
+        effective_date: None,
+        status: None,
+        code: None,
+        description: table::BROUGHT_FORWARD.to_string(),
+        comment: None,
+        postings: vec![Posting {
+            account: accounts::ASSETS_UNKNOWN.to_string(),
+            reality: Reality::Real,
+            amount: Some(simple_posting_amount(zero)),
+            balance: Some(ledger_parser::Balance::Amount(balance)),
+            status: None,
+            comment: Comment::builder()
+                .with_value_tag(tags::BANK, BANK_NAME)
+                .with_tag(tags::UNKNOWN_ACCOUNT)
+                .with_tag(tags::IMPORT_SELF)
+                .with_tag(fp.build().legacy_tag())
+                .build()
+                .into_opt_comment(),
+        }],
+    })
+}
+
 struct TransactionBuilder {
     date: NaiveDate,
     date_counter: i32,
@@ -297,6 +544,9 @@ struct TransactionBuilder {
     effective_date: Option<NaiveDate>,
     description: String,
     balance: Option<Amount>,
+    /// Reference to the first line of the transaction, for
+    /// `tags::SOURCE_LINE`.
+    source_line: String,
 }
 
 enum TransactionType {
@@ -311,6 +561,7 @@ impl TransactionBuilder {
         amount: Amount,
         type_: TransactionType,
         description: String,
+        source_line: String,
     ) -> Result<Self> {
         let date = date.ok_or_else(|| anyhow!("missing date for transaction {:?}", description))?;
         if amount.quantity.is_sign_negative() {
@@ -330,18 +581,19 @@ impl TransactionBuilder {
             effective_date: None,
             description,
             balance: None,
+            source_line,
         })
     }
 
-    fn build(self, fp_ns: &str) -> Result<Transaction> {
-        let record_fpb = FingerprintBuilder::new("nwpdf", 1, fp_ns)?
+    fn build(self, fp_hash: HashSpec, fp_ns: &str) -> Result<Transaction> {
+        let record_fpb = FingerprintBuilder::new("nwpdf", 1, fp_hash, fp_ns)?
             .with(self.date)
             .with(self.date_counter)
             .with(self.description.as_str());
 
         let halves = util::self_and_peer_account_amount(
             match self.type_ {
-                TransactionType::Payment => util::negate_amount(self.amount),
+                TransactionType::Payment => money::negate(self.amount),
                 TransactionType::Receipt => self.amount,
             },
             accounts::ASSETS_UNKNOWN.to_string(),
@@ -364,7 +616,10 @@ impl TransactionBuilder {
             status: None,
             code: None,
             description: self.description,
-            comment: None,
+            comment: Comment::builder()
+                .with_value_tag(tags::SOURCE_LINE, self.source_line)
+                .build()
+                .into_opt_comment(),
             postings: vec![
                 Posting {
                     account: halves.self_.account,
@@ -397,21 +652,6 @@ impl TransactionBuilder {
     }
 }
 
-fn parse_amount(s: &str) -> Result<Amount> {
-    let quantity = if s.contains(',') {
-        Decimal::from_str(&s.replace(',', ""))?
-    } else {
-        Decimal::from_str(s)?
-    };
-    Ok(Amount {
-        quantity,
-        commodity: ledger_parser::Commodity {
-            name: "GBP".to_string(),
-            position: ledger_parser::CommodityPosition::Left,
-        },
-    })
-}
-
 mod table {
     use std::fmt;
 
@@ -421,11 +661,17 @@ mod table {
 
     use crate::importers::tesseract::{self, Line, Page, Paragraph, Word};
 
-    const DATE: &str = "Date";
-    const DETAILS: &str = "Details";
-    const PAYMENTS: &str = "Payments";
-    const RECEPITS: &str = "Receipts";
-    const BALANCE: &str = "Balance";
+    /// Headings of each column of a transactions table, as used by the
+    /// different statement layouts. The columns may appear in any order.
+    const HEADINGS: &[(ColumnKind, &[&str])] = &[
+        (ColumnKind::Date, &["Date"]),
+        (ColumnKind::Details, &["Details", "Description"]),
+        (ColumnKind::Payments, &["Payments", "Money out", "Paid out"]),
+        (ColumnKind::Receipts, &["Receipts", "Money in", "Paid in"]),
+        (ColumnKind::Balance, &["Balance"]),
+    ];
+    pub const BROUGHT_FORWARD: &str = "Balance brought forward";
+    const CARRIED_FORWARD: &str = "Balance carried forward";
     /// Earliest/latest years to accept from a PDF. These values are almost
     /// too forgiving, but should do as a sanity check.
     const EARLIEST_YEAR: i32 = 1980;
@@ -433,6 +679,7 @@ mod table {
 
     pub struct Table<'a> {
         columns: Columns,
+        page_num: i32,
         para: &'a Paragraph,
     }
 
@@ -442,7 +689,11 @@ mod table {
                 .iter()
                 .flat_map(|block| block.paragraphs.iter())
                 .filter_map(|para| {
-                    Columns::find_in_paragraph(para).map(|columns| Table { columns, para })
+                    Columns::find_in_paragraph(para).map(|columns| Table {
+                        columns,
+                        page_num: page.num,
+                        para,
+                    })
                 })
         }
 
@@ -466,6 +717,21 @@ mod table {
                         // A transaction will not start on this line.
                         // Lines starting with years only specify the year, and
                         // maybe a carry-over balance.
+                        if let Some(detail) = self.columns.details.join_words_in(line) {
+                            if balance_kind(&detail).is_some() {
+                                trn_lines.push(TransactionLine {
+                                    implied_date: date,
+                                    detail,
+                                    payment: None,
+                                    receipt: None,
+                                    balance: self.columns.balance.join_words_in(line),
+                                    top: line.top,
+                                    height: line.height,
+                                    page_num: self.page_num,
+                                    line_num: line.num,
+                                });
+                            }
+                        }
                     }
                     _ => {
                         // Lines that start with day and month or nothing at all
@@ -479,6 +745,8 @@ mod table {
                                 balance: self.columns.balance.join_words_in(line),
                                 top: line.top,
                                 height: line.height,
+                                page_num: self.page_num,
+                                line_num: line.num,
                             });
                         }
                     }
@@ -498,6 +766,25 @@ mod table {
         }
     }
 
+    /// The kind of a row that states the balance at a page or statement
+    /// boundary.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub enum BalanceKind {
+        BroughtForward,
+        CarriedForward,
+    }
+
+    fn balance_kind(detail: &str) -> Option<BalanceKind> {
+        let detail = detail.to_lowercase();
+        if detail.starts_with(&BROUGHT_FORWARD.to_lowercase()) {
+            Some(BalanceKind::BroughtForward)
+        } else if detail.starts_with(&CARRIED_FORWARD.to_lowercase()) {
+            Some(BalanceKind::CarriedForward)
+        } else {
+            None
+        }
+    }
+
     /// Line containing some transaction information. It may not encompass
     /// complete information about the transaction, which may continue on
     /// following rows.
@@ -512,6 +799,22 @@ mod table {
         // Spatial position of the line on the page.
         pub top: i32,
         pub height: i32,
+
+        // Position of the line in the OCR output.
+        pub page_num: i32,
+        pub line_num: i32,
+    }
+
+    impl TransactionLine {
+        /// Returns the reference to the line for `tags::SOURCE_LINE`.
+        pub fn source_line(&self) -> String {
+            format!("{}:{}", self.page_num, self.line_num)
+        }
+
+        /// Returns the kind of balance row that this is, if it is one.
+        pub fn balance_kind(&self) -> Option<BalanceKind> {
+            balance_kind(&self.detail)
+        }
     }
 
     impl fmt::Display for TransactionLine {
@@ -554,31 +857,62 @@ mod table {
             None
         }
 
+        /// Finds the columns from a line of headings. Words that are not part
+        /// of a heading are ignored, but each column must have exactly one
+        /// heading.
         fn find_in_line(line_idx: usize, line: &Line) -> Option<Self> {
-            if line.words.len() < 5 {
-                return None;
+            let mut headings: Vec<(ColumnKind, tesseract::Bounds)> = Vec::new();
+            let mut word_idx = 0;
+            while word_idx < line.words.len() {
+                match match_heading(&line.words[word_idx..]) {
+                    Some((kind, num_words)) => {
+                        if headings.iter().any(|(seen, _)| *seen == kind) {
+                            return None;
+                        }
+                        let first = &line.words[word_idx];
+                        let last = &line.words[word_idx + num_words - 1];
+                        headings.push((
+                            kind,
+                            tesseract::Bounds {
+                                min: first.left,
+                                max: last.horiz_bounds().max,
+                            },
+                        ));
+                        word_idx += num_words;
+                    }
+                    None => word_idx += 1,
+                }
             }
-
-            if line.words[0].text != DATE
-                || line.words[1].text != DETAILS
-                || line.words[2].text != PAYMENTS
-                || line.words[3].text != RECEPITS
-                || line.words[4].text != BALANCE
-            {
+            if headings.len() != HEADINGS.len() {
                 return None;
             }
 
+            // The outermost columns are bounded by their headings, and the
+            // others extend up to the heading of the next column.
+            headings.sort_by_key(|(_, bounds)| bounds.min);
+            let last_idx = headings.len() - 1;
+            let column = |kind: ColumnKind| -> ColumnPos {
+                let idx = headings
+                    .iter()
+                    .position(|(k, _)| *k == kind)
+                    .expect("all columns were found");
+                let bounds = headings[idx].1;
+                if idx == 0 || idx == last_idx {
+                    ColumnPos {
+                        horiz_bounds: bounds,
+                    }
+                } else {
+                    ColumnPos::new(bounds.min, headings[idx + 1].1.min)
+                }
+            };
+
             Some(Self {
                 header_line_idx: line_idx,
-                date: ColumnPos {
-                    horiz_bounds: line.words[0].horiz_bounds(),
-                },
-                details: ColumnPos::new(line.words[1].left, line.words[2].left),
-                payments: ColumnPos::new(line.words[2].left, line.words[3].left),
-                receipts: ColumnPos::new(line.words[3].left, line.words[4].left),
-                balance: ColumnPos {
-                    horiz_bounds: line.words[4].horiz_bounds(),
-                },
+                date: column(ColumnKind::Date),
+                details: column(ColumnKind::Details),
+                payments: column(ColumnKind::Payments),
+                receipts: column(ColumnKind::Receipts),
+                balance: column(ColumnKind::Balance),
             })
         }
 
@@ -639,6 +973,35 @@ mod table {
         }
     }
 
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    enum ColumnKind {
+        Date,
+        Details,
+        Payments,
+        Receipts,
+        Balance,
+    }
+
+    /// Matches the start of `words` against the column headings, returning
+    /// the column and the number of words in its heading.
+    fn match_heading(words: &[Word]) -> Option<(ColumnKind, usize)> {
+        HEADINGS.iter().find_map(|(kind, headings)| {
+            headings.iter().find_map(|heading| {
+                let heading_words: Vec<&str> = heading.split(' ').collect();
+                let matches = words.len() >= heading_words.len()
+                    && words
+                        .iter()
+                        .zip(&heading_words)
+                        .all(|(word, heading_word)| word.text.eq_ignore_ascii_case(heading_word));
+                if matches {
+                    Some((*kind, heading_words.len()))
+                } else {
+                    None
+                }
+            })
+        })
+    }
+
     fn parse_date_component(
         parsed: &mut date_fmt::Parsed,
         component: date_fmt::Item,
@@ -696,7 +1059,7 @@ mod table {
 
 /// Looks for a line starting with text like:
 ///
-/// ```
+/// ```text
 /// Account Number 12-34-56 12345678
 /// ```
 ///
@@ -735,3 +1098,146 @@ fn find_account_name(doc: &tesseract::Document) -> Option<String> {
 
     None
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    #[test_case("tesseract 4.1.1\n leptonica-1.79.0" => Some(4); "v4")]
+    #[test_case("tesseract v5.3.0\n leptonica-1.82.0" => Some(5); "v5 with prefix")]
+    #[test_case("tesseract 3.05.02" => Some(3); "v3")]
+    #[test_case("something else" => None; "unrecognized")]
+    fn tesseract_major_version(version_text: &str) -> Option<u32> {
+        super::tesseract_major_version(version_text)
+    }
+
+    fn ocr_line(num: i32, words: &[(i32, &str)]) -> tesseract::Line {
+        tesseract::Line {
+            num,
+            top: num * 10,
+            height: 10,
+            words: words
+                .iter()
+                .map(|&(left, text)| tesseract::Word {
+                    num: 0,
+                    left,
+                    width: 10 * text.len() as i32,
+                    text: text.to_string(),
+                })
+                .collect(),
+        }
+    }
+
+    #[test_case(
+        &[(0, "Date"), (100, "Details"), (400, "Payments"), (500, "Receipts"), (600, "Balance")]
+        => (Some("10.00".to_string()), None);
+        "payments and receipts"
+    )]
+    #[test_case(
+        &[(0, "Date"), (100, "Description"), (400, "Money"), (460, "in"), (500, "Money"), (560, "out"), (600, "Balance")]
+        => (None, Some("10.00".to_string()));
+        "money in before money out"
+    )]
+    fn table_headings(headings: &[(i32, &str)]) -> (Option<String>, Option<String>) {
+        let page = tesseract::Page {
+            num: 1,
+            blocks: vec![tesseract::Block {
+                num: 1,
+                paragraphs: vec![tesseract::Paragraph {
+                    num: 1,
+                    lines: vec![
+                        ocr_line(1, headings),
+                        ocr_line(2, &[(0, "2019")]),
+                        ocr_line(
+                            3,
+                            &[
+                                (0, "02"),
+                                (30, "Jan"),
+                                (100, "Shop"),
+                                (410, "10.00"),
+                                (610, "90.00"),
+                            ],
+                        ),
+                    ],
+                }],
+            }],
+        };
+        let tables: Vec<table::Table> = table::Table::find_in_page(&page).collect();
+        assert_eq!(tables.len(), 1);
+        let trn_lines = tables[0].read_lines().expect("read lines");
+        assert_eq!(trn_lines.len(), 1);
+        assert_eq!(trn_lines[0].detail, "Shop");
+        assert_eq!(trn_lines[0].balance.as_deref(), Some("90.00"));
+        (trn_lines[0].payment.clone(), trn_lines[0].receipt.clone())
+    }
+
+    #[test]
+    fn table_headings_incomplete() {
+        let page = tesseract::Page {
+            num: 1,
+            blocks: vec![tesseract::Block {
+                num: 1,
+                paragraphs: vec![tesseract::Paragraph {
+                    num: 1,
+                    lines: vec![ocr_line(
+                        1,
+                        &[
+                            (0, "Date"),
+                            (100, "Details"),
+                            (400, "Money"),
+                            (460, "out"),
+                            (600, "Balance"),
+                        ],
+                    )],
+                }],
+            }],
+        };
+        assert_eq!(table::Table::find_in_page(&page).count(), 0);
+    }
+
+    fn line(
+        day: u32,
+        detail: &str,
+        payment: Option<&str>,
+        balance: Option<&str>,
+    ) -> table::TransactionLine {
+        table::TransactionLine {
+            implied_date: NaiveDate::from_ymd_opt(2019, 1, day),
+            detail: detail.to_string(),
+            payment: payment.map(str::to_string),
+            receipt: None,
+            balance: balance.map(str::to_string),
+            top: 0,
+            height: 0,
+            page_num: 1,
+            line_num: day as i32,
+        }
+    }
+
+    #[test]
+    fn balances_brought_and_carried_forward() {
+        let mut acc = TransactionsAccumulator::new(HashSpec::default(), "ns".to_string());
+        for trn_line in [
+            line(1, "Balance brought forward", None, Some("100.00")),
+            line(2, "Shop", Some("10.00"), Some("90.00")),
+            line(2, "Balance carried forward", None, Some("90.00")),
+            line(2, "Balance brought forward", None, Some("90.00")),
+        ] {
+            acc.feed_line(&trn_line).expect("feed line");
+        }
+        let (trns, balances) = acc.build().expect("build");
+
+        let descriptions: Vec<&str> = trns.iter().map(|trn| trn.description.as_str()).collect();
+        assert_eq!(descriptions, vec!["Balance brought forward", "Shop"]);
+        assert_eq!(trns[0].date, NaiveDate::from_ymd_opt(2019, 1, 1).unwrap());
+        assert_eq!(
+            trns[0].postings[0].balance,
+            Some(ledger_parser::Balance::Amount(
+                money::parse_gbp("100.00").unwrap()
+            ))
+        );
+        assert_eq!(balances.closing, Some(money::parse_gbp("90.00").unwrap()));
+    }
+}
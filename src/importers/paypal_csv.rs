@@ -1,27 +1,39 @@
+use std::path::Path;
+
 use anyhow::{anyhow, bail, Result};
 use chrono::{DateTime, FixedOffset, NaiveDateTime, TimeZone};
 use chrono_tz::Tz;
 use clap::Args;
 use itertools::Itertools;
-use ledger_parser::{Amount, Balance, Commodity, CommodityPosition, Posting, Reality, Transaction};
+use ledger_parser::{Amount, Balance, CommodityPrice, Posting, Reality, Transaction};
 
 use crate::accounts::ASSETS_UNKNOWN;
 use crate::comment::Comment;
 use crate::filespec::FileSpec;
-use crate::fingerprint::FingerprintBuilder;
+use crate::fingerprint::{FingerprintBuilder, HashSpec};
 use crate::importers::importer::TransactionImporter;
-use crate::importers::util::{self_and_peer_account_amount, self_and_peer_fingerprints};
+use crate::importers::registry::Registry;
+use crate::importers::util::date::DateFormatOpts;
+use crate::importers::util::{
+    conversion_price, self_and_peer_account_amount, self_and_peer_fingerprints,
+};
 use crate::ledgerutil::simple_posting_amount;
+use crate::money::{self, parse_quantity, NumberFormat};
 use crate::tags;
 use crate::tzabbr::TzAbbrDB;
 
-use super::importer::Import;
+use super::importer::{Import, Metadata};
+
+const BANK_NAME: &str = "PayPal";
 
 /// Transaction name field, provided by PayPal.
 const TRANSACTION_NAME_TAG: &str = "trn_name";
 /// Transaction type field, provided by PayPal.
 const TRANSACTION_TYPE_TAG: &str = "trn_type";
 
+/// Value of the type field for the records of a currency conversion.
+const CURRENCY_CONVERSION_TYPE: &str = "General Currency Conversion";
+
 #[derive(Debug, Args)]
 /// Converts from PayPal CSV format to Ledger transactions.
 pub struct PaypalCsv {
@@ -33,30 +45,74 @@ pub struct PaypalCsv {
     #[arg(long = "fingerprint-namespace", default_value = "paypal")]
     // User namespace of the fingerprints to generate.
     fp_ns: String,
-    /// Timezone abbreviations CSV file to use.
+    /// Hash algorithm, encoding and length of fingerprint hashes, e.g. "b64"
+    /// (the default), "b32_16", "hex_12" or "sha256_b64".
+    #[arg(long = "fingerprint-hash", default_value = "b64")]
+    fp_hash: HashSpec,
+    /// Identifier of the PayPal account (e.g. its email address). This is
+    /// used to look up the account in the --accounts-file registry.
+    #[arg(long = "account-id")]
+    account_id: Option<String>,
+    /// Timezone abbreviations CSV file to use, with columns `abbreviation`
+    /// and either or both of `utc_offset` (e.g. "UTC+01") and `zone` (e.g.
+    /// "Europe/London"). An abbreviation defined by its zone takes the
+    /// zone's offset at the time of the transaction, so "BST" and "GMT" can
+    /// both be defined in "Europe/London".
     timezone_abbr_file: FileSpec,
+    /// Chooses between the zones of an abbreviation defined in more than one,
+    /// given as a zone name or prefix (e.g. "America/"). May be given
+    /// multiple times, in order of preference.
+    #[arg(long = "timezone-region", value_name = "REGION")]
+    timezone_regions: Vec<String>,
     /// Generate the legacy fingerprint tag.
     #[arg(long = "include-legacy-fingerprint")]
     include_legacy_fingerprint: bool,
+    /// Tag each transaction with a `tz` value tag naming --output-timezone,
+    /// the timezone of its `time` tag.
+    #[arg(long = "timezone-tag")]
+    timezone_tag: bool,
+    /// How amounts are written in the CSV file: "uk" (1,234.56) or
+    /// "comma-decimal" (1.234,56).
+    #[arg(long = "number-format", default_value = "uk")]
+    number_format: NumberFormat,
+    #[command(flatten)]
+    date_format: DateFormatOpts,
 }
 
 impl TransactionImporter for PaypalCsv {
-    fn get_transactions(&self) -> Result<Import> {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
         let mut csv_rdr = csv::ReaderBuilder::new()
             .has_headers(true)
             .flexible(false)
             .trim(csv::Trim::All)
-            .from_reader(self.input.reader()?);
+            .from_reader(self.input.lf_reader()?);
         let headers = csv_rdr.headers()?.clone();
         let mut csv_records = csv_rdr.records();
 
-        let tz_abbrs = TzAbbrDB::from_reader(self.timezone_abbr_file.reader()?)?;
+        let tz_abbrs = TzAbbrDB::from_reader(self.timezone_abbr_file.reader()?)?
+            .with_preferred_regions(self.timezone_regions.clone());
+
+        let fp_ns = self
+            .account_id
+            .as_deref()
+            .and_then(|id| accounts.fp_namespace(id))
+            .unwrap_or(&self.fp_ns);
 
-        let transactions = self.read_transactions(&headers, &mut csv_records, &tz_abbrs)?;
+        let (transactions, prices) =
+            self.read_transactions(&headers, &mut csv_records, &tz_abbrs, fp_ns)?;
+
+        let mut metadata = Metadata::new(BANK_NAME).with_period_from(&transactions);
+        metadata.account = self.account_id.clone();
 
         Ok(Import {
-            user_fp_namespace: self.fp_ns.clone(),
+            user_fp_namespace: fp_ns.to_string(),
             transactions,
+            prices,
+            metadata,
         })
     }
 }
@@ -67,17 +123,54 @@ impl PaypalCsv {
         headers: &csv::StringRecord,
         csv_records: &mut csv::StringRecordsIter<R>,
         tz_abbrs: &TzAbbrDB,
-    ) -> Result<Vec<Transaction>> {
+        fp_ns: &str,
+    ) -> Result<(Vec<Transaction>, Vec<CommodityPrice>)> {
         let records: Vec<Record> = csv_records
-            .map(|row| deserialize_row(row, headers, tz_abbrs, &self.fp_ns))
+            .map(|row| {
+                deserialize_row(
+                    row,
+                    headers,
+                    tz_abbrs,
+                    self.fp_hash,
+                    fp_ns,
+                    self.number_format,
+                    &self.date_format,
+                )
+            })
             .collect::<Result<Vec<Record>>>()?;
 
         let record_groups = records.into_iter().group_by(|record| record.datetime);
 
-        record_groups
+        let mut transactions = Vec::new();
+        let mut prices = Vec::new();
+        for (dt, group) in &record_groups {
+            let records: Vec<Record> = group.collect();
+            prices.extend(self.conversion_prices(dt, &records));
+            transactions.push(self.form_transaction(dt, records)?);
+        }
+        Ok((transactions, prices))
+    }
+
+    /// Returns the prices implied by the currency conversions within a group
+    /// of records. Each conversion consists of a record debiting one currency
+    /// followed by a record crediting another.
+    fn conversion_prices(
+        &self,
+        dt: DateTime<FixedOffset>,
+        records: &[Record],
+    ) -> Vec<CommodityPrice> {
+        let datetime = dt.with_timezone(&self.output_timezone).naive_local();
+        let conversions: Vec<&Record> = records
+            .iter()
+            .filter(|record| record.type_ == CURRENCY_CONVERSION_TYPE)
+            .collect();
+        let (from, to): (Vec<&Record>, Vec<&Record>) = conversions
             .into_iter()
-            .map(|(dt, group)| self.form_transaction(dt, group.collect::<Vec<Record>>()))
-            .collect::<Result<Vec<Transaction>>>()
+            .partition(|record| record.amount.quantity.is_sign_negative());
+        from.iter()
+            .zip(to.iter())
+            .filter_map(|(from, to)| conversion_price(datetime, &from.amount, &to.amount))
+            .collect()
     }
 
     fn form_transaction(
@@ -85,12 +178,28 @@ impl PaypalCsv {
         dt: DateTime<FixedOffset>,
         records: Vec<Record>,
     ) -> Result<Transaction> {
-        let date = dt.with_timezone(&self.output_timezone).naive_local().date();
+        let datetime = dt.with_timezone(&self.output_timezone).naive_local();
 
         let description = records
             .iter()
             .find_map(|record| record.name.clone())
             .ok_or_else(|| anyhow!("no record has a name for transactions at date time {}", dt))?;
+        let lines: Vec<String> = records
+            .iter()
+            .filter_map(|record| record.line)
+            .map(|line| line.to_string())
+            .collect();
+        let comment = Comment::builder()
+            .with_option_value_tag(
+                tags::SOURCE_LINE,
+                Some(lines.join(",")).filter(|lines| !lines.is_empty()),
+            )
+            .with_value_tag(tags::TIME, datetime.format("%H:%M:%S").to_string())
+            .with_option_value_tag(
+                tags::TIMEZONE,
+                Some(self.output_timezone.name()).filter(|_| self.timezone_tag),
+            )
+            .build();
 
         let mut postings = Vec::new();
         for (i, mut record) in records.into_iter().enumerate() {
@@ -103,8 +212,8 @@ impl PaypalCsv {
         Ok(Transaction {
             description,
             code: None,
-            comment: None,
-            date,
+            comment: comment.into_opt_comment(),
+            date: datetime.date(),
             effective_date: None,
             status: None,
             postings,
@@ -122,6 +231,7 @@ impl PaypalCsv {
                 None
             })
             .with_tag(fp.self_.tag())
+            .with_option_value_tag(tags::MATCH_KEY, record.receipt_id)
             .build();
         let mut peer_comment = Comment::builder()
             .with_tag(tags::IMPORT_PEER)
@@ -131,12 +241,13 @@ impl PaypalCsv {
             } else {
                 None
             })
+            .with_tag(fp.peer.tag())
             .with_value_tag(TRANSACTION_TYPE_TAG, record.type_)
             .build();
         if let Some(name) = record.name {
             peer_comment
                 .value_tags
-                .insert(TRANSACTION_NAME_TAG.to_string(), name);
+                .insert(TRANSACTION_NAME_TAG.into(), name);
         }
 
         let halves = self_and_peer_account_amount(record.amount, ASSETS_UNKNOWN.to_string());
@@ -171,25 +282,34 @@ struct Record {
     status: de::Status,
     amount: Amount,
     balance: Amount,
+    receipt_id: Option<String>,
     partial_fp: FingerprintBuilder,
+    /// Line number of the row in the CSV file.
+    line: Option<u64>,
 }
 
 impl Record {
-    fn from_csv_record(v: de::Record, tz_abbrs: &TzAbbrDB, fp_ns: &str) -> Result<Self> {
-        let commodity = Commodity {
-            name: v.currency,
-            position: CommodityPosition::Left,
-        };
-        let amount = Amount {
-            quantity: v.amount,
-            commodity: commodity.clone(),
-        };
-        let balance = Amount {
-            quantity: v.balance,
-            commodity,
-        };
-        let partial_fp = FingerprintBuilder::new("ppcsv", 1, fp_ns)?
-            .with(v.date.0)
+    fn from_csv_record(
+        v: de::Record,
+        tz_abbrs: &TzAbbrDB,
+        fp_hash: HashSpec,
+        fp_ns: &str,
+        number_format: NumberFormat,
+        date_format: &DateFormatOpts,
+    ) -> Result<Self> {
+        let date = date_format.parse(&v.date)?;
+        // Quantities were previously deserialized via floating point, which
+        // dropped trailing zeros. Normalize to keep fingerprints stable.
+        let amount = money::amount(
+            parse_quantity(&v.amount, number_format)?.normalize(),
+            &v.currency,
+        );
+        let balance = money::amount(
+            parse_quantity(&v.balance, number_format)?.normalize(),
+            &v.currency,
+        );
+        let partial_fp = FingerprintBuilder::new("ppcsv", 1, fp_hash, fp_ns)?
+            .with(date)
             .with(v.time.0)
             .with(v.time_zone.as_str())
             .with(v.name.as_deref())
@@ -199,9 +319,9 @@ impl Record {
             .with(&amount)
             .with(&balance);
 
-        let naive_datetime = NaiveDateTime::new(v.date.0, v.time.0);
+        let naive_datetime = NaiveDateTime::new(date, v.time.0);
 
-        let tz = parse_timezone(tz_abbrs, &v.time_zone)?;
+        let tz = tz_abbrs.resolve(&v.time_zone, &naive_datetime)?;
 
         use chrono::LocalResult;
         let datetime: DateTime<FixedOffset> = match tz.from_local_datetime(&naive_datetime) {
@@ -224,7 +344,9 @@ impl Record {
             status: v.status,
             amount,
             balance,
+            receipt_id: v.receipt_id.filter(|id| !id.is_empty()),
             partial_fp,
+            line: None,
         })
     }
 }
@@ -233,26 +355,38 @@ fn deserialize_row(
     sr: csv::Result<csv::StringRecord>,
     headers: &csv::StringRecord,
     tz_abbrs: &TzAbbrDB,
+    fp_hash: HashSpec,
     fp_ns: &str,
+    number_format: NumberFormat,
+    date_format: &DateFormatOpts,
 ) -> Result<Record> {
-    let de_record: de::Record = sr?.deserialize(Some(headers))?;
-    Record::from_csv_record(de_record, tz_abbrs, fp_ns)
+    let sr = sr?;
+    let de_record: de::Record = sr.deserialize(Some(headers))?;
+    let mut record = Record::from_csv_record(
+        de_record,
+        tz_abbrs,
+        fp_hash,
+        fp_ns,
+        number_format,
+        date_format,
+    )?;
+    record.line = sr.position().map(|pos| pos.line());
+    Ok(record)
 }
 
 mod de {
     use std::fmt;
 
-    use chrono::{NaiveDate, NaiveTime};
+    use chrono::NaiveTime;
 
     use ledger_parser::TransactionStatus;
-    use rust_decimal::Decimal;
-    use serde::{de, Deserialize, Deserializer};
+    use serde::de::{self, Deserialize, Deserializer};
     use serde_derive::Deserialize;
 
     #[derive(Deserialize)]
     pub struct Record {
         #[serde(rename = "Date")]
-        pub date: Date,
+        pub date: String,
         #[serde(rename = "Time")]
         pub time: Time,
         #[serde(rename = "Time zone")]
@@ -266,35 +400,11 @@ mod de {
         #[serde(rename = "Currency")]
         pub currency: String,
         #[serde(rename = "Amount")]
-        pub amount: Decimal,
+        pub amount: String,
         #[serde(rename = "Receipt ID")]
         pub receipt_id: Option<String>,
         #[serde(rename = "Balance")]
-        pub balance: Decimal,
-    }
-
-    #[derive(Debug)]
-    pub struct Date(pub NaiveDate);
-
-    impl<'de> Deserialize<'de> for Date {
-        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
-            d.deserialize_str(DateVisitor)
-        }
-    }
-
-    struct DateVisitor;
-    impl<'de> de::Visitor<'de> for DateVisitor {
-        type Value = Date;
-
-        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-            formatter.write_str("a date string in \"DD/MM/YYYY\" format")
-        }
-
-        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
-            NaiveDate::parse_from_str(s, "%d/%m/%Y")
-                .map(Date)
-                .map_err(de::Error::custom)
-        }
+        pub balance: String,
     }
 
     #[derive(Clone, Copy, Debug, Deserialize)]
@@ -339,13 +449,6 @@ mod de {
     }
 }
 
-fn parse_timezone(tz_abbr: &TzAbbrDB, s: &str) -> Result<FixedOffset> {
-    if let Some(tz) = tz_abbr.abbr_to_tz(s) {
-        return Ok(tz);
-    }
-    bail!("unknown timezone {:?}", s);
-}
-
 #[cfg(test)]
 mod tests {
     use crate::importers::testutil::golden_test;
@@ -360,11 +463,17 @@ mod tests {
                 input: FileSpec::from_str("testdata/importers/paypal_csv.csv").unwrap(),
                 output_timezone: Tz::UTC,
                 fp_ns: "paypal".to_string(),
+                fp_hash: Default::default(),
+                account_id: None,
                 timezone_abbr_file: FileSpec::from_str(
                     "testdata/importers/paypal_csv_tz_abbrs.csv",
                 )
                 .unwrap(),
+                timezone_regions: Vec::new(),
                 include_legacy_fingerprint: true,
+                timezone_tag: true,
+                number_format: NumberFormat::UK,
+                date_format: Default::default(),
             },
             "paypal_csv.golden.journal",
         );
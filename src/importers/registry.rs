@@ -0,0 +1,173 @@
+//! Registry of the user's accounts, mapping the account identifiers found in
+//! statements to per-account import configuration.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde_derive::Deserialize;
+
+use crate::accounts::{Account, Taxonomy};
+use crate::internal::TransactionPostings;
+use crate::rules::table;
+use crate::tags;
+
+/// Import configuration for a single account.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccountConfig {
+    /// Ledger account name to use for the postings of the account being
+    /// imported, in place of `assets:unknown`.
+    #[serde(default)]
+    pub account: Option<Account>,
+    /// Fingerprint user namespace to use for the account, in place of any
+    /// given on the command line.
+    #[serde(default)]
+    pub fp_namespace: Option<String>,
+    /// Rules file to apply to the imported transactions. Relative paths are
+    /// relative to the registry file.
+    #[serde(default)]
+    pub rules: Option<PathBuf>,
+}
+
+impl AccountConfig {
+    /// Applies the configuration to freshly imported transactions: sets the
+    /// account of the import-self postings, then applies any rules file.
+    pub fn apply(&self, mut trns: Vec<TransactionPostings>) -> Result<Vec<TransactionPostings>> {
+        if let Some(account) = &self.account {
+            for post in trns.iter_mut().flat_map(|trn| trn.posts.iter_mut()) {
+                if post.comment.tags.contains(tags::IMPORT_SELF)
+                    && post.comment.tags.contains(tags::UNKNOWN_ACCOUNT)
+                {
+                    post.raw.account = account.to_string();
+                    post.comment.tags.remove(tags::UNKNOWN_ACCOUNT);
+                }
+            }
+        }
+        if let Some(rules) = &self.rules {
+            let table = table::load_from_path(rules)
+                .with_context(|| format!("loading rules file {:?}", rules))?;
+            trns = table.update_transactions(trns)?;
+        }
+        Ok(trns)
+    }
+}
+
+/// Account configurations, keyed by the account identifier as it appears in
+/// statements (e.g. the Nationwide account name, or a PayPal email address).
+#[derive(Debug, Default)]
+pub struct Registry {
+    accounts: HashMap<String, AccountConfig>,
+    /// The roots that imported unknown accounts are put under.
+    taxonomy: Taxonomy,
+}
+
+impl Registry {
+    /// Reads a registry from a RON file containing a map from account
+    /// identifier to `AccountConfig`.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let reader = std::fs::File::open(path)
+            .with_context(|| format!("opening accounts file {:?}", path))?;
+        let mut accounts: HashMap<String, AccountConfig> = ron::de::from_reader(reader)
+            .with_context(|| format!("parsing accounts file {:?}", path))?;
+        if let Some(dir) = path.parent() {
+            for config in accounts.values_mut() {
+                if let Some(rules) = config.rules.as_mut() {
+                    *rules = dir.join(&rules);
+                }
+            }
+        }
+        Ok(Self {
+            accounts,
+            taxonomy: Taxonomy::default(),
+        })
+    }
+
+    /// Sets the roots that imported unknown accounts are put under.
+    pub fn with_taxonomy(mut self, taxonomy: Taxonomy) -> Self {
+        self.taxonomy = taxonomy;
+        self
+    }
+
+    pub fn taxonomy(&self) -> &Taxonomy {
+        &self.taxonomy
+    }
+
+    pub fn get(&self, identifier: &str) -> Option<&AccountConfig> {
+        self.accounts.get(identifier)
+    }
+
+    /// Returns the fingerprint namespace configured for the account, if any.
+    pub fn fp_namespace(&self, identifier: &str) -> Option<&str> {
+        self.get(identifier)?.fp_namespace.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test]
+    fn apply_sets_self_account() {
+        let config = AccountConfig {
+            account: Some(Account::new("assets:current")),
+            ..Default::default()
+        };
+        let got = config
+            .apply(parse_transaction_postings(
+                r#"
+                2001/01/02 description
+                    assets:unknown  GBP10.00
+                    ; :import-self:unknown-account:
+                    assets:unknown  GBP-10.00
+                    ; :import-peer:unknown-account:
+                "#,
+            ))
+            .expect("apply");
+        let want = parse_transaction_postings(
+            r#"
+            2001/01/02 description
+                assets:current  GBP10.00
+                ; :import-self:
+                assets:unknown  GBP-10.00
+                ; :import-peer:unknown-account:
+            "#,
+        );
+        assert_transaction_postings_eq!(want, got);
+    }
+
+    #[test]
+    fn from_path() {
+        let mut f = tempfile::NamedTempFile::new().expect("create temp file");
+        f.write_all(
+            br#"{
+                "FlexAccount 123": (
+                    account: Some("assets:current"),
+                    fp_namespace: Some("current"),
+                    rules: Some("current.ron"),
+                ),
+                "someone@example.com": (
+                    account: Some("assets:paypal"),
+                ),
+            }"#,
+        )
+        .expect("write temp file");
+
+        let registry = Registry::from_path(f.path()).expect("should parse");
+
+        let current = registry.get("FlexAccount 123").expect("current account");
+        assert_eq!(current.account, Some(Account::new("assets:current")));
+        assert_eq!(registry.fp_namespace("FlexAccount 123"), Some("current"));
+        assert_eq!(
+            current.rules,
+            Some(f.path().parent().unwrap().join("current.ron"))
+        );
+
+        assert_eq!(registry.fp_namespace("someone@example.com"), None);
+        assert!(registry.get("unknown").is_none());
+    }
+}
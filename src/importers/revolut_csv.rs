@@ -0,0 +1,288 @@
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use ledger_parser::{Amount, Balance, Posting, Reality, Transaction, TransactionStatus};
+
+use crate::accounts::ASSETS_UNKNOWN;
+use crate::comment::Comment;
+use crate::filespec::FileSpec;
+use crate::fingerprint::{FingerprintBuilder, HashSpec};
+use crate::importers::importer::TransactionImporter;
+use crate::importers::registry::Registry;
+use crate::importers::util::self_and_peer_account_amount;
+use crate::ledgerutil::simple_posting_amount;
+use crate::money::{self, NumberFormat};
+use crate::tags;
+
+use super::importer::{Import, Metadata};
+
+const BANK_NAME: &str = "Revolut";
+
+/// Transaction type field, provided by Revolut (e.g. "CARD_PAYMENT").
+const TRANSACTION_TYPE_TAG: &str = "trn_type";
+/// Product field, provided by Revolut (e.g. "Current", "Savings").
+const PRODUCT_TAG: &str = "product";
+
+#[derive(Debug, Args)]
+/// Converts from Revolut CSV format to Ledger transactions.
+pub struct RevolutCsv {
+    /// Revolut CSV file to read from. "-" reads from stdin.
+    input: FileSpec,
+    /// User namespace of the fingerprints to generate.
+    #[arg(long = "fingerprint-namespace", default_value = "revolut")]
+    fp_ns: String,
+    /// Hash algorithm, encoding and length of fingerprint hashes, e.g. "b64"
+    /// (the default), "b32_16", "hex_12" or "sha256_b64".
+    #[arg(long = "fingerprint-hash", default_value = "b64")]
+    fp_hash: HashSpec,
+    /// Identifier of the Revolut account. This is used to look up the account
+    /// in the --accounts-file registry.
+    #[arg(long = "account-id")]
+    account_id: Option<String>,
+    /// Account to post fees to. Fees are posted separately from the amount
+    /// of the transaction that incurred them.
+    #[arg(long = "fee-account", default_value = "expenses:fees")]
+    fee_account: String,
+}
+
+impl TransactionImporter for RevolutCsv {
+    fn input_path(&self) -> Option<&Path> {
+        self.input.path()
+    }
+
+    fn get_transactions(&self, accounts: &Registry) -> Result<Import> {
+        let mut csv_rdr = csv::ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(false)
+            .trim(csv::Trim::All)
+            .from_reader(self.input.lf_reader()?);
+        let headers = csv_rdr.headers()?.clone();
+
+        let fp_ns = self
+            .account_id
+            .as_deref()
+            .and_then(|id| accounts.fp_namespace(id))
+            .unwrap_or(&self.fp_ns);
+
+        let mut transactions = Vec::new();
+        for row in csv_rdr.records() {
+            let row = row?;
+            let line = row.position().map(|pos| pos.line());
+            let record: de::Record = row
+                .deserialize(Some(&headers))
+                .with_context(|| format!("parsing CSV line {}", line.unwrap_or_default()))?;
+            if let Some(trn) = self
+                .form_transaction(record, line, fp_ns)
+                .with_context(|| format!("on CSV line {}", line.unwrap_or_default()))?
+            {
+                transactions.push(trn);
+            }
+        }
+
+        let mut metadata = Metadata::new(BANK_NAME).with_period_from(&transactions);
+        metadata.account = self.account_id.clone();
+
+        Ok(Import {
+            user_fp_namespace: fp_ns.to_string(),
+            transactions,
+            prices: Vec::new(),
+            metadata,
+        })
+    }
+}
+
+impl RevolutCsv {
+    /// Forms the transaction for a record, or `None` for records that did not
+    /// move any money (i.e. declined or reverted transactions).
+    fn form_transaction(
+        &self,
+        record: de::Record,
+        line: Option<u64>,
+        fp_ns: &str,
+    ) -> Result<Option<Transaction>> {
+        let status = match record.state {
+            de::State::Completed => TransactionStatus::Cleared,
+            de::State::Pending => TransactionStatus::Pending,
+            de::State::Declined | de::State::Reverted | de::State::Failed => return Ok(None),
+        };
+
+        let commodity = record.currency.as_str();
+        let amount = money::parse_amount(&record.amount, commodity, NumberFormat::UK)?;
+        let fee = money::parse_amount(&record.fee, commodity, NumberFormat::UK)?;
+        let balance = record
+            .balance
+            .as_deref()
+            .map(|balance| money::parse_amount(balance, commodity, NumberFormat::UK))
+            .transpose()?;
+
+        let fpb = FingerprintBuilder::new("rvcsv", 1, self.fp_hash, fp_ns)?
+            .with(record.type_.as_str())
+            .with(record.product.as_str())
+            .with(record.started.0.date())
+            .with(record.started.0.time())
+            .with(record.description.as_str())
+            .with(&amount)
+            .with(&fee);
+        // Deliberately not including the state, completed date or balance, as
+        // these change once a pending transaction completes.
+
+        // The fee is taken from the account in addition to the amount.
+        let self_amount = Amount {
+            quantity: amount.quantity - fee.quantity,
+            commodity: amount.commodity.clone(),
+        };
+        let halves = self_and_peer_account_amount(amount, ASSETS_UNKNOWN.to_string());
+
+        let common_comment = Comment::builder()
+            .with_value_tag(tags::BANK, BANK_NAME)
+            .with_value_tag(TRANSACTION_TYPE_TAG, record.type_.clone())
+            .with_value_tag(PRODUCT_TAG, record.product.clone())
+            .with_option_value_tag(tags::ACCOUNT, self.account_id.clone());
+        let self_comment = common_comment
+            .clone()
+            .with_tag(tags::UNKNOWN_ACCOUNT)
+            .with_tag(fpb.clone().with("self").build().tag())
+            .with_tag(tags::IMPORT_SELF);
+        let peer_comment = common_comment
+            .clone()
+            .with_tag(tags::UNKNOWN_ACCOUNT)
+            .with_tag(fpb.clone().with("peer").build().tag())
+            .with_tag(tags::IMPORT_PEER);
+
+        let mut postings = vec![
+            Posting {
+                account: halves.self_.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(self_amount)),
+                balance: balance.map(Balance::Amount),
+                comment: self_comment.build().into_opt_comment(),
+                status: None,
+            },
+            Posting {
+                account: halves.peer.account,
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(halves.peer.amount)),
+                balance: None,
+                comment: peer_comment.build().into_opt_comment(),
+                status: None,
+            },
+        ];
+        if !fee.quantity.is_zero() {
+            let fee_comment = common_comment
+                .with_tag(fpb.with("fee").build().tag())
+                .with_tag(tags::IMPORT_PEER);
+            postings.push(Posting {
+                account: self.fee_account.clone(),
+                reality: Reality::Real,
+                amount: Some(simple_posting_amount(fee)),
+                balance: None,
+                comment: fee_comment.build().into_opt_comment(),
+                status: None,
+            });
+        }
+
+        let completed_date = record.completed.map(|completed| completed.0.date());
+        let date = record.started.0.date();
+        Ok(Some(Transaction {
+            date,
+            effective_date: completed_date.filter(|completed| *completed != date),
+            description: record.description,
+            comment: Comment::builder()
+                .with_option_value_tag(tags::SOURCE_LINE, line.map(|line| line.to_string()))
+                .build()
+                .into_opt_comment(),
+            status: Some(status),
+            code: None,
+            postings,
+        }))
+    }
+}
+
+mod de {
+    use std::fmt;
+
+    use chrono::NaiveDateTime;
+    use serde::de::{self, Deserialize, Deserializer};
+    use serde_derive::Deserialize;
+
+    #[derive(Deserialize)]
+    pub struct Record {
+        #[serde(rename = "Type")]
+        pub type_: String,
+        #[serde(rename = "Product")]
+        pub product: String,
+        #[serde(rename = "Started Date")]
+        pub started: DateTime,
+        #[serde(rename = "Completed Date")]
+        pub completed: Option<DateTime>,
+        #[serde(rename = "Description")]
+        pub description: String,
+        #[serde(rename = "Amount")]
+        pub amount: String,
+        #[serde(rename = "Fee")]
+        pub fee: String,
+        #[serde(rename = "Currency")]
+        pub currency: String,
+        #[serde(rename = "State")]
+        pub state: State,
+        #[serde(rename = "Balance")]
+        pub balance: Option<String>,
+    }
+
+    #[derive(Clone, Copy, Debug, Deserialize)]
+    #[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+    pub enum State {
+        Completed,
+        Pending,
+        Declined,
+        Reverted,
+        Failed,
+    }
+
+    #[derive(Debug)]
+    pub struct DateTime(pub NaiveDateTime);
+
+    impl<'de> Deserialize<'de> for DateTime {
+        fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+            d.deserialize_str(DateTimeVisitor)
+        }
+    }
+
+    struct DateTimeVisitor;
+    impl<'de> de::Visitor<'de> for DateTimeVisitor {
+        type Value = DateTime;
+
+        fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+            formatter.write_str("a date time string in \"YYYY-MM-DD HH:MM:SS\" format")
+        }
+
+        fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+            NaiveDateTime::parse_from_str(s, "%Y-%m-%d %H:%M:%S")
+                .map(DateTime)
+                .map_err(de::Error::custom)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::importers::testutil::golden_test;
+    use std::str::FromStr;
+
+    use super::*;
+
+    #[test]
+    fn golden() {
+        golden_test(
+            &RevolutCsv {
+                input: FileSpec::from_str("testdata/importers/revolut_csv.csv").unwrap(),
+                fp_ns: "revolut".to_string(),
+                fp_hash: Default::default(),
+                account_id: None,
+                fee_account: "expenses:fees".to_string(),
+            },
+            "revolut_csv.golden.journal",
+        );
+    }
+}
@@ -33,19 +33,37 @@ impl Document {
         Self { pages: Vec::new() }
     }
 
-    /// Reads the `Document` from a reader of a Tesseract TSV file.
-    pub fn from_tsv_reader<R: std::io::Read>(reader: R) -> Result<Self> {
-        let r = csv::ReaderBuilder::new()
-            .delimiter(b'\t')
-            .has_headers(true)
-            .trim(csv::Trim::All)
-            .from_reader(reader);
-
+    /// Reads the `Document` from readers of Tesseract TSV files, one for each
+    /// page in page order, as output by performing OCR on each page's image
+    /// separately.
+    pub fn from_page_tsv_readers<R, I>(readers: I) -> Result<Self>
+    where
+        R: std::io::Read,
+        I: IntoIterator<Item = R>,
+    {
         let mut doc = Document::new();
 
-        for record_res in r.into_deserialize() {
-            let record: Record = record_res?;
-            doc.feed_record(record)?;
+        for (idx, reader) in readers.into_iter().enumerate() {
+            let page_num = idx as i32 + 1;
+            let r = csv::ReaderBuilder::new()
+                .delimiter(b'\t')
+                .has_headers(true)
+                .trim(csv::Trim::All)
+                .from_reader(reader);
+
+            for record_res in r.into_deserialize() {
+                let mut record: Record = record_res?;
+                // Each file holds a single page, numbered 1.
+                if record.page_num != 1 {
+                    bail!(
+                        "TSV field page_num has bad TSV value {:?} in the file for page {}",
+                        record.page_num,
+                        page_num
+                    );
+                }
+                record.page_num = page_num;
+                doc.feed_record(record)?;
+            }
         }
 
         Ok(doc)
@@ -247,6 +265,8 @@ impl From<Record> for Line {
 
 #[derive(Debug)]
 pub struct Word {
+    // Word number currently unused.
+    #[allow(dead_code)]
     pub num: i32,
     pub left: i32,
     pub width: i32,
@@ -316,3 +336,58 @@ fn num_to_idx(num: i32, num_field: &'static str) -> Result<usize> {
     }
     Ok(num as usize - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEADER: &str =
+        "level\tpage_num\tblock_num\tpar_num\tline_num\tword_num\tleft\ttop\twidth\theight\tconf\ttext\n";
+
+    fn page_tsv(page_num: i32, word: &str) -> String {
+        format!(
+            "{HEADER}\
+             1\t{page_num}\t0\t0\t0\t0\t0\t0\t2480\t3508\t-1\t\n\
+             2\t{page_num}\t1\t0\t0\t0\t100\t200\t300\t40\t-1\t\n\
+             3\t{page_num}\t1\t1\t0\t0\t100\t200\t300\t40\t-1\t\n\
+             4\t{page_num}\t1\t1\t1\t0\t100\t200\t300\t40\t-1\t\n\
+             5\t{page_num}\t1\t1\t1\t1\t100\t200\t300\t40\t96\t{word}\n"
+        )
+    }
+
+    fn words(doc: &Document) -> Vec<(i32, &str)> {
+        doc.pages
+            .iter()
+            .flat_map(|page| {
+                page.blocks
+                    .iter()
+                    .flat_map(|block| &block.paragraphs)
+                    .flat_map(|paragraph| &paragraph.lines)
+                    .flat_map(|line| &line.words)
+                    .map(move |word| (page.num, word.text.as_str()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn combines_pages_in_order() {
+        let pages = [
+            page_tsv(1, "first"),
+            page_tsv(1, "second"),
+            page_tsv(1, "third"),
+        ];
+        let doc = Document::from_page_tsv_readers(pages.iter().map(String::as_bytes))
+            .expect("read pages");
+        assert_eq!(words(&doc), vec![(1, "first"), (2, "second"), (3, "third")]);
+    }
+
+    #[test]
+    fn rejects_multiple_pages_in_one_file() {
+        let tsv = page_tsv(1, "first") + &page_tsv(2, "second")[HEADER.len()..];
+        let err = Document::from_page_tsv_readers([tsv.as_bytes()]).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "TSV field page_num has bad TSV value 2 in the file for page 1"
+        );
+    }
+}
@@ -2,7 +2,9 @@ use std::io::Write;
 
 use goldenfile::Mint;
 
-use crate::{importers::importer::TransactionImporter, ledgerutil::ledger_from_transactions};
+use crate::importers::importer::TransactionImporter;
+use crate::importers::registry::Registry;
+use crate::ledgerutil::{ledger_from_transactions, ledger_with_prices};
 
 pub fn golden_test(importer: &dyn TransactionImporter, golden_path: &str) {
     let mut mint = Mint::new("testdata/importers");
@@ -11,8 +13,10 @@ pub fn golden_test(importer: &dyn TransactionImporter, golden_path: &str) {
         .new_goldenfile_with_differ(golden_path, differ)
         .expect("new goldenfile");
 
-    let import = importer.get_transactions().expect("perform import");
-    let ledger = ledger_from_transactions(import.transactions);
+    let import = importer
+        .get_transactions(&Registry::default())
+        .expect("perform import");
+    let ledger = ledger_with_prices(import.prices, ledger_from_transactions(import.transactions));
 
     let mut s = format!("{}", ledger);
     // Ensure that the file only ends in a single newline to make git
@@ -0,0 +1,88 @@
+//! Parsing of dates as written in bank statements.
+
+use anyhow::{anyhow, Result};
+use chrono::NaiveDate;
+use clap::Args;
+
+/// Formats that dates are accepted in unless an importer is configured
+/// otherwise, tried in order: "DD/MM/YYYY", "YYYY-MM-DD" and "DD Mon YYYY".
+pub const DEFAULT_FORMATS: &[&str] = &["%d/%m/%Y", "%Y-%m-%d", "%d %b %Y"];
+
+/// Parses a date in the first of `formats` that it matches.
+pub fn parse_date<S: AsRef<str>>(s: &str, formats: &[S]) -> Result<NaiveDate> {
+    formats
+        .iter()
+        .find_map(|format| NaiveDate::parse_from_str(s.trim(), format.as_ref()).ok())
+        .ok_or_else(|| {
+            anyhow!(
+                "bad date {:?}, expected one of the formats: {}",
+                s,
+                formats
+                    .iter()
+                    .map(|format| format!("{:?}", format.as_ref()))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })
+}
+
+/// Options for importers that let the user specify the date format.
+#[derive(Clone, Debug, Default, Args)]
+pub struct DateFormatOpts {
+    /// The format that dates are written in, as a chrono format string (e.g.
+    /// "%d/%m/%Y"). May be given multiple times, in which case each format is
+    /// tried in turn. Defaults to accepting "DD/MM/YYYY", "YYYY-MM-DD" and
+    /// "DD Mon YYYY".
+    #[arg(long = "date-format", value_name = "FORMAT")]
+    date_formats: Vec<String>,
+}
+
+impl DateFormatOpts {
+    /// Parses a date in any of the configured formats.
+    pub fn parse(&self, s: &str) -> Result<NaiveDate> {
+        if self.date_formats.is_empty() {
+            parse_date(s, DEFAULT_FORMATS)
+        } else {
+            parse_date(s, &self.date_formats)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    #[test_case("02/01/2001" => "2001-01-02"; "slashes")]
+    #[test_case("2001-01-02" => "2001-01-02"; "iso")]
+    #[test_case("02 Jan 2001" => "2001-01-02"; "month_name")]
+    #[test_case(" 02/01/2001 " => "2001-01-02"; "surrounding_space")]
+    fn parse_date_default_formats(s: &str) -> String {
+        parse_date(s, DEFAULT_FORMATS)
+            .expect("should parse")
+            .to_string()
+    }
+
+    #[test_case("32/01/2001"; "bad_day")]
+    #[test_case("2001/01/02"; "wrong_order")]
+    #[test_case(""; "empty")]
+    fn parse_date_invalid(s: &str) {
+        let err = parse_date(s, DEFAULT_FORMATS).expect_err("should not parse");
+        let msg = err.to_string();
+        assert!(msg.contains(&format!("{:?}", s)), "{}", msg);
+        assert!(msg.contains("\"%d/%m/%Y\""), "{}", msg);
+    }
+
+    #[test]
+    fn configured_formats() {
+        let opts = DateFormatOpts {
+            date_formats: vec!["%m/%d/%Y".to_string()],
+        };
+        assert_eq!(
+            opts.parse("01/02/2001").expect("should parse"),
+            NaiveDate::from_ymd_opt(2001, 1, 2).unwrap()
+        );
+        assert!(opts.parse("2001-01-02").is_err());
+    }
+}
@@ -1,15 +1,37 @@
-use ledger_parser::Amount;
+pub mod date;
+
+use chrono::NaiveDateTime;
+use ledger_parser::{Amount, CommodityPrice};
 
 use crate::accounts::{EXPENSES_UNKNOWN, INCOME_UNKNOWN};
 use crate::fingerprint::{Fingerprint, FingerprintBuilder};
+use crate::money;
 
-pub fn negate_amount(amt: Amount) -> Amount {
-    Amount {
-        quantity: -amt.quantity,
-        commodity: amt.commodity,
+/// Returns the price of the `from` commodity in terms of the `to` commodity,
+/// given the amounts of each exchanged in a currency conversion. Returns
+/// `None` if the amounts are of the same commodity, or `from` is zero.
+pub fn conversion_price(
+    datetime: NaiveDateTime,
+    from: &Amount,
+    to: &Amount,
+) -> Option<CommodityPrice> {
+    if from.commodity.name == to.commodity.name || from.quantity.is_zero() {
+        return None;
     }
+    let rate = (to.quantity / from.quantity).abs();
+    Some(CommodityPrice {
+        datetime,
+        commodity_name: from.commodity.name.clone(),
+        amount: Amount {
+            quantity: rate.round_dp(PRICE_DECIMAL_PLACES).normalize(),
+            commodity: to.commodity.clone(),
+        },
+    })
 }
 
+/// Decimal places that conversion prices are rounded to.
+const PRICE_DECIMAL_PLACES: u32 = 6;
+
 pub struct AccountAmount {
     pub account: String,
     pub amount: Amount,
@@ -37,7 +59,7 @@ pub fn self_and_peer_account_amount(
         },
         peer: AccountAmount {
             account: peer_account.to_string(),
-            amount: negate_amount(self_amount),
+            amount: money::negate(self_amount),
         },
     }
 }
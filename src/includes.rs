@@ -0,0 +1,364 @@
+//! Reading and writing journals that are split across multiple files joined by
+//! `include` directives.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ledger_parser::{CommodityPrice, LedgerItem};
+
+use crate::filespec::{self, FileSpec, WriteBatch};
+use crate::internal::{JournalEnds, JournalTransactions, KeptItems, TransactionPostings};
+use crate::ledgerutil::JournalItem;
+use crate::prices::Prices;
+use crate::tags::TRANSACTION_ORIGIN_KEY;
+
+/// The structure of a journal read by following `include` directives.
+#[derive(Debug)]
+pub struct IncludedJournal {
+    /// Files in the order that they were first read, starting with the root
+    /// file.
+    files: Vec<JournalFile>,
+}
+
+/// A single file within an `IncludedJournal`.
+#[derive(Debug)]
+struct JournalFile {
+    path: PathBuf,
+    /// The commodity price directives in the file.
+    prices: Vec<CommodityPrice>,
+    /// The directives (including includes) and top-level comments at the top
+    /// and end of the file. Those between its transactions are kept with the
+    /// transactions.
+    ends: JournalEnds,
+}
+
+impl IncludedJournal {
+    /// Reads the journal at `root`, and any journals that it includes
+    /// (recursively). Relative include paths are resolved relative to the
+    /// directory of the including file. Each transaction is tagged with the
+    /// file that it was read from.
+    pub fn read(root: &FileSpec) -> Result<(Self, Vec<TransactionPostings>)> {
+        let root = match root {
+            FileSpec::Path(path) => path,
+            FileSpec::Stdio => bail!("following includes requires a file path, not stdin"),
+        };
+        let mut reader = Reader {
+            files: Vec::new(),
+            seen: HashSet::new(),
+            stack: Vec::new(),
+            trns: Vec::new(),
+        };
+        reader.read(root)?;
+        Ok((
+            Self {
+                files: reader.files,
+            },
+            reader.trns,
+        ))
+    }
+
+    /// Returns the commodity prices from all files in the journal.
+    pub fn prices(&self) -> impl Iterator<Item = &CommodityPrice> {
+        self.files.iter().flat_map(|file| file.prices.iter())
+    }
+
+    /// Returns the directives and top-level comments at the top and end of
+    /// all files in the journal, other than include directives, for writing
+    /// it as a single file.
+    pub fn flattened_ends(&self) -> JournalEnds {
+        let mut ends = JournalEnds::default();
+        let not_include =
+            |item: &&JournalItem| !matches!(item, JournalItem::Ledger(LedgerItem::Include(_)));
+        for file in &self.files {
+            ends.header
+                .extend(file.ends.header.iter().filter(not_include).cloned());
+            ends.footer
+                .extend(file.ends.footer.iter().filter(not_include).cloned());
+        }
+        ends
+    }
+
+    /// Adds the transactions to `batch`, to be written back to the files that
+    /// they were read from, removing their origin tags. Transactions without
+    /// an origin (i.e. new ones) are written to the root file, as are any of
+    /// `new_prices` that are not already in the journal. Include directives
+    /// are written back where they were, relative to the transactions of their
+    /// file.
+    pub fn write_back(
+        &self,
+        trns: Vec<TransactionPostings>,
+        new_prices: Vec<CommodityPrice>,
+        batch: &mut WriteBatch,
+    ) -> Result<()> {
+        let root = &self.files[0].path;
+        let mut file_trns: Vec<Vec<TransactionPostings>> =
+            self.files.iter().map(|_| Vec::new()).collect();
+        for mut trn in trns {
+            let origin = trn
+                .trn
+                .comment
+                .value_tags
+                .remove(TRANSACTION_ORIGIN_KEY)
+                .map(PathBuf::from)
+                .unwrap_or_else(|| root.clone());
+            let index = self
+                .files
+                .iter()
+                .position(|file| file.path == origin)
+                .ok_or_else(|| anyhow!("transaction has unknown origin file {:?}", origin))?;
+            file_trns[index].push(trn);
+        }
+
+        let mut root_prices = Prices::new();
+        root_prices.extend(self.prices().cloned());
+        let new_prices: Vec<CommodityPrice> = new_prices
+            .into_iter()
+            .filter(|price| root_prices.add(price.clone()))
+            .collect();
+
+        for (i, (file, trns)) in self.files.iter().zip(file_trns).enumerate() {
+            let mut prices = file.prices.clone();
+            if i == 0 {
+                prices.extend(new_prices.iter().cloned());
+            }
+            let contents = JournalTransactions {
+                trns,
+                prices,
+                ends: file.ends.clone(),
+            };
+            batch.add_ledger(&FileSpec::Path(file.path.clone()), &contents.into_journal())?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes all origin tags from the transactions, and the include directives
+/// kept with them, for writing them to a single file.
+pub fn flatten(trns: &mut [TransactionPostings]) {
+    for trn in trns {
+        trn.trn.comment.value_tags.remove(TRANSACTION_ORIGIN_KEY);
+        trn.trn
+            .items_before
+            .retain(|item| !matches!(item, JournalItem::Ledger(LedgerItem::Include(_))));
+    }
+}
+
+struct Reader {
+    files: Vec<JournalFile>,
+    /// Canonical paths of all files read so far.
+    seen: HashSet<PathBuf>,
+    /// Paths of the files currently being read, for reporting cycles.
+    stack: Vec<PathBuf>,
+    trns: Vec<TransactionPostings>,
+}
+
+impl Reader {
+    fn read(&mut self, path: &Path) -> Result<()> {
+        let canonical =
+            filespec::canonicalize(path).with_context(|| format!("resolving path {:?}", path))?;
+        if self.stack.contains(&canonical) {
+            bail!(
+                "include cycle: {:?} includes {:?}",
+                self.stack.last().unwrap(),
+                path
+            );
+        }
+        if !self.seen.insert(canonical.clone()) {
+            bail!("{:?} is included more than once", path);
+        }
+
+        let journal = filespec::read_ledger_file(&FileSpec::Path(path.to_path_buf()))
+            .with_context(|| format!("reading {:?}", path))?;
+        let file_index = self.files.len();
+        self.files.push(JournalFile {
+            path: path.to_path_buf(),
+            prices: Vec::new(),
+            ends: JournalEnds::default(),
+        });
+        self.stack.push(canonical);
+
+        let dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let origin = path.to_string_lossy().into_owned();
+        let mut kept = KeptItems::default();
+        for item in journal.items {
+            match item {
+                JournalItem::Ledger(LedgerItem::Transaction(trn)) => {
+                    let mut trn_posts = kept.attach(trn.into());
+                    trn_posts
+                        .trn
+                        .comment
+                        .value_tags
+                        .insert(TRANSACTION_ORIGIN_KEY.into(), origin.clone());
+                    self.trns.push(trn_posts);
+                }
+                JournalItem::Ledger(LedgerItem::Include(include)) => {
+                    self.read(&dir.join(filespec::portable_path(Path::new(&include))))?;
+                    kept.push(LedgerItem::Include(include).into());
+                }
+                JournalItem::Ledger(LedgerItem::CommodityPrice(price)) => {
+                    self.files[file_index].prices.push(price);
+                }
+                other => kept.push(other),
+            }
+        }
+        self.files[file_index].ends = kept.finish();
+
+        self.stack.pop();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).expect("write file");
+        path
+    }
+
+    #[test]
+    fn read_and_write_back() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = write(
+            dir.path(),
+            "main.journal",
+            "include years/2001.journal\n\n2002/01/01 root\n  a  GBP1.00\n  b\n",
+        );
+        std::fs::create_dir(dir.path().join("years")).expect("create dir");
+        let included = write(
+            &dir.path().join("years"),
+            "2001.journal",
+            "2001/01/01 included\n  a  GBP2.00\n  b\n",
+        );
+
+        let (journal, mut trns) =
+            IncludedJournal::read(&FileSpec::Path(root.clone())).expect("read");
+        assert_eq!(trns.len(), 2);
+        assert_eq!(trns[0].trn.raw.description, "included");
+        assert_eq!(trns[1].trn.raw.description, "root");
+
+        for trn in &mut trns {
+            trn.trn.raw.description.push_str(" updated");
+        }
+        let mut batch = WriteBatch::new();
+        journal
+            .write_back(trns, Vec::new(), &mut batch)
+            .expect("write back");
+        batch.commit().expect("commit");
+
+        let root_content = std::fs::read_to_string(&root).expect("read root");
+        assert!(root_content.starts_with("include years/2001.journal\n"));
+        assert!(root_content.contains("root updated"));
+        assert!(!root_content.contains("included updated"));
+        assert!(!root_content.contains(TRANSACTION_ORIGIN_KEY));
+        let included_content = std::fs::read_to_string(&included).expect("read included");
+        assert!(included_content.contains("included updated"));
+        assert!(!included_content.contains("root updated"));
+    }
+
+    #[test]
+    fn write_back_keeps_include_in_place() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let content = "\
+; Main journal.
+
+2001-01-01 first
+  a  GBP1.00
+  b
+
+include other.journal
+
+2001-01-03 third
+  a  GBP3.00
+  b
+";
+        let root = write(dir.path(), "main.journal", content);
+        write(
+            dir.path(),
+            "other.journal",
+            "2001-01-02 second\n  a  GBP2.00\n  b\n",
+        );
+
+        let (journal, trns) = IncludedJournal::read(&FileSpec::Path(root.clone())).expect("read");
+        let mut batch = WriteBatch::new();
+        journal
+            .write_back(trns, Vec::new(), &mut batch)
+            .expect("write back");
+        batch.commit().expect("commit");
+
+        assert_eq!(std::fs::read_to_string(&root).expect("read root"), content);
+    }
+
+    #[test]
+    fn includes_file_without_transactions() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = write(
+            dir.path(),
+            "main.journal",
+            "include accounts.journal\n\n2001-01-01 root\n  assets:bank  GBP1.00\n  income\n",
+        );
+        let accounts_content = "account assets:bank\naccount income\n";
+        let accounts = write(dir.path(), "accounts.journal", accounts_content);
+
+        let (journal, trns) = IncludedJournal::read(&FileSpec::Path(root.clone())).expect("read");
+        assert_eq!(trns.len(), 1);
+        let mut batch = WriteBatch::new();
+        journal
+            .write_back(trns, Vec::new(), &mut batch)
+            .expect("write back");
+        batch.commit().expect("commit");
+
+        assert_eq!(
+            std::fs::read_to_string(&accounts).expect("read accounts"),
+            accounts_content
+        );
+    }
+
+    #[test]
+    fn write_back_preserves_and_adds_prices() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = write(dir.path(), "main.journal", "include prices.journal\n");
+        let included = write(
+            dir.path(),
+            "prices.journal",
+            "P 2001-01-01 00:00:00 USD GBP0.80\n",
+        );
+
+        let (journal, trns) = IncludedJournal::read(&FileSpec::Path(root.clone())).expect("read");
+        let new_prices = ledger_parser::parse(
+            "P 2001-01-01 00:00:00 USD GBP0.80\nP 2001-01-02 00:00:00 USD GBP0.81\n",
+        )
+        .expect("parse prices")
+        .items
+        .into_iter()
+        .filter_map(|item| match item {
+            LedgerItem::CommodityPrice(price) => Some(price),
+            _ => None,
+        })
+        .collect();
+        let mut batch = WriteBatch::new();
+        journal
+            .write_back(trns, new_prices, &mut batch)
+            .expect("write back");
+        batch.commit().expect("commit");
+
+        let root_content = std::fs::read_to_string(&root).expect("read root");
+        assert!(!root_content.contains("2001-01-01"), "{}", root_content);
+        assert!(root_content.contains("P 2001-01-02 00:00:00 USD GBP0.81"));
+        let included_content = std::fs::read_to_string(&included).expect("read included");
+        assert!(included_content.contains("P 2001-01-01 00:00:00 USD GBP0.80"));
+    }
+
+    #[test]
+    fn cycle_is_error() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let root = write(dir.path(), "a.journal", "include b.journal\n");
+        write(dir.path(), "b.journal", "include a.journal\n");
+
+        let err = IncludedJournal::read(&FileSpec::Path(root)).expect_err("should fail");
+        assert!(format!("{:#}", err).contains("include cycle"), "{:#}", err);
+    }
+}
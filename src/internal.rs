@@ -1,9 +1,13 @@
 //! Internal wrapper types for `Posting` and `Transaction`.
 
-use anyhow::{anyhow, Result};
-use ledger_parser::{Ledger, LedgerItem, Posting, Transaction};
+use std::borrow::Cow;
 
-use crate::{comment::Comment, ledgerutil};
+use chrono::NaiveDate;
+use ledger_parser::{CommodityPrice, LedgerItem, Posting, Transaction};
+
+use crate::accounts::{self, Account};
+use crate::comment::Comment;
+use crate::ledgerutil::{Journal, JournalItem};
 
 /// TransactionInternal is a `Transaction` with the comment string (if any) moved
 /// out as a `Comment`.
@@ -11,13 +15,29 @@ use crate::{comment::Comment, ledgerutil};
 pub struct TransactionInternal {
     pub raw: Transaction,
     pub comment: Comment,
+    /// Directives, includes and top-level comments that came between the
+    /// previous transaction in its journal and this one, to be written back
+    /// before it.
+    pub items_before: Vec<JournalItem>,
+}
+
+impl TransactionInternal {
+    /// Drops the directives and comments kept with the transaction, e.g. for
+    /// a transaction being merged from another journal.
+    pub fn clear_kept_items(&mut self) {
+        self.items_before.clear();
+    }
 }
 
 impl From<Transaction> for TransactionInternal {
     fn from(mut raw: Transaction) -> Self {
         let comment = Comment::from_opt_string(&raw.comment);
         raw.comment = None;
-        Self { raw, comment }
+        Self {
+            raw,
+            comment,
+            items_before: Vec::new(),
+        }
     }
 }
 
@@ -39,23 +59,141 @@ pub struct TransactionPostings {
 }
 
 impl TransactionPostings {
-    pub fn from_ledger(ledger: Ledger) -> Result<Vec<Self>> {
-        ledger
-            .items
+    /// Converts transactions into a journal, separated by blank lines, with
+    /// the items kept before each transaction.
+    pub fn into_journal(trns: Vec<Self>) -> Journal {
+        JournalTransactions::new(trns, Vec::new()).into_journal()
+    }
+}
+
+/// The contents of a journal: its transactions and commodity price
+/// directives, along with its other directives, includes and top-level
+/// comments. Those between transactions are kept with the transaction that
+/// follows them, and the rest in `ends`.
+#[derive(Clone, Debug, Default)]
+pub struct JournalTransactions {
+    pub trns: Vec<TransactionPostings>,
+    pub prices: Vec<CommodityPrice>,
+    pub ends: JournalEnds,
+}
+
+/// The directives, includes and top-level comments at the top and end of a
+/// journal, which stay there wherever its transactions end up.
+#[derive(Clone, Debug, Default)]
+pub struct JournalEnds {
+    /// Items before the first transaction, or all of them if there are no
+    /// transactions.
+    pub header: Vec<JournalItem>,
+    /// Items after the last transaction.
+    pub footer: Vec<JournalItem>,
+}
+
+impl JournalTransactions {
+    /// Returns the contents of a journal without a header or footer.
+    pub fn new(trns: Vec<TransactionPostings>, prices: Vec<CommodityPrice>) -> Self {
+        Self {
+            trns,
+            prices,
+            ends: JournalEnds::default(),
+        }
+    }
+
+    /// Converts the contents into a journal: the header, the commodity price
+    /// directives, the transactions separated by blank lines, and the footer.
+    pub fn into_journal(self) -> Journal {
+        // The header keeps the blank line that separated it from what
+        // followed it.
+        let mut items = self.ends.header;
+        let mut body: Vec<JournalItem> = self
+            .prices
             .into_iter()
-            .filter_map(|item| match item {
-                LedgerItem::Transaction(trn) => Some(Ok(TransactionPostings::from(trn))),
-                LedgerItem::EmptyLine => None,
-                other => Some(Err(anyhow!(
-                    "unhandled item type in ledger (these are not yet handled): {:?}",
-                    other
-                ))),
-            })
-            .collect()
+            .map(|price| LedgerItem::CommodityPrice(price).into())
+            .collect();
+        for (i, mut trn) in self.trns.into_iter().enumerate() {
+            if i > 0 || !body.is_empty() {
+                body.push(LedgerItem::EmptyLine.into());
+            }
+            body.append(&mut trn.trn.items_before);
+            body.push(LedgerItem::Transaction(trn.into()).into());
+        }
+        items.append(&mut body);
+        if !self.ends.footer.is_empty() {
+            items.push(LedgerItem::EmptyLine.into());
+            items.extend(self.ends.footer);
+        }
+        Journal { items }
     }
+}
 
-    pub fn into_ledger(trns: Vec<Self>) -> Ledger {
-        ledgerutil::ledger_from_transactions(trns.into_iter().map(|trn| trn.into()))
+impl From<Journal> for JournalTransactions {
+    fn from(journal: Journal) -> Self {
+        let mut contents = Self::default();
+        let mut kept = KeptItems::default();
+        for item in journal.items {
+            match item {
+                JournalItem::Ledger(LedgerItem::Transaction(trn)) => {
+                    contents.trns.push(kept.attach(trn.into()))
+                }
+                JournalItem::Ledger(LedgerItem::CommodityPrice(price)) => {
+                    contents.prices.push(price)
+                }
+                other => kept.push(other),
+            }
+        }
+        contents.ends = kept.finish();
+        contents
+    }
+}
+
+/// Collects the directives, includes and top-level comments of a journal
+/// while its transactions are read, to keep them with the transaction that
+/// follows them, or in the `JournalEnds` of the journal.
+#[derive(Debug, Default)]
+pub struct KeptItems {
+    pending: Vec<JournalItem>,
+    /// The items before the first transaction, once it has been attached.
+    header: Option<Vec<JournalItem>>,
+}
+
+impl KeptItems {
+    pub fn push(&mut self, item: JournalItem) {
+        // Transactions are written with blank lines between them anyway.
+        if self.pending.is_empty() && matches!(item, JournalItem::Ledger(LedgerItem::EmptyLine)) {
+            return;
+        }
+        self.pending.push(item);
+    }
+
+    /// Keeps the items pushed since the last transaction with `trn`, or in
+    /// the header if it is the first transaction.
+    pub fn attach(&mut self, mut trn: TransactionPostings) -> TransactionPostings {
+        let items = std::mem::take(&mut self.pending);
+        if self.header.is_some() {
+            trn.trn.items_before = items;
+        } else {
+            self.header = Some(items);
+        }
+        trn
+    }
+
+    /// Returns the header and footer of the journal.
+    pub fn finish(mut self) -> JournalEnds {
+        while matches!(
+            self.pending.last(),
+            Some(JournalItem::Ledger(LedgerItem::EmptyLine))
+        ) {
+            self.pending.pop();
+        }
+        match self.header {
+            Some(header) => JournalEnds {
+                header,
+                footer: self.pending,
+            },
+            None => JournalEnds {
+                header: self.pending,
+                footer: Vec::new(),
+            },
+        }
     }
 }
 
@@ -95,12 +233,26 @@ impl PostingInternal {
     pub fn clone_into_posting(&self) -> Posting {
         self.clone().into()
     }
+
+    /// Returns the date of the posting, which is the date given in its
+    /// comment as `[DATE]` if any, or otherwise `trn_date`, the date of its
+    /// transaction.
+    pub fn date(&self, trn_date: NaiveDate) -> NaiveDate {
+        self.comment.date.unwrap_or(trn_date)
+    }
 }
 
 impl From<Posting> for PostingInternal {
     fn from(mut raw: Posting) -> Self {
         let comment = Comment::from_opt_string(&raw.comment);
         raw.comment = None;
+        if let Cow::Owned(account) = accounts::normalize(&raw.account) {
+            // Leave names that are not valid accounts as they are, e.g. rather
+            // than trimming one into what would be written as a comment.
+            if account.parse::<Account>().is_ok() {
+                raw.account = account;
+            }
+        }
         Self { raw, comment }
     }
 }
@@ -1,6 +1,176 @@
 //! Helpers for handling ledger-parser structures.
 
-use ledger_parser::{Amount, Ledger, LedgerItem, PostingAmount, Transaction};
+use std::borrow::Cow;
+use std::cell::Cell;
+use std::fmt;
+use std::panic;
+use std::sync::Once;
+
+use ledger_parser::{
+    Amount, CommodityPrice, Ledger, LedgerItem, ParseError, PostingAmount, Transaction,
+};
+
+thread_local! {
+    /// Whether panics on this thread are expected, and so not reported by the
+    /// panic hook.
+    static QUIET_PANICS: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Prefix of the line comments that stand in for directives while the rest of
+/// the content is parsed by ledger-parser.
+const DIRECTIVE_MARKER: char = '\0';
+
+/// Directives that ledger-parser does not parse, which are kept verbatim.
+const DIRECTIVES: [&str; 5] = ["account", "commodity", "tag", "year", "Y"];
+
+/// A journal as a `Ledger`, but which also holds the directives that
+/// ledger-parser does not parse. Its `Display` implementation writes those
+/// directives back unchanged.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Journal {
+    pub items: Vec<JournalItem>,
+}
+
+/// An item of a `Journal`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum JournalItem {
+    Ledger(LedgerItem),
+    /// A directive that ledger-parser does not parse (`account`, `commodity`,
+    /// `tag` or `year`), along with its indented sub-directives, verbatim and
+    /// without the final line ending.
+    Directive(String),
+}
+
+impl Journal {
+    pub fn transactions(&self) -> impl Iterator<Item = &Transaction> {
+        self.items.iter().filter_map(|item| match item {
+            JournalItem::Ledger(LedgerItem::Transaction(trn)) => Some(trn),
+            _ => None,
+        })
+    }
+
+    pub fn transactions_mut(&mut self) -> impl Iterator<Item = &mut Transaction> {
+        self.items.iter_mut().filter_map(|item| match item {
+            JournalItem::Ledger(LedgerItem::Transaction(trn)) => Some(trn),
+            _ => None,
+        })
+    }
+}
+
+impl From<Ledger> for Journal {
+    fn from(ledger: Ledger) -> Self {
+        Self {
+            items: ledger.items.into_iter().map(JournalItem::Ledger).collect(),
+        }
+    }
+}
+
+impl From<LedgerItem> for JournalItem {
+    fn from(item: LedgerItem) -> Self {
+        Self::Ledger(item)
+    }
+}
+
+impl fmt::Display for Journal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for item in &self.items {
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for JournalItem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Ledger(item) => write!(f, "{}", item),
+            Self::Directive(text) => writeln!(f, "{}", text),
+        }
+    }
+}
+
+/// Parses Ledger content as `ledger_parser::parse` does, but returns an error
+/// for the malformed input that makes it panic, such as a truncated entry.
+/// Directives that ledger-parser does not parse are kept in place as
+/// `JournalItem::Directive`s.
+pub fn parse(content: &str) -> Result<Journal, ParseError> {
+    let (content, directives) = extract_directives(content);
+    let ledger = parse_without_directives(&content)?;
+    let items = ledger
+        .items
+        .into_iter()
+        .map(|item| {
+            if let LedgerItem::LineComment(text) = &item {
+                let index = text
+                    .strip_prefix(DIRECTIVE_MARKER)
+                    .and_then(|index| index.parse::<usize>().ok());
+                if let Some(directive) = index.and_then(|index| directives.get(index)) {
+                    return JournalItem::Directive(directive.clone());
+                }
+            }
+            JournalItem::Ledger(item)
+        })
+        .collect();
+    Ok(Journal { items })
+}
+
+/// Returns whether `line` starts a directive in `DIRECTIVES`.
+fn is_directive(line: &str) -> bool {
+    let word = line.split_whitespace().next().unwrap_or_default();
+    DIRECTIVES.contains(&word)
+        || word
+            .strip_prefix('Y')
+            .is_some_and(|year| !year.is_empty() && year.bytes().all(|b| b.is_ascii_digit()))
+}
+
+/// Replaces each directive in `content` that ledger-parser does not parse
+/// with a line comment holding its index in the returned directives.
+fn extract_directives(content: &str) -> (Cow<'_, str>, Vec<String>) {
+    if !content.lines().any(is_directive) {
+        return (Cow::Borrowed(content), Vec::new());
+    }
+    let mut out = String::with_capacity(content.len());
+    let mut directives = Vec::<String>::new();
+    let mut in_directive = false;
+    for line in content.split_inclusive('\n') {
+        let text = line.trim_end_matches(['\r', '\n']);
+        let indented = line.starts_with(char::is_whitespace);
+        if in_directive && indented && !text.trim().is_empty() {
+            let directive = directives.last_mut().expect("in a directive");
+            directive.push('\n');
+            directive.push_str(text);
+            continue;
+        }
+        in_directive = !indented && is_directive(line);
+        if in_directive {
+            out.push_str(&format!("; {}{}\n", DIRECTIVE_MARKER, directives.len()));
+            directives.push(text.to_string());
+        } else {
+            out.push_str(line);
+        }
+    }
+    (Cow::Owned(out), directives)
+}
+
+fn parse_without_directives(content: &str) -> Result<Ledger, ParseError> {
+    static INSTALL_HOOK: Once = Once::new();
+    INSTALL_HOOK.call_once(|| {
+        let default_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if !QUIET_PANICS.with(Cell::get) {
+                default_hook(info);
+            }
+        }));
+    });
+    QUIET_PANICS.with(|quiet| quiet.set(true));
+    let result = panic::catch_unwind(|| ledger_parser::parse(content));
+    QUIET_PANICS.with(|quiet| quiet.set(false));
+    result.unwrap_or_else(|_| {
+        Err(ParseError::String(
+            "malformed input: the parser could not read to the end of it".to_string(),
+        ))
+    })
+}
 
 /// Returns a `PostingAmount` with only `.amount` set.
 pub fn simple_posting_amount(amount: Amount) -> PostingAmount {
@@ -11,15 +181,101 @@ pub fn simple_posting_amount(amount: Amount) -> PostingAmount {
     }
 }
 
-pub fn ledger_from_transactions<T>(transactions: T) -> Ledger
+pub fn ledger_from_transactions<T>(transactions: T) -> Journal
 where
     T: IntoIterator<Item = Transaction>,
 {
-    Ledger {
+    Journal {
         items: itertools::intersperse(
-            transactions.into_iter().map(LedgerItem::Transaction),
-            LedgerItem::EmptyLine,
+            transactions
+                .into_iter()
+                .map(|trn| LedgerItem::Transaction(trn).into()),
+            LedgerItem::EmptyLine.into(),
         )
         .collect(),
     }
 }
+
+/// Prepends commodity price directives to a journal.
+pub fn ledger_with_prices(prices: Vec<CommodityPrice>, journal: Journal) -> Journal {
+    if prices.is_empty() {
+        return journal;
+    }
+    let mut items: Vec<JournalItem> = prices
+        .into_iter()
+        .map(|price| LedgerItem::CommodityPrice(price).into())
+        .collect();
+    if !journal.items.is_empty() {
+        items.push(LedgerItem::EmptyLine.into());
+    }
+    items.extend(journal.items);
+    Journal { items }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_directives_verbatim() {
+        let content = "\
+account assets:bank
+    note Current account
+    alias bank
+commodity GBP
+  format GBP1,000.00
+tag receipt
+Y2020
+year 2021
+
+; A comment.
+include other.journal
+2021-01-01 Shop
+  assets:bank  GBP-1.00
+  expenses:food  GBP1.00
+
+account expenses:food
+";
+        let journal = parse(content).expect("parse");
+        let directives: Vec<&str> = journal
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                JournalItem::Directive(text) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(
+            directives,
+            vec![
+                "account assets:bank\n    note Current account\n    alias bank",
+                "commodity GBP\n  format GBP1,000.00",
+                "tag receipt",
+                "Y2020",
+                "year 2021",
+                "account expenses:food",
+            ]
+        );
+        let comments: Vec<&str> = journal
+            .items
+            .iter()
+            .filter_map(|item| match item {
+                JournalItem::Ledger(LedgerItem::LineComment(text)) => Some(text.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(comments, vec!["A comment."]);
+        assert_eq!(journal.to_string(), content);
+    }
+
+    #[test]
+    fn does_not_mistake_transactions_for_directives() {
+        let content = "2021-01-01 account opened\n  assets:bank  GBP1.00\n  equity  GBP-1.00\n";
+        let journal = parse(content).expect("parse");
+        assert!(journal
+            .items
+            .iter()
+            .all(|item| !matches!(item, JournalItem::Directive(_))));
+        assert_eq!(journal.to_string(), content);
+    }
+}
@@ -0,0 +1,34 @@
+//! Tools for managing Ledger journals.
+//!
+//! The modules are exposed as a library so that the benchmarks under
+//! `benches/` can exercise them directly. The command line interface lives in
+//! `main.rs`.
+
+#[cfg(test)]
+mod testutil;
+
+mod accounts;
+pub mod candidates;
+pub mod cli;
+pub mod comment;
+pub mod commodities;
+mod datefilter;
+pub mod export;
+mod filespec;
+mod fingerprint;
+pub mod fmt;
+pub mod fpgen;
+pub mod importers;
+mod includes;
+pub mod internal;
+pub mod ledgerutil;
+pub mod merge;
+mod money;
+mod mutcell;
+pub mod precision;
+pub mod prices;
+pub mod review;
+pub mod rules;
+mod tags;
+mod tzabbr;
+pub mod watch;
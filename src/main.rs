@@ -1,27 +1,16 @@
 use anyhow::Result;
-use clap::{Parser, Subcommand};
+use clap::{CommandFactory, Parser, Subcommand};
 
-#[cfg(test)]
-mod testutil;
-
-mod accounts;
-mod comment;
-mod filespec;
-mod fingerprint;
-mod fmt;
-mod fpgen;
-mod importers;
-mod internal;
-mod ledgerutil;
-mod merge;
-mod mutcell;
-mod rules;
-mod tags;
-mod tzabbr;
+use accountmerge::{
+    candidates, cli, commodities, export, fmt, fpgen, importers, merge, precision, review, rules,
+    watch,
+};
 
 #[derive(Debug, Parser)]
 /// Utilities for working with Ledger journals.
 struct Command {
+    #[command(flatten)]
+    global: cli::GlobalOpts,
     #[command(subcommand)]
     subcmd: SubCommand,
 }
@@ -31,6 +20,17 @@ enum SubCommand {
     #[command(name = "apply-rules")]
     /// Applies a rules file to an input file and dumps the results to stdout,
     ApplyRules(rules::cmd::Command),
+    #[command(name = "clear-candidates")]
+    /// Removes `candidate-*` tags from journal file(s), e.g. after resolving
+    /// unmerged transactions.
+    ClearCandidates(candidates::Cmd),
+    #[command(name = "completions")]
+    /// Writes shell completions to stdout.
+    Completions(cli::Completions),
+    #[command(name = "export", subcommand)]
+    /// Writes a journal in another format, e.g. for analysis in a
+    /// spreadsheet.
+    Export(export::ExportCommand),
     #[command(name = "fmt")]
     /// Formats journal file(s).
     Format(fmt::Cmd),
@@ -38,6 +38,9 @@ enum SubCommand {
     /// Generates random fingerprints to the postings in the input file and
     /// writes them back out.
     GenerateFingerprints(fpgen::Cmd),
+    #[command(name = "gen-man", hide = true)]
+    /// Writes man pages for all subcommands and the rules file format.
+    GenMan(cli::GenMan),
     #[command(name = "import")]
     /// Reads financial transaction data from a given source, converts them to
     /// Ledger transactions, and dumps them to stdout.
@@ -45,16 +48,50 @@ enum SubCommand {
     #[command(name = "merge")]
     /// Merges multiple Ledger journals together.
     Merge(merge::cmd::Command),
+    #[command(name = "normalize")]
+    /// Normalizes the decimal precision of amounts in journal file(s), and
+    /// optionally removes excess fingerprint tags.
+    Normalize(precision::Cmd),
+    #[command(name = "normalize-commodities")]
+    /// Renames commodities in journal file(s) according to aliases, e.g. to
+    /// unify data imported with inconsistent currency symbols.
+    NormalizeCommodities(commodities::Cmd),
+    #[command(name = "resolve-candidates")]
+    /// Accepts or rejects the merge candidates of unmerged postings, ready
+    /// for them to be merged again.
+    ResolveCandidates(candidates::ResolveCmd),
+    #[command(name = "review", subcommand)]
+    /// Lists and resolves the postings that need a human to look at them:
+    /// unknown accounts, merge candidates and postings tagged needs-review.
+    Review(review::ReviewCommand),
+    #[command(name = "rules", subcommand)]
+    /// Tools for working with rules files.
+    Rules(rules::cmd::RulesCommand),
+    #[command(name = "watch")]
+    /// Watches a directory for statement files, and imports, applies rules
+    /// to and merges each into a journal.
+    Watch(watch::Cmd),
 }
 
 fn main() -> Result<()> {
     let cmd = Command::parse();
+    cmd.global.apply();
     use SubCommand::*;
     match cmd.subcmd {
         ApplyRules(cmd) => cmd.run(),
+        ClearCandidates(cmd) => cmd.run(),
+        Completions(cmd) => cmd.run(Command::command()),
+        Export(cmd) => cmd.run(),
         Format(cmd) => cmd.run(),
         GenerateFingerprints(cmd) => cmd.run(),
+        GenMan(cmd) => cmd.run(Command::command()),
         Import(cmd) => cmd.run(),
         Merge(cmd) => cmd.run(),
+        Normalize(cmd) => cmd.run(),
+        NormalizeCommodities(cmd) => cmd.run(),
+        ResolveCandidates(cmd) => cmd.run(),
+        Review(cmd) => cmd.run(),
+        Rules(cmd) => cmd.run(),
+        Watch(cmd) => cmd.run(),
     }
 }
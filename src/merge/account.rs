@@ -0,0 +1,96 @@
+//! Handling of merged postings whose account differs from the existing
+//! posting that they are merged into.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use chrono::NaiveDate;
+
+use crate::internal::PostingInternal;
+use crate::tags;
+
+/// How a known account on a merged input posting updates the known account
+/// of the existing posting, when they differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AccountPolicy {
+    /// Keep the existing account.
+    #[default]
+    Keep,
+    /// Use the account from the input, e.g. after rules were changed to
+    /// reclassify transactions.
+    Update,
+    /// Fail the merge.
+    Error,
+}
+
+impl FromStr for AccountPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "keep" => Ok(Self::Keep),
+            "update" => Ok(Self::Update),
+            "error" => Ok(Self::Error),
+            _ => bail!("invalid account policy: {:?}", s),
+        }
+    }
+}
+
+impl AccountPolicy {
+    /// Applies the policy to `dest`, which `src` is being merged into.
+    /// Returns the change if the postings have different known accounts.
+    pub fn merge(
+        self,
+        date: NaiveDate,
+        dest: &mut PostingInternal,
+        src: &PostingInternal,
+    ) -> Result<Option<AccountChange>> {
+        let known = |post: &PostingInternal| !post.comment.tags.contains(tags::UNKNOWN_ACCOUNT);
+        if !known(dest) || !known(src) || dest.raw.account == src.raw.account {
+            return Ok(None);
+        }
+        let change = AccountChange {
+            date,
+            existing: dest.raw.account.clone(),
+            input: src.raw.account.clone(),
+            updated: self == Self::Update,
+        };
+        match self {
+            Self::Keep => {}
+            Self::Update => {
+                dest.raw.account = src.raw.account.clone();
+                dest.raw.reality = src.raw.reality;
+            }
+            Self::Error => bail!("{}", change),
+        }
+        Ok(Some(change))
+    }
+}
+
+/// A merged input posting whose known account differed from that of the
+/// existing posting.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct AccountChange {
+    pub date: NaiveDate,
+    /// The account of the existing posting, before merging.
+    pub existing: String,
+    pub input: String,
+    /// Whether the existing posting was updated to the input's account.
+    pub updated: bool,
+}
+
+impl fmt::Display for AccountChange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "posting on {} in account {:?} was merged with an input posting in account {:?}",
+            self.date, self.existing, self.input
+        )?;
+        if self.updated {
+            write!(f, ", updated to {:?}", self.input)
+        } else {
+            write!(f, ", kept {:?}", self.existing)
+        }
+    }
+}
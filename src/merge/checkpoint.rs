@@ -0,0 +1,147 @@
+//! Intermediate journals of the statements imported by `merge --import`, so
+//! that a long run of imports (e.g. OCR of many PDF statements) can resume
+//! after a failure without importing the completed statements again.
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use ledger_parser::CommodityPrice;
+use sha1::{Digest, Sha1};
+
+use crate::filespec::{self, FileSpec, WriteBatch};
+use crate::internal::{JournalTransactions, TransactionPostings};
+
+pub struct ImportCheckpoints {
+    dir: PathBuf,
+    /// Whether to use existing intermediate journals.
+    resume: bool,
+    /// The contents of the configuration files (e.g. the accounts registry)
+    /// that affect the imported transactions.
+    config: Vec<u8>,
+}
+
+impl ImportCheckpoints {
+    /// Creates `dir` if it does not exist. If `resume` is true, statements
+    /// with an intermediate journal in `dir` are not imported again.
+    pub fn new(dir: &Path, resume: bool, config_files: &[&Path]) -> Result<Self> {
+        std::fs::create_dir_all(dir).with_context(|| format!("creating {:?}", dir))?;
+        let mut config = Vec::new();
+        for path in config_files {
+            config.extend(std::fs::read(path).with_context(|| format!("reading {:?}", path))?);
+            config.push(0);
+        }
+        Ok(Self {
+            dir: dir.to_owned(),
+            resume,
+            config,
+        })
+    }
+
+    /// Returns the transactions and prices imported from `statement` with
+    /// the importer `args`. These are read from the intermediate journal if
+    /// resuming and it exists, and otherwise come from `import` and are
+    /// written to the intermediate journal.
+    pub fn import<F>(
+        &self,
+        args: &[String],
+        statement: &Path,
+        import: F,
+    ) -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)>
+    where
+        F: FnOnce() -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)>,
+    {
+        let path = FileSpec::Path(self.path(args, statement)?);
+        if self.resume && path.path().is_some_and(Path::exists) {
+            eprintln!("{:?}: using previous import {}", statement, path);
+            let contents = JournalTransactions::from(filespec::read_ledger_file(&path)?);
+            return Ok((contents.trns, contents.prices));
+        }
+
+        let (trns, prices) = import()?;
+        let ledger = JournalTransactions::new(trns.clone(), prices.clone()).into_journal();
+        // Written by renaming into place, so that a failure part way through
+        // cannot leave a truncated journal to resume from.
+        let mut batch = WriteBatch::new();
+        batch.add_ledger(&path, &ledger)?;
+        batch.commit()?;
+        Ok((trns, prices))
+    }
+
+    /// Returns the path of the intermediate journal for a statement, named
+    /// after a hash of everything that affects the import.
+    fn path(&self, args: &[String], statement: &Path) -> Result<PathBuf> {
+        let mut hasher = Sha1::new();
+        for arg in args {
+            hasher.update(arg.as_bytes());
+            hasher.update([0]);
+        }
+        hasher
+            .update(std::fs::read(statement).with_context(|| format!("reading {:?}", statement))?);
+        hasher.update([0]);
+        hasher.update(&self.config);
+        Ok(self.dir.join(format!("{:x}.journal", hasher.finalize())))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    fn imported() -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)> {
+        Ok((
+            parse_transaction_postings(
+                "
+                2001/01/02 description
+                    assets:bank  GBP-1.00  ; :fp-a:
+                    expenses:unknown  GBP1.00  ; :fp-b:",
+            ),
+            Vec::new(),
+        ))
+    }
+
+    fn not_imported() -> Result<(Vec<TransactionPostings>, Vec<CommodityPrice>)> {
+        panic!("should have used the previous import");
+    }
+
+    #[test]
+    fn resumes_from_previous_import() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let work_dir = dir.path().join("work");
+        let statement = dir.path().join("statement.csv");
+        std::fs::write(&statement, "a,b,c\n").unwrap();
+        let args = vec!["nationwide-csv".to_string()];
+
+        let checkpoints = ImportCheckpoints::new(&work_dir, false, &[]).expect("new");
+        let (want, _) = checkpoints
+            .import(&args, &statement, imported)
+            .expect("first import");
+
+        let checkpoints = ImportCheckpoints::new(&work_dir, true, &[]).expect("new");
+        let (got, _) = checkpoints
+            .import(&args, &statement, not_imported)
+            .expect("resumed import");
+        assert_transaction_postings_eq!(want, got);
+
+        // Changes to the arguments or the statement require a new import.
+        let mut called = false;
+        let other_args = vec!["nationwide-pdf".to_string()];
+        checkpoints
+            .import(&other_args, &statement, || {
+                called = true;
+                imported()
+            })
+            .expect("import with other args");
+        assert!(called);
+        std::fs::write(&statement, "d,e,f\n").unwrap();
+        let mut called = false;
+        checkpoints
+            .import(&args, &statement, || {
+                called = true;
+                imported()
+            })
+            .expect("import of changed statement");
+        assert!(called);
+    }
+}
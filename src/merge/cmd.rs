@@ -1,40 +1,591 @@
-use anyhow::{bail, Result};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Instant;
+
+use anyhow::{bail, Context, Result};
 use clap::Args;
 
-use crate::filespec::{self, FileSpec};
-use crate::internal::TransactionPostings;
-use crate::merge::{merger, sources};
+use crate::accounts::TaxonomyOpts;
+use crate::commodities::AliasOpts;
+use crate::datefilter::DateFilter;
+use crate::filespec::{self, FileSpec, ReadOptions, WriteBatch};
+use crate::importers::cmd::{import_statement, parse_importer};
+use crate::importers::history::{self, ImportHistory, ImportRecord};
+use crate::importers::registry::Registry;
+use crate::includes::{self, IncludedJournal};
+use crate::internal::{JournalEnds, JournalTransactions, TransactionPostings};
+use crate::merge::account::AccountPolicy;
+use crate::merge::checkpoint::ImportCheckpoints;
+use crate::merge::commodity::{CommodityChecker, CommodityPolicy};
+use crate::merge::fpindex::{self, FingerprintIndex};
+use crate::merge::hook::DecisionHook;
+use crate::merge::namespaces::NamespaceChecker;
+use crate::merge::routing::{RoutedJournal, Routes};
+use crate::merge::stats::{self, PhaseTimes};
+use crate::merge::status::StatusPolicy;
+use crate::merge::trust::Trust;
+use crate::merge::{merger, periods, sources};
+use crate::prices::Prices;
+use crate::rules;
+use crate::rules::script::ScriptState;
+use crate::tags;
 
 #[derive(Debug, Args)]
 pub struct Command {
-    /// The Ledger journals to read from.
+    /// The Ledger journals to read from. The first is the journal to merge
+    /// into, unless --dest-dir is given.
     inputs: Vec<FileSpec>,
 
+    /// Imports a statement and merges its transactions, as if the output of
+    /// the `import` subcommand had been given as an input. The value is the
+    /// importer subcommand and its arguments as given to `import`, including
+    /// the statement file, and is split into words as by a shell, e.g.
+    /// `--import "nationwide-csv 'my statement.csv'"`. May be repeated.
+    /// Requires the journal to merge into.
+    #[arg(long = "import", value_name = "IMPORTER_ARGS", requires = "inputs")]
+    imports: Vec<String>,
+
+    /// Merges a journal with a trust level, after the positional inputs.
+    /// "authoritative" sources replace the statuses, known accounts, balance
+    /// assertions and value tags of the postings that they are merged into,
+    /// e.g. bank statements. "secondary" sources only fill in what those
+    /// postings lack, e.g. hand entered journals. "normal" merges as for the
+    /// positional inputs, following --status-policy and --account-policy.
+    /// May be repeated, e.g. `--source statements.ledger=authoritative`.
+    /// Requires the journal to merge into.
+    #[arg(long = "source", value_name = "FILE=TRUST", requires = "inputs")]
+    sources: Vec<Source>,
+
+    /// RON file mapping account identifiers found in statements to the
+    /// ledger account, fingerprint namespace and rules file to use for them,
+    /// as for `import --accounts-file`.
+    #[arg(long = "accounts-file", requires = "imports")]
+    accounts_file: Option<PathBuf>,
+
+    /// The root accounts to put unknown accounts under for --import.
+    #[command(flatten)]
+    taxonomy: TaxonomyOpts,
+
+    /// Directory to write the transactions imported from each --import
+    /// statement to, as soon as the statement is imported. Each journal is
+    /// named after a hash of the importer arguments and the contents of the
+    /// statement and --accounts-file.
+    #[arg(long = "import-work-dir", value_name = "DIR", requires = "imports")]
+    import_work_dir: Option<PathBuf>,
+
+    /// Use the journals already in --import-work-dir instead of importing
+    /// their statements again, e.g. to continue after a failure part way
+    /// through a long run of imports.
+    #[arg(long = "resume", requires = "import_work_dir")]
+    resume: bool,
+
+    /// JSON file recording the hash of each statement imported with
+    /// --import, as for `import --import-history`, and written back along
+    /// with the merged journal. Importing a statement with the same contents
+    /// as one already recorded gives a warning.
+    #[arg(long = "import-history", value_name = "FILE", requires = "imports")]
+    import_history: Option<PathBuf>,
+
+    /// Fail instead of warning when --import-history shows that a statement
+    /// was already imported.
+    #[arg(long = "reject-reimport", requires = "import_history")]
+    reject_reimport: bool,
+
     /// The file to write any unmerged transactions into.
     #[arg(short = 'u', long = "unmerged")]
     unmerged: Option<FileSpec>,
 
+    /// A `.ron` rules table file to apply to the unmerged transactions before
+    /// writing them to --unmerged, e.g. to tag them for review.
+    #[arg(long = "unmerged-rules", requires = "unmerged")]
+    unmerged_rules: Option<PathBuf>,
+
     /// The file to write the merged ledger to.
     #[arg(short = 'o', long = "output", default_value = "-")]
     output: FileSpec,
+
+    /// Warn when an input leaves a gap of more than this many days without
+    /// transactions for an account, relative to previously merged inputs.
+    #[arg(long = "warn-gap-days", default_value = "31")]
+    warn_gap_days: i64,
+
+    /// Warn instead of failing when two sources have fingerprints of the same
+    /// algorithm and namespace, but none in common over the dates that they
+    /// both cover. This usually means that the statements were imported with
+    /// inconsistent fingerprint options, and merging them would duplicate or
+    /// mis-merge transactions.
+    #[arg(long = "allow-namespace-clashes")]
+    allow_namespace_clashes: bool,
+
+    /// Check that the postings of each input are in commodities that their
+    /// accounts already have in the journal (or, for accounts new to the
+    /// journal, in earlier inputs). A mismatch usually means a misconfigured
+    /// importer or a misread statement. "tag" tags mismatching postings
+    /// `commodity-mismatch` and `needs-review` and reports them to stderr,
+    /// and "error" fails the merge. Postings with the `unknown-account` tag
+    /// are not checked.
+    #[arg(long = "check-commodities", value_name = "POLICY")]
+    check_commodities: Option<CommodityPolicy>,
+
+    /// Follow `include` directives when reading the first input journal.
+    #[arg(long = "follow-includes")]
+    follow_includes: bool,
+
+    /// Write merged transactions back to the files of the first input journal
+    /// that they were read from, instead of to --output. New transactions are
+    /// written to the first input journal itself. Requires --follow-includes.
+    #[arg(long = "write-back", requires = "follow_includes")]
+    write_back: bool,
+
+    /// How statuses from inputs update the statuses of transactions and
+    /// postings that they are merged into. "upgrade" only updates pending to
+    /// cleared. "latest" uses the input status whenever it has one, so that a
+    /// later import of a statement can update the statuses of earlier ones.
+    #[arg(long = "status-policy", default_value = "upgrade")]
+    status_policy: StatusPolicy,
+
+    /// Merge a transaction whose postings match no existing postings into an
+    /// existing transaction with the same date and description, whose
+    /// postings have the same amounts. This avoids duplicates when
+    /// re-importing after a change that altered fingerprints or accounts,
+    /// such as a rules change. Transactions that could be merged into more
+    /// than one existing transaction are left unmerged with candidate tags.
+    #[arg(long = "match-description", conflicts_with = "fingerprint_index")]
+    match_description: bool,
+
+    /// Only merge postings that match existing postings by fingerprint.
+    /// Postings that would otherwise be matched by their match key, or by
+    /// their date, amount and other values, are added as new postings. This
+    /// avoids mis-merging distinct payments of the same amount on the same
+    /// day, when importing from a source with reliable fingerprints. The
+    /// other postings of a transaction matched by fingerprint are still
+    /// paired with the rest of that transaction.
+    #[arg(long = "no-soft-match", conflicts_with = "match_description")]
+    no_soft_match: bool,
+
+    // Commodities are renamed in all of the journals before merging, so that
+    // postings written with either name can match.
+    #[command(flatten)]
+    commodity_aliases: AliasOpts,
+
+    /// Add at most N candidate tags to a posting that ambiguously matches
+    /// existing postings, preferring those in the same account and then
+    /// those with the most similar transaction descriptions. A
+    /// `candidate-hint` value tag describes the best candidate either way.
+    #[arg(long = "max-candidates", value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    max_candidates: Option<u64>,
+
+    /// Reconcile cleared input postings with pending postings in the journal,
+    /// e.g. manually entered transactions when their bank statement is
+    /// merged. A cleared posting that matches no posting by fingerprint or on
+    /// its own date is matched with a pending posting of the same amount
+    /// dated up to DAYS (at most 366) either side of it. Among several
+    /// postings that it matches on its own date, the only pending one is
+    /// preferred.
+    #[arg(
+        long = "reconcile-days",
+        value_name = "DAYS",
+        conflicts_with = "no_soft_match",
+        value_parser = clap::value_parser!(u32).range(0..=366)
+    )]
+    reconcile_days: Option<u32>,
+
+    /// How a known account on a merged posting updates the existing posting
+    /// when their known accounts differ, e.g. after rules were changed to
+    /// reclassify transactions: "keep" (the default) keeps the existing
+    /// account, "update" uses the merged posting's account, and "error"
+    /// fails the merge. Each difference is reported to stderr.
+    #[arg(long = "account-policy", default_value = "keep")]
+    account_policy: AccountPolicy,
+
+    /// A rhai script that decides what to do with input postings that match
+    /// several existing postings. It must define a function
+    /// `decide(kind, posting, candidates)`, where kind is "soft-match" or
+    /// "conflict" (for a fingerprint match), and posting and the candidates
+    /// (best first) are maps with the fields date, description, account,
+    /// amount, status, tags and value_tags. It may return the index of the
+    /// candidate to merge into, "new" to add the posting as new, "unmerge"
+    /// to leave its transaction unmerged, or () for the default behaviour.
+    /// Only "unmerge" and () are possible for a conflict.
+    #[arg(long = "decision-hook", value_name = "SCRIPT")]
+    decision_hook: Option<PathBuf>,
+
+    /// A directory to look up modules in, for `import` statements in the
+    /// --decision-hook script. Modules are first looked up relative to the
+    /// script itself. May be given multiple times, and directories are
+    /// searched in order.
+    #[arg(long = "script-path", value_name = "DIR", requires = "decision_hook")]
+    script_path: Vec<PathBuf>,
+
+    /// A JSON file holding an object that the --decision-hook script keeps
+    /// across runs, which it accesses as `this`. It is created if it does
+    /// not exist, and written back along with the merged journal.
+    #[arg(long = "hook-state", value_name = "FILE", requires = "decision_hook")]
+    hook_state: Option<PathBuf>,
+
+    /// Perform matching and conflict detection, but write nothing. Prints a
+    /// summary for each input, and exits with an error if any input
+    /// transactions conflict with the journal. Checking stops at the first
+    /// conflict, as the inputs after it would be checked against a
+    /// partially merged journal.
+    #[arg(long = "dry-run")]
+    dry_run: bool,
+
+    /// Report and skip entries that fail to parse, instead of failing. This
+    /// applies to all inputs except the first, so that entries are never
+    /// dropped from the journal being merged into.
+    #[arg(long = "skip-bad-transactions")]
+    skip_bad_transactions: bool,
+
+    /// Fail on suspicious constructs in comments, such as lines that look
+    /// like malformed value tags, tags with invalid characters, or duplicate
+    /// value tags on a posting, instead of treating them as plain text. Does
+    /// not apply to a journal read with --follow-includes.
+    #[arg(long = "strict")]
+    strict: bool,
+
+    /// Tag postings added or modified by this merge with a `merged` value tag
+    /// containing LABEL, or the current local time if no LABEL is given. This
+    /// applies to all inputs except the first. The label must be given as
+    /// `--merged-tag=LABEL`.
+    #[arg(
+        long = "merged-tag",
+        value_name = "LABEL",
+        num_args = 0..=1,
+        default_missing_value = ""
+    )]
+    merged_tag: Option<String>,
+
+    /// Remove the `src_line` value tags that importers add to transactions,
+    /// which reference the rows of the imported statement. This applies to
+    /// all inputs except the first.
+    #[arg(long = "strip-source-lines")]
+    strip_source_lines: bool,
+
+    /// Check that each journal written parses back into the same
+    /// transactions, and fail without writing anything if not. This guards
+    /// against formatting quirks silently changing amounts or comments, at
+    /// the cost of parsing the output again.
+    #[arg(long = "verify-roundtrip")]
+    verify_roundtrip: bool,
+
+    /// Print internal statistics to stderr after merging: the sizes of the
+    /// merger's arenas and index maps, how input postings were matched, and
+    /// the time spent reading, merging, building and writing. Nothing is
+    /// printed when --fingerprint-index avoids a full merge.
+    #[arg(long = "stats")]
+    stats: bool,
+
+    /// Only merge transactions from within these dates. This applies to all
+    /// inputs except the first, which is always read in full.
+    #[command(flatten)]
+    date_filter: DateFilter,
+
+    /// Sidecar file caching an index of the postings in the first input
+    /// journal, which is updated after each merge. While it matches the
+    /// journal, inputs whose transactions are each either entirely new or
+    /// entirely already merged are merged without reading the journal: new
+    /// transactions dated no earlier than the journal's last transaction are
+    /// appended, and already merged ones are skipped. Otherwise, including
+    /// when merging would update an already merged transaction (e.g. its
+    /// status, account or tags), a full merge is performed, so the output is
+    /// the same either way.
+    #[arg(long = "fingerprint-index", conflicts_with = "follow_includes")]
+    fingerprint_index: Option<PathBuf>,
+
+    /// Merge into a directory of journal files, one per account, instead of
+    /// into the first input journal. All the files named by --routes are
+    /// read as the journal to merge into, and merged transactions are written
+    /// back to the files that they were read from instead of to --output.
+    /// New transactions are written to the file that they are routed to.
+    #[arg(
+        long = "dest-dir",
+        requires = "routes",
+        conflicts_with_all = ["follow_includes", "fingerprint_index"]
+    )]
+    dest_dir: Option<PathBuf>,
+
+    /// RON file routing account prefixes to files within --dest-dir, e.g:
+    /// `(routes: {"assets:bank": "bank.journal"}, default: Some("other.journal"))`.
+    /// A new transaction is routed by the longest prefix matching any of its
+    /// posting accounts, or otherwise to the default file.
+    #[arg(long = "routes", requires = "dest_dir")]
+    routes: Option<PathBuf>,
 }
 
 impl Command {
     pub fn run(&self) -> Result<()> {
-        let mut merger = merger::Merger::new();
+        filespec::check_single_stdin(
+            self.inputs
+                .iter()
+                .chain(self.sources.iter().map(|source| &source.file)),
+        )?;
+        // Load the rules before merging, to report any errors in them early.
+        let unmerged_rules = self
+            .unmerged_rules
+            .as_deref()
+            .map(rules::table::load_from_path)
+            .transpose()?;
+        let hook_state = self
+            .hook_state
+            .as_deref()
+            .map(ScriptState::load)
+            .transpose()?
+            .map(Arc::new);
+        let decision_hook = self
+            .decision_hook
+            .as_deref()
+            .map(|path| {
+                DecisionHook::from_path(path, &self.script_path)
+                    .map(|hook| hook.with_state(hook_state.clone()))
+            })
+            .transpose()?;
+        let mut accounts = match &self.accounts_file {
+            Some(path) => Registry::from_path(path)?,
+            None => Registry::default(),
+        };
+        if let Some(taxonomy) = self.taxonomy.load()? {
+            accounts = accounts.with_taxonomy(taxonomy);
+        }
+        let config_files: Vec<&Path> = self
+            .accounts_file
+            .as_deref()
+            .into_iter()
+            .chain(self.taxonomy.path())
+            .collect();
+        let mut import_history = self
+            .import_history
+            .as_deref()
+            .map(ImportHistory::load)
+            .transpose()?;
+        let checkpoints = self
+            .import_work_dir
+            .as_deref()
+            .map(|dir| ImportCheckpoints::new(dir, self.resume, &config_files))
+            .transpose()?;
+        let merged_label = self.merged_tag.as_ref().map(|label| {
+            if label.is_empty() {
+                chrono::Local::now().format("%Y-%m-%dT%H:%M:%S").to_string()
+            } else {
+                label.clone()
+            }
+        });
+        if let Some(index_path) = &self.fingerprint_index {
+            if !self.dry_run && self.append_indexed(index_path, merged_label.as_deref())? {
+                return Ok(());
+            }
+        }
+
+        let mut merger = merger::Merger::new()
+            .with_status_policy(self.status_policy)
+            .with_description_matching(self.match_description)
+            .with_soft_matching(!self.no_soft_match)
+            .with_max_candidates(self.max_candidates.map(|n| n as usize))
+            .with_reconcile_days(self.reconcile_days)
+            .with_account_policy(self.account_policy)
+            .with_decision_hook(decision_hook);
+        let mut period_checker = periods::PeriodChecker::new(self.warn_gap_days);
+        let mut namespace_checker = NamespaceChecker::new();
+        let mut commodity_checker = self.check_commodities.map(CommodityChecker::new);
 
         let mut unmerged = Vec::<TransactionPostings>::new();
+        let mut prices = Prices::new();
+        let mut included_journal: Option<IncludedJournal> = None;
+        let mut journal_ends = JournalEnds::default();
+        let mut total_conflicts = 0;
+        let mut phases = PhaseTimes::new();
+
+        let routed_journal = match (&self.dest_dir, &self.routes) {
+            (Some(dest_dir), Some(routes)) => {
+                let (journal, trns) = phases.time("read", || {
+                    RoutedJournal::read(dest_dir, Routes::from_path(routes)?)
+                })?;
+                prices.extend(journal.prices().cloned());
+                for mut trns in sources::group_by_source(trns, &FileSpec::Path(dest_dir.clone())) {
+                    self.commodity_aliases
+                        .normalize_transaction_postings(&mut trns);
+                    self.check_namespaces(&mut namespace_checker, &trns)?;
+                    for warning in period_checker.check_and_add(&trns) {
+                        eprintln!("warning: {}", warning);
+                    }
+                    if let Some(checker) = &mut commodity_checker {
+                        checker.add(&trns);
+                    }
+                    phases.time("merge", || merger.merge(trns))?;
+                    report_account_changes(&mut merger, &dest_dir.display());
+                }
+                Some(journal)
+            }
+            _ => None,
+        };
 
-        for ledger_file in &self.inputs {
-            for trns in sources::read_ledger_file(ledger_file)? {
-                let mut unmerged_trns = merger.merge(trns)?;
-                unmerged.append(&mut unmerged_trns.0);
+        let inputs = self
+            .inputs
+            .iter()
+            .map(Input::Journal)
+            .chain(
+                self.sources
+                    .iter()
+                    .map(|source| Input::Source(&source.file, source.trust)),
+            )
+            .chain(self.imports.iter().map(|args| Input::Import(args)));
+        for (i, input) in inputs.enumerate() {
+            // Whether this is an input being merged into the destination.
+            let is_source = i > 0 || routed_journal.is_some();
+            merger.set_tag_unclassified(is_source);
+            merger.set_trust(match input {
+                Input::Source(_, trust) => trust,
+                Input::Journal(_) | Input::Import(_) => Trust::Normal,
+            });
+            let stats_before = merger.stats();
+            let mut conflicts = 0;
+            let read_start = Instant::now();
+            let mut trns_by_source: Box<dyn Iterator<Item = Vec<TransactionPostings>>> = match input
+            {
+                Input::Journal(ledger_file) if i == 0 && self.follow_includes => {
+                    let (journal, trns) = IncludedJournal::read(ledger_file)?;
+                    prices.extend(journal.prices().cloned());
+                    journal_ends = journal.flattened_ends();
+                    included_journal = Some(journal);
+                    Box::new(sources::group_by_source(trns, ledger_file))
+                }
+                Input::Journal(ledger_file) | Input::Source(ledger_file, _) => {
+                    let options = ReadOptions {
+                        skip_bad: is_source && self.skip_bad_transactions,
+                        strict: self.strict,
+                    };
+                    let (ends, trns) =
+                        sources::read_ledger_file(ledger_file, &mut prices, options)?;
+                    // Only the destination journal keeps its directives and
+                    // comments.
+                    if !is_source {
+                        journal_ends = ends;
+                    }
+                    Box::new(trns)
+                }
+                Input::Import(args) => {
+                    let args = shell_words::split(args).with_context(|| format!("{}", input))?;
+                    let importer = parse_importer(&args).with_context(|| format!("{}", input))?;
+                    let path = importer
+                        .input_path()
+                        .with_context(|| format!("{}: the statement must be a file", input))?
+                        .to_owned();
+                    let hash = match &import_history {
+                        Some(import_history) => {
+                            let hash = history::hash_file(&path)?;
+                            import_history
+                                .check(&path, &hash, self.reject_reimport)
+                                .with_context(|| format!("{}", input))?;
+                            Some(hash)
+                        }
+                        None => None,
+                    };
+                    let import = || import_statement(&importer, &accounts);
+                    let (trns, import_prices) = match &checkpoints {
+                        Some(checkpoints) => checkpoints.import(&args, &path, import),
+                        None => import(),
+                    }
+                    .with_context(|| format!("{}", input))?;
+                    if let (Some(import_history), Some(hash)) = (&mut import_history, hash) {
+                        import_history.record(hash, ImportRecord::new(&path, &self.output));
+                    }
+                    prices.extend(import_prices);
+                    Box::new(sources::group_by_source(trns, &FileSpec::Path(path)))
+                }
+            };
+            phases.add("read", read_start.elapsed());
+            while let Some(mut trns) = phases.time("read", || trns_by_source.next()) {
+                if is_source {
+                    trns.retain(|trn| self.date_filter.contains(trn));
+                    if trns.is_empty() {
+                        continue;
+                    }
+                    if let Some(label) = &merged_label {
+                        tag_merged(&mut trns, label);
+                    }
+                    if self.strip_source_lines {
+                        strip_source_lines(&mut trns);
+                    }
+                    clear_kept_items(&mut trns);
+                }
+                self.commodity_aliases
+                    .normalize_transaction_postings(&mut trns);
+                self.check_namespaces(&mut namespace_checker, &trns)?;
+                for warning in period_checker.check_and_add(&trns) {
+                    eprintln!("warning: {}", warning);
+                }
+                if let Some(checker) = &mut commodity_checker {
+                    if is_source {
+                        for mismatch in checker
+                            .check_and_add(&mut trns)
+                            .with_context(|| format!("{}", input))?
+                        {
+                            eprintln!("warning: {}: {}", input, mismatch);
+                        }
+                    } else {
+                        checker.add(&trns);
+                    }
+                }
+                match phases.time("merge", || merger.merge(trns)) {
+                    Ok(mut unmerged_trns) => unmerged.append(&mut unmerged_trns.0),
+                    Err(e) if self.dry_run => {
+                        eprintln!("conflict in {}: {:#}", input, e);
+                        conflicts += 1;
+                        break;
+                    }
+                    Err(e) => return Err(e),
+                }
+                report_account_changes(&mut merger, &input);
+            }
+            let stats = merger.stats().since(&stats_before);
+            for trn in merger.take_needs_classification() {
+                eprintln!("{}: new transaction needs classification: {}", input, trn);
+            }
+            if self.dry_run {
+                println!(
+                    "{}: {} would merge, {} new ({} needing classification), {} unmerged, {} conflicts",
+                    input,
+                    stats.merged,
+                    stats.new,
+                    stats.needs_classification,
+                    stats.unmerged,
+                    conflicts,
+                );
+                total_conflicts += conflicts;
+                if conflicts > 0 {
+                    // The merger may have applied some of the conflicting
+                    // transactions before failing.
+                    eprintln!(
+                        "dry run stopped at the first conflict, later inputs were not checked"
+                    );
+                    break;
+                }
+            }
+        }
+
+        if self.dry_run {
+            if self.stats {
+                eprint!("{}", stats::report(&merger.internal_stats(), &phases));
+            }
+            if total_conflicts > 0 {
+                bail!("dry run found {} conflicts", total_conflicts);
             }
+            return Ok(());
         }
 
+        // All outputs are written together, so that a failure cannot leave
+        // the journal updated without the unmerged transactions written.
+        let mut batch = WriteBatch::new().with_roundtrip_verification(self.verify_roundtrip);
         if !unmerged.is_empty() {
             match self.unmerged.as_ref() {
                 Some(fs) => {
+                    if let Some(table) = &unmerged_rules {
+                        unmerged = table
+                            .update_transactions(unmerged)
+                            .context("applying rules to unmerged transactions")?;
+                    }
                     // Deliberately leave the source tags on the unmerged files
                     // so that:
                     // * The human has more context of where the transaction
@@ -42,8 +593,8 @@ impl Command {
                     // * When re-attempting to merge from the unmerged file, the
                     //   sources::read_ledger_file can cause each source in the
                     //   file to be merged independently.
-                    let ledger = TransactionPostings::into_ledger(unmerged);
-                    filespec::write_ledger_file(fs, &ledger)?;
+                    let ledger = TransactionPostings::into_journal(unmerged);
+                    batch.add_ledger(fs, &ledger)?;
                 }
                 None => {
                     bail!("{} input transactions have gone unmerged and no --unmerged output file was specified",
@@ -52,10 +603,263 @@ impl Command {
             }
         }
 
-        let mut trns = merger.build();
+        let internal_stats = merger.internal_stats();
+        let mut trns = phases.time("build", || merger.build());
         sources::strip_sources(&mut trns);
-        let ledger = TransactionPostings::into_ledger(trns);
 
-        filespec::write_ledger_file(&self.output, &ledger)
+        let write_start = Instant::now();
+        match (routed_journal, included_journal) {
+            (Some(journal), _) => journal.write_back(trns, prices.into_sorted(), &mut batch)?,
+            (None, Some(journal)) if self.write_back => {
+                journal.write_back(trns, prices.into_sorted(), &mut batch)?
+            }
+            (None, included_journal) => {
+                if included_journal.is_some() {
+                    includes::flatten(&mut trns);
+                }
+                let mut index = self
+                    .fingerprint_index
+                    .as_ref()
+                    .map(|_| FingerprintIndex::build(&trns));
+                let contents = JournalTransactions {
+                    trns,
+                    prices: prices.into_sorted(),
+                    ends: journal_ends,
+                };
+                let ledger = contents.into_journal();
+                let content = ledger.to_string();
+                if let (Some(index), Some(index_path)) = (&mut index, &self.fingerprint_index) {
+                    index.set_content(&content);
+                    index.save(index_path, &mut batch)?;
+                }
+                batch.add_formatted_ledger(&self.output, &ledger, content)?;
+            }
+        }
+        if let Some(state) = &hook_state {
+            state.save(&mut batch)?;
+        }
+        if let (Some(import_history), Some(path)) = (&import_history, &self.import_history) {
+            import_history.save(path, &mut batch)?;
+        }
+        batch.commit()?;
+        phases.add("write", write_start.elapsed());
+        if self.stats {
+            eprint!("{}", stats::report(&internal_stats, &phases));
+        }
+        Ok(())
+    }
+
+    /// Fails if the source transactions clash with the fingerprint namespaces
+    /// of those already merged, or only warns if allowed.
+    fn check_namespaces(
+        &self,
+        checker: &mut NamespaceChecker,
+        trns: &[TransactionPostings],
+    ) -> Result<()> {
+        for clash in checker.check_and_add(trns) {
+            if !self.allow_namespace_clashes {
+                bail!("{} (use --allow-namespace-clashes to merge anyway)", clash);
+            }
+            eprintln!("warning: {}", clash);
+        }
+        Ok(())
+    }
+
+    /// Attempts to merge the inputs using the fingerprint index at
+    /// `index_path`, without reading the first input journal. Returns false
+    /// if a full merge is required.
+    fn append_indexed(&self, index_path: &Path, merged_label: Option<&str>) -> Result<bool> {
+        let (dest, inputs) = match self.inputs.split_first() {
+            Some(split) => split,
+            None => return Ok(false),
+        };
+        if dest.path().is_none() || inputs.iter().any(|input| input.path().is_none()) {
+            // Inputs from stdin cannot be read again for a full merge.
+            return Ok(false);
+        }
+        if !self.imports.is_empty() || !self.sources.is_empty() {
+            // Statements are only imported once, for the full merge, and
+            // trusted sources may update already merged postings.
+            return Ok(false);
+        }
+        if self.check_commodities.is_some() {
+            // Checking needs the commodities of the journal's accounts.
+            return Ok(false);
+        }
+        if self.reconcile_days.is_some() {
+            // Input postings may reconcile with pending postings on other
+            // dates, which the index does not record.
+            return Ok(false);
+        }
+        let content = filespec::read_file(dest)?;
+        let mut index = match FingerprintIndex::load(index_path, &content)? {
+            Some(index) => index,
+            None => return Ok(false),
+        };
+
+        let options = ReadOptions {
+            skip_bad: self.skip_bad_transactions,
+            strict: self.strict,
+        };
+        // The new transactions are merged on their own, in the same groups as
+        // for a full merge, so that they are sorted, normalized and merged
+        // with each other as they would be with the journal.
+        let mut merger = merger::Merger::new().with_status_policy(self.status_policy);
+        merger.set_tag_unclassified(true);
+        // Reported only once the full merge is known to be unnecessary.
+        let mut needs_classification = Vec::new();
+        let mut namespace_checker = NamespaceChecker::new();
+        let dest_source = format!("{}", dest);
+        namespace_checker.check_and_add_tags(&dest_source, index.dated_fingerprints());
+        for input in inputs {
+            let contents =
+                JournalTransactions::from(filespec::read_ledger_file_with(input, options)?);
+            if !contents.prices.is_empty() {
+                // Whether these are already in the journal is unknown.
+                return Ok(false);
+            }
+            for mut trns in sources::group_by_source(contents.trns, input) {
+                trns.retain(|trn| self.date_filter.contains(trn));
+                // Prepare the transactions as for a full merge, so that they
+                // can be compared with the journal's.
+                if let Some(label) = merged_label {
+                    tag_merged(&mut trns, label);
+                }
+                if self.strip_source_lines {
+                    strip_source_lines(&mut trns);
+                }
+                clear_kept_items(&mut trns);
+                self.commodity_aliases
+                    .normalize_transaction_postings(&mut trns);
+                if !namespace_checker.check_and_add(&trns).is_empty() {
+                    // Leave reporting the clashes to the full merge.
+                    return Ok(false);
+                }
+                let mut new_trns = Vec::new();
+                for trn in trns {
+                    match index.status(&trn, self.status_policy)? {
+                        fpindex::Status::Merged => {}
+                        fpindex::Status::New => new_trns.push(trn),
+                        fpindex::Status::NeedsMerge => return Ok(false),
+                    }
+                }
+                match merger.merge(new_trns) {
+                    Ok(unmerged) if unmerged.0.is_empty() => {}
+                    // Leave reporting to the full merge.
+                    _ => return Ok(false),
+                }
+            }
+            for trn in merger.take_needs_classification() {
+                needs_classification.push(format!(
+                    "{}: new transaction needs classification: {}",
+                    input, trn
+                ));
+            }
+        }
+        for message in needs_classification {
+            eprintln!("{}", message);
+        }
+
+        let mut new_trns = merger.build();
+        sources::strip_sources(&mut new_trns);
+        eprintln!(
+            "merged using fingerprint index: {} new transactions appended",
+            new_trns.len()
+        );
+        index.add(&new_trns);
+        let mut output = content;
+        if !new_trns.is_empty() {
+            if !output.is_empty() && !output.ends_with('\n') {
+                output.push('\n');
+            }
+            output.push('\n');
+            let ledger = TransactionPostings::into_journal(new_trns);
+            let appended = ledger.to_string();
+            if self.verify_roundtrip {
+                filespec::verify_roundtrip(&ledger, &appended)
+                    .with_context(|| format!("verifying output to {}", self.output))?;
+            }
+            output.push_str(&appended);
+        }
+        let mut batch = WriteBatch::new();
+        index.set_content(&output);
+        index.save(index_path, &mut batch)?;
+        batch.add(&self.output, output);
+        batch.commit()?;
+        Ok(true)
+    }
+}
+
+/// An input to merge.
+enum Input<'a> {
+    /// A journal, given as a positional argument.
+    Journal(&'a FileSpec),
+    /// A journal given with --source, and its trust level.
+    Source(&'a FileSpec, Trust),
+    /// The arguments of an importer, given with --import.
+    Import(&'a str),
+}
+
+impl fmt::Display for Input<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Input::Journal(ledger_file) | Input::Source(ledger_file, _) => ledger_file.fmt(f),
+            Input::Import(args) => write!(f, "--import {:?}", args),
+        }
+    }
+}
+
+/// A journal given with --source, as `FILE=TRUST`.
+#[derive(Clone, Debug)]
+struct Source {
+    file: FileSpec,
+    trust: Trust,
+}
+
+impl FromStr for Source {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (file, trust) = s
+            .rsplit_once('=')
+            .with_context(|| format!("{:?} is not of the form FILE=TRUST", s))?;
+        Ok(Self {
+            file: file.parse()?,
+            trust: trust.parse()?,
+        })
+    }
+}
+
+/// Reports the account changes from the latest merges into `merger`.
+fn report_account_changes(merger: &mut merger::Merger, input: &dyn fmt::Display) {
+    for change in merger.take_account_changes() {
+        eprintln!("warning: {}: {}", input, change);
+    }
+}
+
+/// Tags the postings with the merge run label. This marks newly added
+/// postings, and the merger moves the label to the existing postings that
+/// they are merged into only if the merge changes them.
+fn tag_merged(trns: &mut [TransactionPostings], label: &str) {
+    for trn in trns {
+        for post in &mut trn.posts {
+            post.comment.replace_value_tag(tags::MERGED, label);
+        }
+    }
+}
+
+/// Drops the directives and comments of input journals, which belong to
+/// those journals rather than to the journal being merged into.
+fn clear_kept_items(trns: &mut [TransactionPostings]) {
+    for trn in trns {
+        trn.trn.clear_kept_items();
+    }
+}
+
+/// Removes the references to statement rows that importers add to
+/// transactions.
+fn strip_source_lines(trns: &mut [TransactionPostings]) {
+    for trn in trns {
+        trn.trn.comment.value_tags.remove(tags::SOURCE_LINE);
     }
 }
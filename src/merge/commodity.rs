@@ -0,0 +1,244 @@
+//! Detects input postings whose commodity differs from those that their
+//! account has in the journal, which is usually a sign of a misconfigured
+//! importer or a misread statement.
+
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use chrono::NaiveDate;
+
+use crate::internal::{PostingInternal, TransactionPostings};
+use crate::tags;
+
+/// What to do with an input posting whose commodity is not one that its
+/// account has in the journal.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CommodityPolicy {
+    /// Tag the posting `commodity-mismatch` and `needs-review`, and merge
+    /// it.
+    Tag,
+    /// Fail the merge.
+    Error,
+}
+
+impl FromStr for CommodityPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "tag" => Ok(Self::Tag),
+            "error" => Ok(Self::Error),
+            _ => bail!("invalid commodity policy: {:?}", s),
+        }
+    }
+}
+
+/// An input posting whose commodity is not one that its account has in the
+/// journal.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CommodityMismatch {
+    pub date: NaiveDate,
+    pub account: String,
+    pub commodity: String,
+    /// The commodities that the account has in the journal.
+    pub expected: Vec<String>,
+}
+
+impl fmt::Display for CommodityMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "posting on {} in account {:?} has commodity {:?}, but the account only has {:?}",
+            self.date,
+            self.account,
+            self.commodity,
+            self.expected.join(", ")
+        )
+    }
+}
+
+/// Returns the commodity of the posting amount, or of its balance assertion
+/// if it has no amount. Postings in unknown accounts have none, as those
+/// accounts collect postings from anywhere.
+fn commodity(post: &PostingInternal) -> Option<&str> {
+    if post.comment.tags.contains(tags::UNKNOWN_ACCOUNT) {
+        return None;
+    }
+    let amount = post.raw.amount.as_ref().map(|amt| &amt.amount);
+    let amount = amount.or(match &post.raw.balance {
+        Some(ledger_parser::Balance::Amount(balance)) => Some(balance),
+        _ => None,
+    });
+    amount.map(|amount| amount.commodity.name.as_str())
+}
+
+/// Records the commodities of each account in the journal, and checks input
+/// postings against them.
+pub struct CommodityChecker {
+    policy: CommodityPolicy,
+    /// Commodities by account.
+    seen: HashMap<String, BTreeSet<String>>,
+}
+
+impl CommodityChecker {
+    pub fn new(policy: CommodityPolicy) -> Self {
+        Self {
+            policy,
+            seen: HashMap::new(),
+        }
+    }
+
+    /// Records the commodities of the journal's transactions.
+    pub fn add(&mut self, trns: &[TransactionPostings]) {
+        for post in trns.iter().flat_map(|trn| &trn.posts) {
+            if let Some(commodity) = commodity(post) {
+                self.seen
+                    .entry(post.raw.account.clone())
+                    .or_default()
+                    .insert(commodity.to_string());
+            }
+        }
+    }
+
+    /// Checks the input transactions against the recorded commodities,
+    /// returning the mismatches after tagging their postings, or failing on
+    /// the first, according to the policy. The commodities of accounts that
+    /// were not already recorded are then recorded, so that later inputs
+    /// are checked against them.
+    pub fn check_and_add(
+        &mut self,
+        trns: &mut [TransactionPostings],
+    ) -> Result<Vec<CommodityMismatch>> {
+        let mut mismatches = Vec::new();
+        let mut new_accounts = HashMap::<String, BTreeSet<String>>::new();
+        for trn in trns.iter_mut() {
+            let date = trn.trn.raw.date;
+            for post in &mut trn.posts {
+                let commodity = match commodity(post) {
+                    Some(commodity) => commodity.to_string(),
+                    None => continue,
+                };
+                let expected = match self.seen.get(&post.raw.account) {
+                    Some(expected) => expected,
+                    None => {
+                        new_accounts
+                            .entry(post.raw.account.clone())
+                            .or_default()
+                            .insert(commodity);
+                        continue;
+                    }
+                };
+                if expected.contains(&commodity) {
+                    continue;
+                }
+                let mismatch = CommodityMismatch {
+                    date: post.date(date),
+                    account: post.raw.account.clone(),
+                    commodity,
+                    expected: expected.iter().cloned().collect(),
+                };
+                match self.policy {
+                    CommodityPolicy::Tag => {
+                        post.comment.tags.insert(tags::COMMODITY_MISMATCH.into());
+                        post.comment.tags.insert(tags::NEEDS_REVIEW.into());
+                        mismatches.push(mismatch);
+                    }
+                    CommodityPolicy::Error => bail!("{}", mismatch),
+                }
+            }
+        }
+        self.seen.extend(new_accounts);
+        Ok(mismatches)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    fn journal() -> Vec<TransactionPostings> {
+        parse_transaction_postings(
+            "
+            2000/01/01 shop
+                assets:bank  GBP-10.00
+                expenses:food  GBP10.00
+
+            2000/01/02 travel
+                assets:wallet  = EUR20.00
+                expenses:unknown  USD5.00  ; :unknown-account:",
+        )
+    }
+
+    #[test]
+    fn tags_mismatches() {
+        let mut checker = CommodityChecker::new(CommodityPolicy::Tag);
+        checker.add(&journal());
+        let mut trns = parse_transaction_postings(
+            "
+            2000/02/01 shop
+                assets:bank  USD-10.00
+                expenses:food  GBP10.00
+
+            2000/02/02 other
+                assets:wallet  EUR-1.00
+                expenses:unknown  EUR1.00  ; :unknown-account:
+                assets:savings  USD1.00",
+        );
+        let mismatches = checker.check_and_add(&mut trns).expect("check");
+        assert_eq!(
+            mismatches,
+            vec![CommodityMismatch {
+                date: NaiveDate::from_ymd_opt(2000, 2, 1).unwrap(),
+                account: "assets:bank".to_string(),
+                commodity: "USD".to_string(),
+                expected: vec!["GBP".to_string()],
+            }]
+        );
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2000/02/01 shop
+                    assets:bank  USD-10.00  ; :commodity-mismatch:needs-review:
+                    expenses:food  GBP10.00
+
+                2000/02/02 other
+                    assets:wallet  EUR-1.00
+                    expenses:unknown  EUR1.00  ; :unknown-account:
+                    assets:savings  USD1.00",
+            )
+        );
+
+        // The new account's commodity is checked in later inputs.
+        let mut trns = parse_transaction_postings(
+            "
+            2000/03/01 interest
+                assets:savings  GBP1.00
+                income:interest  GBP-1.00",
+        );
+        let mismatches = checker.check_and_add(&mut trns).expect("check");
+        assert_eq!(mismatches.len(), 1, "{:?}", mismatches);
+        assert_eq!(mismatches[0].account, "assets:savings");
+    }
+
+    #[test]
+    fn fails_on_mismatch() {
+        let mut checker = CommodityChecker::new(CommodityPolicy::Error);
+        checker.add(&journal());
+        let mut trns = parse_transaction_postings(
+            "
+            2000/02/01 shop
+                assets:bank  GBP-10.00
+                expenses:food  EUR10.00",
+        );
+        let err = checker.check_and_add(&mut trns).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "posting on 2000-02-01 in account \"expenses:food\" has commodity \"EUR\", but the account only has \"GBP\"",
+        );
+    }
+}
@@ -0,0 +1,450 @@
+//! A sidecar index of the postings in a destination journal, so that merging
+//! transactions that are entirely new or entirely already merged does not
+//! require parsing and indexing the whole journal.
+//!
+//! The index is only a cache: it records enough of each posting and
+//! transaction to tell whether merging an input transaction into them would
+//! change anything, so that skipping already merged transactions gives the
+//! same journal as a full merge.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use chrono::NaiveDate;
+use ledger_parser::TransactionStatus;
+use serde_derive::{Deserialize, Serialize};
+use sha1::{Digest, Sha1};
+
+use crate::comment::Comment;
+use crate::filespec::{FileSpec, WriteBatch};
+use crate::internal::{PostingInternal, TransactionPostings};
+use crate::merge::posting::{self, Input};
+use crate::merge::status::StatusPolicy;
+use crate::tags;
+
+/// Increment when the contents of the index change meaning.
+const FORMAT_VERSION: u32 = 2;
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FingerprintIndex {
+    version: u32,
+    /// Hex encoded SHA-1 hash of the journal content that the index describes.
+    journal_hash: String,
+    /// The index into `postings` of the posting with each fingerprint in the
+    /// journal.
+    fingerprints: HashMap<String, usize>,
+    /// The transaction fingerprints in the journal.
+    transaction_fingerprints: HashSet<String>,
+    postings: Vec<PostingState>,
+    transactions: Vec<TransactionState>,
+    /// The date and amount of each posting in the journal. A posting can only
+    /// soft match a journal posting with the same date and amount.
+    soft_keys: HashSet<String>,
+    /// The account and match key of postings in the journal that have one.
+    match_keys: HashSet<String>,
+    /// The latest transaction date in the journal.
+    last_date: Option<NaiveDate>,
+}
+
+/// How a transaction relates to the postings in the index.
+pub enum Status {
+    /// All postings have already been merged into the journal, and merging
+    /// them again would not change it.
+    Merged,
+    /// No postings can match any posting in the journal, and the transaction
+    /// would be sorted after all of the journal's transactions.
+    New,
+    /// Some postings may match postings in the journal, or merging would
+    /// change the journal, so a full merge is required.
+    NeedsMerge,
+}
+
+/// The parts of a journal posting that merging an input posting into it can
+/// change.
+#[derive(Debug, Deserialize, Serialize)]
+struct PostingState {
+    /// The index into `transactions` of the posting's transaction.
+    trn: usize,
+    /// The posting's date, or that of its transaction.
+    date: NaiveDate,
+    account: String,
+    /// The posting's status, or that of its transaction.
+    status: Option<PostingStatus>,
+    has_balance: bool,
+    comment: CommentState,
+}
+
+/// The parts of a journal transaction that merging an input transaction into
+/// it can change.
+#[derive(Debug, Deserialize, Serialize)]
+struct TransactionState {
+    has_code: bool,
+    has_effective_date: bool,
+    comment: CommentState,
+}
+
+#[derive(Debug, Deserialize, Serialize)]
+struct CommentState {
+    lines: Vec<String>,
+    tags: BTreeSet<String>,
+    value_tags: BTreeMap<String, String>,
+    date: Option<NaiveDate>,
+    aux_date: Option<NaiveDate>,
+}
+
+impl FingerprintIndex {
+    /// Builds an index of the transactions in a journal. `set_content` must
+    /// be called before saving it.
+    pub fn build(trns: &[TransactionPostings]) -> Self {
+        let mut index = Self {
+            version: FORMAT_VERSION,
+            ..Default::default()
+        };
+        index.add(trns);
+        index
+    }
+
+    /// Loads the index at `path`, returning `None` if it does not exist or
+    /// does not describe the journal with the given content.
+    pub fn load(path: &Path, content: &str) -> Result<Option<Self>> {
+        let file = match std::fs::File::open(path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("opening {:?} for reading", path)),
+        };
+        let index: Self = match serde_json::from_reader(std::io::BufReader::new(file)) {
+            Ok(index) => index,
+            // Treat an unreadable index as stale; it is rebuilt after merging.
+            Err(_) => return Ok(None),
+        };
+        if index.version != FORMAT_VERSION || index.journal_hash != hash(content) {
+            return Ok(None);
+        }
+        Ok(Some(index))
+    }
+
+    /// Adds the index to `batch`, to be written to `path`.
+    pub fn save(&self, path: &Path, batch: &mut WriteBatch) -> Result<()> {
+        let content = serde_json::to_string(self)?;
+        batch.add(&FileSpec::Path(path.to_owned()), content);
+        Ok(())
+    }
+
+    /// Adds the postings of the transactions to the index.
+    pub fn add(&mut self, trns: &[TransactionPostings]) {
+        for trn in trns {
+            let trn_idx = self.transactions.len();
+            self.transactions.push(TransactionState {
+                has_code: trn.trn.raw.code.is_some(),
+                has_effective_date: trn.trn.raw.effective_date.is_some(),
+                comment: CommentState::from(&trn.trn.comment),
+            });
+            self.transaction_fingerprints.extend(
+                trn.trn
+                    .comment
+                    .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+                    .map(str::to_string),
+            );
+            self.last_date = self.last_date.max(Some(trn.trn.raw.date));
+            for post in &trn.posts {
+                let post_idx = self.postings.len();
+                self.postings.push(PostingState {
+                    trn: trn_idx,
+                    date: post.date(trn.trn.raw.date),
+                    account: post.raw.account.clone(),
+                    status: post.raw.status.or(trn.trn.raw.status).map(Into::into),
+                    has_balance: post.raw.balance.is_some(),
+                    comment: CommentState::from(&post.comment),
+                });
+                for fp in posting::fingerprints_from_comment(&post.comment) {
+                    self.fingerprints.insert(fp.to_string(), post_idx);
+                }
+                self.soft_keys.insert(soft_key(trn, post));
+                if let Some(key) = posting::match_key(post) {
+                    self.match_keys.insert(match_key_string(key));
+                }
+            }
+        }
+    }
+
+    /// Returns the fingerprints in the journal, with the dates of their
+    /// postings.
+    pub fn dated_fingerprints(&self) -> impl Iterator<Item = (NaiveDate, &str)> {
+        self.fingerprints
+            .iter()
+            .map(|(fp, post_idx)| (self.postings[*post_idx].date, fp.as_str()))
+    }
+
+    /// Records that the journal now has the given content.
+    pub fn set_content(&mut self, content: &str) {
+        self.journal_hash = hash(content);
+    }
+
+    /// Determines how the transaction would be merged with `status_policy`.
+    /// Errors if it is not valid input to merging.
+    pub fn status(&self, trn: &TransactionPostings, status_policy: StatusPolicy) -> Result<Status> {
+        // The journal transaction that the postings have been merged into.
+        let mut merged_trn: Option<usize> = None;
+        let mut all_merged = true;
+        for post in &trn.posts {
+            // Check the same constraints as merging would.
+            let input = Input::from_posting_internal(post.clone(), trn.trn.raw.date)?;
+
+            let mut found = posting::fingerprints_from_comment(&post.comment)
+                .filter_map(|fp| self.fingerprints.get(fp).copied());
+            let post_idx = match found.next() {
+                Some(post_idx) => post_idx,
+                None => {
+                    all_merged = false;
+                    let may_soft_match = self.soft_keys.contains(&soft_key(trn, post))
+                        || posting::match_key(post)
+                            .is_some_and(|key| self.match_keys.contains(&match_key_string(key)));
+                    if may_soft_match {
+                        return Ok(Status::NeedsMerge);
+                    }
+                    continue;
+                }
+            };
+            let state = &self.postings[post_idx];
+            let status = input.posting.raw.status.or(trn.trn.raw.status);
+            if found.any(|idx| idx != post_idx)
+                || merged_trn.is_some_and(|trn_idx| trn_idx != state.trn)
+                || !state.merges_unchanged(&input.posting, status, status_policy)
+            {
+                return Ok(Status::NeedsMerge);
+            }
+            merged_trn = Some(state.trn);
+        }
+        Ok(match (merged_trn, all_merged) {
+            (Some(trn_idx), true) if self.transactions[trn_idx].merges_unchanged(trn) => {
+                Status::Merged
+            }
+            (None, false) if self.is_new(trn) => Status::New,
+            _ => Status::NeedsMerge,
+        })
+    }
+
+    /// Returns true if a transaction whose postings match none in the journal
+    /// would neither be merged by its transaction fingerprint, nor sorted
+    /// before any transaction in the journal.
+    fn is_new(&self, trn: &TransactionPostings) -> bool {
+        let comment = &trn.trn.comment;
+        let known_trn_fp = comment
+            .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+            .any(|fp| self.transaction_fingerprints.contains(fp));
+        // Transactions with a time are sorted among the others on their date.
+        let timed = comment.value_tags.contains_key(tags::TIME);
+        !known_trn_fp && !timed && self.last_date.is_none_or(|last| trn.trn.raw.date >= last)
+    }
+}
+
+impl PostingState {
+    /// Returns true if merging the input posting, whose status is `status`,
+    /// into this posting would not change it.
+    fn merges_unchanged(
+        &self,
+        input: &PostingInternal,
+        status: Option<TransactionStatus>,
+        status_policy: StatusPolicy,
+    ) -> bool {
+        // Differing known accounts are reported, and may update the posting.
+        let account_unchanged = input.comment.tags.contains(tags::UNKNOWN_ACCOUNT)
+            || (!self.comment.tags.contains(tags::UNKNOWN_ACCOUNT)
+                && self.account == input.raw.account);
+
+        let existing = self.status.map(TransactionStatus::from);
+        let mut merged = existing;
+        status_policy.merge(&mut merged, status);
+
+        // The merge run label is only given to postings that the merge
+        // changes, so it is not compared.
+        let value_tags_unchanged = input.comment.value_tags.iter().all(|(key, value)| {
+            key == tags::MERGED || self.comment.value_tags.get(key.as_str()) == Some(value)
+        });
+
+        account_unchanged
+            && merged == existing
+            && (self.has_balance || input.raw.balance.is_none())
+            && value_tags_unchanged
+            && self
+                .comment
+                .contains_tags_and_lines(&input.comment, Some(tags::UNKNOWN_ACCOUNT))
+    }
+}
+
+impl TransactionState {
+    /// Returns true if merging the input transaction into this transaction
+    /// would not change it.
+    fn merges_unchanged(&self, input: &TransactionPostings) -> bool {
+        let raw = &input.trn.raw;
+        let comment = &input.trn.comment;
+        // Only value tags with new keys are merged, and source tags are
+        // stripped from the output.
+        let value_tags_unchanged = comment.value_tags.keys().all(|key| {
+            key == tags::TRANSACTION_SOURCE_KEY
+                || self.comment.value_tags.contains_key(key.as_str())
+        });
+        (self.has_code || raw.code.is_none())
+            && (self.has_effective_date || raw.effective_date.is_none())
+            && value_tags_unchanged
+            && self.comment.contains_tags_and_lines(comment, None)
+    }
+}
+
+impl CommentState {
+    /// Returns true if merging the dates, tags (other than `ignore_tag`) and
+    /// lines of `other` into this comment would not change it.
+    fn contains_tags_and_lines(&self, other: &Comment, ignore_tag: Option<&str>) -> bool {
+        let dates_unchanged = (other.date.is_none() && other.aux_date.is_none())
+            || (other.date == self.date && other.aux_date == self.aux_date);
+        dates_unchanged
+            && other
+                .tags
+                .iter()
+                .all(|tag| ignore_tag == Some(tag.as_str()) || self.tags.contains(tag.as_str()))
+            && other.lines.iter().all(|line| self.lines.contains(line))
+    }
+}
+
+impl From<&Comment> for CommentState {
+    fn from(comment: &Comment) -> Self {
+        Self {
+            lines: comment.lines.clone(),
+            tags: comment.tags.iter().map(|tag| tag.to_string()).collect(),
+            value_tags: comment
+                .value_tags
+                .iter()
+                .map(|(key, value)| (key.to_string(), value.clone()))
+                .collect(),
+            date: comment.date,
+            aux_date: comment.aux_date,
+        }
+    }
+}
+
+/// A `TransactionStatus` that can be serialized.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+enum PostingStatus {
+    Pending,
+    Cleared,
+}
+
+impl From<TransactionStatus> for PostingStatus {
+    fn from(status: TransactionStatus) -> Self {
+        match status {
+            TransactionStatus::Pending => Self::Pending,
+            TransactionStatus::Cleared => Self::Cleared,
+        }
+    }
+}
+
+impl From<PostingStatus> for TransactionStatus {
+    fn from(status: PostingStatus) -> Self {
+        match status {
+            PostingStatus::Pending => Self::Pending,
+            PostingStatus::Cleared => Self::Cleared,
+        }
+    }
+}
+
+fn hash(content: &str) -> String {
+    format!("{:x}", Sha1::digest(content.as_bytes()))
+}
+
+/// Returns the key of the posting's date and amount. Postings are soft
+/// matched on their own dates, which may differ from their transactions'.
+fn soft_key(trn: &TransactionPostings, post: &PostingInternal) -> String {
+    let date = post.date(trn.trn.raw.date);
+    match &post.raw.amount {
+        // Soft matching compares quantities and commodity names only, so the
+        // key ignores how the amount is written.
+        Some(amount) => format!(
+            "{} {} {}",
+            date,
+            amount.amount.quantity.normalize(),
+            amount.amount.commodity.name
+        ),
+        None => format!("{}", date),
+    }
+}
+
+fn match_key_string((account, key): (String, String)) -> String {
+    format!("{} {}", account, key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    use test_case::test_case;
+
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-1:" => "merged"; "known fingerprint")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-1:fp-9:" => "needs merge"; "new fingerprint")]
+    #[test_case("!", "assets:bank  GBP10.00  ; :fp-1:" => "merged"; "same status")]
+    #[test_case("*", "assets:bank  GBP10.00  ; :fp-1:" => "needs merge"; "status upgrade")]
+    #[test_case("", "assets:other  GBP10.00  ; :fp-1:" => "needs merge"; "different account")]
+    #[test_case("", "assets:other  GBP10.00  ; :fp-1:unknown-account:" => "merged"; "unknown account")]
+    #[test_case("", "expenses:food  GBP50.00  ; :fp-3:" => "needs merge"; "classifies unknown account")]
+    #[test_case("", "expenses:unknown  GBP50.00  ; :fp-3:unknown-account:" => "merged"; "still unknown account")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-1:needs-review:" => "needs merge"; "new tag")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-1:\n    ; note: a" => "merged"; "same value tag")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-1:\n    ; note: b" => "needs merge"; "changed value tag")]
+    #[test_case("", "assets:bank  GBP10.00 = GBP10.00  ; :fp-1:" => "needs merge"; "new balance")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-new:" => "needs merge"; "may soft match")]
+    #[test_case("", "assets:bank  GBP20.00  ; :fp-new:" => "new"; "different amount")]
+    #[test_case("", "assets:bank  GBP30.00  ; :fp-new:\n    ; [2001/01/05]" => "needs merge"; "may soft match on posting date")]
+    #[test_case("", "assets:bank  10 GBP  ; :fp-new:" => "needs merge"; "amount written differently")]
+    #[test_case("", "assets:bank  GBP10.00  ; :fp-new:\n    ; [2001/01/05]" => "new"; "different posting date")]
+    fn status(status: &str, post: &str) -> &'static str {
+        let dest = parse_transaction_postings(
+            "2001/01/01 ! existing
+                assets:bank  GBP10.00  ; :fp-1:
+                ; note: a
+                assets:bank  GBP30.00  ; :fp-2:
+                ; [2001/01/05]
+                expenses:unknown  GBP50.00  ; :fp-3:unknown-account:",
+        );
+        let index = FingerprintIndex::build(&dest);
+        let src = parse_transaction_postings(&format!(
+            "2001/01/01 {} incoming
+                {}",
+            status, post
+        ));
+        match index
+            .status(&src[0], StatusPolicy::Upgrade)
+            .expect("status")
+        {
+            Status::Merged => "merged",
+            Status::New => "new",
+            Status::NeedsMerge => "needs merge",
+        }
+    }
+
+    #[test_case("2001/01/02 incoming" => "new"; "later")]
+    #[test_case("2001/01/01 incoming" => "new"; "same date")]
+    #[test_case("2000/12/31 incoming" => "needs merge"; "earlier")]
+    #[test_case("2001/01/02 incoming  ; time: 10:00" => "needs merge"; "with time")]
+    #[test_case("2001/01/02 incoming  ; :trn-fp-1:" => "needs merge"; "known transaction fingerprint")]
+    fn status_new(header: &str) -> &'static str {
+        let dest = parse_transaction_postings(
+            "2001/01/01 existing  ; :trn-fp-1:
+                assets:bank  GBP10.00  ; :fp-1:",
+        );
+        let index = FingerprintIndex::build(&dest);
+        let src = parse_transaction_postings(&format!(
+            "{}
+                assets:bank  GBP20.00  ; :fp-2:",
+            header
+        ));
+        match index
+            .status(&src[0], StatusPolicy::Upgrade)
+            .expect("status")
+        {
+            Status::Merged => "merged",
+            Status::New => "new",
+            Status::NeedsMerge => "needs merge",
+        }
+    }
+}
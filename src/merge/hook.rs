@@ -0,0 +1,329 @@
+//! Scripted decisions for ambiguous merges. A rhai script given to
+//! `merge --decision-hook` defines a function
+//! `decide(kind, posting, candidates)`, which is called for each input
+//! posting that the merge cannot decide about by itself.
+//!
+//! Scripts may `import` helper modules, which are looked up relative to the
+//! hook script's directory, and then in each `merge --script-path` directory.
+//! With `merge --hook-state`, `this` is bound to state kept across runs.
+
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chrono::NaiveDate;
+use rhai::module_resolvers::{FileModuleResolver, ModuleResolversCollection};
+use rhai::{Array, Dynamic, Engine, Map, Scope, AST};
+
+use crate::internal::PostingInternal;
+use crate::rules::script::{self, ScriptState};
+
+const DECIDE_FN: &str = "decide";
+
+/// The kind of decision that the hook is asked to make.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Ambiguity {
+    /// The input posting soft matches several existing postings. By default,
+    /// its transaction is left unmerged with candidate tags.
+    SoftMatch,
+    /// The input posting matches several existing postings by fingerprint.
+    /// By default, the merge fails.
+    Conflict,
+}
+
+impl fmt::Display for Ambiguity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Self::SoftMatch => "soft-match",
+            Self::Conflict => "conflict",
+        })
+    }
+}
+
+/// A decision returned by the hook.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Merge into the candidate at this index.
+    Pick(usize),
+    /// Add the input posting as a new posting.
+    New,
+    /// Leave the input posting's transaction unmerged, with candidate tags.
+    Unmerge,
+}
+
+/// A posting as passed to the hook, along with its transaction's details.
+#[derive(Clone, Copy)]
+pub struct HookPosting<'a> {
+    pub date: NaiveDate,
+    pub description: &'a str,
+    pub posting: &'a PostingInternal,
+}
+
+impl HookPosting<'_> {
+    /// Converts the posting into a rhai object map with the fields `date`,
+    /// `description`, `account`, `amount`, `status`, `tags` and `value_tags`.
+    pub fn to_map(self) -> Map {
+        let raw = &self.posting.raw;
+        let mut map = Map::new();
+        map.insert("date".into(), Dynamic::from(self.date.to_string()));
+        map.insert(
+            "description".into(),
+            Dynamic::from(self.description.to_string()),
+        );
+        map.insert("account".into(), Dynamic::from(raw.account.clone()));
+        map.insert(
+            "amount".into(),
+            match &raw.amount {
+                Some(amount) => Dynamic::from(amount.amount.to_string()),
+                None => Dynamic::UNIT,
+            },
+        );
+        map.insert(
+            "status".into(),
+            Dynamic::from(
+                match raw.status {
+                    Some(ledger_parser::TransactionStatus::Cleared) => "cleared",
+                    Some(ledger_parser::TransactionStatus::Pending) => "pending",
+                    None => "",
+                }
+                .to_string(),
+            ),
+        );
+        script::insert_comment_fields(&mut map, &self.posting.comment);
+        map
+    }
+}
+
+/// A compiled decision hook script.
+pub struct DecisionHook {
+    engine: Engine,
+    ast: AST,
+    state: Option<Arc<ScriptState>>,
+}
+
+impl DecisionHook {
+    /// Loads the hook script at `path`. Modules that it imports are looked
+    /// up relative to its directory, and then in each of `script_path`.
+    pub fn from_path(path: &Path, script_path: &[PathBuf]) -> Result<Self> {
+        let script = std::fs::read_to_string(path)
+            .with_context(|| format!("reading decision hook {:?}", path))?;
+        let script_dir = path
+            .parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        let search_path: Vec<&Path> = std::iter::once(script_dir)
+            .chain(script_path.iter().map(PathBuf::as_path))
+            .collect();
+        Self::from_script(&script, &search_path)
+            .with_context(|| format!("in decision hook {:?}", path))
+    }
+
+    /// Compiles the hook script, looking up the modules that it imports in
+    /// each of `search_path` in turn. Imports are resolved immediately, so
+    /// that a missing module is reported before merging.
+    pub fn from_script(script: &str, search_path: &[&Path]) -> Result<Self> {
+        let mut engine = script::new_engine();
+        let mut resolvers = ModuleResolversCollection::new();
+        for dir in search_path {
+            resolvers.push(FileModuleResolver::new_with_path(dir));
+        }
+        engine.set_module_resolver(resolvers);
+        let ast = engine
+            .compile_into_self_contained(&Scope::new(), script)
+            .map_err(|e| anyhow!("compiling decision hook: {}", e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == DECIDE_FN && f.params.len() == 3)
+        {
+            bail!(
+                "decision hook does not define a function {}(kind, posting, candidates)",
+                DECIDE_FN
+            );
+        }
+        Ok(Self {
+            engine,
+            ast,
+            state: None,
+        })
+    }
+
+    /// Binds `state` to `this` when calling the hook.
+    pub fn with_state(mut self, state: Option<Arc<ScriptState>>) -> Self {
+        self.state = state;
+        self
+    }
+
+    /// Asks the hook what to do with `posting`, given the existing postings
+    /// that it matches. Returns `None` if the hook returns `()`, leaving the
+    /// decision to the merge. The hook may return the index of a candidate
+    /// to merge into, `"new"` or `"unmerge"`.
+    pub fn decide(
+        &self,
+        ambiguity: Ambiguity,
+        posting: &HookPosting,
+        candidates: &[HookPosting],
+    ) -> Result<Option<Decision>> {
+        let candidate_maps: Array = candidates
+            .iter()
+            .map(|candidate| Dynamic::from(candidate.to_map()))
+            .collect();
+        let args = (ambiguity.to_string(), posting.to_map(), candidate_maps);
+        let result: Dynamic = match &self.state {
+            Some(state) => state.call_fn(&self.engine, &self.ast, DECIDE_FN, args),
+            None => self
+                .engine
+                .call_fn(&mut Scope::new(), &self.ast, DECIDE_FN, args),
+        }
+        .map_err(|e| anyhow!("decision hook failed: {}", e))?;
+        if result.is_unit() {
+            return Ok(None);
+        }
+        if let Some(i) = result.clone().try_cast::<i64>() {
+            return match usize::try_from(i) {
+                Ok(i) if i < candidates.len() => Ok(Some(Decision::Pick(i))),
+                _ => bail!(
+                    "decision hook picked candidate {}, but there are {} candidates",
+                    i,
+                    candidates.len()
+                ),
+            };
+        }
+        let type_name = result.type_name();
+        match result.into_string().as_deref() {
+            Ok("new") => Ok(Some(Decision::New)),
+            Ok("unmerge") => Ok(Some(Decision::Unmerge)),
+            Ok(s) => bail!("decision hook returned unknown decision {:?}", s),
+            Err(_) => bail!(
+                "decision hook returned a {}, not a candidate index, \"new\", \"unmerge\" or ()",
+                type_name
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::testutil::parse_posting_internal;
+
+    #[test_case("()" => Some(None); "default")]
+    #[test_case("1" => Some(Some(Decision::Pick(1))); "pick")]
+    #[test_case("\"new\"" => Some(Some(Decision::New)); "new")]
+    #[test_case("\"unmerge\"" => Some(Some(Decision::Unmerge)); "unmerge")]
+    #[test_case("2" => None; "pick_out_of_range")]
+    #[test_case("\"other\"" => None; "unknown_decision")]
+    #[test_case("true" => None; "wrong_type")]
+    fn decide(result: &str) -> Option<Option<Decision>> {
+        let hook = DecisionHook::from_script(
+            &format!("fn decide(kind, posting, candidates) {{ {} }}", result),
+            &[],
+        )
+        .expect("from_script");
+        let posting = parse_posting_internal("foo  GBP 10.00  ; :fp-1:");
+        let hook_posting = HookPosting {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            description: "Shop",
+            posting: &posting,
+        };
+        hook.decide(
+            Ambiguity::SoftMatch,
+            &hook_posting,
+            &[hook_posting, hook_posting],
+        )
+        .ok()
+    }
+
+    #[test]
+    fn state_is_bound_to_this() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let state = Arc::new(ScriptState::load(&dir.path().join("state.json")).expect("load"));
+        let hook = DecisionHook::from_script(
+            "fn decide(kind, posting, candidates) { this.calls = (this.calls ?? 0) + 1; this.calls }",
+            &[],
+        )
+        .expect("from_script")
+        .with_state(Some(state));
+        let posting = parse_posting_internal("foo  GBP 10.00  ; :fp-1:");
+        let hook_posting = HookPosting {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            description: "Shop",
+            posting: &posting,
+        };
+        let candidates = [hook_posting, hook_posting, hook_posting];
+        let decide = || {
+            hook.decide(Ambiguity::SoftMatch, &hook_posting, &candidates)
+                .expect("decide")
+        };
+        assert_eq!(decide(), Some(Decision::Pick(1)));
+        assert_eq!(decide(), Some(Decision::Pick(2)));
+    }
+
+    #[test_case(
+        "fn decide(kind, posting, candidates) { loop {} }",
+        "Too many operations";
+        "endless loop"
+    )]
+    #[test_case(
+        "fn f(n) { f(n + 1) } fn decide(kind, posting, candidates) { f(0) }",
+        "Stack overflow";
+        "endless recursion"
+    )]
+    fn runaway_hook_fails(script: &str, want: &str) {
+        let hook = DecisionHook::from_script(script, &[]).expect("from_script");
+        let posting = parse_posting_internal("foo  GBP 10.00  ; :fp-1:");
+        let hook_posting = HookPosting {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            description: "Shop",
+            posting: &posting,
+        };
+        let err = hook
+            .decide(Ambiguity::SoftMatch, &hook_posting, &[hook_posting])
+            .expect_err("should fail");
+        assert!(format!("{:#}", err).contains(want), "{:#}", err);
+    }
+
+    #[test]
+    fn requires_decide_function() {
+        assert!(DecisionHook::from_script("fn other(x) { x }", &[]).is_err());
+    }
+
+    #[test]
+    fn imports_from_script_path() {
+        let script_dir = tempfile::tempdir().expect("tempdir");
+        let lib_dir = tempfile::tempdir().expect("tempdir");
+        std::fs::write(
+            lib_dir.path().join("helpers.rhai"),
+            "fn is_shop(posting) { posting.description == \"Shop\" }",
+        )
+        .expect("write helpers");
+        let hook_path = script_dir.path().join("hook.rhai");
+        std::fs::write(
+            &hook_path,
+            "import \"helpers\" as h;\n\
+             fn decide(kind, posting, candidates) { if h::is_shop(posting) { 1 } }",
+        )
+        .expect("write hook");
+
+        assert!(DecisionHook::from_path(&hook_path, &[]).is_err());
+        let hook =
+            DecisionHook::from_path(&hook_path, &[lib_dir.path().to_path_buf()]).expect("load");
+        let posting = parse_posting_internal("foo  GBP 10.00  ; :fp-1:");
+        let hook_posting = HookPosting {
+            date: NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+            description: "Shop",
+            posting: &posting,
+        };
+        assert_eq!(
+            hook.decide(
+                Ambiguity::SoftMatch,
+                &hook_posting,
+                &[hook_posting, hook_posting],
+            )
+            .expect("decide"),
+            Some(Decision::Pick(1))
+        );
+    }
+}
@@ -1,8 +1,19 @@
+use std::cell::Cell;
+use std::cmp::Reverse;
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 
 use anyhow::{anyhow, bail, Result};
+use chrono::NaiveDate;
+use ledger_parser::TransactionStatus;
 
 use crate::internal::{PostingInternal, TransactionPostings};
+use crate::merge::account::{AccountChange, AccountPolicy};
+use crate::merge::hook::{Ambiguity, Decision, DecisionHook, HookPosting};
+use crate::merge::matchset::MatchSet;
+use crate::merge::stats::{InternalStats, MatchCounts};
+use crate::merge::status::{self, StatusPolicy};
+use crate::merge::trust::Trust;
 use crate::merge::{posting, transaction};
 use crate::mutcell::MutCell;
 use crate::tags;
@@ -11,9 +22,81 @@ use crate::tags;
 /// intervention to resolve.
 pub struct UnmergedTransactions(pub Vec<TransactionPostings>);
 
+/// A new transaction that was tagged as needing classification.
+#[derive(Debug, PartialEq, Eq)]
+pub struct NeedsClassification {
+    pub date: NaiveDate,
+    pub description: String,
+}
+
+impl fmt::Display for NeedsClassification {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.date, self.description)
+    }
+}
+
+/// Counts of how source transactions have been handled by a `Merger`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MergeStats {
+    /// Transactions merged into an existing transaction.
+    pub merged: usize,
+    /// Transactions added as new transactions.
+    pub new: usize,
+    /// Transactions left unmerged for a human to resolve.
+    pub unmerged: usize,
+    /// New transactions tagged as needing classification, which are also
+    /// counted in `new`.
+    pub needs_classification: usize,
+}
+
+impl MergeStats {
+    /// Returns the counts accumulated since `earlier`.
+    pub fn since(&self, earlier: &MergeStats) -> MergeStats {
+        MergeStats {
+            merged: self.merged - earlier.merged,
+            new: self.new - earlier.new,
+            unmerged: self.unmerged - earlier.unmerged,
+            needs_classification: self.needs_classification - earlier.needs_classification,
+        }
+    }
+}
+
 pub struct Merger {
     posts: posting::IndexedPostings,
     trns: transaction::IndexedTransactions,
+    stats: MergeStats,
+    /// Counts of how input postings have been matched. This is a `Cell` as
+    /// postings are matched while only borrowing the `Merger`.
+    match_counts: Cell<MatchCounts>,
+    status_policy: StatusPolicy,
+    account_policy: AccountPolicy,
+    /// The trust of the inputs currently being merged.
+    trust: Trust,
+    /// Account changes from merging, not yet taken by
+    /// `take_account_changes`.
+    account_changes: Vec<AccountChange>,
+    /// Whether new transactions with only unknown accounts are tagged as
+    /// needing classification.
+    tag_unclassified: bool,
+    /// New transactions tagged as needing classification, not yet taken by
+    /// `take_needs_classification`.
+    needs_classification: Vec<NeedsClassification>,
+    description_matching: bool,
+    soft_matching: bool,
+    /// Maximum number of candidate tags added to an ambiguously matched
+    /// posting.
+    max_candidates: Option<usize>,
+    /// Maximum number of days between a cleared input posting and a pending
+    /// existing posting for them to be matched, if reconciling.
+    reconcile_days: Option<u32>,
+    /// Script deciding ambiguous matches, if any.
+    decision_hook: Option<DecisionHook>,
+}
+
+impl Default for Merger {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Merger {
@@ -21,12 +104,145 @@ impl Merger {
         Merger {
             posts: posting::IndexedPostings::new(),
             trns: transaction::IndexedTransactions::new(),
+            stats: MergeStats::default(),
+            match_counts: Cell::default(),
+            status_policy: StatusPolicy::default(),
+            account_policy: AccountPolicy::default(),
+            trust: Trust::default(),
+            account_changes: Vec::new(),
+            tag_unclassified: false,
+            needs_classification: Vec::new(),
+            description_matching: false,
+            soft_matching: true,
+            max_candidates: None,
+            reconcile_days: None,
+            decision_hook: None,
+        }
+    }
+
+    /// Creates a `Merger` with space for `trns` transactions and `posts`
+    /// postings, to avoid repeated reallocation when the size of the merged
+    /// journal is known up front.
+    pub fn with_capacity(trns: usize, posts: usize) -> Self {
+        Merger {
+            posts: posting::IndexedPostings::with_capacity(posts),
+            trns: transaction::IndexedTransactions::with_capacity(trns),
+            ..Self::new()
+        }
+    }
+
+    /// Sets how the statuses of merged transactions and postings are updated.
+    pub fn with_status_policy(mut self, status_policy: StatusPolicy) -> Self {
+        self.status_policy = status_policy;
+        self
+    }
+
+    /// Sets how a merged posting's known account updates a differing known
+    /// account of the existing posting.
+    pub fn with_account_policy(mut self, account_policy: AccountPolicy) -> Self {
+        self.account_policy = account_policy;
+        self
+    }
+
+    /// Sets how far the inputs to later calls to `merge` are trusted over the
+    /// postings that they are merged into.
+    pub fn set_trust(&mut self, trust: Trust) {
+        self.trust = trust;
+    }
+
+    /// Returns the postings merged since the last call whose known account
+    /// differed from the existing posting's.
+    pub fn take_account_changes(&mut self) -> Vec<AccountChange> {
+        std::mem::take(&mut self.account_changes)
+    }
+
+    /// Sets whether transactions added as new by later calls to `merge`,
+    /// whose postings all have the `unknown-account` tag, are tagged with
+    /// `needs-classification`. This is not wanted while merging the journal
+    /// being merged into, whose transactions are all "new".
+    pub fn set_tag_unclassified(&mut self, tag_unclassified: bool) {
+        self.tag_unclassified = tag_unclassified;
+    }
+
+    /// Returns the transactions tagged as needing classification since the
+    /// last call.
+    pub fn take_needs_classification(&mut self) -> Vec<NeedsClassification> {
+        std::mem::take(&mut self.needs_classification)
+    }
+
+    /// Sets whether a source transaction, none of whose postings match an
+    /// existing posting, is merged into an existing transaction with the same
+    /// date and description and postings with the same amounts. This catches
+    /// re-imports whose fingerprints have changed, e.g. after a change to the
+    /// rules that rename accounts.
+    pub fn with_description_matching(mut self, description_matching: bool) -> Self {
+        self.description_matching = description_matching;
+        self
+    }
+
+    /// Sets whether postings are matched by their match key or by their
+    /// values, in addition to by fingerprint. When disabled, a posting whose
+    /// fingerprints match no existing posting is always added as new.
+    pub fn with_soft_matching(mut self, soft_matching: bool) -> Self {
+        self.soft_matching = soft_matching;
+        self
+    }
+
+    /// Sets the maximum number of candidate tags added to a posting that
+    /// ambiguously matches existing postings. The best candidates are kept:
+    /// those in the same account, and then those whose transaction
+    /// descriptions share the most words with the posting's.
+    pub fn with_max_candidates(mut self, max_candidates: Option<usize>) -> Self {
+        self.max_candidates = max_candidates;
+        self
+    }
+
+    /// Sets whether cleared input postings are reconciled with pending
+    /// existing postings, as when the bank statement arrives for manually
+    /// entered transactions. When set, a cleared input posting that soft
+    /// matches several existing postings is matched with the only one that
+    /// is pending, if there is one. One that matches nothing is soft matched
+    /// against pending postings dated up to this many days either side of it.
+    pub fn with_reconcile_days(mut self, reconcile_days: Option<u32>) -> Self {
+        self.reconcile_days = reconcile_days;
+        self
+    }
+
+    /// Sets a script that decides what to do with postings that ambiguously
+    /// match existing postings, instead of the default behaviour described
+    /// by `hook::Ambiguity`.
+    pub fn with_decision_hook(mut self, decision_hook: Option<DecisionHook>) -> Self {
+        self.decision_hook = decision_hook;
+        self
+    }
+
+    /// Returns counts of how transactions have been handled by all calls to
+    /// `merge` so far.
+    pub fn stats(&self) -> MergeStats {
+        self.stats
+    }
+
+    /// Returns the sizes of the internal arenas and index maps, and counts
+    /// of how input postings have been matched by all calls to `merge` so
+    /// far.
+    pub fn internal_stats(&self) -> InternalStats {
+        let mut maps = vec![("transactions by date", self.trns.map_stats())];
+        maps.extend(self.posts.map_stats());
+        InternalStats {
+            transaction_arena: self.trns.arena_stats(),
+            posting_arena: self.posts.arena_stats(),
+            maps,
+            matches: self.match_counts.get(),
         }
     }
 
     /// This merging algorithm is described in README.md under "Matching
     /// algorithm".
     pub fn merge(&mut self, src_trns: Vec<TransactionPostings>) -> Result<UnmergedTransactions> {
+        // Reserve for the worst case of every transaction being new.
+        self.trns.reserve(src_trns.len());
+        self.posts
+            .reserve(src_trns.iter().map(|trn| trn.posts.len()).sum());
         let pending = self.make_pending(src_trns)?;
         self.check_pending(&pending)?;
         self.apply_pending(pending)
@@ -36,11 +252,12 @@ impl Merger {
         &self,
         orig_trns: Vec<TransactionPostings>,
     ) -> Result<Vec<TransactionMergeAction>> {
-        let mut pending = Vec::<TransactionMergeAction>::new();
+        let mut pending = Vec::<TransactionMergeAction>::with_capacity(orig_trns.len());
 
         // Set of fingerprints found in `pending.posts` so far.
         // This is used to check if duplicate fingerprints exist in the input.
-        let mut fingerprints_seen = HashSet::<String>::new();
+        let mut fingerprints_seen =
+            HashSet::<String>::with_capacity(orig_trns.iter().map(|trn| trn.posts.len()).sum());
 
         for orig_trn in orig_trns.into_iter() {
             let trn_action = self.to_transaction_merge_action(&mut fingerprints_seen, orig_trn)?;
@@ -120,7 +337,17 @@ impl Merger {
             use TransactionMergeAction::*;
 
             match trn_action {
-                New(pending_trn) => {
+                New(mut pending_trn) => {
+                    self.stats.new += 1;
+                    if self.tag_unclassified && is_unclassified(&pending_trn) {
+                        self.stats.needs_classification += 1;
+                        let trn = &mut pending_trn.src_trn.trn;
+                        trn.comment.tags.insert(tags::NEEDS_CLASSIFICATION.into());
+                        self.needs_classification.push(NeedsClassification {
+                            date: trn.raw.date,
+                            description: trn.raw.description.clone(),
+                        });
+                    }
                     let dest_trn = self.trns.add(pending_trn.src_trn);
                     self.apply_post_actions_to_trn(dest_trn, pending_trn.post_actions)?;
                 }
@@ -128,11 +355,19 @@ impl Merger {
                     pending_trn,
                     dest_trn,
                 } => {
-                    // `src_trn` currently unused.
-                    drop(pending_trn.src_trn);
+                    self.stats.merged += 1;
+                    self.trns.merge_into(dest_trn, pending_trn.src_trn);
+                    if pending_trn
+                        .post_actions
+                        .iter()
+                        .any(|(_, action)| matches!(action, PostingMergeAction::New))
+                    {
+                        self.trns.mark_posts_added(dest_trn);
+                    }
                     self.apply_post_actions_to_trn(dest_trn, pending_trn.post_actions)?;
                 }
                 LeaveUnmerged(trn) => {
+                    self.stats.unmerged += 1;
                     unmerged.push(trn);
                 }
             }
@@ -152,7 +387,14 @@ impl Merger {
                     self.trns.add_post_to_trn(dest_trn_idx, post_idx);
                 }
                 PostingMergeAction::MergeIntoExisting(dest_post_idx) => {
-                    self.posts.merge_into(dest_post_idx, post)?;
+                    let account_change = self.posts.merge_into(
+                        dest_post_idx,
+                        post,
+                        self.trust.status_policy(self.status_policy),
+                        self.trust.account_policy(self.account_policy),
+                        self.trust,
+                    )?;
+                    self.account_changes.extend(account_change);
                 }
             }
         }
@@ -173,13 +415,15 @@ impl Merger {
             return Ok(TransactionMergeAction::LeaveUnmerged(orig_trn_postings));
         }
 
+        // Statuses are merged per posting, and recombined in `build`.
+        let mut orig_trn_postings = orig_trn_postings;
+        status::push_down(&mut orig_trn_postings);
         let (orig_trn, orig_posts) = (orig_trn_postings.trn, orig_trn_postings.posts);
         let src_trn = transaction::Holder::from_transaction_internal(orig_trn);
 
-        let mut src_post_actions = MergeActionsAccumulator::new();
+        let mut src_posts = Vec::with_capacity(orig_posts.len());
         for orig_post in orig_posts.into_iter() {
-            let mut src_post =
-                posting::Input::from_posting_internal(orig_post, src_trn.trn.raw.date)?;
+            let src_post = posting::Input::from_posting_internal(orig_post, src_trn.trn.raw.date)?;
 
             for fp in src_post.iter_fingerprints().map(str::to_string) {
                 if fingerprints_seen.contains(&fp) {
@@ -187,8 +431,33 @@ impl Merger {
                 }
                 fingerprints_seen.insert(fp);
             }
+            src_posts.push(src_post);
+        }
 
-            let action = self.determine_posting_action(&mut src_post)?;
+        if let Some((dest_trn, dest_posts)) =
+            self.find_by_transaction_fingerprint(&src_trn, &src_posts)
+        {
+            self.count_matches(|counts| counts.transaction_fingerprint += src_posts.len());
+            let post_actions = src_posts
+                .into_iter()
+                .zip(dest_posts)
+                .map(|(src_post, dest_post)| {
+                    (src_post, PostingMergeAction::MergeIntoExisting(dest_post))
+                })
+                .collect();
+            return Ok(TransactionMergeAction::MergeInto {
+                pending_trn: PendingTransaction {
+                    src_trn,
+                    post_actions,
+                },
+                dest_trn,
+            });
+        }
+
+        let mut src_post_actions = MergeActionsAccumulator::new();
+        for mut src_post in src_posts {
+            let action =
+                self.determine_posting_action(&src_trn.trn.raw.description, &mut src_post)?;
             src_post_actions.push(src_post, action);
         }
 
@@ -204,10 +473,46 @@ impl Merger {
                     src_trn.into_transaction_postings(postings),
                 ))
             }
-            MergeActions::Actions(src_post_actions) => {
+            MergeActions::Actions(mut src_post_actions) => {
+                if self.description_matching
+                    && src_post_actions.iter().all(|(src_post, action)| {
+                        matches!(action, PostingMergeAction::New) && !src_post.merge_as_new()
+                    })
+                {
+                    let mut candidates = self.find_by_description(&src_trn, &src_post_actions);
+                    if candidates.len() == 1 {
+                        let dest_posts = candidates.remove(0);
+                        for ((_, action), dest_post) in src_post_actions.iter_mut().zip(dest_posts)
+                        {
+                            *action = PostingMergeAction::MergeIntoExisting(dest_post);
+                        }
+                    } else if candidates.len() > 1 {
+                        // Add candidate tags of the destinations to the
+                        // src postings, and let a human decide.
+                        let postings: Vec<PostingInternal> = src_post_actions
+                            .into_iter()
+                            .enumerate()
+                            .map(|(i, (mut src_post, _))| {
+                                self.add_candidate_tags(
+                                    &src_trn.trn.raw.description,
+                                    &mut src_post,
+                                    candidates.iter().map(|dest_posts| dest_posts[i]).collect(),
+                                );
+                                src_post.into_posting_internal()
+                            })
+                            .collect();
+                        return Ok(TransactionMergeAction::LeaveUnmerged(
+                            src_trn.into_transaction_postings(postings),
+                        ));
+                    }
+                }
+
                 // Determine default destination transaction.
                 let opt_dest_trn: Option<transaction::Index> =
                     self.find_existing_dest_trn(&src_trn, &src_post_actions)?;
+                if let Some(dest_trn) = opt_dest_trn {
+                    self.pair_new_postings(dest_trn, &mut src_post_actions);
+                }
 
                 let pending_trn = PendingTransaction {
                     src_trn,
@@ -227,18 +532,42 @@ impl Merger {
 
     fn determine_posting_action(
         &self,
+        src_description: &str,
         src_post: &mut posting::Input,
     ) -> Result<Option<PostingMergeAction>> {
         use posting::Match::*;
         use posting::MatchedIndices::*;
         use PostingMergeAction::*;
-        match self.posts.find_matching_postings(src_post) {
+        let mut found = self.posts.find_matching_postings(src_post);
+        if let Some(days) = self.reconcile_days {
+            if posting::has_status(&src_post.posting, TransactionStatus::Cleared) {
+                found = self.reconcile(src_post, days, found);
+            }
+        }
+        self.count_matches(|counts| match found {
+            Fingerprint(_) => counts.fingerprint += 1,
+            Soft(_) => counts.soft += 1,
+            Zero => counts.zero += 1,
+        });
+        match found {
             Fingerprint(m) => match m {
                 One(dest_idx) => {
                     // Unambiguous match by fingerprint.
                     Ok(Some(MergeIntoExisting(dest_idx)))
                 }
-                Many(matched_idxs) => {
+                Many(mut matched_idxs) => {
+                    self.rank_candidates(src_description, src_post, &mut matched_idxs);
+                    match self.decide(Ambiguity::Conflict, src_description, src_post, &matched_idxs)? {
+                        None => {}
+                        Some(Decision::Unmerge) => {
+                            self.add_candidate_tags(src_description, src_post, matched_idxs);
+                            return Ok(None);
+                        }
+                        Some(decision) => bail!(
+                            "decision hook: {:?} is not possible for a posting whose fingerprints match several postings",
+                            decision
+                        ),
+                    }
                     // Multiple destinations postings matched the
                     // fingerprint(s) of the input posting, this is a
                     // fatal merge error.
@@ -256,23 +585,34 @@ impl Merger {
                 }
             },
 
+            Soft(_) if !self.soft_matching || src_post.merge_as_new() => {
+                // Only fingerprint matches are wanted, so the soft match is
+                // ignored.
+                Ok(Some(New))
+            }
             Soft(m) => match m {
                 One(dest_idx) => {
                     // Unambiguous single soft match.
                     Ok(Some(MergeIntoExisting(dest_idx)))
                 }
-                Many(matched_idxs) => {
+                Many(mut matched_idxs) => {
+                    self.rank_candidates(src_description, src_post, &mut matched_idxs);
+                    match self.decide(
+                        Ambiguity::SoftMatch,
+                        src_description,
+                        src_post,
+                        &matched_idxs,
+                    )? {
+                        Some(Decision::Pick(i)) => {
+                            return Ok(Some(MergeIntoExisting(matched_idxs[i])))
+                        }
+                        Some(Decision::New) => return Ok(Some(New)),
+                        Some(Decision::Unmerge) | None => {}
+                    }
                     // Add candidate tags of the destinations to the
                     // single src_post and mark the entire transaction
                     // as unmerged.
-                    for idx in matched_idxs.into_iter() {
-                        let candidate_dest_post = self.posts.get(idx);
-                        src_post.add_tag(format!(
-                            "{}{}",
-                            tags::CANDIDATE_FP_PREFIX,
-                            candidate_dest_post.primary_fingerprint()
-                        ));
-                    }
+                    self.add_candidate_tags(src_description, src_post, matched_idxs);
                     // No clear matched posting, let a human decide what action
                     // to take.
                     Ok(None)
@@ -286,6 +626,284 @@ impl Merger {
         }
     }
 
+    fn count_matches<F: FnOnce(&mut MatchCounts)>(&self, f: F) {
+        let mut counts = self.match_counts.get();
+        f(&mut counts);
+        self.match_counts.set(counts);
+    }
+
+    /// Prefers pending existing postings as matches for a cleared input
+    /// posting, as described by `with_reconcile_days`.
+    fn reconcile(
+        &self,
+        src_post: &posting::Input,
+        days: u32,
+        found: posting::Match,
+    ) -> posting::Match {
+        use posting::Match::*;
+        use posting::MatchedIndices::*;
+        match found {
+            Soft(Many(idxs)) => {
+                let pending: Vec<posting::Index> = idxs
+                    .iter()
+                    .copied()
+                    .filter(|idx| {
+                        posting::has_status(
+                            &self.posts.get(*idx).posting,
+                            TransactionStatus::Pending,
+                        )
+                    })
+                    .collect();
+                match pending[..] {
+                    [idx] => Soft(One(idx)),
+                    _ => Soft(Many(idxs)),
+                }
+            }
+            Zero => match self.posts.find_pending_postings(src_post, days) {
+                MatchSet::Zero => Zero,
+                MatchSet::One(idx) => Soft(One(idx)),
+                MatchSet::Many(idxs) => Soft(Many(idxs.into_iter().collect())),
+            },
+            found => found,
+        }
+    }
+
+    /// Asks the decision hook, if any, what to do with `src_post` given the
+    /// existing postings that it matches.
+    fn decide(
+        &self,
+        ambiguity: Ambiguity,
+        src_description: &str,
+        src_post: &posting::Input,
+        candidates: &[posting::Index],
+    ) -> Result<Option<Decision>> {
+        let hook = match &self.decision_hook {
+            Some(hook) => hook,
+            None => return Ok(None),
+        };
+        let candidates: Vec<HookPosting> = candidates
+            .iter()
+            .map(|idx| {
+                let dest_post = self.posts.get(*idx);
+                let dest_trn = &self.trns.get(dest_post.get_parent_trn()).trn.raw;
+                HookPosting {
+                    date: dest_post.posting.date(dest_trn.date),
+                    description: &dest_trn.description,
+                    posting: &dest_post.posting,
+                }
+            })
+            .collect();
+        let src = HookPosting {
+            date: src_post.date(),
+            description: src_description,
+            posting: &src_post.posting,
+        };
+        hook.decide(ambiguity, &src, &candidates)
+    }
+
+    /// Orders the existing postings that `src_post` ambiguously matches, best
+    /// first: those in the same account, and then those whose transaction
+    /// descriptions share the most words with the posting's.
+    fn rank_candidates(
+        &self,
+        src_description: &str,
+        src_post: &posting::Input,
+        candidates: &mut [posting::Index],
+    ) {
+        let src_words = description_words(src_description);
+        candidates.sort_by_cached_key(|idx| {
+            let dest_post = self.posts.get(*idx);
+            let dest_trn = &self.trns.get(dest_post.get_parent_trn()).trn.raw;
+            let shared_words = description_words(&dest_trn.description)
+                .intersection(&src_words)
+                .count();
+            (
+                dest_post.posting.raw.account != src_post.posting.raw.account,
+                Reverse(shared_words),
+                dest_post.primary_fingerprint().to_string(),
+            )
+        });
+    }
+
+    /// Adds candidate tags for the existing postings that `src_post`
+    /// ambiguously matches, best first and up to `max_candidates` of them,
+    /// along with a value tag describing the best candidate to help a human
+    /// decide.
+    fn add_candidate_tags(
+        &self,
+        src_description: &str,
+        src_post: &mut posting::Input,
+        mut candidates: Vec<posting::Index>,
+    ) {
+        self.rank_candidates(src_description, src_post, &mut candidates);
+        let total = candidates.len();
+        if let Some(max_candidates) = self.max_candidates {
+            candidates.truncate(max_candidates);
+        }
+
+        for idx in &candidates {
+            src_post.add_tag(format!(
+                "{}{}",
+                tags::CANDIDATE_FP_PREFIX,
+                self.posts.get(*idx).primary_fingerprint()
+            ));
+        }
+        if let Some(best) = candidates.first() {
+            let dest_post = self.posts.get(*best);
+            let dest_trn = &self.trns.get(dest_post.get_parent_trn()).trn.raw;
+            let mut hint = format!("{} {}", dest_trn.date, dest_trn.description);
+            if let Some(amount) = &dest_post.posting.raw.amount {
+                hint.push_str(&format!(" {}", amount.amount));
+            }
+            hint.push_str(&format!(" (1 of {})", total));
+            src_post
+                .posting
+                .comment
+                .replace_value_tag(tags::CANDIDATE_HINT, hint);
+        }
+    }
+
+    /// Finds existing transactions with the same date and description as
+    /// `src_trn`, whose postings pair up one-to-one with `src_posts` by amount.
+    /// Returns the paired destination postings for each such transaction, in
+    /// the order of `src_posts`.
+    fn find_by_description(
+        &self,
+        src_trn: &transaction::Holder,
+        src_posts: &[(posting::Input, PostingMergeAction)],
+    ) -> Vec<Vec<posting::Index>> {
+        let src_raw = &src_trn.trn.raw;
+        self.trns
+            .date_to_indices(src_raw.date)
+            .map(|trn_idx| self.trns.get(trn_idx))
+            .filter(|dest_trn| dest_trn.trn.raw.description == src_raw.description)
+            .filter_map(|dest_trn| {
+                self.pair_postings(
+                    dest_trn,
+                    src_posts.iter().map(|(src_post, _)| src_post),
+                    false,
+                )
+            })
+            .collect()
+    }
+
+    /// Finds the single existing transaction on the same date that shares a
+    /// transaction fingerprint with `src_trn`, along with its postings paired
+    /// with `src_posts` by their accounts and amounts.
+    /// Returns `None` if there is no such transaction or more than one, or if
+    /// any of `src_posts` matches an existing posting by fingerprint, in
+    /// which case the postings are matched individually as usual.
+    fn find_by_transaction_fingerprint(
+        &self,
+        src_trn: &transaction::Holder,
+        src_posts: &[posting::Input],
+    ) -> Option<(transaction::Index, Vec<posting::Index>)> {
+        let src_fps: HashSet<&str> = src_trn
+            .trn
+            .comment
+            .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+            .collect();
+        if src_fps.is_empty()
+            || src_posts.iter().any(|src_post| {
+                matches!(
+                    self.posts.find_matching_postings(src_post),
+                    posting::Match::Fingerprint(_)
+                )
+            })
+        {
+            return None;
+        }
+        let mut candidates = self
+            .trns
+            .date_to_indices(src_trn.trn.raw.date)
+            .filter(|trn_idx| {
+                self.trns
+                    .get(*trn_idx)
+                    .trn
+                    .comment
+                    .tags_with_prefix(tags::TRANSACTION_FINGERPRINT_PREFIX)
+                    .any(|fp| src_fps.contains(fp))
+            })
+            .filter_map(|trn_idx| {
+                let dest_posts =
+                    self.pair_postings(self.trns.get(trn_idx), src_posts.iter(), true)?;
+                Some((trn_idx, dest_posts))
+            });
+        match (candidates.next(), candidates.next()) {
+            (Some(candidate), None) => Some(candidate),
+            _ => None,
+        }
+    }
+
+    /// Pairs each of `src_posts` with a distinct posting of `dest_trn` with
+    /// the same amount and reality, and the same account if `match_accounts`.
+    /// Returns `None` if they cannot all be paired.
+    fn pair_postings<'a, I>(
+        &self,
+        dest_trn: &transaction::Holder,
+        src_posts: I,
+        match_accounts: bool,
+    ) -> Option<Vec<posting::Index>>
+    where
+        I: ExactSizeIterator<Item = &'a posting::Input>,
+    {
+        let mut unpaired: Vec<posting::Index> = dest_trn.iter_posting_indices().collect();
+        if unpaired.len() != src_posts.len() {
+            return None;
+        }
+        src_posts
+            .map(|src_post| {
+                let src_raw = &src_post.posting.raw;
+                let i = unpaired.iter().position(|dest_idx| {
+                    let dest_raw = &self.posts.get(*dest_idx).posting.raw;
+                    posting::posting_amounts_equal(&dest_raw.amount, &src_raw.amount)
+                        && dest_raw.reality == src_raw.reality
+                        && (!match_accounts || dest_raw.account == src_raw.account)
+                })?;
+                Some(unpaired.remove(i))
+            })
+            .collect()
+    }
+
+    /// Pairs source postings that matched nothing with the remaining postings
+    /// of the destination transaction that their siblings matched, whatever
+    /// their dates, so that a transaction matched by only some of its
+    /// postings (e.g. by fingerprint or match key) does not gain duplicates of
+    /// the others. This applies even without soft matching, as the
+    /// destination transaction is already known. Postings that cannot be
+    /// paired are left to be added as new.
+    fn pair_new_postings(
+        &self,
+        dest_trn: transaction::Index,
+        src_post_actions: &mut [(posting::Input, PostingMergeAction)],
+    ) {
+        use PostingMergeAction::*;
+        let claimed: Vec<posting::Index> = src_post_actions
+            .iter()
+            .filter_map(|(_, action)| match action {
+                MergeIntoExisting(dest_idx) => Some(*dest_idx),
+                New => None,
+            })
+            .collect();
+        let mut unclaimed: Vec<posting::Index> = self
+            .trns
+            .get(dest_trn)
+            .iter_posting_indices()
+            .filter(|dest_idx| !claimed.contains(dest_idx))
+            .collect();
+        for (src_post, action) in src_post_actions.iter_mut() {
+            if !matches!(action, New) || src_post.merge_as_new() {
+                continue;
+            }
+            if let MatchSet::One(dest_idx) =
+                self.posts.find_among(src_post, unclaimed.iter().copied())
+            {
+                unclaimed.retain(|idx| *idx != dest_idx);
+                *action = MergeIntoExisting(dest_idx);
+            }
+        }
+    }
+
     /// Gethers the existing transactions that are the parents of the
     /// `src_posts_matched`. Returns None if `src_posts_matched` contains no
     /// postings. Returns an error if multiple transactions are parents of the
@@ -334,11 +952,19 @@ impl Merger {
 
         let mut out = Vec::<TransactionPostings>::new();
         for trn_holder in self.trns.into_iter() {
-            let posts = trn_holder
+            let mut posts: Vec<PostingInternal> = trn_holder
                 .iter_posting_indices()
                 .map(|post_idx| posts.take(post_idx))
                 .collect();
-            let trn = trn_holder.into_transaction_postings(posts);
+            // Only transactions that postings were merged into are sorted,
+            // so that others keep the order that they were written in. The
+            // sort is stable, so that postings otherwise keep the order that
+            // they were added in.
+            if trn_holder.posts_added() {
+                posts.sort_by_key(posting_order);
+            }
+            let mut trn = trn_holder.into_transaction_postings(posts);
+            status::normalize(&mut trn);
             out.push(trn);
         }
 
@@ -346,6 +972,36 @@ impl Merger {
     }
 }
 
+/// Returns true if all the postings of a transaction have unknown accounts.
+fn is_unclassified(pending_trn: &PendingTransaction) -> bool {
+    pending_trn
+        .post_actions
+        .iter()
+        .all(|(post, _)| post.posting.comment.tags.contains(tags::UNKNOWN_ACCOUNT))
+}
+
+/// Returns the lowercased words of a transaction description.
+fn description_words(description: &str) -> HashSet<String> {
+    description
+        .split_whitespace()
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Orders postings that an importer marked as being of the imported account
+/// before other postings, and those marked as being of the peer account after
+/// other postings.
+fn posting_order(post: &PostingInternal) -> u8 {
+    let tags = &post.comment.tags;
+    if tags.contains(tags::IMPORT_SELF) {
+        0
+    } else if tags.contains(tags::IMPORT_PEER) {
+        2
+    } else {
+        1
+    }
+}
+
 /// Accumulates pairs of `posting::Input` and the chosen `PostingMergeAction`
 /// for it, until a `None` is added, at which point it throws away the
 /// current and future `PostingMergeAction`s.
@@ -545,6 +1201,36 @@ mod tests {
         "#;
         "stable_sorts_by_date"
     )]
+    #[test_case(
+        r#"
+            2000/01/01 Evening
+                ; time: 18:00:00
+                assets:paypal    GBP -5.00    ; :fp-1:
+            2000/01/01 Manual
+                assets:checking  GBP -1.00    ; :fp-2:
+            2000/01/01 Morning
+                ; time: 09:30:00
+                assets:paypal    GBP -3.00    ; :fp-3:
+            2000/01/01 Noon
+                ; time: 12:00
+                assets:paypal    GBP -4.00    ; :fp-4:
+        "#,
+        // Transactions without a time keep their place.
+        r#"
+            2000/01/01 Morning
+                ; time: 09:30:00
+                assets:paypal    GBP -3.00    ; :fp-3:
+            2000/01/01 Manual
+                assets:checking  GBP -1.00    ; :fp-2:
+            2000/01/01 Noon
+                ; time: 12:00
+                assets:paypal    GBP -4.00    ; :fp-4:
+            2000/01/01 Evening
+                ; time: 18:00:00
+                assets:paypal    GBP -5.00    ; :fp-1:
+        "#;
+        "sorts_by_time_within_date"
+    )]
     #[test_case(
         // Postings from a call to merge should not match earlier postings from the
         // same call to merge.
@@ -605,6 +1291,128 @@ mod tests {
         "#;
         "soft_matches_existing"
     )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; [2000/01/03] :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        // The statement dates the payment on the date that it cleared, which
+        // only the posting has.
+        r#"
+            2000/01/03 Shop
+                assets:checking  GBP -10.00  ; :fp-3:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; [2000/01/03] :fp-1:fp-3:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#;
+        "soft_matches_posting_date"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  100.00 GBP   ; :fp-3:
+                income:salary    -100.00 GBP  ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:fp-3:
+                income:salary    GBP -100.00  ; :fp-2:fp-4:
+        "#;
+        "soft_matches_commodity_position"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                ; match-key: REF1
+                income:salary    GBP -100.00  ; :fp-2:
+        "#,
+        // Different date, but the same match key.
+        r#"
+            2000/01/03 Salary
+                assets:checking  GBP 100.00   ; :fp-3:
+                ; match-key: REF1
+                income:salary    GBP -100.00  ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:fp-3:
+                ; match-key: REF1
+                income:salary    GBP -100.00  ; :fp-2:fp-4:
+        "#;
+        "match_key_matches_across_dates"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+        "#,
+        r#"
+            2000/01/01 Salary
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+                assets:checking  GBP 100.00   ; :fp-1:import-self:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:import-self:
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+        "#;
+        "self_postings_ordered_before_peer_postings"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+                assets:checking  GBP 100.00   ; :fp-1:import-self:
+        "#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:import-self:
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                income:salary    GBP -100.00  ; :fp-2:import-peer:
+                assets:checking  GBP 100.00   ; :fp-1:import-self:
+        "#;
+        "posting_order_kept_without_new_postings"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                ; match-key: REF1
+        "#,
+        // Would otherwise soft match, but has a different match key.
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-2:
+                ; match-key: REF2
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                ; match-key: REF1
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-2:
+                ; match-key: REF2
+        "#;
+        "different_match_keys_do_not_soft_match"
+    )]
     #[test_case(
         r#"
             2000/01/01 Salary
@@ -653,8 +1461,10 @@ mod tests {
             2000/01/01 Salary
                 income:salary    GBP-100.00   ; :fp-sal1:
                 assets:checking  GBP 100.00   ; :candidate-fp-orig1:candidate-fp-orig2:candidate-fp-orig3:fp-new1:
+                ; candidate-hint: 2000-01-01 Salary GBP100.00 (1 of 3)
             2000/01/01 Salary
                 assets:checking  GBP 100.00   ; :candidate-fp-orig1:candidate-fp-orig2:candidate-fp-orig3:fp-new2:
+                ; candidate-hint: 2000-01-01 Salary GBP100.00 (1 of 3)
                 income:salary    GBP-100.00   ; :fp-sal2:
         "#,
         // The original transactions should be unchanged.
@@ -706,9 +1516,733 @@ mod tests {
         "#;
         "does_not_overwrite_some_fields"
     )]
+    #[test_case(
+        r#"
+            2000/01/01 ! Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 * Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 * Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#;
+        "pending_transaction_cleared"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 * Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Shop
+                ! assets:checking  GBP -10.00  ; :fp-1:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 * Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#;
+        "cleared_transaction_not_downgraded"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                ; Existing comment.
+                ; statement: first
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/01=2000/01/02 (123) Shop
+                ; Existing comment.
+                ; New comment.
+                ; :imported:
+                ; statement: second
+                ; reference: REF1
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01=2000/01/02 (123) Shop
+                ; Existing comment.
+                ; New comment.
+                ; :imported:
+                ; reference: REF1
+                ; statement: first
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:shop    GBP 10.00   ; :fp-2:
+        "#;
+        "transaction_fields_merged_into_existing"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 (100) Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+        "#,
+        r#"
+            2000/01/01 (200) Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 (100) Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+        "#;
+        "existing_transaction_code_kept"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                ; :trn-fp-b:
+                assets:checking  GBP -10.00  ; :fp-3:
+                expenses:food    GBP 10.00   ; :fp-4:
+        "#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-b:
+                expenses:food    GBP 10.00   ; :fp-5:
+                assets:checking  GBP -10.00  ; :fp-6:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                ; :trn-fp-b:
+                assets:checking  GBP -10.00  ; :fp-3:fp-6:
+                expenses:food    GBP 10.00   ; :fp-4:fp-5:
+        "#;
+        "transaction_fingerprint_match"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -12.00  ; :fp-3:
+                expenses:food    GBP 12.00   ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -12.00  ; :fp-3:
+                expenses:food    GBP 12.00   ; :fp-4:
+        "#;
+        "transaction_fingerprint_with_different_amounts_not_merged"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 5.00    ; :fp-2:
+                expenses:drink   GBP 5.00    ; :fp-3:
+        "#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                expenses:drink   GBP 5.00    ; :fp-4:
+                expenses:food    GBP 5.00    ; :fp-5:
+                assets:checking  GBP -10.00  ; :fp-6:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                ; :trn-fp-a:
+                assets:checking  GBP -10.00  ; :fp-1:fp-6:
+                expenses:food    GBP 5.00    ; :fp-2:fp-5:
+                expenses:drink   GBP 5.00    ; :fp-3:fp-4:
+        "#;
+        "transaction_fingerprint_pairs_by_account"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+        "#,
+        // Would otherwise soft match, but its candidates were rejected.
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-3:merge-as-new:
+                expenses:food    GBP 10.00   ; :fp-4:merge-as-new:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-3:
+                expenses:food    GBP 10.00   ; :fp-4:
+        "#;
+        "merge_as_new_not_soft_matched"
+    )]
     fn merge_merge_build(first: &str, second: &str, want_unmerged_second: &str, want: &str) {
+        merge_merge_build_with(Merger::new(), first, second, want_unmerged_second, want);
+    }
+
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+        "#,
+        // Fingerprints and accounts changed after the rules were changed.
+        r#"
+            2000/01/01 Shop
+                assets:current      GBP -10.00  ; :fp-3:
+                expenses:groceries  GBP 10.00   ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:fp-3:
+                expenses:food    GBP 10.00   ; :fp-2:fp-4:
+        "#;
+        "merged_by_description"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Shop
+                assets:current   GBP -12.00  ; :fp-3:
+                expenses:food    GBP 12.00   ; :fp-4:
+            2000/01/01 Other shop
+                assets:current      GBP -10.00  ; :fp-5:
+                expenses:groceries  GBP 10.00   ; :fp-6:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                assets:current   GBP -12.00  ; :fp-3:
+                expenses:food    GBP 12.00   ; :fp-4:
+            2000/01/01 Other shop
+                assets:current      GBP -10.00  ; :fp-5:
+                expenses:groceries  GBP 10.00   ; :fp-6:
+        "#;
+        "different_amounts_or_descriptions_not_merged"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-3:
+                expenses:food    GBP 10.00   ; :fp-4:
+        "#,
+        r#"
+            2000/01/01 Shop
+                assets:current      GBP -10.00  ; :fp-5:
+                expenses:groceries  GBP 10.00   ; :fp-6:
+        "#,
+        r#"
+            2000/01/01 Shop
+                assets:current      GBP -10.00  ; :candidate-fp-1:candidate-fp-3:fp-5:
+                ; candidate-hint: 2000-01-01 Shop GBP-10.00 (1 of 2)
+                expenses:groceries  GBP 10.00   ; :candidate-fp-2:candidate-fp-4:fp-6:
+                ; candidate-hint: 2000-01-01 Shop GBP10.00 (1 of 2)
+        "#,
+        r#"
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-1:
+                expenses:food    GBP 10.00   ; :fp-2:
+            2000/01/01 Shop
+                assets:checking  GBP -10.00  ; :fp-3:
+                expenses:food    GBP 10.00   ; :fp-4:
+        "#;
+        "ambiguous_description_match_left_unmerged"
+    )]
+    fn merge_merge_build_description_matching(
+        first: &str,
+        second: &str,
+        want_unmerged_second: &str,
+        want: &str,
+    ) {
+        merge_merge_build_with(
+            Merger::new().with_description_matching(true),
+            first,
+            second,
+            want_unmerged_second,
+            want,
+        );
+    }
+
+    #[test_case(
+        r#"
+            2000/01/01 ! Coffee
+                assets:checking  GBP -3.00  ; :fp-1:
+                expenses:food    GBP 3.00   ; :fp-2:
+        "#,
+        r#"
+            2000/01/03 * COFFEE SHOP
+                assets:checking   GBP -3.00  ; :fp-3:
+                expenses:unknown  GBP 3.00   ; :fp-4:unknown-account:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 * Coffee
+                assets:checking  GBP -3.00  ; :fp-1:fp-3:
+                expenses:food    GBP 3.00   ; :fp-2:fp-4:
+        "#;
+        "cleared_reconciled_with_pending_on_nearby_date"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 ! Coffee
+                assets:checking  GBP -3.00  ; :fp-1:
+        "#,
+        r#"
+            2000/01/05 * COFFEE SHOP
+                assets:checking  GBP -3.00  ; :fp-3:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 ! Coffee
+                assets:checking  GBP -3.00  ; :fp-1:
+            2000/01/05 * COFFEE SHOP
+                assets:checking  GBP -3.00  ; :fp-3:
+        "#;
+        "pending_too_far_away_not_reconciled"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 * Coffee
+                assets:checking  GBP -3.00  ; :fp-1:
+            2000/01/01 ! Coffee
+                assets:checking  GBP -3.00  ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 * COFFEE SHOP
+                assets:checking  GBP -3.00  ; :fp-3:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 * Coffee
+                assets:checking  GBP -3.00  ; :fp-1:
+            2000/01/01 * Coffee
+                assets:checking  GBP -3.00  ; :fp-2:fp-3:
+        "#;
+        "pending_preferred_among_soft_matches"
+    )]
+    fn merge_merge_build_reconcile(
+        first: &str,
+        second: &str,
+        want_unmerged_second: &str,
+        want: &str,
+    ) {
+        merge_merge_build_with(
+            Merger::new().with_reconcile_days(Some(3)),
+            first,
+            second,
+            want_unmerged_second,
+            want,
+        );
+    }
+
+    #[test]
+    fn merge_merge_build_max_candidates() {
+        let existing = r#"
+            2000/01/01 Petrol station
+                assets:checking  GBP -10.00  ; :fp-1:
+            2000/01/01 Corner shop
+                assets:checking  GBP -10.00  ; :fp-2:
+            2000/01/01 Coffee shop
+                assets:checking  GBP -10.00  ; :fp-3:
+        "#;
+        // The candidates are ranked by how many words of their descriptions
+        // are shared, and only the best two are kept.
+        merge_merge_build_with(
+            Merger::new().with_max_candidates(Some(2)),
+            existing,
+            r#"
+            2000/01/01 CORNER SHOP LTD
+                assets:checking  GBP -10.00  ; :fp-new:
+            "#,
+            r#"
+            2000/01/01 CORNER SHOP LTD
+                assets:checking  GBP -10.00  ; :candidate-fp-2:candidate-fp-3:fp-new:
+                ; candidate-hint: 2000-01-01 Corner shop GBP-10.00 (1 of 3)
+            "#,
+            existing,
+        );
+    }
+
+    #[test_case(
+        r#"if posting.description == "NEW SHOP" { "new" }"#,
+        r#"
+            2000/01/01 NEW SHOP
+                assets:checking  GBP -10.00  ; :fp-new:
+        "#,
+        r#"
+            2000/01/01 Corner shop
+                assets:checking  GBP -10.00  ; :fp-2:
+            2000/01/01 Coffee shop
+                assets:checking  GBP -10.00  ; :fp-3:
+            2000/01/01 NEW SHOP
+                assets:checking  GBP -10.00  ; :fp-new:
+        "#;
+        "new"
+    )]
+    #[test_case(
+        // Candidates are ranked, so the last is the least similar.
+        r#"candidates.len() - 1"#,
+        r#"
+            2000/01/01 CORNER SHOP
+                assets:checking  GBP -10.00  ; :fp-new:
+        "#,
+        r#"
+            2000/01/01 Corner shop
+                assets:checking  GBP -10.00  ; :fp-2:
+            2000/01/01 Coffee shop
+                assets:checking  GBP -10.00  ; :fp-3:fp-new:
+        "#;
+        "pick"
+    )]
+    fn merge_merge_build_decision_hook(decide: &str, second: &str, want: &str) {
+        let hook = DecisionHook::from_script(
+            &format!("fn decide(kind, posting, candidates) {{ {} }}", decide),
+            &[],
+        )
+        .expect("from_script");
+        merge_merge_build_with(
+            Merger::new().with_decision_hook(Some(hook)),
+            r#"
+            2000/01/01 Corner shop
+                assets:checking  GBP -10.00  ; :fp-2:
+            2000/01/01 Coffee shop
+                assets:checking  GBP -10.00  ; :fp-3:
+            "#,
+            second,
+            "",
+            want,
+        );
+    }
+
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-3:
+                income:salary    GBP -100.00  ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-2:
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-3:
+                income:salary    GBP -100.00  ; :fp-4:
+        "#;
+        "would_soft_match_added_as_new"
+    )]
+    #[test_case(
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-2:
+        "#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-4:
+        "#,
+        r#""#,
+        r#"
+            2000/01/01 Salary
+                assets:checking  GBP 100.00   ; :fp-1:
+                income:salary    GBP -100.00  ; :fp-2:fp-4:
+        "#;
+        "fingerprint_match_still_merged"
+    )]
+    fn merge_merge_build_no_soft_matching(
+        first: &str,
+        second: &str,
+        want_unmerged_second: &str,
+        want: &str,
+    ) {
+        merge_merge_build_with(
+            Merger::new().with_soft_matching(false),
+            first,
+            second,
+            want_unmerged_second,
+            want,
+        );
+    }
+
+    #[test_case(AccountPolicy::Keep => "expenses:food"; "keep")]
+    #[test_case(AccountPolicy::Update => "expenses:groceries"; "update")]
+    fn account_changes(account_policy: AccountPolicy) -> String {
+        let mut merger = Merger::new().with_account_policy(account_policy);
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking  GBP -10.00  ; :fp-1:
+                    expenses:food    GBP 10.00   ; :fp-2:
+                "#,
+            ))
+            .unwrap();
+        assert!(merger.take_account_changes().is_empty());
+
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking     GBP -10.00  ; :fp-1:
+                    expenses:groceries  GBP 10.00   ; :fp-2:
+                "#,
+            ))
+            .unwrap();
+        assert_eq!(
+            merger.take_account_changes(),
+            vec![AccountChange {
+                date: chrono::NaiveDate::from_ymd_opt(2000, 1, 1).unwrap(),
+                existing: "expenses:food".to_string(),
+                input: "expenses:groceries".to_string(),
+                updated: account_policy == AccountPolicy::Update,
+            }]
+        );
+
+        let result = merger.build();
+        result[0].posts[1].raw.account.clone()
+    }
+
+    #[test_case(Trust::Normal => ("expenses:food".to_string(), Some("=GBP50.00".to_string())); "normal")]
+    #[test_case(Trust::Authoritative => ("expenses:groceries".to_string(), Some("=GBP40.00".to_string())); "authoritative")]
+    #[test_case(Trust::Secondary => ("expenses:food".to_string(), Some("=GBP50.00".to_string())); "secondary")]
+    fn trust(trust: Trust) -> (String, Option<String>) {
         let mut merger = Merger::new();
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking  GBP -10.00 = GBP 50.00  ; :fp-1:
+                    expenses:food    GBP 10.00               ; :fp-2:
+                "#,
+            ))
+            .unwrap();
+        merger.set_trust(trust);
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking     GBP -10.00 = GBP 40.00  ; :fp-1:
+                    expenses:groceries  GBP 10.00               ; :fp-2:
+                "#,
+            ))
+            .unwrap();
+
+        let result = merger.build();
+        (
+            result[0].posts[1].raw.account.clone(),
+            result[0].posts[0]
+                .raw
+                .balance
+                .as_ref()
+                .map(|balance| format!("={}", balance)),
+        )
+    }
+
+    #[test]
+    fn account_change_error() {
+        let mut merger = Merger::new().with_account_policy(AccountPolicy::Error);
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking  GBP -10.00  ; :fp-1:
+                    expenses:food    GBP 10.00   ; :fp-2:
+                "#,
+            ))
+            .unwrap();
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking     GBP -10.00  ; :fp-1:
+                    expenses:groceries  GBP 10.00   ; :fp-2:unknown-account:
+                "#,
+            ))
+            .expect("an unknown account is not a change");
+        merger
+            .merge(parse_transaction_postings(
+                r#"
+                2000/01/01 Shop
+                    assets:checking     GBP -10.00  ; :fp-1:
+                    expenses:groceries  GBP 10.00   ; :fp-2:
+                "#,
+            ))
+            .err()
+            .expect("should fail on the changed account");
+    }
+
+    #[test]
+    fn tags_new_unclassified_transactions() {
+        let mut merger = Merger::new();
+        merger
+            .merge(parse_transaction_postings(
+                "
+                2000/01/01 existing
+                    assets:bank  GBP-1.00  ; :fp-1:
+                    expenses:unknown  GBP1.00  ; :fp-2:unknown-account:",
+            ))
+            .unwrap();
+        assert!(merger.take_needs_classification().is_empty());
+
+        merger.set_tag_unclassified(true);
+        let unmerged = merger
+            .merge(parse_transaction_postings(
+                "
+                2000/01/01 existing
+                    assets:bank  GBP-1.00  ; :fp-1:
+                    expenses:unknown  GBP1.00  ; :fp-2:unknown-account:
+
+                2000/01/02 partly known
+                    assets:bank  GBP-2.00  ; :fp-3:
+                    expenses:unknown  GBP2.00  ; :fp-4:unknown-account:
+
+                2000/01/03 unknown
+                    assets:unknown  GBP-3.00  ; :fp-5:unknown-account:
+                    expenses:unknown  GBP3.00  ; :fp-6:unknown-account:",
+            ))
+            .unwrap();
+        assert!(unmerged.0.is_empty());
+        assert_eq!(
+            merger.take_needs_classification(),
+            vec![NeedsClassification {
+                date: NaiveDate::from_ymd_opt(2000, 1, 3).unwrap(),
+                description: "unknown".to_string(),
+            }]
+        );
+        assert_eq!(merger.stats().needs_classification, 1);
+
+        let result = merger.build();
+        assert_transaction_postings_eq!(
+            result,
+            parse_transaction_postings(
+                "
+                2000/01/01 existing
+                    assets:bank  GBP-1.00  ; :fp-1:
+                    expenses:unknown  GBP1.00  ; :fp-2:unknown-account:
+
+                2000/01/02 partly known
+                    assets:bank  GBP-2.00  ; :fp-3:
+                    expenses:unknown  GBP2.00  ; :fp-4:unknown-account:
+
+                2000/01/03 unknown
+                    ; :needs-classification:
+                    assets:unknown  GBP-3.00  ; :fp-5:unknown-account:
+                    expenses:unknown  GBP3.00  ; :fp-6:unknown-account:"
+            )
+        );
+    }
+
+    #[test]
+    fn internal_stats() {
+        let mut merger = Merger::new();
+        merger
+            .merge(parse_transaction_postings(
+                "
+                2000/01/01 Shop
+                    ; :trn-fp-a:
+                    assets:checking  GBP-10.00  ; :fp-1:
+                    expenses:food  GBP10.00  ; :fp-2:
+
+                2000/01/02 Cafe
+                    assets:checking  GBP-3.00  ; :fp-3:
+                    expenses:food  GBP3.00  ; :fp-4:",
+            ))
+            .unwrap();
+        merger
+            .merge(parse_transaction_postings(
+                "
+                2000/01/01 Shop
+                    ; :trn-fp-a:
+                    assets:checking  GBP-10.00  ; :fp-5:
+                    expenses:food  GBP10.00  ; :fp-6:
+
+                2000/01/02 Cafe
+                    assets:checking  GBP-3.00  ; :fp-3:
+                    expenses:food  GBP3.00  ; :fp-7:
+
+                2000/01/03 Bus
+                    assets:checking  GBP-2.00  ; :fp-8:",
+            ))
+            .unwrap();
+
+        let stats = merger.internal_stats();
+        assert_eq!(
+            stats.matches,
+            MatchCounts {
+                fingerprint: 1,
+                soft: 1,
+                zero: 5,
+                transaction_fingerprint: 2,
+            }
+        );
+        assert_eq!(stats.transaction_arena.len, 3);
+        assert_eq!(stats.posting_arena.len, 5);
+        assert!(stats.posting_arena.capacity >= 5);
+        let map = |name: &str| {
+            stats
+                .maps
+                .iter()
+                .find(|(map_name, _)| *map_name == name)
+                .map(|(_, map)| (map.keys, map.indices))
+                .unwrap()
+        };
+        assert_eq!(map("transactions by date"), (3, 3));
+        assert_eq!(map("postings by date"), (3, 5));
+        assert_eq!(map("postings by fingerprint"), (8, 8));
+    }
 
+    fn merge_merge_build_with(
+        mut merger: Merger,
+        first: &str,
+        second: &str,
+        want_unmerged_second: &str,
+        want: &str,
+    ) {
         let unmerged_first = merger.merge(parse_transaction_postings(first)).unwrap();
         assert!(unmerged_first.0.is_empty());
 
@@ -1,6 +1,17 @@
+pub mod account;
+mod checkpoint;
 pub mod cmd;
+pub mod commodity;
+mod fpindex;
+pub mod hook;
 mod matchset;
-mod merger;
+pub mod merger;
+mod namespaces;
+mod periods;
 mod posting;
-mod sources;
+mod routing;
+pub mod sources;
+pub mod stats;
+pub mod status;
 mod transaction;
+pub mod trust;
@@ -0,0 +1,191 @@
+//! Detects sources whose fingerprints share an algorithm and namespace, but
+//! which have no fingerprints in common over the dates that they overlap.
+//! This is the symptom of importing statements with inconsistent fingerprint
+//! options, such as two accounts given the same `--fingerprint-namespace`,
+//! and would otherwise go unnoticed until duplicates appear.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use chrono::NaiveDate;
+
+use crate::fingerprint;
+use crate::internal::TransactionPostings;
+use crate::merge::sources;
+
+/// Fingerprint algorithm name and user namespace.
+type Namespace = (String, String);
+
+/// Fingerprint tags of a source within one namespace, by date.
+type DatedFingerprints = BTreeMap<NaiveDate, HashSet<String>>;
+
+fn namespace_fingerprints<'a>(
+    tags: impl IntoIterator<Item = (NaiveDate, &'a str)>,
+) -> HashMap<Namespace, DatedFingerprints> {
+    let mut namespaces = HashMap::<Namespace, DatedFingerprints>::new();
+    for (date, tag) in tags {
+        let parts = match fingerprint::parse_tag(tag) {
+            Some(parts) => parts,
+            None => continue,
+        };
+        namespaces
+            .entry((
+                parts.algorithm_name.to_string(),
+                parts.user_namespace.to_string(),
+            ))
+            .or_default()
+            .entry(date)
+            .or_default()
+            .insert(tag.to_string());
+    }
+    namespaces
+}
+
+/// Returns the tags of the postings of the transactions, with the dates of
+/// the postings.
+fn dated_tags(trns: &[TransactionPostings]) -> impl Iterator<Item = (NaiveDate, &str)> {
+    trns.iter().flat_map(|trn| {
+        trn.posts.iter().flat_map(|post| {
+            let date = post.date(trn.trn.raw.date);
+            post.comment
+                .tags
+                .iter()
+                .map(move |tag| (date, tag.as_str()))
+        })
+    })
+}
+
+/// Returns the dates over which `a` and `b` overlap, if both have
+/// fingerprints between them but none in common.
+fn clash(a: &DatedFingerprints, b: &DatedFingerprints) -> Option<(NaiveDate, NaiveDate)> {
+    let (a_start, a_end) = (*a.keys().next()?, *a.keys().next_back()?);
+    let (b_start, b_end) = (*b.keys().next()?, *b.keys().next_back()?);
+    let (start, end) = (a_start.max(b_start), a_end.min(b_end));
+    if start > end {
+        return None;
+    }
+    let within = |fps: &DatedFingerprints| -> HashSet<String> {
+        fps.range(start..=end)
+            .flat_map(|(_, tags)| tags.iter().cloned())
+            .collect()
+    };
+    let (a_within, b_within) = (within(a), within(b));
+    if a_within.is_empty() || b_within.is_empty() || !a_within.is_disjoint(&b_within) {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Accumulates the fingerprints of sources as they are merged, and reports
+/// where a new source clashes with one already seen in the same namespace.
+#[derive(Default)]
+pub struct NamespaceChecker {
+    /// Sources by namespace, with their fingerprints.
+    seen: HashMap<Namespace, Vec<(String, DatedFingerprints)>>,
+}
+
+impl NamespaceChecker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Checks the source transactions against those previously added,
+    /// returning a description of each clash, and then records the source's
+    /// fingerprints.
+    pub fn check_and_add(&mut self, trns: &[TransactionPostings]) -> Vec<String> {
+        self.check_and_add_tags(sources::source_name(trns), dated_tags(trns))
+    }
+
+    /// As `check_and_add`, for the source named `source` with the given
+    /// posting tags and their dates.
+    pub fn check_and_add_tags<'a>(
+        &mut self,
+        source: &str,
+        tags: impl IntoIterator<Item = (NaiveDate, &'a str)>,
+    ) -> Vec<String> {
+        let mut clashes = Vec::new();
+        for (ns, fps) in namespace_fingerprints(tags) {
+            let seen = self.seen.entry(ns.clone()).or_default();
+            for (seen_source, seen_fps) in seen.iter() {
+                if seen_source == source {
+                    continue;
+                }
+                if let Some((start, end)) = clash(&fps, seen_fps) {
+                    clashes.push(format!(
+                        "{} and {} both have transactions from {} to {} with fingerprints of algorithm {:?} in namespace {:?}, but none of their fingerprints match; check the --fingerprint-namespace that each was imported with",
+                        source, seen_source, start, end, ns.0, ns.1,
+                    ));
+                }
+            }
+            seen.push((source.to_string(), fps));
+        }
+        clashes.sort();
+        clashes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    fn trns(source: &str, ns: &str, posts: &[(&str, &str)]) -> Vec<TransactionPostings> {
+        let text: String = posts
+            .iter()
+            .map(|(date, fp)| {
+                format!(
+                    "{} description\n    ; source-file: {}\n    assets:bank  GBP10.00 ; :fp-nwcsv6.1.{}-{}:\n    expenses:unknown\n\n",
+                    date, source, ns, fp,
+                )
+            })
+            .collect();
+        parse_transaction_postings(&text)
+    }
+
+    #[test]
+    fn no_clash_for_overlapping_statements() {
+        let mut checker = NamespaceChecker::new();
+        assert!(checker
+            .check_and_add(&trns(
+                "a.csv",
+                "ns1",
+                &[("2000/01/01", "1"), ("2000/01/20", "2")]
+            ))
+            .is_empty());
+        assert!(checker
+            .check_and_add(&trns(
+                "b.csv",
+                "ns1",
+                &[("2000/01/20", "2"), ("2000/02/10", "3")]
+            ))
+            .is_empty());
+        // Neither are sources with disjoint dates, or in other namespaces.
+        assert!(checker
+            .check_and_add(&trns("c.csv", "ns1", &[("2000/03/01", "4")]))
+            .is_empty());
+        assert!(checker
+            .check_and_add(&trns("d.csv", "ns2", &[("2000/01/10", "5")]))
+            .is_empty());
+    }
+
+    #[test]
+    fn clash_for_disjoint_fingerprints() {
+        let mut checker = NamespaceChecker::new();
+        checker.check_and_add(&trns(
+            "a.csv",
+            "ns1",
+            &[("2000/01/01", "1"), ("2000/01/20", "2")],
+        ));
+        let clashes = checker.check_and_add(&trns(
+            "b.csv",
+            "ns1",
+            &[("2000/01/10", "3"), ("2000/02/10", "4")],
+        ));
+        assert_eq!(clashes.len(), 1, "{:?}", clashes);
+        assert!(
+            clashes[0]
+                .contains("b.csv and a.csv both have transactions from 2000-01-10 to 2000-01-20"),
+            "{:?}",
+            clashes
+        );
+    }
+}
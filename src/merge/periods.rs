@@ -0,0 +1,223 @@
+//! Detects gaps and overlaps between the date ranges of merged sources, per
+//! account.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use chrono::NaiveDate;
+
+use crate::fingerprint;
+use crate::internal::TransactionPostings;
+use crate::merge::sources;
+use crate::tags;
+
+/// Inclusive range of dates.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct DateRange {
+    start: NaiveDate,
+    end: NaiveDate,
+}
+
+impl DateRange {
+    fn new(date: NaiveDate) -> Self {
+        Self {
+            start: date,
+            end: date,
+        }
+    }
+
+    fn extend(&mut self, date: NaiveDate) {
+        self.start = self.start.min(date);
+        self.end = self.end.max(date);
+    }
+
+    fn union(&mut self, other: &DateRange) {
+        self.extend(other.start);
+        self.extend(other.end);
+    }
+
+    fn overlaps(&self, other: &DateRange) -> bool {
+        self.start <= other.end && other.start <= self.end
+    }
+}
+
+impl fmt::Display for DateRange {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} to {}", self.start, self.end)
+    }
+}
+
+/// Date ranges keyed by `account:` tag value, then by fingerprint user
+/// namespace.
+type AccountRanges = HashMap<String, HashMap<String, DateRange>>;
+
+fn account_ranges(trns: &[TransactionPostings]) -> AccountRanges {
+    let mut ranges = AccountRanges::new();
+    for trn in trns {
+        let date = trn.trn.raw.date;
+        for post in &trn.posts {
+            let account = match post.comment.value_tags.get(tags::ACCOUNT) {
+                Some(account) => account,
+                None => continue,
+            };
+            for ns in post
+                .comment
+                .tags
+                .iter()
+                .filter_map(|tag| fingerprint::user_namespace(tag))
+            {
+                ranges
+                    .entry(account.clone())
+                    .or_default()
+                    .entry(ns.to_string())
+                    .and_modify(|range| range.extend(date))
+                    .or_insert_with(|| DateRange::new(date));
+            }
+        }
+    }
+    ranges
+}
+
+/// Accumulates the date ranges of sources as they are merged, and reports
+/// where a new source leaves a gap against, or overlaps under a different
+/// fingerprint namespace with, those already seen for the same account.
+pub struct PeriodChecker {
+    seen: AccountRanges,
+    max_gap_days: i64,
+}
+
+impl PeriodChecker {
+    pub fn new(max_gap_days: i64) -> Self {
+        Self {
+            seen: AccountRanges::new(),
+            max_gap_days,
+        }
+    }
+
+    /// Checks the source transactions against those previously added,
+    /// returning any warnings, and then records the source's date ranges.
+    pub fn check_and_add(&mut self, trns: &[TransactionPostings]) -> Vec<String> {
+        let source = sources::source_name(trns);
+
+        let mut warnings = Vec::new();
+        for (account, src_ranges) in account_ranges(trns) {
+            if let Some(seen_ranges) = self.seen.get(&account) {
+                warnings.extend(self.check_account(source, &account, &src_ranges, seen_ranges));
+            }
+            let seen_ranges = self.seen.entry(account).or_default();
+            for (ns, range) in src_ranges {
+                seen_ranges
+                    .entry(ns)
+                    .and_modify(|seen| seen.union(&range))
+                    .or_insert(range);
+            }
+        }
+        warnings.sort();
+        warnings
+    }
+
+    fn check_account(
+        &self,
+        source: &str,
+        account: &str,
+        src_ranges: &HashMap<String, DateRange>,
+        seen_ranges: &HashMap<String, DateRange>,
+    ) -> Vec<String> {
+        let mut warnings = Vec::new();
+
+        let mut src_all: Option<DateRange> = None;
+        for (ns, src_range) in src_ranges {
+            src_all.get_or_insert(*src_range).union(src_range);
+            for (seen_ns, seen_range) in seen_ranges {
+                if seen_ns != ns && src_range.overlaps(seen_range) {
+                    warnings.push(format!(
+                        "account {:?}: {} covers {} under fingerprint namespace {:?}, which overlaps {} already merged under namespace {:?}",
+                        account, source, src_range, ns, seen_range, seen_ns,
+                    ));
+                }
+            }
+        }
+
+        let mut seen_all: Option<DateRange> = None;
+        for seen_range in seen_ranges.values() {
+            seen_all.get_or_insert(*seen_range).union(seen_range);
+        }
+
+        if let (Some(src_all), Some(seen_all)) = (src_all, seen_all) {
+            let gap = if src_all.start > seen_all.end {
+                Some((seen_all.end, src_all.start))
+            } else if src_all.end < seen_all.start {
+                Some((src_all.end, seen_all.start))
+            } else {
+                None
+            };
+            if let Some((from, to)) = gap {
+                let days = (to - from).num_days();
+                if days > self.max_gap_days {
+                    warnings.push(format!(
+                        "account {:?}: {} leaves a gap of {} days with no transactions between {} and {}",
+                        account, source, days, from, to,
+                    ));
+                }
+            }
+        }
+
+        warnings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    fn trns(ns: &str, account: &str, dates: &[&str]) -> Vec<TransactionPostings> {
+        let text: String = dates
+            .iter()
+            .enumerate()
+            .map(|(i, date)| {
+                format!(
+                    "{} description ; source-file: {}\n    assets:bank  GBP10.00 ; account: {}\n    ; :fp-nwcsv6.1.{}-{}:\n    expenses:unknown\n\n",
+                    date, ns, account, ns, i,
+                )
+            })
+            .collect();
+        parse_transaction_postings(&text)
+    }
+
+    #[test]
+    fn no_warnings_for_contiguous_sources() {
+        let mut checker = PeriodChecker::new(31);
+        assert!(checker
+            .check_and_add(&trns("ns1", "acct", &["2000/01/01", "2000/01/31"]))
+            .is_empty());
+        assert!(checker
+            .check_and_add(&trns("ns1", "acct", &["2000/02/10", "2000/02/28"]))
+            .is_empty());
+        // A different account is checked independently.
+        assert!(checker
+            .check_and_add(&trns("ns2", "other", &["2001/01/01"]))
+            .is_empty());
+    }
+
+    #[test]
+    fn warns_on_gap() {
+        let mut checker = PeriodChecker::new(31);
+        checker.check_and_add(&trns("ns1", "acct", &["2000/01/01", "2000/01/31"]));
+        let warnings = checker.check_and_add(&trns("ns1", "acct", &["2000/04/01"]));
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("gap of 61 days"), "{:?}", warnings);
+    }
+
+    #[test]
+    fn warns_on_overlap_with_other_namespace() {
+        let mut checker = PeriodChecker::new(31);
+        checker.check_and_add(&trns("ns1", "acct", &["2000/01/01", "2000/01/31"]));
+        assert!(checker
+            .check_and_add(&trns("ns1", "acct", &["2000/01/15"]))
+            .is_empty());
+        let warnings = checker.check_and_add(&trns("ns2", "acct", &["2000/01/20"]));
+        assert_eq!(warnings.len(), 1, "{:?}", warnings);
+        assert!(warnings[0].contains("overlaps"), "{:?}", warnings);
+    }
+}
@@ -1,14 +1,19 @@
 use std::collections::HashMap;
 
 use anyhow::{bail, Result};
-use chrono::NaiveDate;
+use chrono::{Duration, NaiveDate};
+use ledger_parser::{Balance, PostingAmount, Price, TransactionStatus};
 use typed_generational_arena::{StandardArena, StandardIndex};
 
 use crate::comment::Comment;
-use crate::fingerprint;
+use crate::commodities;
 use crate::internal::PostingInternal;
+use crate::merge::account::{AccountChange, AccountPolicy};
 use crate::merge::matchset::MatchSet;
+use crate::merge::stats::{ArenaStats, MapStats};
+use crate::merge::status::StatusPolicy;
 use crate::merge::transaction;
+use crate::merge::trust::Trust;
 
 use crate::tags;
 
@@ -35,15 +40,62 @@ pub struct IndexedPostings {
     post_arena: Arena,
     posts_by_date: HashMap<NaiveDate, Vec<Index>>,
     post_by_fingerprint: HashMap<String, Index>,
+    posts_by_match_key: HashMap<MatchKey, Vec<Index>>,
 }
 
+/// The account and value of a posting's match key tag.
+type MatchKey = (String, String);
+
 impl IndexedPostings {
     pub fn new() -> Self {
         Self {
             post_arena: Arena::new(),
             posts_by_date: HashMap::new(),
             post_by_fingerprint: HashMap::new(),
+            posts_by_match_key: HashMap::new(),
+        }
+    }
+
+    /// Creates an empty `IndexedPostings` with space for `posts` postings
+    /// without reallocating.
+    pub fn with_capacity(posts: usize) -> Self {
+        let mut indexed = Self::new();
+        indexed.reserve(posts);
+        indexed
+    }
+
+    /// Reserves space for at least `additional` more postings.
+    pub fn reserve(&mut self, additional: usize) {
+        // Arena::reserve always grows the arena by exactly the amount given, so
+        // only reserve what is not already free, and at least double the
+        // capacity to keep repeated small reservations amortized.
+        let free = self.post_arena.capacity() - self.post_arena.len();
+        if additional > free {
+            self.post_arena
+                .reserve((additional - free).max(self.post_arena.capacity()));
         }
+        // Most postings have a single fingerprint.
+        self.post_by_fingerprint.reserve(additional);
+    }
+
+    /// Returns the size of the posting arena.
+    pub fn arena_stats(&self) -> ArenaStats {
+        ArenaStats::of(&self.post_arena)
+    }
+
+    /// Returns the sizes of the posting index maps, by name.
+    pub fn map_stats(&self) -> Vec<(&'static str, MapStats)> {
+        vec![
+            ("postings by date", MapStats::of_vecs(&self.posts_by_date)),
+            (
+                "postings by fingerprint",
+                MapStats::of(&self.post_by_fingerprint),
+            ),
+            (
+                "postings by match key",
+                MapStats::of_vecs(&self.posts_by_match_key),
+            ),
+        ]
     }
 
     pub fn into_consume(self) -> ConsumePostings {
@@ -56,11 +108,13 @@ impl IndexedPostings {
         let fingerprints: Vec<String> = fingerprints_from_comment(&input.posting.comment)
             .map(str::to_string)
             .collect();
-        let (holder, trn_date) = Holder::from_input(input, parent_trn);
+        let match_key = match_key(&input.posting);
+        let (holder, date) = Holder::from_input(input, parent_trn);
         let idx = self.post_arena.insert(holder);
         self.register_fingerprints(fingerprints.into_iter(), idx)?;
+        self.register_match_key(match_key, idx);
 
-        self.posts_by_date.entry(trn_date).or_default().push(idx);
+        self.posts_by_date.entry(date).or_default().push(idx);
         Ok(idx)
     }
 
@@ -83,15 +137,46 @@ impl IndexedPostings {
         opt_vec.into_iter().flat_map(|vec| vec.iter()).copied()
     }
 
-    /// Updates an existing posting, updating the fingerprint index.
-    pub fn merge_into(&mut self, existing_post_idx: Index, input_posting: Input) -> Result<()> {
+    /// Updates an existing posting, updating the fingerprint index. Returns
+    /// the change if the input posting had a different known account.
+    pub fn merge_into(
+        &mut self,
+        existing_post_idx: Index,
+        mut input_posting: Input,
+        status_policy: StatusPolicy,
+        account_policy: AccountPolicy,
+        trust: Trust,
+    ) -> Result<Option<AccountChange>> {
+        // The merge run label is only given to the existing posting if the
+        // merge changes it, so that re-merging an input does not relabel
+        // postings that were already merged.
+        let merged_label = input_posting
+            .posting
+            .comment
+            .value_tags
+            .remove(tags::MERGED);
+        let before = merged_label
+            .as_ref()
+            .map(|_| self.get(existing_post_idx).posting.clone());
+        let account_change = account_policy.merge(
+            input_posting.date,
+            &mut self.get_mut(existing_post_idx).posting,
+            &input_posting.posting,
+        )?;
         self.register_fingerprints(
             fingerprints_from_comment(&input_posting.posting.comment).map(str::to_string),
             existing_post_idx,
         )?;
+        self.register_match_key(match_key(&input_posting.posting), existing_post_idx);
         let dest_post = self.get_mut(existing_post_idx);
-        dest_post.merge_from_input_posting(input_posting);
-        Ok(())
+        dest_post.merge_from_input_posting(input_posting, status_policy, trust);
+        if let (Some(label), Some(before)) = (merged_label, before) {
+            let after = &mut dest_post.posting;
+            if after.raw != before.raw || after.comment != before.comment {
+                after.comment.replace_value_tag(tags::MERGED, label);
+            }
+        }
+        Ok(account_change)
     }
 
     /// Adds fingerprints to posting fingerprints index.
@@ -116,15 +201,34 @@ impl IndexedPostings {
         Ok(())
     }
 
+    /// Adds a posting to the match key index.
+    fn register_match_key(&mut self, match_key: Option<MatchKey>, post_idx: Index) {
+        if let Some(match_key) = match_key {
+            let idxs = self.posts_by_match_key.entry(match_key).or_default();
+            if !idxs.contains(&post_idx) {
+                idxs.push(post_idx);
+            }
+        }
+    }
+
     pub fn find_matching_postings(&self, post: &Input) -> Match {
         use MatchSet::*;
         match self.find_posting_by_fingerprints(post) {
             One(idx) => Match::Fingerprint(MatchedIndices::One(idx)),
             Many(idxs) => Match::Fingerprint(MatchedIndices::Many(idxs.into_iter().collect())),
             Zero => {
+                // Look for a match based on the match key, regardless of date.
+                match self.find_posting_by_match_key(post) {
+                    One(idx) => return Match::Soft(MatchedIndices::One(idx)),
+                    Many(idxs) => {
+                        return Match::Soft(MatchedIndices::Many(idxs.into_iter().collect()))
+                    }
+                    Zero => {}
+                }
+
                 // Look for a match based on internal values.
                 let soft_idxs: MatchSet<Index> = self
-                    .date_to_indices(post.trn_date)
+                    .date_to_indices(post.date)
                     .filter(|idx| {
                         let candidate = self.get(*idx);
                         candidate.matches(post)
@@ -140,6 +244,43 @@ impl IndexedPostings {
         }
     }
 
+    /// Looks for pending postings dated within `days` of the input posting,
+    /// that otherwise soft match it.
+    pub fn find_pending_postings(&self, post: &Input, days: u32) -> MatchSet<Index> {
+        let days = i64::from(days);
+        (-days..=days)
+            .filter_map(|offset| post.date.checked_add_signed(Duration::days(offset)))
+            .flat_map(|date| self.date_to_indices(date))
+            .filter(|idx| {
+                let candidate = self.get(*idx);
+                has_status(&candidate.posting, TransactionStatus::Pending)
+                    && candidate.matches(post)
+            })
+            .collect()
+    }
+
+    /// Looks for postings among `candidates` that soft match the input
+    /// posting, regardless of their dates.
+    pub fn find_among<I>(&self, post: &Input, candidates: I) -> MatchSet<Index>
+    where
+        I: Iterator<Item = Index>,
+    {
+        candidates
+            .filter(|idx| self.get(*idx).matches(post))
+            .collect()
+    }
+
+    /// Look for matches by match key, that otherwise soft match.
+    fn find_posting_by_match_key(&self, post: &Input) -> MatchSet<Index> {
+        match_key(&post.posting)
+            .and_then(|key| self.posts_by_match_key.get(&key))
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|idx| self.get(*idx).matches(post))
+            .collect()
+    }
+
     /// Look for match by existing fingerprint(s). Matches zero or one postings
     /// on success, multiple matches are an error.
     fn find_posting_by_fingerprints(&self, post: &Input) -> MatchSet<Index> {
@@ -171,23 +312,29 @@ impl ConsumePostings {
     }
 }
 
-// TODO: Consider removing the `Input` type and moving `trn_date` into
-// `PostingInternal`.
+// TODO: Consider removing the `Input` type.
 
 pub struct Input {
-    trn_date: NaiveDate,
+    /// The date of the posting, which is that of its transaction unless the
+    /// posting has its own date.
+    date: NaiveDate,
     pub posting: PostingInternal,
+    /// Whether the posting had the `merge-as-new` tag, which is removed.
+    merge_as_new: bool,
 }
 
 impl Input {
-    pub fn from_posting_internal(posting: PostingInternal, trn_date: NaiveDate) -> Result<Self> {
+    pub fn from_posting_internal(
+        mut posting: PostingInternal,
+        trn_date: NaiveDate,
+    ) -> Result<Self> {
         // Error if any src_post has a candidate tag on it. The user should have
         // removed it.
         if posting
             .comment
-            .tags
-            .iter()
-            .any(|tag| tag.starts_with(tags::CANDIDATE_FP_PREFIX))
+            .tags_with_prefix(tags::CANDIDATE_FP_PREFIX)
+            .next()
+            .is_some()
         {
             bail!(
                 "bad input to merge: posting \"{}\" has a candidate tag",
@@ -198,12 +345,11 @@ impl Input {
         // Ensure that there is at least one fingerprint to serve as the
         // primary. Having at least one fingerprint is required by the merging
         // process. I.e `primary_fingerprint` may panic if we don't check this.
-        if !posting
+        if posting
             .comment
-            .tags
-            .iter()
-            .map(String::as_str)
-            .any(fingerprint::is_fingerprint)
+            .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+            .next()
+            .is_none()
         {
             bail!(
                 "posting \"{}\" does not have a fingerprint tag",
@@ -211,15 +357,38 @@ impl Input {
             );
         }
 
-        Ok(Self { trn_date, posting })
+        // A hint left from an earlier unmerged run no longer describes the
+        // candidates, which have been resolved.
+        posting.comment.value_tags.remove(tags::CANDIDATE_HINT);
+
+        let merge_as_new = posting.comment.tags.remove(tags::MERGE_AS_NEW);
+        Ok(Self {
+            date: posting.date(trn_date),
+            posting,
+            merge_as_new,
+        })
     }
 
-    pub fn into_posting_internal(self) -> PostingInternal {
+    /// Returns the posting, with its `merge-as-new` tag restored if it had
+    /// one, as when it is left unmerged.
+    pub fn into_posting_internal(mut self) -> PostingInternal {
+        if self.merge_as_new {
+            self.posting.comment.tags.insert(tags::MERGE_AS_NEW.into());
+        }
         self.posting
     }
 
+    /// Returns true if the posting is only to be matched by fingerprint.
+    pub fn merge_as_new(&self) -> bool {
+        self.merge_as_new
+    }
+
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
     pub fn add_tag(&mut self, tag: String) {
-        self.posting.comment.tags.insert(tag);
+        self.posting.comment.tags.insert(tag.into());
     }
 
     pub fn iter_fingerprints(&'_ self) -> impl Iterator<Item = &str> + '_ {
@@ -240,7 +409,7 @@ impl Holder {
                 parent_trn,
                 posting: proto.posting,
             },
-            proto.trn_date,
+            proto.date,
         )
     }
 
@@ -260,8 +429,8 @@ impl Holder {
         matches(&self.posting, &input.posting)
     }
 
-    fn merge_from_input_posting(&mut self, src: Input) {
-        merge(&mut self.posting, src.posting)
+    fn merge_from_input_posting(&mut self, src: Input, status_policy: StatusPolicy, trust: Trust) {
+        merge(&mut self.posting, src.posting, status_policy, trust)
     }
 }
 
@@ -276,34 +445,74 @@ fn matches(a: &PostingInternal, b: &PostingInternal) -> bool {
             true
         };
 
-    let amounts_match = ap.amount == bp.amount;
+    let amounts_match = posting_amounts_equal(&ap.amount, &bp.amount);
+
+    let realities_match = ap.reality == bp.reality;
 
     let balances_match = match (&ap.balance, &bp.balance) {
+        (Some(Balance::Amount(a_bal)), Some(Balance::Amount(b_bal))) => {
+            commodities::amounts_equal(a_bal, b_bal)
+        }
         (Some(a_bal), Some(b_bal)) => a_bal == b_bal,
         _ => true,
     };
 
-    accounts_match && amounts_match && balances_match
+    let match_keys_match = match (
+        ac.value_tags.get(tags::MATCH_KEY),
+        bc.value_tags.get(tags::MATCH_KEY),
+    ) {
+        (Some(a_key), Some(b_key)) => a_key == b_key,
+        _ => true,
+    };
+
+    accounts_match && amounts_match && realities_match && balances_match && match_keys_match
 }
 
-fn merge(dest: &mut PostingInternal, mut src: PostingInternal) {
-    use ledger_parser::TransactionStatus::*;
-    match (dest.raw.status.as_ref(), src.raw.status) {
-        (None, src_status) => {
-            dest.raw.status = src_status;
+/// Returns true if the posting amounts are equal, as for
+/// `commodities::amounts_equal`.
+pub fn posting_amounts_equal(a: &Option<PostingAmount>, b: &Option<PostingAmount>) -> bool {
+    let prices_equal = |a: &Option<Price>, b: &Option<Price>| match (a, b) {
+        (None, None) => true,
+        (Some(Price::Unit(a)), Some(Price::Unit(b)))
+        | (Some(Price::Total(a)), Some(Price::Total(b))) => commodities::amounts_equal(a, b),
+        _ => false,
+    };
+    match (a, b) {
+        (None, None) => true,
+        (Some(a), Some(b)) => {
+            commodities::amounts_equal(&a.amount, &b.amount)
+                && prices_equal(&a.price, &b.price)
+                && prices_equal(&a.lot_price, &b.lot_price)
         }
-        (Some(_), None) => {
-            // Don't update with less information.
+        _ => false,
+    }
+}
+
+pub fn has_status(post: &PostingInternal, status: TransactionStatus) -> bool {
+    post.raw.status == Some(status)
+}
+
+fn merge(
+    dest: &mut PostingInternal,
+    mut src: PostingInternal,
+    status_policy: StatusPolicy,
+    trust: Trust,
+) {
+    if trust.fills_gaps_only() {
+        if dest.raw.status.is_none() {
+            dest.raw.status = src.raw.status;
         }
-        (Some(dest_status), Some(src_status)) => {
-            // Only update towards cleared, assuming that the state can only
-            // go from pending to cleared.
-            if let (Pending, Cleared) = (dest_status, src_status) {
-                dest.raw.status = Some(Cleared);
-            }
+        src.comment
+            .value_tags
+            .retain(|key, _| !dest.comment.value_tags.contains_key(key));
+        if dest.comment.date.is_some() || dest.comment.aux_date.is_some() {
+            src.comment.date = None;
+            src.comment.aux_date = None;
         }
+    } else {
+        status_policy.merge(&mut dest.raw.status, src.raw.status);
     }
-    if dest.raw.balance.is_none() {
+    if dest.raw.balance.is_none() || (trust.overwrites_balances() && src.raw.balance.is_some()) {
         dest.raw.balance = src.raw.balance.clone()
     }
     if dest.comment.tags.contains(tags::UNKNOWN_ACCOUNT)
@@ -311,12 +520,26 @@ fn merge(dest: &mut PostingInternal, mut src: PostingInternal) {
     {
         dest.comment.tags.remove(tags::UNKNOWN_ACCOUNT);
         dest.raw.account = src.raw.account;
+        dest.raw.reality = src.raw.reality;
     }
     src.comment.tags.remove(tags::UNKNOWN_ACCOUNT);
 
     dest.comment.merge_from(src.comment);
 }
 
+/// Returns the match key of the posting, if it has one. The account is taken
+/// from the bank provided account tag if present, or otherwise the posting's
+/// account.
+pub(super) fn match_key(post: &PostingInternal) -> Option<MatchKey> {
+    let key = post.comment.value_tags.get(tags::MATCH_KEY)?;
+    let account = post
+        .comment
+        .value_tags
+        .get(tags::ACCOUNT)
+        .unwrap_or(&post.raw.account);
+    Some((account.clone(), key.clone()))
+}
+
 fn primary_fingerprint(comment: &Comment) -> &str {
     fingerprints_from_comment(comment)
         .next()
@@ -324,12 +547,8 @@ fn primary_fingerprint(comment: &Comment) -> &str {
 }
 
 /// Extracts the fingerprint tag(s) from `comment`.
-fn fingerprints_from_comment(comment: &Comment) -> impl Iterator<Item = &str> {
-    comment
-        .tags
-        .iter()
-        .map(String::as_str)
-        .filter(|tag| fingerprint::is_fingerprint(tag))
+pub(super) fn fingerprints_from_comment(comment: &Comment) -> impl Iterator<Item = &str> {
+    comment.tags_with_prefix(tags::FINGERPRINT_PREFIX)
 }
 
 #[cfg(test)]
@@ -377,6 +596,31 @@ mod tests {
        "Updates unknown account and removes unknown-account tag."
     )]
     fn input_from_posting(dest: &str, src: &str, want: &str) {
+        input_from_posting_with_trust(Trust::Normal, dest, src, want);
+    }
+
+    #[test_case(
+       Trust::Authoritative,
+       "foo  GBP 10.00 =GBP 50.00 ; :fp-1:\n  ; key: old-value",
+       "foo  GBP 10.00 =GBP 90.00 ; :fp-2:\n  ; key: new-value",
+       "foo  GBP 10.00 =GBP 90.00 ; :fp-1:fp-2:\n  ; key: new-value";
+       "authoritative overwrites balance and value tags"
+    )]
+    #[test_case(
+       Trust::Secondary,
+       "foo  GBP 10.00 =GBP 50.00 ; :fp-1:\n  ; key: old-value",
+       "foo  GBP 10.00 =GBP 90.00 ; :fp-2:\n  ; key: new-value\n  ; other: value",
+       "foo  GBP 10.00 =GBP 50.00 ; :fp-1:fp-2:\n  ; key: old-value\n  ; other: value";
+       "secondary only fills missing value tags"
+    )]
+    #[test_case(
+       Trust::Secondary,
+       "foo  GBP 10.00 ; :fp-1:",
+       "foo  GBP 10.00 =GBP 90.00 ; :fp-1:",
+       "foo  GBP 10.00 =GBP 90.00 ; :fp-1:";
+       "secondary fills missing balance"
+    )]
+    fn input_from_posting_with_trust(trust: Trust, dest: &str, src: &str, want: &str) {
         let dummy_date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
         let dummy_idx = StandardIndex::from_idx_first_gen(0);
 
@@ -385,7 +629,7 @@ mod tests {
         let src_posting =
             Input::from_posting_internal(parse_posting_internal(src), dummy_date).unwrap();
         let (mut dest_holder, _) = Holder::from_input(dest_posting, dummy_idx);
-        dest_holder.merge_from_input_posting(src_posting);
+        dest_holder.merge_from_input_posting(src_posting, StatusPolicy::default(), trust);
         let result = dest_holder.into_posting_internal();
 
         assert_posting_internal_eq!(result, parse_posting_internal(want));
@@ -451,6 +695,12 @@ mod tests {
         false;
         "differing_known_accounts_do_not_match"
     )]
+    #[test_case(
+        "foo  GBP 10.00  ; :fp-1:",
+        "(foo)  GBP 10.00  ; :fp-1:",
+        false;
+        "differing_realities_do_not_match"
+    )]
     fn holding_matches(dest: &str, src: &str, want: bool) {
         let dummy_date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
         let dummy_idx = StandardIndex::from_idx_first_gen(0);
@@ -464,4 +714,31 @@ mod tests {
 
         assert_eq!(got, want);
     }
+
+    #[test]
+    fn input_strips_candidate_hint() {
+        let dummy_date = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+        let input = Input::from_posting_internal(
+            parse_posting_internal(
+                "foo  GBP 10.00  ; :fp-1:\n    ; candidate-hint: 2000-01-01 bar GBP10.00 (1 of 2)",
+            ),
+            dummy_date,
+        )
+        .unwrap();
+        assert!(!input
+            .posting
+            .comment
+            .value_tags
+            .contains_key(tags::CANDIDATE_HINT));
+    }
+
+    #[test]
+    fn reserve_only_grows_when_needed() {
+        let mut posts = IndexedPostings::with_capacity(10);
+        assert_eq!(posts.post_arena.capacity(), 10);
+        posts.reserve(5);
+        assert_eq!(posts.post_arena.capacity(), 10);
+        posts.reserve(15);
+        assert_eq!(posts.post_arena.capacity(), 20);
+    }
 }
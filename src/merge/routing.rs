@@ -0,0 +1,278 @@
+//! Merging into a destination that is split across one journal file per
+//! account, according to a routing config.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, bail, Context, Result};
+use ledger_parser::CommodityPrice;
+use serde_derive::Deserialize;
+
+use crate::accounts::Account;
+use crate::filespec::{self, FileSpec, WriteBatch};
+use crate::internal::{JournalEnds, JournalTransactions, TransactionPostings};
+use crate::prices::Prices;
+use crate::tags::TRANSACTION_ORIGIN_KEY;
+
+/// Configuration of which journal file transactions are written to, read
+/// from a RON file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Routes {
+    /// Maps account prefixes to journal files, relative to the destination
+    /// directory. A prefix matches the account itself and its subaccounts.
+    routes: BTreeMap<Account, PathBuf>,
+    /// The journal file for transactions that match no route.
+    #[serde(default)]
+    default: Option<PathBuf>,
+}
+
+impl Routes {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        ron::de::from_reader(
+            std::fs::File::open(path).with_context(|| format!("opening {:?} for reading", path))?,
+        )
+        .with_context(|| format!("parsing {:?}", path))
+    }
+
+    #[cfg(test)]
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(ron::de::from_str(s)?)
+    }
+
+    /// Returns the files that are routed to, without duplicates, with the
+    /// default file (if any) first.
+    fn files(&self) -> Vec<&Path> {
+        let mut files: Vec<&Path> = Vec::new();
+        for file in self.default.iter().chain(self.routes.values()) {
+            if !files.contains(&file.as_path()) {
+                files.push(file);
+            }
+        }
+        files
+    }
+
+    /// Returns the file for a new transaction. This is the route with the
+    /// longest prefix matching any of its posting accounts, or otherwise the
+    /// default file.
+    fn route(&self, trn: &TransactionPostings) -> Option<&Path> {
+        trn.posts
+            .iter()
+            .flat_map(|post| {
+                let account = Account::new(&post.raw.account);
+                self.routes
+                    .iter()
+                    .filter(move |(prefix, _)| account.starts_with_segments(prefix))
+            })
+            // Prefer the first posting for equally long prefixes.
+            .rev()
+            .max_by_key(|(prefix, _)| prefix.segments().count())
+            .map(|(_, file)| file.as_path())
+            .or(self.default.as_deref())
+    }
+}
+
+/// The files of a destination directory that is merged into according to
+/// `Routes`.
+#[derive(Debug)]
+pub struct RoutedJournal {
+    dir: PathBuf,
+    routes: Routes,
+    /// The routed files, in the order of `Routes::files`.
+    files: Vec<RoutedFile>,
+}
+
+#[derive(Debug)]
+struct RoutedFile {
+    path: PathBuf,
+    /// The commodity price directives in the file.
+    prices: Vec<CommodityPrice>,
+    /// The directives and top-level comments at the top and end of the file.
+    ends: JournalEnds,
+}
+
+impl RoutedJournal {
+    /// Reads the routed files within `dir` that exist. Each transaction is
+    /// tagged with the file that it was read from.
+    pub fn read(dir: &Path, routes: Routes) -> Result<(Self, Vec<TransactionPostings>)> {
+        let mut files = Vec::new();
+        let mut trns = Vec::new();
+        for file in routes.files() {
+            let path = dir.join(file);
+            let mut routed = RoutedFile {
+                path,
+                prices: Vec::new(),
+                ends: JournalEnds::default(),
+            };
+            if routed.path.exists() {
+                routed.read(&mut trns)?;
+            }
+            files.push(routed);
+        }
+        if files.is_empty() {
+            bail!("routing config has no routes");
+        }
+        Ok((
+            Self {
+                dir: dir.to_path_buf(),
+                routes,
+                files,
+            },
+            trns,
+        ))
+    }
+
+    /// Returns the commodity prices from all files.
+    pub fn prices(&self) -> impl Iterator<Item = &CommodityPrice> {
+        self.files.iter().flat_map(|file| file.prices.iter())
+    }
+
+    /// Adds the transactions to `batch`, to be written back to the files that
+    /// they were read from, removing their origin tags. Transactions without
+    /// an origin (i.e. new ones) are written to the file that they are routed
+    /// to. Any of `new_prices` that are not already in a file are written to
+    /// the first file (the default file, if there is one).
+    pub fn write_back(
+        &self,
+        trns: Vec<TransactionPostings>,
+        new_prices: Vec<CommodityPrice>,
+        batch: &mut WriteBatch,
+    ) -> Result<()> {
+        let mut file_trns: Vec<Vec<TransactionPostings>> =
+            self.files.iter().map(|_| Vec::new()).collect();
+        for mut trn in trns {
+            let path = match trn.trn.comment.value_tags.remove(TRANSACTION_ORIGIN_KEY) {
+                Some(origin) => PathBuf::from(origin),
+                None => {
+                    let file = self.routes.route(&trn).ok_or_else(|| {
+                        anyhow!(
+                            "no route for transaction on {} {:?}, and no default file",
+                            trn.trn.raw.date,
+                            trn.trn.raw.description
+                        )
+                    })?;
+                    self.dir.join(file)
+                }
+            };
+            let index = self
+                .files
+                .iter()
+                .position(|file| file.path == path)
+                .ok_or_else(|| anyhow!("transaction has unknown origin file {:?}", path))?;
+            file_trns[index].push(trn);
+        }
+
+        let mut known_prices = Prices::new();
+        known_prices.extend(self.prices().cloned());
+        let new_prices: Vec<CommodityPrice> = new_prices
+            .into_iter()
+            .filter(|price| known_prices.add(price.clone()))
+            .collect();
+
+        for (i, (file, trns)) in self.files.iter().zip(file_trns).enumerate() {
+            let mut prices = file.prices.clone();
+            if i == 0 {
+                prices.extend(new_prices.iter().cloned());
+            }
+            let contents = JournalTransactions {
+                trns,
+                prices,
+                ends: file.ends.clone(),
+            };
+            batch.add_ledger(&FileSpec::Path(file.path.clone()), &contents.into_journal())?;
+        }
+        Ok(())
+    }
+}
+
+impl RoutedFile {
+    fn read(&mut self, trns: &mut Vec<TransactionPostings>) -> Result<()> {
+        let journal = filespec::read_ledger_file(&FileSpec::Path(self.path.clone()))
+            .with_context(|| format!("reading {:?}", self.path))?;
+        let contents = JournalTransactions::from(journal);
+        let origin = self.path.to_string_lossy().into_owned();
+        for mut trn in contents.trns {
+            trn.trn
+                .comment
+                .value_tags
+                .insert(TRANSACTION_ORIGIN_KEY.into(), origin.clone());
+            trns.push(trn);
+        }
+        self.prices = contents.prices;
+        self.ends = contents.ends;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    use test_case::test_case;
+
+    const ROUTES: &str = r#"(
+        routes: {
+            "assets:bank": "bank.journal",
+            "assets:bank:savings": "savings.journal",
+            "liabilities:card": "card.journal",
+        },
+        default: Some("other.journal"),
+    )"#;
+
+    #[test_case("assets:bank:current", "expenses:food" => "bank.journal"; "prefix")]
+    #[test_case("assets:bank", "expenses:food" => "bank.journal"; "exact")]
+    #[test_case("assets:bank:savings", "assets:bank" => "savings.journal"; "longest prefix")]
+    #[test_case("liabilities:card", "assets:bank" => "card.journal"; "first posting on tie")]
+    #[test_case("assets:banking", "expenses:food" => "other.journal"; "not subaccount")]
+    fn route(account1: &str, account2: &str) -> String {
+        let routes = Routes::from_str(ROUTES).expect("parse routes");
+        let trns = parse_transaction_postings(&format!(
+            "2001/01/01 t
+                {}  GBP10.00
+                {}",
+            account1, account2
+        ));
+        routes
+            .route(&trns[0])
+            .expect("route")
+            .to_string_lossy()
+            .into_owned()
+    }
+
+    #[test]
+    fn read_and_write_back() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        std::fs::write(
+            dir.path().join("bank.journal"),
+            "2001/01/01 existing\n  assets:bank  GBP1.00\n  income\n",
+        )
+        .expect("write file");
+        let routes = Routes::from_str(ROUTES).expect("parse routes");
+
+        let (journal, mut trns) = RoutedJournal::read(dir.path(), routes).expect("read");
+        assert_eq!(trns.len(), 1);
+        trns.extend(parse_transaction_postings(
+            "
+            2001/01/02 card
+                liabilities:card  GBP-2.00
+                expenses:food
+
+            2001/01/03 bank
+                assets:bank  GBP3.00
+                income",
+        ));
+        let mut batch = WriteBatch::new();
+        journal
+            .write_back(trns, Vec::new(), &mut batch)
+            .expect("write back");
+        batch.commit().expect("commit");
+
+        let read = |name: &str| std::fs::read_to_string(dir.path().join(name)).expect(name);
+        let bank = read("bank.journal");
+        assert!(bank.contains("existing") && bank.contains("2001-01-03 bank"));
+        assert!(!bank.contains(TRANSACTION_ORIGIN_KEY), "{}", bank);
+        assert!(read("card.journal").contains("2001-01-02 card"));
+        assert_eq!(read("other.journal"), "");
+    }
+}
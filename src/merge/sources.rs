@@ -2,18 +2,33 @@ use std::collections::HashMap;
 
 use anyhow::Result;
 
-use crate::filespec::{self, FileSpec};
-use crate::internal::TransactionPostings;
+use crate::filespec::{self, FileSpec, ReadOptions};
+use crate::internal::{JournalEnds, JournalTransactions, TransactionPostings};
+use crate::prices::Prices;
 use crate::tags::TRANSACTION_SOURCE_KEY;
 
 /// Reads a Ledger file, and yields sets of `TransactionPostings` according to
 /// how the transactions declare where they came from based on their source
-/// tags.
+/// tags. Any commodity prices in the file are added to `prices`. The items at
+/// the top and end of the file are returned with the transactions.
 pub fn read_ledger_file(
     ledger_file: &FileSpec,
-) -> Result<impl Iterator<Item = Vec<TransactionPostings>>> {
-    let ledger = filespec::read_ledger_file(ledger_file)?;
-    let trns = TransactionPostings::from_ledger(ledger)?;
+    prices: &mut Prices,
+    options: ReadOptions,
+) -> Result<(JournalEnds, impl Iterator<Item = Vec<TransactionPostings>>)> {
+    let contents =
+        JournalTransactions::from(filespec::read_ledger_file_with(ledger_file, options)?);
+    prices.extend(contents.prices);
+    Ok((contents.ends, group_by_source(contents.trns, ledger_file)))
+}
+
+/// Groups transactions according to their source tags, as for
+/// `read_ledger_file`. Transactions without a source tag are annotated as
+/// coming from `ledger_file`.
+pub fn group_by_source(
+    trns: Vec<TransactionPostings>,
+    ledger_file: &FileSpec,
+) -> impl Iterator<Item = Vec<TransactionPostings>> {
     let default_source = format!("{}", ledger_file);
 
     let mut trns_by_source: HashMap<String, Vec<TransactionPostings>> = HashMap::new();
@@ -24,7 +39,7 @@ pub fn read_ledger_file(
             .trn
             .comment
             .value_tags
-            .entry(TRANSACTION_SOURCE_KEY.to_string())
+            .entry(TRANSACTION_SOURCE_KEY.into())
             .or_insert_with(|| default_source.clone())
             .clone();
         // Group the transaction by its source.
@@ -36,9 +51,17 @@ pub fn read_ledger_file(
     // Sort by source.
     source_trn_posts.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
 
-    Ok(source_trn_posts
+    source_trn_posts
         .into_iter()
-        .map(|(_source, trn_posts)| trn_posts))
+        .map(|(_source, trn_posts)| trn_posts)
+}
+
+/// Returns the source of a group of transactions from `group_by_source`.
+pub fn source_name(trns: &[TransactionPostings]) -> &str {
+    trns.first()
+        .and_then(|trn| trn.trn.comment.value_tags.get(TRANSACTION_SOURCE_KEY))
+        .map(String::as_str)
+        .unwrap_or("<unknown source>")
 }
 
 /// Remove all source tags from the transactions.
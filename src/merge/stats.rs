@@ -0,0 +1,247 @@
+//! Internal statistics of a merge, reported by `merge --stats` to help
+//! diagnose slow merges and the memory use of large journals.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use typed_generational_arena::StandardArena;
+
+/// The size of an arena of transactions or postings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ArenaStats {
+    pub len: usize,
+    pub capacity: usize,
+    /// Approximate bytes allocated for the arena's capacity, not counting
+    /// memory owned by its elements.
+    pub bytes: usize,
+}
+
+impl ArenaStats {
+    pub fn of<T>(arena: &StandardArena<T>) -> Self {
+        Self {
+            len: arena.len(),
+            capacity: arena.capacity(),
+            bytes: arena.capacity() * std::mem::size_of::<T>(),
+        }
+    }
+}
+
+/// The size of an index map.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MapStats {
+    /// Number of keys.
+    pub keys: usize,
+    /// Number of indices held across all keys.
+    pub indices: usize,
+    pub capacity: usize,
+}
+
+impl MapStats {
+    /// Returns the size of a map from each key to a single index.
+    pub fn of<K, V>(map: &HashMap<K, V>) -> Self {
+        Self {
+            keys: map.len(),
+            indices: map.len(),
+            capacity: map.capacity(),
+        }
+    }
+
+    /// Returns the size of a map from each key to several indices.
+    pub fn of_vecs<K, V>(map: &HashMap<K, Vec<V>>) -> Self {
+        Self {
+            keys: map.len(),
+            indices: map.values().map(Vec::len).sum(),
+            capacity: map.capacity(),
+        }
+    }
+}
+
+/// Counts of how input postings were matched against existing postings.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MatchCounts {
+    /// Postings whose fingerprints matched an existing posting.
+    pub fingerprint: usize,
+    /// Postings that soft matched one or more existing postings.
+    pub soft: usize,
+    /// Postings that matched no existing posting.
+    pub zero: usize,
+    /// Postings merged along with their transaction, which matched an
+    /// existing transaction by its transaction fingerprint.
+    pub transaction_fingerprint: usize,
+}
+
+/// A snapshot of the sizes of a `Merger`'s internal structures, and of how
+/// it has matched postings.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct InternalStats {
+    pub transaction_arena: ArenaStats,
+    pub posting_arena: ArenaStats,
+    /// Index maps by name.
+    pub maps: Vec<(&'static str, MapStats)>,
+    pub matches: MatchCounts,
+}
+
+/// Time spent in each phase of a merge, in the order that the phases were
+/// first timed.
+#[derive(Debug, Default)]
+pub struct PhaseTimes(Vec<(&'static str, Duration)>);
+
+impl PhaseTimes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Calls `f`, adding the time that it takes to `phase`.
+    pub fn time<T, F: FnOnce() -> T>(&mut self, phase: &'static str, f: F) -> T {
+        let start = Instant::now();
+        let result = f();
+        self.add(phase, start.elapsed());
+        result
+    }
+
+    /// Adds `elapsed` to the time spent in `phase`.
+    pub fn add(&mut self, phase: &'static str, elapsed: Duration) {
+        match self.0.iter_mut().find(|(name, _)| *name == phase) {
+            Some((_, total)) => *total += elapsed,
+            None => self.0.push((phase, elapsed)),
+        }
+    }
+
+    pub fn get(&self, phase: &str) -> Option<Duration> {
+        self.0
+            .iter()
+            .find(|(name, _)| *name == phase)
+            .map(|(_, elapsed)| *elapsed)
+    }
+}
+
+/// Formats a report of the internal statistics and the time spent in each
+/// phase.
+pub fn report(stats: &InternalStats, phases: &PhaseTimes) -> String {
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{:>10} {:>10} {:>12}  arena",
+        "len", "capacity", "bytes"
+    )
+    .unwrap();
+    for (name, arena) in [
+        ("transactions", &stats.transaction_arena),
+        ("postings", &stats.posting_arena),
+    ] {
+        writeln!(
+            out,
+            "{:>10} {:>10} {:>12}  {}",
+            arena.len, arena.capacity, arena.bytes, name
+        )
+        .unwrap();
+    }
+
+    out.push('\n');
+    writeln!(
+        out,
+        "{:>10} {:>10} {:>12}  index",
+        "keys", "indices", "capacity"
+    )
+    .unwrap();
+    for (name, map) in &stats.maps {
+        writeln!(
+            out,
+            "{:>10} {:>10} {:>12}  {}",
+            map.keys, map.indices, map.capacity, name
+        )
+        .unwrap();
+    }
+
+    out.push('\n');
+    writeln!(out, "{:>10}  match", "postings").unwrap();
+    let matches = &stats.matches;
+    for (name, count) in [
+        ("fingerprint", matches.fingerprint),
+        ("soft", matches.soft),
+        ("zero", matches.zero),
+        ("transaction fingerprint", matches.transaction_fingerprint),
+    ] {
+        writeln!(out, "{:>10}  {}", count, name).unwrap();
+    }
+
+    out.push('\n');
+    writeln!(out, "{:>12}  phase", "time").unwrap();
+    for (name, elapsed) in &phases.0 {
+        writeln!(out, "{:>12}  {}", format!("{:.3?}", elapsed), name).unwrap();
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn phase_times_accumulate() {
+        let mut phases = PhaseTimes::new();
+        phases.add("read", Duration::from_millis(2));
+        phases.add("merge", Duration::from_millis(5));
+        phases.add("read", Duration::from_millis(3));
+        assert_eq!(phases.time("build", || 42), 42);
+        assert_eq!(phases.get("read"), Some(Duration::from_millis(5)));
+        assert_eq!(phases.get("merge"), Some(Duration::from_millis(5)));
+        assert!(phases.get("build").is_some());
+        assert_eq!(phases.get("write"), None);
+
+        let names: Vec<&str> = phases.0.iter().map(|(name, _)| *name).collect();
+        assert_eq!(names, vec!["read", "merge", "build"]);
+    }
+
+    #[test]
+    fn report_format() {
+        let stats = InternalStats {
+            transaction_arena: ArenaStats {
+                len: 2,
+                capacity: 4,
+                bytes: 400,
+            },
+            posting_arena: ArenaStats {
+                len: 5,
+                capacity: 8,
+                bytes: 1600,
+            },
+            maps: vec![(
+                "postings by date",
+                MapStats {
+                    keys: 2,
+                    indices: 5,
+                    capacity: 3,
+                },
+            )],
+            matches: MatchCounts {
+                fingerprint: 1,
+                soft: 2,
+                zero: 3,
+                transaction_fingerprint: 0,
+            },
+        };
+        let mut phases = PhaseTimes::new();
+        phases.add("merge", Duration::from_millis(12));
+        assert_eq!(
+            report(&stats, &phases),
+            "       len   capacity        bytes  arena
+         2          4          400  transactions
+         5          8         1600  postings
+
+      keys    indices     capacity  index
+         2          5            3  postings by date
+
+  postings  match
+         1  fingerprint
+         2  soft
+         3  zero
+         0  transaction fingerprint
+
+        time  phase
+    12.000ms  merge
+"
+        );
+    }
+}
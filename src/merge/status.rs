@@ -0,0 +1,132 @@
+//! Merging of transaction and posting statuses.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+use ledger_parser::TransactionStatus::{self, *};
+
+use crate::internal::TransactionPostings;
+
+/// How statuses from merged inputs update existing statuses.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StatusPolicy {
+    /// Only update towards cleared, assuming that the state can only go from
+    /// pending to cleared.
+    #[default]
+    Upgrade,
+    /// Use the status from the input whenever it has one, e.g. when a later
+    /// import of the same statement is considered authoritative.
+    Latest,
+}
+
+impl FromStr for StatusPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "upgrade" => Ok(Self::Upgrade),
+            "latest" => Ok(Self::Latest),
+            _ => bail!("invalid status policy: {:?}", s),
+        }
+    }
+}
+
+impl StatusPolicy {
+    /// Updates `dest` with the status from an input.
+    pub fn merge(self, dest: &mut Option<TransactionStatus>, src: Option<TransactionStatus>) {
+        match (dest.as_ref(), src) {
+            (_, None) => {
+                // Don't update with less information.
+            }
+            (None, src) => *dest = src,
+            (Some(dest_status), Some(src_status)) => match self {
+                StatusPolicy::Upgrade => {
+                    if let (Pending, Cleared) = (dest_status, &src_status) {
+                        *dest = Some(Cleared);
+                    }
+                }
+                StatusPolicy::Latest => *dest = Some(src_status),
+            },
+        }
+    }
+}
+
+/// Moves any transaction status onto the postings that have no status of
+/// their own.
+pub fn push_down(trn: &mut TransactionPostings) {
+    if let Some(status) = trn.trn.raw.status.take() {
+        for post in &mut trn.posts {
+            if post.raw.status.is_none() {
+                post.raw.status = Some(status);
+            }
+        }
+    }
+}
+
+/// Derives the transaction status from its postings. If all postings have the
+/// same status, then it is recorded on the transaction instead of on each
+/// posting.
+pub fn normalize(trn: &mut TransactionPostings) {
+    push_down(trn);
+    let first = match trn.posts.first() {
+        Some(post) => post.raw.status,
+        None => return,
+    };
+    if first.is_some() && trn.posts.iter().all(|post| post.raw.status == first) {
+        trn.trn.raw.status = first;
+        for post in &mut trn.posts {
+            post.raw.status = None;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::parse_transaction_postings;
+
+    use test_case::test_case;
+
+    #[test_case(StatusPolicy::Upgrade, None, None => None; "upgrade none none")]
+    #[test_case(StatusPolicy::Upgrade, None, Some(Pending) => Some(Pending); "upgrade none pending")]
+    #[test_case(StatusPolicy::Upgrade, Some(Pending), Some(Cleared) => Some(Cleared); "upgrade pending cleared")]
+    #[test_case(StatusPolicy::Upgrade, Some(Cleared), Some(Pending) => Some(Cleared); "upgrade cleared pending")]
+    #[test_case(StatusPolicy::Upgrade, Some(Cleared), None => Some(Cleared); "upgrade cleared none")]
+    #[test_case(StatusPolicy::Latest, Some(Cleared), Some(Pending) => Some(Pending); "latest cleared pending")]
+    #[test_case(StatusPolicy::Latest, Some(Pending), None => Some(Pending); "latest pending none")]
+    fn merge(
+        policy: StatusPolicy,
+        mut dest: Option<TransactionStatus>,
+        src: Option<TransactionStatus>,
+    ) -> Option<TransactionStatus> {
+        policy.merge(&mut dest, src);
+        dest
+    }
+
+    // Statuses are set directly, as ledger-parser reads indented lines
+    // starting with "*" as comments rather than cleared postings.
+    #[test_case(None, &[Some(Cleared), Some(Cleared)] => (Some(Cleared), vec![None, None]); "all postings cleared")]
+    #[test_case(Some(Cleared), &[Some(Pending), None] => (None, vec![Some(Pending), Some(Cleared)]); "cleared transaction with pending posting")]
+    #[test_case(Some(Pending), &[None, Some(Pending)] => (Some(Pending), vec![None, None]); "pending transaction")]
+    #[test_case(None, &[Some(Cleared), None] => (None, vec![Some(Cleared), None]); "some postings cleared")]
+    fn normalize_status(
+        trn_status: Option<TransactionStatus>,
+        post_statuses: &[Option<TransactionStatus>],
+    ) -> (Option<TransactionStatus>, Vec<Option<TransactionStatus>>) {
+        let mut trn = parse_transaction_postings(
+            "2001/01/01 foo
+                a  GBP1.00
+                b  GBP-1.00",
+        )
+        .remove(0);
+        trn.trn.raw.status = trn_status;
+        for (post, status) in trn.posts.iter_mut().zip(post_statuses) {
+            post.raw.status = *status;
+        }
+        normalize(&mut trn);
+        (
+            trn.trn.raw.status,
+            trn.posts.iter().map(|post| post.raw.status).collect(),
+        )
+    }
+}
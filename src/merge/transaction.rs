@@ -1,10 +1,12 @@
 use std::collections::HashMap;
 
-use chrono::NaiveDate;
+use chrono::{NaiveDate, NaiveTime};
 use typed_generational_arena::{StandardArena, StandardIndex};
 
 use crate::internal::{PostingInternal, TransactionInternal, TransactionPostings};
 use crate::merge::posting;
+use crate::merge::stats::{ArenaStats, MapStats};
+use crate::tags;
 
 const BAD_TRANSACTION_INDEX: &str = "internal error: used invalid transaction::Index";
 
@@ -24,8 +26,37 @@ impl IndexedTransactions {
         }
     }
 
+    /// Creates an empty `IndexedTransactions` with space for `trns`
+    /// transactions without reallocating.
+    pub fn with_capacity(trns: usize) -> Self {
+        let mut indexed = Self::new();
+        indexed.reserve(trns);
+        indexed
+    }
+
+    /// Reserves space for at least `additional` more transactions.
+    pub fn reserve(&mut self, additional: usize) {
+        // See `posting::IndexedPostings::reserve`.
+        let free = self.trn_arena.capacity() - self.trn_arena.len();
+        if additional > free {
+            self.trn_arena
+                .reserve((additional - free).max(self.trn_arena.capacity()));
+        }
+    }
+
+    /// Returns the size of the transaction arena.
+    pub fn arena_stats(&self) -> ArenaStats {
+        ArenaStats::of(&self.trn_arena)
+    }
+
+    /// Returns the size of the transactions by date index map.
+    pub fn map_stats(&self) -> MapStats {
+        MapStats::of_vecs(&self.trns_by_date)
+    }
+
     /// Iterates over the transactions in date order, preserving insertion
-    /// order.
+    /// order except that transactions with `time` tags are ordered by time
+    /// among themselves.
     pub fn into_iter(self) -> impl Iterator<Item = Holder> {
         let mut trn_arena = self.trn_arena;
         let mut date_trns: Vec<(NaiveDate, Vec<Holder>)> = self
@@ -36,11 +67,11 @@ impl IndexedTransactions {
                     .into_iter()
                     .map(|index| trn_arena.remove(index).expect(BAD_TRANSACTION_INDEX))
                     .collect();
-                (date, holders)
+                (date, sort_by_time(holders))
             })
             .collect();
         // Sort by dates (first item in tuple).
-        date_trns.sort_by(|a, b| a.0.cmp(&b.0));
+        date_trns.sort_by_key(|a| a.0);
 
         date_trns
             .into_iter()
@@ -59,6 +90,12 @@ impl IndexedTransactions {
             .expect(BAD_TRANSACTION_INDEX)
     }
 
+    /// Returns the indices of the transactions on `date`.
+    pub fn date_to_indices(&'_ self, date: NaiveDate) -> impl Iterator<Item = Index> + '_ {
+        let opt_vec = self.trns_by_date.get(&date);
+        opt_vec.into_iter().flat_map(|vec| vec.iter()).copied()
+    }
+
     pub fn add(&mut self, trn: Holder) -> Index {
         let date = trn.trn.raw.date;
         let idx = self.trn_arena.insert(trn);
@@ -66,10 +103,49 @@ impl IndexedTransactions {
         idx
     }
 
+    /// Merges the transaction-level fields of `src` into an existing
+    /// transaction that it matched.
+    pub fn merge_into(&mut self, trn_idx: Index, src: Holder) {
+        self.get_mut(trn_idx).merge_from(src);
+    }
+
     pub fn add_post_to_trn(&mut self, trn_idx: Index, post_idx: posting::Index) {
         let dest_trn = self.get_mut(trn_idx);
         dest_trn.postings.push(post_idx);
     }
+
+    /// Records that postings were added to an existing transaction that an
+    /// input transaction was merged into.
+    pub fn mark_posts_added(&mut self, trn_idx: Index) {
+        self.get_mut(trn_idx).posts_added = true;
+    }
+}
+
+/// Sorts the transactions that have `time` tags by time, keeping those
+/// without in place.
+fn sort_by_time(holders: Vec<Holder>) -> Vec<Holder> {
+    let times: Vec<Option<NaiveTime>> = holders.iter().map(Holder::time).collect();
+    if times.iter().flatten().count() < 2 {
+        return holders;
+    }
+    let (mut timed, untimed): (Vec<_>, Vec<_>) = holders
+        .into_iter()
+        .zip(&times)
+        .partition(|(_, time)| time.is_some());
+    // Stable sort, so that transactions at the same time keep their order.
+    timed.sort_by_key(|(_, time)| **time);
+    let mut timed = timed.into_iter();
+    let mut untimed = untimed.into_iter();
+    times
+        .iter()
+        .map(|time| {
+            let next = match time {
+                Some(_) => timed.next(),
+                None => untimed.next(),
+            };
+            next.expect("as many transactions as times").0
+        })
+        .collect()
 }
 
 /// Contains a partially unpacked `Transaction` with arena references to its
@@ -78,6 +154,9 @@ pub struct Holder {
     pub trn: TransactionInternal,
 
     postings: Vec<posting::Index>,
+    /// Whether postings were added when merging into the transaction, after
+    /// it was first added.
+    posts_added: bool,
 }
 
 impl Holder {
@@ -87,9 +166,18 @@ impl Holder {
         Holder {
             trn,
             postings: Vec::new(),
+            posts_added: false,
         }
     }
 
+    /// Returns the time of day from the transaction's `time` tag, if any.
+    fn time(&self) -> Option<NaiveTime> {
+        let time = self.trn.comment.value_tags.get(tags::TIME)?;
+        NaiveTime::parse_from_str(time, "%H:%M:%S")
+            .or_else(|_| NaiveTime::parse_from_str(time, "%H:%M"))
+            .ok()
+    }
+
     pub fn into_transaction_postings(self, postings: Vec<PostingInternal>) -> TransactionPostings {
         TransactionPostings {
             trn: self.trn,
@@ -97,7 +185,33 @@ impl Holder {
         }
     }
 
+    /// Merges the transaction-level fields of `src` into this transaction,
+    /// without losing information: the code and effective date are only set
+    /// if not already set, comment lines and tags are added, and value tags
+    /// are added for keys that are not already set.
+    ///
+    /// Statuses are not merged here, as the transaction status has already
+    /// been pushed down onto the postings being merged.
+    fn merge_from(&mut self, src: Holder) {
+        let dest = &mut self.trn;
+        let mut src = src.trn;
+        if dest.raw.code.is_none() {
+            dest.raw.code = src.raw.code;
+        }
+        if dest.raw.effective_date.is_none() {
+            dest.raw.effective_date = src.raw.effective_date;
+        }
+        src.comment
+            .value_tags
+            .retain(|key, _| !dest.comment.value_tags.contains_key(key));
+        dest.comment.merge_from(src.comment);
+    }
+
     pub fn iter_posting_indices(&'_ self) -> impl Iterator<Item = posting::Index> + '_ {
         self.postings.iter().copied()
     }
+
+    pub fn posts_added(&self) -> bool {
+        self.posts_added
+    }
 }
@@ -0,0 +1,66 @@
+//! Trust levels of merged inputs, which decide how far their values replace
+//! those of the postings that they are merged into.
+
+use std::str::FromStr;
+
+use anyhow::{bail, Error, Result};
+
+use crate::merge::account::AccountPolicy;
+use crate::merge::status::StatusPolicy;
+
+/// How far an input is trusted over the journal that it is merged into.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Trust {
+    /// Merge according to the status and account policies.
+    #[default]
+    Normal,
+    /// Statuses, known accounts, balance assertions and value tags from the
+    /// input replace those of existing postings, e.g. for bank statements.
+    Authoritative,
+    /// Only fill in what existing postings lack: statuses, accounts where
+    /// unknown, balance assertions and value tags, e.g. for hand entered
+    /// journals.
+    Secondary,
+}
+
+impl FromStr for Trust {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "normal" => Ok(Self::Normal),
+            "authoritative" => Ok(Self::Authoritative),
+            "secondary" => Ok(Self::Secondary),
+            _ => bail!("invalid trust level: {:?}", s),
+        }
+    }
+}
+
+impl Trust {
+    /// Returns the status policy to merge with, given the configured one.
+    pub fn status_policy(self, status_policy: StatusPolicy) -> StatusPolicy {
+        match self {
+            Self::Authoritative => StatusPolicy::Latest,
+            Self::Normal | Self::Secondary => status_policy,
+        }
+    }
+
+    /// Returns the account policy to merge with, given the configured one.
+    pub fn account_policy(self, account_policy: AccountPolicy) -> AccountPolicy {
+        match self {
+            Self::Normal => account_policy,
+            Self::Authoritative => AccountPolicy::Update,
+            Self::Secondary => AccountPolicy::Keep,
+        }
+    }
+
+    /// Whether values from the input only fill gaps in existing postings.
+    pub fn fills_gaps_only(self) -> bool {
+        self == Self::Secondary
+    }
+
+    /// Whether balance assertions from the input replace existing ones.
+    pub fn overwrites_balances(self) -> bool {
+        self == Self::Authoritative
+    }
+}
@@ -0,0 +1,273 @@
+//! Monetary amounts: parsing them as written in bank statements, and
+//! constructing `ledger_parser::Amount`s of any commodity.
+
+use std::fmt;
+use std::marker::PhantomData;
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use ledger_parser::{Amount, Commodity, CommodityPosition};
+use rust_decimal::Decimal;
+use serde::de::{self, Deserialize, Deserializer};
+
+use crate::fingerprint::{Accumulator, Fingerprintable};
+
+/// The commodity name used for pounds sterling.
+pub const GBP: &str = "GBP";
+
+/// Currency symbols that may prefix a quantity, and are ignored.
+const CURRENCY_SYMBOLS: &[char] = &['£', '$', '€'];
+
+/// Describes the separators used when writing a number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct NumberFormat {
+    pub decimal_separator: char,
+    pub thousands_separator: Option<char>,
+}
+
+impl NumberFormat {
+    /// Numbers written like "1,234.56".
+    pub const UK: Self = Self {
+        decimal_separator: '.',
+        thousands_separator: Some(','),
+    };
+    /// Numbers written like "1.234,56".
+    pub const COMMA_DECIMAL: Self = Self {
+        decimal_separator: ',',
+        thousands_separator: Some('.'),
+    };
+}
+
+impl FromStr for NumberFormat {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "uk" => Ok(Self::UK),
+            "comma-decimal" => Ok(Self::COMMA_DECIMAL),
+            _ => bail!("invalid number format: {:?}", s),
+        }
+    }
+}
+
+/// Parses a quantity as written in a statement.
+///
+/// Accepts an optional currency symbol, thousands separators, and negative
+/// values written as "-12.34", "£-12.34", "(12.34)" or "12.34 DR". A "CR"
+/// suffix is accepted and ignored.
+pub fn parse_quantity(s: &str, format: NumberFormat) -> Result<Decimal> {
+    let bad = || anyhow!("bad monetary amount {:?}", s);
+
+    let mut negative = false;
+    let mut rest = s.trim();
+
+    if let Some(inner) = rest.strip_prefix('(').and_then(|r| r.strip_suffix(')')) {
+        negative = true;
+        rest = inner.trim();
+    }
+    if let Some(r) = strip_suffix_ignore_case(rest, "DR") {
+        negative = !negative;
+        rest = r.trim_end();
+    } else if let Some(r) = strip_suffix_ignore_case(rest, "CR") {
+        rest = r.trim_end();
+    }
+
+    // Sign and currency symbol may appear in either order.
+    for _ in 0..2 {
+        if let Some(r) = rest.strip_prefix('-') {
+            negative = !negative;
+            rest = r;
+        } else if let Some(r) = rest.strip_prefix('+') {
+            rest = r;
+        }
+        rest = rest.trim_start_matches(CURRENCY_SYMBOLS);
+    }
+
+    let (integer, fraction) = match rest.split_once(format.decimal_separator) {
+        Some((integer, fraction)) => (integer, Some(fraction)),
+        None => (rest, None),
+    };
+    let integer: String = match format.thousands_separator {
+        Some(sep) => {
+            let groups: Vec<&str> = integer.split(sep).collect();
+            let well_grouped = groups.len() == 1
+                || (!groups[0].is_empty()
+                    && groups[0].len() <= 3
+                    && groups[1..].iter().all(|group| group.len() == 3));
+            if !well_grouped {
+                return Err(bad());
+            }
+            groups.concat()
+        }
+        None => integer.to_string(),
+    };
+
+    let all_digits = |v: &str| !v.is_empty() && v.chars().all(|c| c.is_ascii_digit());
+    if !all_digits(&integer) || !fraction.map(all_digits).unwrap_or(true) {
+        return Err(bad());
+    }
+
+    let normalized = match fraction {
+        Some(fraction) => format!("{}.{}", integer, fraction),
+        None => integer,
+    };
+    let mut quantity = Decimal::from_str(&normalized).map_err(|_| bad())?;
+    quantity.set_sign_negative(negative);
+    Ok(quantity)
+}
+
+/// Returns an amount of `commodity`, which is written before the quantity.
+pub fn amount(quantity: Decimal, commodity: &str) -> Amount {
+    Amount {
+        quantity,
+        commodity: Commodity {
+            name: commodity.to_string(),
+            position: CommodityPosition::Left,
+        },
+    }
+}
+
+/// Parses an amount of `commodity`, with the quantity written as accepted by
+/// `parse_quantity`.
+pub fn parse_amount(s: &str, commodity: &str, format: NumberFormat) -> Result<Amount> {
+    Ok(amount(parse_quantity(s, format)?, commodity))
+}
+
+/// Parses an amount in pounds sterling, as written in UK bank statements.
+pub fn parse_gbp(s: &str) -> Result<Amount> {
+    parse_amount(s, GBP, NumberFormat::UK)
+}
+
+/// Returns the amount with its quantity negated.
+pub fn negate(amount: Amount) -> Amount {
+    Amount {
+        quantity: -amount.quantity,
+        commodity: amount.commodity,
+    }
+}
+
+/// Returns a zero amount of the same commodity as `amount`.
+pub fn zero_like(amount: &Amount) -> Amount {
+    Amount {
+        quantity: Decimal::ZERO,
+        commodity: amount.commodity.clone(),
+    }
+}
+
+/// A currency that a statement format writes all of its amounts in.
+pub trait Currency {
+    const COMMODITY: &'static str;
+    const FORMAT: NumberFormat;
+}
+
+/// Pounds sterling, written like "£1,234.56".
+#[derive(Clone, Copy, Debug)]
+pub struct Gbp;
+
+impl Currency for Gbp {
+    const COMMODITY: &'static str = GBP;
+    const FORMAT: NumberFormat = NumberFormat::UK;
+}
+
+/// An amount of currency `C`, deserialized from a string as written in a
+/// statement.
+#[derive(Clone, Debug)]
+pub struct Money<C> {
+    amount: Amount,
+    currency: PhantomData<C>,
+}
+
+impl<C> Money<C> {
+    pub fn into_amount(self) -> Amount {
+        self.amount
+    }
+}
+
+impl<C> Fingerprintable for &Money<C> {
+    fn fingerprint(self, acc: Accumulator) -> Accumulator {
+        acc.with(&self.amount)
+    }
+}
+
+impl<'de, C: Currency> Deserialize<'de> for Money<C> {
+    fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+        d.deserialize_str(MoneyVisitor(PhantomData))
+    }
+}
+
+struct MoneyVisitor<C>(PhantomData<C>);
+
+impl<C: Currency> de::Visitor<'_> for MoneyVisitor<C> {
+    type Value = Money<C>;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(formatter, "a monetary value in {}", C::COMMODITY)
+    }
+
+    fn visit_str<E: de::Error>(self, s: &str) -> Result<Self::Value, E> {
+        let amount = parse_amount(s, C::COMMODITY, C::FORMAT).map_err(de::Error::custom)?;
+        Ok(Money {
+            amount,
+            currency: PhantomData,
+        })
+    }
+}
+
+fn strip_suffix_ignore_case<'a>(s: &'a str, suffix: &str) -> Option<&'a str> {
+    let split = s.len().checked_sub(suffix.len())?;
+    if s.is_char_boundary(split) && s[split..].eq_ignore_ascii_case(suffix) {
+        Some(&s[..split])
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    #[test_case("12.34", NumberFormat::UK => "12.34"; "plain")]
+    #[test_case("12", NumberFormat::UK => "12"; "integer")]
+    #[test_case("-12.34", NumberFormat::UK => "-12.34"; "negative")]
+    #[test_case("£12.34", NumberFormat::UK => "12.34"; "currency_symbol")]
+    #[test_case("£-12.34", NumberFormat::UK => "-12.34"; "symbol_then_sign")]
+    #[test_case("-£12.34", NumberFormat::UK => "-12.34"; "sign_then_symbol")]
+    #[test_case("1,234,567.89", NumberFormat::UK => "1234567.89"; "thousands")]
+    #[test_case("(12.34)", NumberFormat::UK => "-12.34"; "parenthesised")]
+    #[test_case("12.34 DR", NumberFormat::UK => "-12.34"; "debit_suffix")]
+    #[test_case("12.34dr", NumberFormat::UK => "-12.34"; "lower_debit_suffix")]
+    #[test_case("12.34 CR", NumberFormat::UK => "12.34"; "credit_suffix")]
+    #[test_case("1.234,56", NumberFormat::COMMA_DECIMAL => "1234.56"; "comma_decimal")]
+    #[test_case("-0,5", NumberFormat::COMMA_DECIMAL => "-0.5"; "comma_decimal_negative")]
+    fn parse_quantity_valid(s: &str, format: NumberFormat) -> String {
+        parse_quantity(s, format).expect("should parse").to_string()
+    }
+
+    #[test_case("", NumberFormat::UK; "empty")]
+    #[test_case("£", NumberFormat::UK; "symbol_only")]
+    #[test_case("12.34.56", NumberFormat::UK; "two_decimal_points")]
+    #[test_case("1,23.45", NumberFormat::UK; "bad_grouping")]
+    #[test_case(",123.45", NumberFormat::UK; "leading_separator")]
+    #[test_case("12.", NumberFormat::UK; "empty_fraction")]
+    #[test_case("12a", NumberFormat::UK; "letters")]
+    #[test_case("1.234,56", NumberFormat::UK; "wrong_locale")]
+    fn parse_quantity_invalid(s: &str, format: NumberFormat) {
+        assert!(parse_quantity(s, format).is_err());
+    }
+
+    #[test]
+    fn deserialize_money() {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .from_reader("\"£1,234.50\",\n".as_bytes());
+        let record: (Money<Gbp>, Option<Money<Gbp>>) = reader
+            .deserialize()
+            .next()
+            .expect("record")
+            .expect("deserialize");
+        assert_eq!(record.0.into_amount(), parse_gbp("1234.50").unwrap());
+        assert!(record.1.is_none());
+    }
+}
@@ -0,0 +1,276 @@
+//! Normalizing the decimal precision of amounts per commodity, and other
+//! cleanups of journals.
+
+use std::collections::HashMap;
+use std::str::FromStr;
+
+use anyhow::{anyhow, Error, Result};
+use chrono::NaiveDate;
+use clap::Args;
+use ledger_parser::{Amount, Balance, Posting, Transaction};
+use rust_decimal::RoundingStrategy;
+
+use crate::comment::Comment;
+use crate::filespec::{self, FileSpec, WriteBatch};
+use crate::fingerprint;
+use crate::internal::TransactionPostings;
+use crate::ledgerutil::Journal;
+
+/// The number of decimal places to use for a commodity.
+#[derive(Clone, Debug)]
+pub struct CommodityPrecision {
+    commodity: String,
+    places: u32,
+}
+
+impl FromStr for CommodityPrecision {
+    type Err = Error;
+
+    /// Parses from the form `COMMODITY=PLACES`, e.g. `GBP=2`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (commodity, places) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected COMMODITY=PLACES, got {:?}", s))?;
+        let places = places
+            .parse()
+            .map_err(|e| anyhow!("parsing decimal places in {:?}: {}", s, e))?;
+        Ok(Self {
+            commodity: commodity.to_string(),
+            places,
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct PrecisionOpts {
+    /// Sets the number of decimal places for amounts of a commodity, given as
+    /// COMMODITY=PLACES (e.g. GBP=2). May be given multiple times. Amounts
+    /// with more decimal places are rounded, with a warning.
+    #[arg(long = "precision", value_name = "COMMODITY=PLACES")]
+    precision: Vec<CommodityPrecision>,
+}
+
+impl PrecisionOpts {
+    /// Returns the configured number of decimal places for each commodity.
+    pub fn places(&self) -> HashMap<String, u32> {
+        self.precision
+            .iter()
+            .map(|p| (p.commodity.clone(), p.places))
+            .collect()
+    }
+
+    /// Normalizes the amounts in the transactions to the configured
+    /// precisions, warning of any rounding.
+    pub fn normalize_transactions(&self, trns: &mut [Transaction]) {
+        if self.precision.is_empty() {
+            return;
+        }
+        let places = self.places();
+        for warning in normalize_transactions(trns.iter_mut(), &places) {
+            eprintln!("warning: {}", warning);
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct Cmd {
+    /// The Ledger journals to normalize.
+    journals: Vec<FileSpec>,
+    /// Commodities that are not given a precision use the largest number of
+    /// decimal places found for them in each journal.
+    #[command(flatten)]
+    precision: PrecisionOpts,
+    /// Keep at most this many fingerprint tags of each fingerprint algorithm
+    /// and namespace on each posting, preferring those of the newest
+    /// algorithm versions. Postings accumulate fingerprints when merging
+    /// statements imported with different versions of an importer. Legacy
+    /// fingerprint tags are never removed.
+    #[arg(
+        long = "max-fingerprints",
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    max_fingerprints: Option<usize>,
+    /// Check that each normalized journal parses back into the same
+    /// transactions, and fail without writing any journal if not.
+    #[arg(long = "verify-roundtrip")]
+    verify_roundtrip: bool,
+}
+
+impl Cmd {
+    pub fn run(&self) -> Result<()> {
+        filespec::check_single_stdin(&self.journals)?;
+        let mut batch = WriteBatch::new().with_roundtrip_verification(self.verify_roundtrip);
+        for ledger_file in &self.journals {
+            let mut ledger = filespec::read_ledger_file(ledger_file)?;
+            let mut places = max_places(&ledger);
+            places.extend(self.precision.places());
+            for warning in normalize_transactions(ledger.transactions_mut(), &places) {
+                eprintln!("warning: {}: {}", ledger_file, warning);
+            }
+            if let Some(max) = self.max_fingerprints {
+                let removed = limit_fingerprints(ledger.transactions_mut(), max);
+                if removed > 0 {
+                    eprintln!("{}: removed {} fingerprint tags", ledger_file, removed);
+                }
+            }
+            batch.add_ledger(ledger_file, &ledger)?;
+        }
+
+        batch.commit()
+    }
+}
+
+/// Returns the largest number of decimal places used by each commodity in
+/// posting amounts and balances.
+fn max_places(ledger: &Journal) -> HashMap<String, u32> {
+    let mut places = HashMap::<String, u32>::new();
+    for trn in ledger.transactions() {
+        for amount in trn.postings.iter().flat_map(posting_amounts) {
+            let entry = places.entry(amount.commodity.name.clone()).or_default();
+            *entry = (*entry).max(amount.quantity.scale());
+        }
+    }
+    places
+}
+
+fn posting_amounts(post: &ledger_parser::Posting) -> impl Iterator<Item = &Amount> {
+    let amount = post.amount.as_ref().map(|amt| &amt.amount);
+    let balance = match &post.balance {
+        Some(Balance::Amount(balance)) => Some(balance),
+        _ => None,
+    };
+    amount.into_iter().chain(balance)
+}
+
+/// Limits the fingerprint tags on each posting, as for
+/// `fingerprint::limit_fingerprints`. Returns the number of tags removed.
+fn limit_fingerprints<'a>(trns: impl Iterator<Item = &'a mut Transaction>, max: usize) -> usize {
+    let mut removed = 0;
+    for trn in trns {
+        for post in &mut trn.postings {
+            let mut comment = Comment::from_opt_string(&post.comment);
+            let post_removed = fingerprint::limit_fingerprints(&mut comment.tags, max);
+            if post_removed > 0 {
+                post.comment = comment.into_opt_comment();
+                removed += post_removed;
+            }
+        }
+    }
+    removed
+}
+
+/// Normalizes posting amounts and balances of commodities in `places`.
+/// Returns warnings for any amounts that lost precision.
+fn normalize_transactions<'a>(
+    trns: impl Iterator<Item = &'a mut Transaction>,
+    places: &HashMap<String, u32>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for trn in trns {
+        for post in &mut trn.postings {
+            normalize_posting(&trn.date, &trn.description, post, places, &mut warnings);
+        }
+    }
+    warnings
+}
+
+/// As `normalize_transactions`, but for transactions being processed by
+/// rules.
+pub(crate) fn normalize_transaction_postings(
+    trns: &mut [TransactionPostings],
+    places: &HashMap<String, u32>,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+    for trn in trns {
+        for post in &mut trn.posts {
+            normalize_posting(
+                &trn.trn.raw.date,
+                &trn.trn.raw.description,
+                &mut post.raw,
+                places,
+                &mut warnings,
+            );
+        }
+    }
+    warnings
+}
+
+fn normalize_posting(
+    date: &NaiveDate,
+    description: &str,
+    post: &mut Posting,
+    places: &HashMap<String, u32>,
+    warnings: &mut Vec<String>,
+) {
+    let amount = post.amount.as_mut().map(|amt| &mut amt.amount);
+    let balance = match &mut post.balance {
+        Some(Balance::Amount(balance)) => Some(balance),
+        _ => None,
+    };
+    for amount in amount.into_iter().chain(balance) {
+        if let Some(lost) = normalize_amount(amount, places) {
+            warnings.push(format!(
+                "{} {:?}: rounded {} to {}",
+                date, description, lost, amount
+            ));
+        }
+    }
+}
+
+/// Sets the number of decimal places of the amount if its commodity is in
+/// `places`, rounding halves away from zero as banks do. Returns the
+/// original amount if rounding lost precision.
+fn normalize_amount(amount: &mut Amount, places: &HashMap<String, u32>) -> Option<Amount> {
+    let dp = *places.get(&amount.commodity.name)?;
+    let original = amount.clone();
+    amount.quantity = amount
+        .quantity
+        .round_dp_with_strategy(dp, RoundingStrategy::MidpointAwayFromZero);
+    amount.quantity.rescale(dp);
+    if amount.quantity != original.quantity {
+        Some(original)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    #[test_case("GBP10.5", 2 => ("GBP10.50".to_string(), false); "pads")]
+    #[test_case("GBP10.500", 2 => ("GBP10.50".to_string(), false); "trims zeros")]
+    #[test_case("GBP10.505", 2 => ("GBP10.51".to_string(), true); "rounds half away from zero")]
+    #[test_case("GBP-10.505", 2 => ("GBP-10.51".to_string(), true); "rounds negative half away from zero")]
+    #[test_case("GBP10.504", 2 => ("GBP10.50".to_string(), true); "rounds down")]
+    #[test_case("USD10.5", 2 => ("USD10.5".to_string(), false); "other commodity")]
+    fn normalize_amount(amount: &str, places: u32) -> (String, bool) {
+        let ledger =
+            ledger_parser::parse(&format!("2001/01/01 t\n  a  {}\n  b\n", amount)).expect("parse");
+        let mut amount = match &ledger.items[0] {
+            ledger_parser::LedgerItem::Transaction(trn) => {
+                trn.postings[0].amount.clone().unwrap().amount
+            }
+            _ => panic!("expected transaction"),
+        };
+        let places = HashMap::from([("GBP".to_string(), places)]);
+        let lost = super::normalize_amount(&mut amount, &places);
+        (amount.to_string(), lost.is_some())
+    }
+
+    #[test]
+    fn max_places_by_commodity() {
+        let ledger = crate::ledgerutil::parse(
+            "2001/01/01 t\n  a  GBP10.5\n  b  GBP-10.500 = GBP0.0\n  c  USD1\n",
+        )
+        .expect("parse");
+        let got = max_places(&ledger);
+        assert_eq!(
+            got,
+            HashMap::from([("GBP".to_string(), 3), ("USD".to_string(), 0)])
+        );
+    }
+}
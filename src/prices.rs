@@ -0,0 +1,87 @@
+//! Commodity price (`P`) directives.
+
+use std::collections::HashSet;
+
+use ledger_parser::CommodityPrice;
+
+/// Collects commodity price directives, ignoring exact duplicates.
+#[derive(Debug, Default)]
+pub struct Prices {
+    seen: HashSet<String>,
+    prices: Vec<CommodityPrice>,
+}
+
+impl Prices {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a price, returning false if an identical price was already added.
+    pub fn add(&mut self, price: CommodityPrice) -> bool {
+        if self.seen.insert(price.to_string()) {
+            self.prices.push(price);
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.prices.is_empty()
+    }
+
+    /// Returns the prices ordered by date and time. Prices at the same time
+    /// keep the order in which they were added.
+    pub fn into_sorted(self) -> Vec<CommodityPrice> {
+        let mut prices = self.prices;
+        prices.sort_by_key(|price| price.datetime);
+        prices
+    }
+}
+
+impl Extend<CommodityPrice> for Prices {
+    fn extend<T: IntoIterator<Item = CommodityPrice>>(&mut self, iter: T) {
+        for price in iter {
+            self.add(price);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+
+    use super::*;
+    use crate::money;
+
+    fn price(day: u32, commodity: &str, quantity: i64) -> CommodityPrice {
+        CommodityPrice {
+            datetime: NaiveDate::from_ymd_opt(2001, 1, day)
+                .unwrap()
+                .and_hms_opt(0, 0, 0)
+                .unwrap(),
+            commodity_name: commodity.to_string(),
+            amount: money::amount(Decimal::new(quantity, 2), money::GBP),
+        }
+    }
+
+    #[test]
+    fn dedups_and_sorts() {
+        let mut prices = Prices::new();
+        prices.extend([
+            price(2, "USD", 80),
+            price(1, "USD", 79),
+            price(2, "USD", 80),
+            price(2, "EUR", 85),
+        ]);
+        assert_eq!(
+            prices.into_sorted(),
+            vec![
+                price(1, "USD", 79),
+                price(2, "USD", 80),
+                price(2, "EUR", 85)
+            ]
+        );
+    }
+}
@@ -0,0 +1,455 @@
+//! A queue of the postings in a journal, and in the unmerged transactions
+//! left by `merge`, that need a human to look at them: postings with unknown
+//! accounts, ambiguous merge candidates, and postings that rules flagged for
+//! review.
+
+use std::collections::HashMap;
+use std::fmt;
+
+use anyhow::{anyhow, bail, Result};
+use clap::{Args, Subcommand};
+use sha1::{Digest, Sha1};
+
+use crate::candidates;
+use crate::filespec::{self, FileSpec};
+use crate::internal::{JournalTransactions, PostingInternal, TransactionPostings};
+use crate::tags;
+
+/// Number of hex digits of the posting hash used in item ids.
+const ID_HASH_LEN: usize = 8;
+
+#[derive(Debug, Subcommand)]
+pub enum ReviewCommand {
+    #[command(name = "list")]
+    /// Lists the items needing review, ordered by date.
+    List(ListCmd),
+    #[command(name = "resolve")]
+    /// Resolves an item listed by `review list`, updating the file that it
+    /// is in.
+    Resolve(ResolveCmd),
+}
+
+impl ReviewCommand {
+    pub fn run(&self) -> Result<()> {
+        use ReviewCommand::*;
+        match self {
+            List(cmd) => cmd.run(),
+            Resolve(cmd) => cmd.run(),
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct Files {
+    /// The Ledger journal to review.
+    journal: FileSpec,
+    /// The unmerged transactions written by `merge --unmerged`, to review
+    /// along with the journal.
+    #[arg(long = "unmerged")]
+    unmerged: Option<FileSpec>,
+}
+
+impl Files {
+    fn read(&self) -> Result<Vec<ReviewFile>> {
+        filespec::check_single_stdin(std::iter::once(&self.journal).chain(&self.unmerged))?;
+        std::iter::once(&self.journal)
+            .chain(&self.unmerged)
+            .map(ReviewFile::read)
+            .collect()
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ListCmd {
+    #[command(flatten)]
+    files: Files,
+}
+
+impl ListCmd {
+    pub fn run(&self) -> Result<()> {
+        let files = self.files.read()?;
+        let trns: Vec<&[TransactionPostings]> =
+            files.iter().map(|f| f.contents.trns.as_slice()).collect();
+        for item in find_items(&trns) {
+            println!("{}", item.describe(&files));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Args)]
+pub struct ResolveCmd {
+    /// The id of the item to resolve, as listed by `review list`.
+    id: String,
+    #[command(flatten)]
+    files: Files,
+    #[command(flatten)]
+    resolution: Resolution,
+}
+
+#[derive(Debug, Args)]
+#[group(required = true, multiple = false)]
+struct Resolution {
+    /// Sets the posting's account, for an unknown-account or needs-review
+    /// item. This removes the unknown-account and needs-review tags.
+    #[arg(long = "account")]
+    account: Option<String>,
+    /// Picks the candidate with this fingerprint (e.g. fp-abc, with or
+    /// without the "candidate-" prefix) for a candidates item, so that the
+    /// posting merges into it when the file is next merged. This removes the
+    /// other candidate tags.
+    #[arg(long = "pick-candidate", value_name = "FINGERPRINT")]
+    pick_candidate: Option<String>,
+}
+
+impl ResolveCmd {
+    pub fn run(&self) -> Result<()> {
+        let mut files = self.files.read()?;
+        let trns: Vec<&[TransactionPostings]> =
+            files.iter().map(|f| f.contents.trns.as_slice()).collect();
+        let item = find_items(&trns)
+            .into_iter()
+            .find(|item| item.id == self.id)
+            .ok_or_else(|| anyhow!("no item {:?} needs review", self.id))?;
+        let mut file = files.swap_remove(item.file);
+        let trn = &mut file.contents.trns[item.trn];
+        resolve(item.kind, &mut trn.posts[item.post], &self.resolution)
+            .map_err(|e| anyhow!("cannot resolve {}: {}", item.id, e))?;
+        if !trn
+            .posts
+            .iter()
+            .any(|post| post.comment.tags.contains(tags::UNKNOWN_ACCOUNT))
+        {
+            trn.trn.comment.tags.remove(tags::NEEDS_CLASSIFICATION);
+        }
+        let spec = file.spec.clone();
+        file.write()?;
+        if item.kind == Kind::Candidates && item.file > 0 {
+            eprintln!(
+                "resolved {}; merge {} again to merge it into the journal",
+                item.id, spec
+            );
+        } else {
+            eprintln!("resolved {}", item.id);
+        }
+        Ok(())
+    }
+}
+
+/// A journal being reviewed.
+struct ReviewFile {
+    spec: FileSpec,
+    contents: JournalTransactions,
+}
+
+impl ReviewFile {
+    fn read(spec: &FileSpec) -> Result<Self> {
+        Ok(Self {
+            spec: spec.clone(),
+            contents: filespec::read_ledger_file(spec)?.into(),
+        })
+    }
+
+    fn write(self) -> Result<()> {
+        filespec::write_ledger_file(&self.spec, &self.contents.into_journal())
+    }
+}
+
+/// Why a posting needs review.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Kind {
+    /// The importer did not know the account, and no rule set it.
+    UnknownAccount,
+    /// Merging found several postings that the posting might be.
+    Candidates,
+    /// A rule tagged the posting for review.
+    NeedsReview,
+}
+
+impl Kind {
+    const ALL: [Kind; 3] = [Kind::UnknownAccount, Kind::Candidates, Kind::NeedsReview];
+
+    fn id_prefix(self) -> &'static str {
+        match self {
+            Kind::UnknownAccount => "u",
+            Kind::Candidates => "c",
+            Kind::NeedsReview => "r",
+        }
+    }
+
+    fn applies_to(self, post: &PostingInternal) -> bool {
+        let comment = &post.comment;
+        match self {
+            Kind::UnknownAccount => comment.tags.contains(tags::UNKNOWN_ACCOUNT),
+            Kind::Candidates => comment
+                .tags_with_prefix(tags::CANDIDATE_FP_PREFIX)
+                .next()
+                .is_some(),
+            Kind::NeedsReview => comment.tags.contains(tags::NEEDS_REVIEW),
+        }
+    }
+}
+
+impl fmt::Display for Kind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Kind::UnknownAccount => "unknown-account",
+            Kind::Candidates => "candidates",
+            Kind::NeedsReview => "needs-review",
+        })
+    }
+}
+
+/// A posting needing review.
+#[derive(Debug, PartialEq, Eq)]
+struct Item {
+    /// Identifies the item across runs, as long as the posting's
+    /// fingerprints (or, if it has none, its details) do not change.
+    id: String,
+    kind: Kind,
+    /// Index of the file that the posting is in: the journal, then the
+    /// unmerged transactions.
+    file: usize,
+    trn: usize,
+    post: usize,
+}
+
+impl Item {
+    /// Describes the item on a single line.
+    fn describe(&self, files: &[ReviewFile]) -> String {
+        let trn = &files[self.file].contents.trns[self.trn];
+        let post = &trn.posts[self.post];
+        let mut line = format!(
+            "{}  {}  {:<15}  {:?}  {}",
+            self.id, trn.trn.raw.date, self.kind, trn.trn.raw.description, post.raw.account
+        );
+        if let Some(amount) = &post.raw.amount {
+            line.push_str(&format!("  {}", amount.amount));
+        }
+        if self.file > 0 {
+            line.push_str(&format!("  (in {})", files[self.file].spec));
+        }
+        if self.kind == Kind::Candidates {
+            let mut candidates: Vec<&str> = post
+                .comment
+                .tags_with_prefix(tags::CANDIDATE_FP_PREFIX)
+                .map(|tag| &tag[tags::CANDIDATE_FP_PREFIX.len()..])
+                .collect();
+            candidates.sort();
+            line.push_str(&format!("  candidates: {}", candidates.join(", ")));
+            if let Some(hint) = post.comment.value_tags.get(tags::CANDIDATE_HINT) {
+                line.push_str(&format!("  (best: {})", hint));
+            }
+        }
+        line
+    }
+}
+
+/// Finds the postings needing review in `files`, ordered by date and then
+/// by their position in the files.
+fn find_items(files: &[&[TransactionPostings]]) -> Vec<Item> {
+    let mut items = Vec::new();
+    let mut id_counts = HashMap::<String, usize>::new();
+    for (file, trns) in files.iter().enumerate() {
+        for (trn_idx, trn) in trns.iter().enumerate() {
+            for (post_idx, post) in trn.posts.iter().enumerate() {
+                for kind in Kind::ALL.into_iter().filter(|kind| kind.applies_to(post)) {
+                    let mut id = format!("{}-{}", kind.id_prefix(), posting_hash(trn, post));
+                    // Identical postings without fingerprints are told apart
+                    // by their order.
+                    let count = id_counts.entry(id.clone()).or_default();
+                    *count += 1;
+                    if *count > 1 {
+                        id = format!("{}.{}", id, count);
+                    }
+                    items.push(Item {
+                        id,
+                        kind,
+                        file,
+                        trn: trn_idx,
+                        post: post_idx,
+                    });
+                }
+            }
+        }
+    }
+    items.sort_by_key(|item| files[item.file][item.trn].trn.raw.date);
+    items
+}
+
+/// Hashes the posting's fingerprints, or its details if it has none.
+fn posting_hash(trn: &TransactionPostings, post: &PostingInternal) -> String {
+    let mut fingerprints: Vec<&str> = post
+        .comment
+        .tags_with_prefix(tags::FINGERPRINT_PREFIX)
+        .collect();
+    fingerprints.sort();
+    let mut hasher = Sha1::new();
+    if fingerprints.is_empty() {
+        hasher.update(format!(
+            "{}\n{}\n{}\n",
+            trn.trn.raw.date, trn.trn.raw.description, post.raw.account
+        ));
+        if let Some(amount) = &post.raw.amount {
+            hasher.update(amount.amount.to_string());
+        }
+    } else {
+        for fp in fingerprints {
+            hasher.update(fp);
+            hasher.update("\n");
+        }
+    }
+    let mut hash = format!("{:x}", hasher.finalize());
+    hash.truncate(ID_HASH_LEN);
+    hash
+}
+
+/// Applies `resolution` to a posting needing review of the given kind.
+fn resolve(kind: Kind, post: &mut PostingInternal, resolution: &Resolution) -> Result<()> {
+    match (kind, resolution) {
+        (
+            Kind::UnknownAccount | Kind::NeedsReview,
+            Resolution {
+                account: Some(account),
+                ..
+            },
+        ) => {
+            post.raw.account = account.clone();
+            post.comment.tags.remove(tags::UNKNOWN_ACCOUNT);
+            post.comment.tags.remove(tags::NEEDS_REVIEW);
+        }
+        (
+            Kind::Candidates,
+            Resolution {
+                pick_candidate: Some(fp),
+                ..
+            },
+        ) => candidates::pick_candidate(post, fp)?,
+        (Kind::Candidates, _) => bail!("a candidates item is resolved with --pick-candidate"),
+        (_, _) => bail!("{} items are resolved with --account", kind),
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    fn journal() -> Vec<TransactionPostings> {
+        parse_transaction_postings(
+            "
+            2001/01/03 later
+                assets:bank  GBP-2.00  ; :fp-b:import-self:
+                expenses:food  GBP2.00  ; :fp-b:needs-review:
+
+            2001/01/01 earlier
+                assets:bank  GBP-1.00  ; :fp-a:import-self:
+                expenses:unknown  GBP1.00  ; :fp-a:unknown-account:",
+        )
+    }
+
+    fn unmerged() -> Vec<TransactionPostings> {
+        parse_transaction_postings(
+            "
+            2001/01/02 ambiguous
+                assets:bank  GBP-1.00  ; :fp-c:candidate-fp-x:candidate-fp-y:
+                ; candidate-hint: 2001-01-02 shop GBP-1.00 (1 of 2)
+                expenses:unknown  GBP1.00",
+        )
+    }
+
+    fn kinds_and_positions(items: &[Item]) -> Vec<(Kind, usize, usize, usize)> {
+        items
+            .iter()
+            .map(|item| (item.kind, item.file, item.trn, item.post))
+            .collect()
+    }
+
+    #[test]
+    fn finds_items_in_date_order() {
+        let (journal, unmerged) = (journal(), unmerged());
+        let items = find_items(&[&journal, &unmerged]);
+        assert_eq!(
+            kinds_and_positions(&items),
+            vec![
+                (Kind::UnknownAccount, 0, 1, 1),
+                (Kind::Candidates, 1, 0, 0),
+                (Kind::NeedsReview, 0, 0, 1),
+            ]
+        );
+        let ids: Vec<&str> = items.iter().map(|item| item.id.as_str()).collect();
+        assert!(ids[0].starts_with("u-"), "{:?}", ids);
+        assert!(ids[1].starts_with("c-"), "{:?}", ids);
+        assert!(ids[2].starts_with("r-"), "{:?}", ids);
+    }
+
+    #[test]
+    fn ids_are_stable_across_edits() {
+        let (mut journal, unmerged) = (journal(), unmerged());
+        let before = find_items(&[&journal, &unmerged]);
+        journal[1].posts[1].raw.account = "expenses:still:unknown".to_string();
+        journal.remove(0);
+        let after = find_items(&[&journal, &unmerged]);
+        assert_eq!(before[0].id, after[0].id);
+        assert_eq!(before[1].id, after[1].id);
+    }
+
+    #[test]
+    fn resolves_account() {
+        let mut trns = journal();
+        let items = find_items(&[&trns]);
+        let resolution = Resolution {
+            account: Some("expenses:food".to_string()),
+            pick_candidate: None,
+        };
+        for item in &items {
+            resolve(item.kind, &mut trns[item.trn].posts[item.post], &resolution).expect("resolve");
+        }
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/03 later
+                    assets:bank  GBP-2.00  ; :fp-b:import-self:
+                    expenses:food  GBP2.00  ; :fp-b:
+
+                2001/01/01 earlier
+                    assets:bank  GBP-1.00  ; :fp-a:import-self:
+                    expenses:food  GBP1.00  ; :fp-a:",
+            )
+        );
+    }
+
+    #[test]
+    fn resolves_candidate() {
+        let mut trns = unmerged();
+        let pick = |fp: &str| Resolution {
+            account: None,
+            pick_candidate: Some(fp.to_string()),
+        };
+        let post = &mut trns[0].posts[0];
+        assert!(resolve(Kind::Candidates, post, &pick("fp-z")).is_err());
+        resolve(Kind::Candidates, post, &pick("candidate-fp-y")).expect("resolve");
+        assert_transaction_postings_eq!(
+            trns,
+            parse_transaction_postings(
+                "
+                2001/01/02 ambiguous
+                    assets:bank  GBP-1.00  ; :fp-c:fp-y:
+                    expenses:unknown  GBP1.00",
+            )
+        );
+    }
+
+    #[test]
+    fn rejects_mismatched_resolution() {
+        let mut trns = unmerged();
+        let resolution = Resolution {
+            account: Some("assets:other".to_string()),
+            pick_candidate: None,
+        };
+        assert!(resolve(Kind::Candidates, &mut trns[0].posts[0], &resolution).is_err());
+    }
+}
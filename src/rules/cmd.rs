@@ -1,9 +1,12 @@
 use anyhow::Result;
 use clap::{Args, Subcommand};
 
-use crate::filespec::{self, FileSpec};
-use crate::internal::TransactionPostings;
+use crate::datefilter::DateFilter;
+use crate::filespec::{self, FileSpec, ReadOptions, WriteBatch};
+use crate::includes::{self, IncludedJournal};
+use crate::internal::JournalTransactions;
 use crate::rules::processor::TransactionProcessorFactory;
+use crate::rules::tagfilter::TagFilter;
 
 #[derive(Debug, Args)]
 pub struct Command {
@@ -16,12 +19,63 @@ pub struct Command {
     /// to stdout.
     #[arg(short = 'o', long = "output", default_value = "-")]
     output: FileSpec,
+    /// Number of threads to apply rules with. 0 uses one thread per CPU.
+    /// Output ordering is the same regardless of this value.
+    #[arg(short = 'j', long = "jobs", default_value = "1")]
+    jobs: usize,
+    /// Follow `include` directives when reading the input journal.
+    #[arg(long = "follow-includes")]
+    follow_includes: bool,
+    /// Write transactions back to the files that they were read from, instead
+    /// of to --output. Requires --follow-includes.
+    #[arg(long = "write-back", requires = "follow_includes")]
+    write_back: bool,
+    /// Fail on suspicious constructs in comments, such as lines that look
+    /// like malformed value tags, tags with invalid characters, or duplicate
+    /// value tags on a posting, instead of treating them as plain text.
+    #[arg(long = "strict", conflicts_with = "follow_includes")]
+    strict: bool,
+    /// Check that each journal written parses back into the same
+    /// transactions, and fail without writing anything if not.
+    #[arg(long = "verify-roundtrip")]
+    verify_roundtrip: bool,
+    /// Rules are only applied to transactions within these dates. Other
+    /// transactions are written unchanged, in their original positions.
+    #[command(flatten)]
+    date_filter: DateFilter,
+    #[command(flatten)]
+    tag_filter: TagFilter,
+}
+
+/// Subcommands for working with rules files.
+#[derive(Debug, Subcommand)]
+pub enum RulesCommand {
+    #[command(name = "test")]
+    /// Runs test cases against a rules table, reporting any failures.
+    Test(crate::rules::testcases::Command),
+    #[command(name = "schema")]
+    /// Lists the entries, predicates and actions that rules files can use.
+    Schema(crate::rules::table::schema::Command),
+}
+
+impl RulesCommand {
+    pub fn run(&self) -> Result<()> {
+        use RulesCommand::*;
+        match self {
+            Test(cmd) => cmd.run(),
+            Schema(cmd) => cmd.run(),
+        }
+    }
 }
 
 #[derive(Debug, Subcommand)]
 enum Engine {
     #[command(name = "table")]
     Table(crate::rules::table::Command),
+    #[command(name = "pipeline")]
+    /// Applies the steps listed in a pipeline file in order, e.g. several
+    /// rules tables followed by normalizing amounts.
+    Pipeline(crate::rules::pipeline::Command),
 }
 
 impl Engine {
@@ -29,6 +83,7 @@ impl Engine {
         use Engine::*;
         match self {
             Table(cmd) => cmd,
+            Pipeline(cmd) => cmd,
         }
     }
 }
@@ -36,13 +91,51 @@ impl Engine {
 impl Command {
     pub fn run(&self) -> Result<()> {
         let processor = self.engine.get_factory().make_processor()?;
-        let ledger = filespec::read_ledger_file(&self.input_journal)?;
-        let trns = TransactionPostings::from_ledger(ledger)?;
+        let (included_journal, mut contents) = if self.follow_includes {
+            let (journal, trns) = IncludedJournal::read(&self.input_journal)?;
+            let contents = JournalTransactions {
+                trns,
+                prices: journal.prices().cloned().collect(),
+                ends: journal.flattened_ends(),
+            };
+            (Some(journal), contents)
+        } else {
+            let options = ReadOptions {
+                strict: self.strict,
+                ..Default::default()
+            };
+            let journal = filespec::read_ledger_file_with(&self.input_journal, options)?;
+            (None, JournalTransactions::from(journal))
+        };
+        let trns = std::mem::take(&mut contents.trns);
 
-        let new_trns = processor.update_transactions(trns)?;
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(self.jobs)
+            .build()?;
+        let mut new_trns = self.date_filter.update(trns, |trns| {
+            pool.install(|| {
+                self.tag_filter.update(trns, |trns, num_posts| {
+                    processor.update_filtered_transactions(trns, num_posts)
+                })
+            })
+        })?;
+        processor.report_stats();
 
-        let ledger = TransactionPostings::into_ledger(new_trns);
-        filespec::write_ledger_file(&self.output, &ledger)?;
-        Ok(())
+        let mut batch = WriteBatch::new().with_roundtrip_verification(self.verify_roundtrip);
+        match included_journal {
+            Some(journal) if self.write_back => {
+                journal.write_back(new_trns, Vec::new(), &mut batch)?;
+            }
+            included_journal => {
+                if included_journal.is_some() {
+                    includes::flatten(&mut new_trns);
+                }
+                contents.trns = new_trns;
+                batch.add_ledger(&self.output, &contents.into_journal())?;
+            }
+        }
+        processor.save_state(&mut batch)?;
+        batch.commit()?;
+        processor.collected_errors()
     }
 }
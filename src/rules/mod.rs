@@ -1,3 +1,7 @@
 pub mod cmd;
+pub mod pipeline;
 mod processor;
-mod table;
+pub mod script;
+pub mod table;
+mod tagfilter;
+pub mod testcases;
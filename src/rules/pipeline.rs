@@ -0,0 +1,289 @@
+//! Pipelines of transaction processors, applied in order.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::{Context, Result};
+use clap::Args;
+use serde_derive::Deserialize;
+
+use crate::filespec::{self, WriteBatch};
+use crate::internal::TransactionPostings;
+use crate::precision;
+use crate::rules::processor::{TransactionProcessor, TransactionProcessorFactory};
+use crate::rules::script::{self, ScriptState};
+use crate::rules::table;
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// The `.ron` file listing the steps to apply to the transactions, in
+    /// order.
+    pipeline: PathBuf,
+    /// A JSON file holding an object that `Script` steps keep across runs,
+    /// which they access as `this`. It is created if it does not exist, and
+    /// written back along with the output.
+    #[arg(long = "state", value_name = "FILE")]
+    state: Option<PathBuf>,
+}
+
+impl TransactionProcessorFactory for Command {
+    fn make_processor(&self) -> Result<Box<dyn TransactionProcessor>> {
+        let state = self
+            .state
+            .as_deref()
+            .map(ScriptState::load)
+            .transpose()?
+            .map(Arc::new);
+        let pipeline = Pipeline::from_path(&self.pipeline, state)?;
+        Ok(Box::new(pipeline))
+    }
+}
+
+/// A step in a pipeline file.
+///
+/// There is no classifier step: accounts are classified by rules tables, or
+/// by a script.
+#[derive(Debug, Deserialize)]
+enum Step {
+    /// Applies a rules table. The path is relative to the pipeline file.
+    Table {
+        rules: PathBuf,
+        #[serde(default)]
+        profile: bool,
+        #[serde(default)]
+        keep_going: bool,
+        /// Directories to look for included rules files in, relative to the
+        /// pipeline file.
+        #[serde(default)]
+        rules_dirs: Vec<PathBuf>,
+    },
+    /// Normalizes the decimal precision of amounts of the given commodities,
+    /// as for the `normalize` subcommand.
+    Normalize { precision: HashMap<String, u32> },
+    /// Updates each posting with the `update` function of a rhai script. The
+    /// path is relative to the pipeline file.
+    Script { script: PathBuf },
+}
+
+impl Step {
+    fn make_processor(
+        &self,
+        base_dir: &Path,
+        state: &Option<Arc<ScriptState>>,
+    ) -> Result<Box<dyn TransactionProcessor>> {
+        use Step::*;
+        Ok(match self {
+            Table {
+                rules,
+                profile,
+                keep_going,
+                rules_dirs,
+            } => {
+                let rules_dirs: Vec<PathBuf> = rules_dirs
+                    .iter()
+                    .map(|dir| base_dir.join(filespec::portable_path(dir)))
+                    .collect();
+                let rules = base_dir.join(filespec::portable_path(rules));
+                let mut table = table::load_from_path_with(&rules, &rules_dirs)?;
+                if *profile {
+                    table.enable_profiling();
+                }
+                if *keep_going {
+                    table.enable_keep_going();
+                }
+                Box::new(table)
+            }
+            Normalize { precision } => Box::new(Normalizer {
+                places: precision.clone(),
+            }),
+            Script { script } => Box::new(
+                script::Script::from_path(&base_dir.join(filespec::portable_path(script)))?
+                    .with_state(state.clone()),
+            ),
+        })
+    }
+}
+
+/// Applies each of its processors in turn.
+pub struct Pipeline {
+    steps: Vec<Box<dyn TransactionProcessor>>,
+    state: Option<Arc<ScriptState>>,
+}
+
+impl Pipeline {
+    /// Loads the pipeline file at `path`. Its `Script` steps share `state`.
+    pub fn from_path(path: &Path, state: Option<Arc<ScriptState>>) -> Result<Self> {
+        let steps: Vec<Step> = ron::de::from_reader(
+            std::fs::File::open(path).with_context(|| format!("opening {:?} for reading", path))?,
+        )
+        .with_context(|| format!("parsing {:?}", path))?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        let steps = steps
+            .iter()
+            .enumerate()
+            .map(|(i, step)| {
+                step.make_processor(base_dir, &state)
+                    .with_context(|| format!("in step #{} of {:?}", i + 1, path))
+            })
+            .collect::<Result<_>>()?;
+        Ok(Self { steps, state })
+    }
+}
+
+impl TransactionProcessor for Pipeline {
+    fn update_transactions(
+        &self,
+        mut trns: Vec<TransactionPostings>,
+    ) -> Result<Vec<TransactionPostings>> {
+        for (i, step) in self.steps.iter().enumerate() {
+            trns = step
+                .update_transactions(trns)
+                .with_context(|| format!("in pipeline step #{}", i + 1))?;
+        }
+        Ok(trns)
+    }
+
+    fn update_filtered_transactions(
+        &self,
+        mut trns: Vec<TransactionPostings>,
+        num_posts: &[usize],
+    ) -> Result<Vec<TransactionPostings>> {
+        for (i, step) in self.steps.iter().enumerate() {
+            trns = step
+                .update_filtered_transactions(trns, num_posts)
+                .with_context(|| format!("in pipeline step #{}", i + 1))?;
+        }
+        Ok(trns)
+    }
+
+    fn report_stats(&self) {
+        for step in &self.steps {
+            step.report_stats();
+        }
+    }
+
+    fn collected_errors(&self) -> Result<()> {
+        for step in &self.steps {
+            step.collected_errors()?;
+        }
+        Ok(())
+    }
+
+    fn save_state(&self, batch: &mut WriteBatch) -> Result<()> {
+        match &self.state {
+            Some(state) => state.save(batch),
+            None => Ok(()),
+        }
+    }
+}
+
+struct Normalizer {
+    places: HashMap<String, u32>,
+}
+
+impl TransactionProcessor for Normalizer {
+    fn update_transactions(
+        &self,
+        mut trns: Vec<TransactionPostings>,
+    ) -> Result<Vec<TransactionPostings>> {
+        for warning in precision::normalize_transaction_postings(&mut trns, &self.places) {
+            eprintln!("warning: {}", warning);
+        }
+        Ok(trns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    fn write(dir: &Path, name: &str, content: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, content).expect("write file");
+        path
+    }
+
+    #[test]
+    fn applies_steps_in_order() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        write(
+            dir.path(),
+            "first.ron",
+            r#"[
+                Chain("start", [
+                    Rule(action: SetAccount("foo"), predicate: Account(Eq("bar")), result: Continue),
+                ]),
+            ]"#,
+        );
+        write(
+            dir.path(),
+            "second.ron",
+            r#"[
+                Chain("start", [
+                    Rule(action: SetAccount("baz"), predicate: Account(Eq("foo")), result: Continue),
+                ]),
+            ]"#,
+        );
+        write(
+            dir.path(),
+            "third.rhai",
+            r#"fn update(posting) {
+                if posting.account == "baz" {
+                    posting.tags.push("scripted");
+                    posting
+                }
+            }"#,
+        );
+        let pipeline_path = write(
+            dir.path(),
+            "pipeline.ron",
+            r#"[
+                Table(rules: "first.ron"),
+                Normalize(precision: {"GBP": 2}),
+                Table(rules: "second.ron"),
+                Script(script: "third.rhai"),
+            ]"#,
+        );
+
+        let pipeline = Pipeline::from_path(&pipeline_path, None).expect("load pipeline");
+        let got = pipeline
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 description
+                    bar  GBP1.5
+                    other",
+            ))
+            .expect("update_transactions");
+
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "
+                2001/01/02 description
+                    baz  GBP1.50  ; :scripted:
+                    other"
+            ),
+            got
+        );
+    }
+
+    #[test]
+    fn reports_failing_step() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let pipeline_path = write(
+            dir.path(),
+            "pipeline.ron",
+            r#"[
+                Normalize(precision: {}),
+                Table(rules: "missing.ron"),
+            ]"#,
+        );
+
+        let err = Pipeline::from_path(&pipeline_path, None)
+            .err()
+            .expect("should fail to load");
+        assert!(format!("{:#}", err).contains("in step #2"), "{:#}", err);
+    }
+}
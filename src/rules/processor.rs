@@ -1,14 +1,42 @@
 use anyhow::Result;
 
+use crate::filespec::WriteBatch;
 use crate::internal::TransactionPostings;
 
 pub trait TransactionProcessorFactory {
     fn make_processor(&self) -> Result<Box<dyn TransactionProcessor>>;
 }
 
-pub trait TransactionProcessor {
+pub trait TransactionProcessor: Sync {
     fn update_transactions(
         &self,
         trns: Vec<TransactionPostings>,
     ) -> Result<Vec<TransactionPostings>>;
+
+    /// As `update_transactions`, but the transactions may have been filtered
+    /// to some of their postings, and `num_posts` gives the number of
+    /// postings that each had before.
+    fn update_filtered_transactions(
+        &self,
+        trns: Vec<TransactionPostings>,
+        num_posts: &[usize],
+    ) -> Result<Vec<TransactionPostings>> {
+        let _ = num_posts;
+        self.update_transactions(trns)
+    }
+
+    /// Reports any statistics gathered while updating transactions.
+    fn report_stats(&self) {}
+
+    /// Returns an error describing any errors that were collected, rather
+    /// than returned, while updating transactions.
+    fn collected_errors(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Adds any state kept across runs to `batch`, to be written along with
+    /// the updated transactions.
+    fn save_state(&self, _batch: &mut WriteBatch) -> Result<()> {
+        Ok(())
+    }
 }
@@ -0,0 +1,429 @@
+//! Updating postings with rhai scripts. A script given as a `Script` pipeline
+//! step defines a function `update(posting)`, which is called for each
+//! posting in turn.
+//!
+//! Scripts may keep state across runs in a `ScriptState`, which is bound to
+//! `this` in the functions that they define.
+//!
+//! Scripts can also edit the tags of posting maps with the `Comment` helpers
+//! `tags_with_prefix`, `remove_tags_with_prefix` and `replace_value_tag`,
+//! which are registered as methods of maps.
+
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use anyhow::{anyhow, bail, Context, Result};
+use rhai::{Array, CallFnOptions, Dynamic, Engine, EvalAltResult, FuncArgs, Map, Scope, AST};
+use smol_str::SmolStr;
+
+use crate::comment::Comment;
+use crate::filespec::{FileSpec, WriteBatch};
+use crate::internal::{PostingInternal, TransactionPostings};
+use crate::merge::hook::HookPosting;
+use crate::rules::processor::TransactionProcessor;
+
+const UPDATE_FN: &str = "update";
+
+/// Limits on each call of a script, so that a runaway script fails rather
+/// than hanging or overflowing the stack.
+const MAX_OPERATIONS: u64 = 10_000_000;
+const MAX_CALL_LEVELS: usize = 64;
+const MAX_EXPR_DEPTH: usize = 64;
+const MAX_FUNCTION_EXPR_DEPTH: usize = 32;
+
+/// Returns an engine for running scripts, with limits on their resources and
+/// the `Comment` helpers registered.
+pub fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_max_operations(MAX_OPERATIONS)
+        .set_max_call_levels(MAX_CALL_LEVELS)
+        .set_max_expr_depths(MAX_EXPR_DEPTH, MAX_FUNCTION_EXPR_DEPTH);
+    engine
+        .register_fn(
+            "tags_with_prefix",
+            |posting: &mut Map, prefix: &str| -> Result<Array, Box<EvalAltResult>> {
+                let comment = comment_of(posting)?;
+                let mut tags: Vec<&str> = comment.tags_with_prefix(prefix).collect();
+                tags.sort();
+                Ok(tags
+                    .into_iter()
+                    .map(|tag| Dynamic::from(tag.to_string()))
+                    .collect())
+            },
+        )
+        .register_fn(
+            "remove_tags_with_prefix",
+            |posting: &mut Map, prefix: &str| -> Result<i64, Box<EvalAltResult>> {
+                let mut comment = comment_of(posting)?;
+                let removed = comment.remove_tags_with_prefix(prefix);
+                insert_comment_fields(posting, &comment);
+                Ok(removed as i64)
+            },
+        )
+        .register_fn(
+            "replace_value_tag",
+            |posting: &mut Map, key: &str, value: &str| -> Result<Dynamic, Box<EvalAltResult>> {
+                let mut comment = comment_of(posting)?;
+                let previous = comment.replace_value_tag(key, value);
+                insert_comment_fields(posting, &comment);
+                Ok(previous.map_or(Dynamic::UNIT, Dynamic::from))
+            },
+        );
+    engine
+}
+
+/// Inserts the `tags` (sorted) and `value_tags` fields of a posting map.
+pub fn insert_comment_fields(map: &mut Map, comment: &Comment) {
+    let mut tags: Vec<&str> = comment.tags.iter().map(|tag| tag.as_str()).collect();
+    tags.sort();
+    let value_tags: Map = comment
+        .value_tags
+        .iter()
+        .map(|(k, v)| (k.as_str().into(), Dynamic::from(v.clone())))
+        .collect();
+    map.insert(
+        "tags".into(),
+        Dynamic::from(
+            tags.into_iter()
+                .map(|tag| Dynamic::from(tag.to_string()))
+                .collect::<Array>(),
+        ),
+    );
+    map.insert("value_tags".into(), Dynamic::from(value_tags));
+}
+
+/// Returns a comment with the tags of a posting map.
+fn comment_of(map: &Map) -> Result<Comment, Box<EvalAltResult>> {
+    let mut comment = Comment::new();
+    update_comment(map, &mut comment).map_err(|e| format!("{:#}", e))?;
+    Ok(comment)
+}
+
+/// Updates `comment` from the `tags` and `value_tags` fields of a posting
+/// map, where it has them.
+fn update_comment(map: &Map, comment: &mut Comment) -> Result<()> {
+    if let Some(tags) = map.get("tags") {
+        let type_name = tags.type_name();
+        comment.tags = tags
+            .clone()
+            .into_typed_array::<rhai::ImmutableString>()
+            .map_err(|_| anyhow!("posting tags are a {}, not an array of strings", type_name))?
+            .into_iter()
+            .map(|tag| SmolStr::from(tag.as_str()))
+            .collect();
+    }
+    if let Some(value_tags) = map.get("value_tags") {
+        let type_name = value_tags.type_name();
+        let value_tags: Map = value_tags
+            .clone()
+            .try_cast()
+            .ok_or_else(|| anyhow!("posting value_tags are a {}, not a map", type_name))?;
+        comment.value_tags = value_tags
+            .into_iter()
+            .map(|(k, v)| {
+                let v = v.into_string().map_err(|type_name| {
+                    anyhow!(
+                        "value tag {:?} is a {}, not a string",
+                        k.as_str(),
+                        type_name
+                    )
+                })?;
+                Ok((SmolStr::from(k.as_str()), v))
+            })
+            .collect::<Result<_>>()?;
+    }
+    Ok(())
+}
+
+/// An object map that scripts keep across runs, stored as JSON in a file.
+pub struct ScriptState {
+    path: PathBuf,
+    value: Mutex<Dynamic>,
+}
+
+impl ScriptState {
+    /// Loads the state from `path`, starting with an empty map if the file
+    /// does not exist yet.
+    pub fn load(path: &Path) -> Result<Self> {
+        let value = match std::fs::read_to_string(path) {
+            Ok(content) => {
+                let json: serde_json::Value = serde_json::from_str(&content)
+                    .with_context(|| format!("parsing script state {:?}", path))?;
+                if !json.is_object() {
+                    bail!("script state {:?} is not a JSON object", path);
+                }
+                rhai::serde::to_dynamic(json)
+                    .map_err(|e| anyhow!("loading script state {:?}: {}", path, e))?
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Dynamic::from_map(Map::new()),
+            Err(e) => {
+                return Err(e).with_context(|| format!("reading script state {:?}", path));
+            }
+        };
+        Ok(Self {
+            path: path.to_owned(),
+            value: Mutex::new(value),
+        })
+    }
+
+    /// Calls the function `name` of the script, with the state bound to
+    /// `this`.
+    pub fn call_fn(
+        &self,
+        engine: &Engine,
+        ast: &AST,
+        name: &str,
+        args: impl FuncArgs,
+    ) -> Result<Dynamic, Box<rhai::EvalAltResult>> {
+        let mut value = self.value.lock().expect("script state lock poisoned");
+        let options = CallFnOptions::new().bind_this_ptr(&mut value);
+        engine.call_fn_with_options(options, &mut Scope::new(), ast, name, args)
+    }
+
+    /// Adds the state to `batch`, to be written back to its file.
+    pub fn save(&self, batch: &mut WriteBatch) -> Result<()> {
+        let value = self.value.lock().expect("script state lock poisoned");
+        let content = serde_json::to_string_pretty(&*value)
+            .with_context(|| format!("saving script state {:?}", self.path))?;
+        batch.add(&FileSpec::Path(self.path.clone()), content + "\n");
+        Ok(())
+    }
+}
+
+/// A compiled script that updates postings.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    state: Option<Arc<ScriptState>>,
+}
+
+impl Script {
+    pub fn from_path(path: &Path) -> Result<Self> {
+        let script =
+            std::fs::read_to_string(path).with_context(|| format!("reading script {:?}", path))?;
+        Self::from_script(&script).with_context(|| format!("in script {:?}", path))
+    }
+
+    /// Binds `state` to `this` when calling the script.
+    pub fn with_state(mut self, state: Option<Arc<ScriptState>>) -> Self {
+        self.state = state;
+        self
+    }
+
+    pub fn from_script(script: &str) -> Result<Self> {
+        let engine = new_engine();
+        let ast = engine
+            .compile(script)
+            .map_err(|e| anyhow!("compiling script: {}", e))?;
+        if !ast
+            .iter_functions()
+            .any(|f| f.name == UPDATE_FN && f.params.len() == 1)
+        {
+            bail!("script does not define a function {}(posting)", UPDATE_FN);
+        }
+        Ok(Self {
+            engine,
+            ast,
+            state: None,
+        })
+    }
+
+    /// Calls the script's `update` function with the posting, as an object
+    /// map with the same fields as given to a decision hook. The function may
+    /// return `()` to leave the posting unchanged, or the map with changes to
+    /// its `account`, `tags` and `value_tags`. Changes to other fields are
+    /// ignored.
+    fn update_posting(
+        &self,
+        date: chrono::NaiveDate,
+        description: &str,
+        post: &mut PostingInternal,
+    ) -> Result<()> {
+        let map = HookPosting {
+            date,
+            description,
+            posting: post,
+        }
+        .to_map();
+        let result: Dynamic = match &self.state {
+            Some(state) => state.call_fn(&self.engine, &self.ast, UPDATE_FN, (map,)),
+            None => self
+                .engine
+                .call_fn(&mut Scope::new(), &self.ast, UPDATE_FN, (map,)),
+        }
+        .map_err(|e| anyhow!("script failed: {}", e))?;
+        if result.is_unit() {
+            return Ok(());
+        }
+        let type_name = result.type_name();
+        let map: Map = result
+            .try_cast()
+            .ok_or_else(|| anyhow!("script returned a {}, not a posting or ()", type_name))?;
+        apply_changes(map, post)
+    }
+}
+
+/// Updates the posting from the `account`, `tags` and `value_tags` fields of
+/// `map`.
+fn apply_changes(mut map: Map, post: &mut PostingInternal) -> Result<()> {
+    if let Some(account) = map.remove("account") {
+        post.raw.account = account
+            .into_string()
+            .map_err(|type_name| anyhow!("posting account is a {}, not a string", type_name))?;
+    }
+    update_comment(&map, &mut post.comment)
+}
+
+impl TransactionProcessor for Script {
+    fn update_transactions(
+        &self,
+        mut trns: Vec<TransactionPostings>,
+    ) -> Result<Vec<TransactionPostings>> {
+        for trn in &mut trns {
+            let date = trn.trn.raw.date;
+            for post in &mut trn.posts {
+                self.update_posting(date, &trn.trn.raw.description, post)
+                    .with_context(|| format!("updating posting in transaction on {}", date))?;
+            }
+        }
+        Ok(trns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test_case(
+        "fn update(posting) { () }",
+        "
+        2001/01/02 Shop
+            foo  GBP 10.00  ; :fp-1:
+            bar"
+        ; "unchanged"
+    )]
+    #[test_case(
+        r#"fn update(posting) {
+            if posting.description == "Shop" && posting.account == "foo" {
+                posting.account = "expenses:shop";
+                posting.tags.push("shopping");
+                posting.value_tags["seen"] = posting.date;
+                posting
+            }
+        }"#,
+        "
+        2001/01/02 Shop
+            expenses:shop  GBP 10.00  ; :fp-1:shopping:
+              ; seen: 2001-01-02
+            bar"
+        ; "changed"
+    )]
+    #[test_case(
+        r#"fn update(posting) {
+            if posting.account == "foo" {
+                let fps = posting.tags_with_prefix("fp-");
+                posting.remove_tags_with_prefix("fp-");
+                posting.replace_value_tag("old-fp", fps[0]);
+                posting
+            }
+        }"#,
+        "
+        2001/01/02 Shop
+            foo  GBP 10.00
+              ; old-fp: fp-1
+            bar"
+        ; "comment helpers"
+    )]
+    fn update(script: &str, want: &str) {
+        let script = Script::from_script(script).expect("from_script");
+        let got = script
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 Shop
+                    foo  GBP 10.00  ; :fp-1:
+                    bar",
+            ))
+            .expect("update_transactions");
+        assert_transaction_postings_eq!(parse_transaction_postings(want), got);
+    }
+
+    #[test_case("fn other(posting) { () }"; "missing update")]
+    #[test_case("fn update(posting) {"; "syntax error")]
+    fn from_script_fails(script: &str) {
+        assert!(Script::from_script(script).is_err());
+    }
+
+    #[test_case(r#"fn update(posting) { 1 }"#; "not a map")]
+    #[test_case(r#"fn update(posting) { posting.account = 1; posting }"#; "bad account")]
+    #[test_case(r#"fn update(posting) { posting.tags = [1]; posting }"#; "bad tags")]
+    #[test_case(r#"fn update(posting) { posting.value_tags.x = 1; posting }"#; "bad value tag")]
+    fn update_fails(script: &str) {
+        let script = Script::from_script(script).expect("from_script");
+        assert!(script
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 Shop
+                    foo  GBP 10.00
+                    bar",
+            ))
+            .is_err());
+    }
+
+    #[test]
+    fn state_persists_across_runs() {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("state.json");
+        let run = || {
+            let state = Arc::new(ScriptState::load(&path).expect("load"));
+            let script = Script::from_script(
+                r#"fn update(posting) {
+                    this.count = (this.count ?? 0) + 1;
+                    posting.value_tags["n"] = `${this.count}`;
+                    posting
+                }"#,
+            )
+            .expect("from_script")
+            .with_state(Some(state.clone()));
+            let trns = script
+                .update_transactions(parse_transaction_postings(
+                    "
+                    2001/01/02 Shop
+                        foo  GBP 10.00
+                        bar",
+                ))
+                .expect("update_transactions");
+            let mut batch = WriteBatch::new();
+            state.save(&mut batch).expect("save");
+            batch.commit().expect("commit");
+            trns
+        };
+
+        run();
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "
+                2001/01/02 Shop
+                    foo  GBP 10.00  ; n: 3
+                    bar  ; n: 4"
+            ),
+            run()
+        );
+        let saved: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).expect("read")).expect("parse");
+        assert_eq!(saved, serde_json::json!({"count": 4}));
+    }
+
+    #[test_case("[1]"; "not an object")]
+    #[test_case("{"; "malformed")]
+    fn load_state_fails(content: &str) {
+        let dir = tempfile::tempdir().expect("tempdir");
+        let path = dir.path().join("state.json");
+        std::fs::write(&path, content).expect("write");
+        assert!(ScriptState::load(&path).is_err());
+    }
+}
@@ -0,0 +1,59 @@
+//! Mapping of spending categories, as provided by some banks' exports, to
+//! accounts.
+
+use std::collections::HashMap;
+
+/// The built-in taxonomy of categories, enabled with the `BuiltinCategories`
+/// rules file entry. Names are as normalized by `normalize`.
+const BUILTIN: &[(&str, &str)] = &[
+    ("bills", "expenses:bills"),
+    ("cash", "expenses:cash"),
+    ("charity", "expenses:gifts:charity"),
+    ("eating-out", "expenses:food:eating-out"),
+    ("entertainment", "expenses:entertainment"),
+    ("family", "expenses:family"),
+    ("fuel", "expenses:transport:fuel"),
+    ("general", "expenses:general"),
+    ("gifts", "expenses:gifts"),
+    ("groceries", "expenses:food:groceries"),
+    ("health", "expenses:health"),
+    ("holidays", "expenses:travel"),
+    ("insurance", "expenses:insurance"),
+    ("interest", "income:interest"),
+    ("personal-care", "expenses:personal-care"),
+    ("rent", "expenses:housing:rent"),
+    ("salary", "income:salary"),
+    ("shopping", "expenses:shopping"),
+    ("subscriptions", "expenses:subscriptions"),
+    ("transport", "expenses:transport"),
+    ("travel", "expenses:travel"),
+    ("utilities", "expenses:bills:utilities"),
+];
+
+/// Normalizes a category name so that e.g. "Eating out", "eating_out" and
+/// "eating-out" are the same category.
+pub fn normalize(category: &str) -> String {
+    category.trim().to_lowercase().replace([' ', '_'], "-")
+}
+
+/// Adds the built-in categories to `categories`, except where already
+/// defined.
+pub fn add_builtin(categories: &mut HashMap<String, String>) {
+    for (category, account) in BUILTIN {
+        categories
+            .entry(category.to_string())
+            .or_insert_with(|| account.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    #[test_case("groceries" => "groceries"; "unchanged")]
+    #[test_case(" Eating out " => "eating-out"; "spaces")]
+    #[test_case("PERSONAL_CARE" => "personal-care"; "underscores")]
+    fn normalize(category: &str) -> String {
+        super::normalize(category)
+    }
+}
@@ -3,4 +3,18 @@ use crate::internal::{PostingInternal, TransactionInternal};
 pub struct PostingContext<'a> {
     pub trn: &'a mut TransactionInternal,
     pub post: &'a mut PostingInternal,
+    /// Number of postings in the transaction.
+    pub num_posts: usize,
+}
+
+impl PostingContext<'_> {
+    /// Returns the value of the named value tag of the posting, or otherwise
+    /// of the transaction.
+    pub fn value_tag(&self, name: &str) -> Option<&String> {
+        self.post
+            .comment
+            .value_tags
+            .get(name)
+            .or_else(|| self.trn.comment.value_tags.get(name))
+    }
 }
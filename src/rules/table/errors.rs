@@ -0,0 +1,89 @@
+//! Collecting errors from applying rules, for `--keep-going`.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use anyhow::{anyhow, Error, Result};
+
+/// Identifies the rule that an error occurred in. Attached as context to
+/// errors from applying rules, and found again with `downcast_ref`.
+#[derive(Debug)]
+pub struct RuleLocation {
+    pub chain: String,
+    pub index: usize,
+    /// The rule's name, if it has one.
+    pub name: Option<String>,
+}
+
+impl RuleLocation {
+    pub fn new(chain: &str, index: usize, name: Option<&str>) -> Self {
+        Self {
+            chain: chain.to_string(),
+            index,
+            name: name.map(str::to_string),
+        }
+    }
+
+    /// Identifies the rule by name if it has one, e.g. "groceries in chain
+    /// start", or else by index, e.g. "start[3]".
+    pub fn label(&self) -> String {
+        match &self.name {
+            Some(name) => format!("{} in chain {}", name, self.chain),
+            None => format!("{}[{}]", self.chain, self.index),
+        }
+    }
+}
+
+impl fmt::Display for RuleLocation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "in rule {}", self.label())
+    }
+}
+
+/// Adds the location of the rule to the error, unless it already has the
+/// location of a rule in a chain that was jumped to.
+pub fn with_location(err: Error, location: RuleLocation) -> Error {
+    if err.downcast_ref::<RuleLocation>().is_some() {
+        err
+    } else {
+        err.context(location)
+    }
+}
+
+/// Errors collected while applying rules, in the order of the postings they
+/// occurred on.
+#[derive(Debug, Default)]
+pub struct CollectedErrors {
+    /// Number of errors by the rule that they occurred in.
+    by_rule: BTreeMap<String, usize>,
+    messages: Vec<String>,
+}
+
+impl CollectedErrors {
+    pub fn record(&mut self, err: Error) {
+        let rule = match err.downcast_ref::<RuleLocation>() {
+            Some(loc) => loc.label(),
+            None => "(no rule)".to_string(),
+        };
+        *self.by_rule.entry(rule).or_default() += 1;
+        self.messages.push(format!("{:#}", err));
+    }
+
+    /// Returns an error describing all of the collected errors, if there
+    /// were any.
+    pub fn check(&self) -> Result<()> {
+        if self.messages.is_empty() {
+            return Ok(());
+        }
+        let mut report = String::new();
+        for msg in &self.messages {
+            report.push_str(msg);
+            report.push_str("\n\n");
+        }
+        report.push_str(&format!("{} errors applying rules:", self.messages.len()));
+        for (rule, count) in &self.by_rule {
+            report.push_str(&format!("\n  {}: {}", rule, count));
+        }
+        Err(anyhow!(report))
+    }
+}
@@ -1,37 +1,67 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Instant;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use chrono::{Duration, NaiveDate};
 use clap::Args;
-use serde_derive::Deserialize;
+use ledger_parser::{Amount, Balance};
+use rayon::prelude::*;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
 
+use crate::accounts::{Account, Taxonomy, TaxonomyOpts};
 use crate::internal::TransactionPostings;
+use crate::money::{self, parse_quantity};
 use crate::rules::processor::{TransactionProcessor, TransactionProcessorFactory};
 use crate::rules::table::ctx::PostingContext;
+use crate::rules::table::errors::{CollectedErrors, RuleLocation};
 use crate::rules::table::predicate::Predicate;
+use crate::rules::table::profile::{Profile, Stats};
 
+mod categories;
 mod ctx;
-mod predicate;
+mod errors;
+pub mod predicate;
+mod profile;
+pub mod schema;
 mod source;
+mod template;
 
-const START_CHAIN: &str = "start";
+/// The name of the chain that rules are applied from.
+pub const START_CHAIN: &str = "start";
 
-fn load_from_path(path: &std::path::Path) -> Result<Table> {
+/// Man page describing the rules file format.
+pub const FORMAT_MAN_PAGE: &str = include_str!("format.5");
+
+pub fn load_from_path(path: &Path) -> Result<Table> {
+    load_from_path_with(path, &[])
+}
+
+/// Loads a table, looking for files that it includes in `search_dirs` if they
+/// are not found relative to the including file.
+pub fn load_from_path_with(path: &Path, search_dirs: &[PathBuf]) -> Result<Table> {
     let rf = source::File::from_path(path)?;
-    let table = rf.load()?;
+    let table = rf.load(search_dirs)?;
     table.validate()?;
     Ok(table)
 }
 
+/// Returns the JSON schema of a rules file.
+pub fn schema() -> schemars::schema::RootSchema {
+    source::schema()
+}
+
 #[cfg(test)]
 fn load_from_str_unvalidated(s: &str) -> Result<Table> {
     let rf = source::File::from_str(s)?;
-    let table = rf.load()?;
+    let table = rf.load(&[])?;
     Ok(table)
 }
 
 #[cfg(test)]
-fn load_from_str(s: &str) -> Result<Table> {
+pub(crate) fn load_from_str(s: &str) -> Result<Table> {
     let table = load_from_str_unvalidated(s)?;
     table.validate()?;
     Ok(table)
@@ -41,58 +71,303 @@ fn load_from_str(s: &str) -> Result<Table> {
 pub struct Command {
     /// The `.ron` file containing rules to apply to the transactions.
     rules: PathBuf,
+    /// Count the evaluations and matches of each rule and defined predicate,
+    /// along with the cumulative time spent in each, and report them to
+    /// stderr after applying the rules.
+    #[arg(long = "profile")]
+    profile: bool,
+    /// Collect errors from applying rules, including `Error` actions, rather
+    /// than stopping at the first. Postings that fail are left unchanged, all
+    /// other changes are written, and then the errors are reported together
+    /// with a count for each rule.
+    #[arg(long = "keep-going")]
+    keep_going: bool,
+    /// Directory to look for files named by `Include` entries in, when they
+    /// are not found relative to the including file, e.g. for a library of
+    /// chains shared between rules files. May be given multiple times, in
+    /// which case the directories are searched in order.
+    #[arg(long = "rules-dir", value_name = "DIR")]
+    rules_dirs: Vec<PathBuf>,
+    #[command(flatten)]
+    taxonomy: TaxonomyOpts,
 }
 
 impl TransactionProcessorFactory for Command {
     fn make_processor(&self) -> Result<Box<dyn TransactionProcessor>> {
-        Ok(Box::new(load_from_path(&self.rules)?))
+        let mut table = load_from_path_with(&self.rules, &self.rules_dirs)?;
+        if let Some(taxonomy) = self.taxonomy.load()? {
+            for warning in table.check_taxonomy(&taxonomy) {
+                eprintln!("warning: {}", warning);
+            }
+        }
+        if self.profile {
+            table.enable_profiling();
+        }
+        if self.keep_going {
+            table.enable_keep_going();
+        }
+        Ok(Box::new(table))
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Default)]
 pub struct Table {
     chains: HashMap<String, Chain>,
+    predicates: HashMap<String, Predicate>,
+    /// Maps normalized category names to accounts, for
+    /// `Action::SetAccountFromCategoryTag`.
+    categories: HashMap<String, String>,
+    /// Statistics gathered while applying rules, if profiling is enabled.
+    profile: Option<Profile>,
+    /// Errors collected while applying rules, if keep-going is enabled.
+    errors: Option<Mutex<CollectedErrors>>,
 }
 
 impl Table {
-    pub fn new(chains: HashMap<String, Chain>) -> Self {
-        Self { chains }
+    pub fn new(chains: HashMap<String, Chain>, predicates: HashMap<String, Predicate>) -> Self {
+        Self {
+            chains,
+            predicates,
+            categories: HashMap::new(),
+            profile: None,
+            errors: None,
+        }
+    }
+
+    /// Adds a chain, replacing any existing chain with the same name.
+    pub fn with_chain<S: Into<String>>(mut self, name: S, chain: Chain) -> Self {
+        self.chains.insert(name.into(), chain);
+        self
+    }
+
+    /// Adds a predicate that can be referred to with `Predicate::Ref`,
+    /// replacing any existing predicate with the same name.
+    pub fn with_predicate<S: Into<String>>(mut self, name: S, predicate: Predicate) -> Self {
+        self.predicates.insert(name.into(), predicate);
+        self
+    }
+
+    /// Maps a category to an account for `Action::SetAccountFromCategoryTag`,
+    /// replacing any existing mapping for the category.
+    pub fn with_category<C: AsRef<str>, A: Into<String>>(
+        mut self,
+        category: C,
+        account: A,
+    ) -> Self {
+        self.categories
+            .insert(categories::normalize(category.as_ref()), account.into());
+        self
+    }
+
+    /// Enables gathering statistics for each rule and defined predicate when
+    /// applying rules. Any previously gathered statistics are discarded.
+    pub fn enable_profiling(&mut self) {
+        self.profile = Some(Profile::new(&self.chains, &self.predicates));
+    }
+
+    /// Collects errors from applying rules to postings instead of returning
+    /// the first, leaving each failed posting unchanged. Any previously
+    /// collected errors are discarded.
+    pub fn enable_keep_going(&mut self) {
+        self.errors = Some(Mutex::new(CollectedErrors::default()));
+    }
+
+    /// Returns an error describing the errors collected so far, if
+    /// keep-going is enabled and there were any.
+    pub fn check_collected_errors(&self) -> Result<()> {
+        match &self.errors {
+            Some(errors) => errors.lock().expect("errors lock poisoned").check(),
+            None => Ok(()),
+        }
+    }
+
+    /// Returns the statistics gathered so far, if profiling is enabled.
+    pub fn profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
     }
 
+    /// Serializes the table into the RON rules file format. Categories are
+    /// written first, then predicates, then the start chain, then any other
+    /// chains, each ordered by name.
+    pub fn to_ron(&self) -> Result<String> {
+        source::to_ron(self)
+    }
+
+    /// Applies the rules to each transaction. Transactions are processed in
+    /// parallel on the current rayon thread pool, and returned in their
+    /// original order.
     pub fn update_transactions(
         &self,
         trns: Vec<TransactionPostings>,
     ) -> Result<Vec<TransactionPostings>> {
-        trns.into_iter()
-            .map(|trn| self.update_transaction(trn))
-            .collect::<Result<Vec<TransactionPostings>>>()
+        let num_posts: Vec<usize> = trns.iter().map(|trn| trn.posts.len()).collect();
+        self.update_filtered_transactions(trns, &num_posts)
     }
 
-    pub fn update_transaction(&self, mut trn: TransactionPostings) -> Result<TransactionPostings> {
+    /// As `update_transactions`, but `num_posts` gives the number of postings
+    /// that each transaction had before it was filtered to some of them.
+    pub fn update_filtered_transactions(
+        &self,
+        trns: Vec<TransactionPostings>,
+        num_posts: &[usize],
+    ) -> Result<Vec<TransactionPostings>> {
+        let Some(errors) = &self.errors else {
+            return trns
+                .into_par_iter()
+                .zip(num_posts)
+                .map(|(trn, num_posts)| self.update_transaction_with(trn, *num_posts))
+                .collect::<Result<Vec<TransactionPostings>>>();
+        };
+        let results: Vec<_> = trns
+            .into_par_iter()
+            .zip(num_posts)
+            .map(|(trn, num_posts)| self.update_transaction_keep_going(trn, *num_posts))
+            .collect();
+        let mut errors = errors.lock().expect("errors lock poisoned");
+        Ok(results
+            .into_iter()
+            .map(|(trn, trn_errors)| {
+                for err in trn_errors {
+                    errors.record(err);
+                }
+                trn
+            })
+            .collect())
+    }
+
+    pub fn update_transaction(&self, trn: TransactionPostings) -> Result<TransactionPostings> {
+        let num_posts = trn.posts.len();
+        self.update_transaction_with(trn, num_posts)
+    }
+
+    /// As `update_transaction`, where the transaction had `num_posts`
+    /// postings before it was filtered.
+    fn update_transaction_with(
+        &self,
+        mut trn: TransactionPostings,
+        num_posts: usize,
+    ) -> Result<TransactionPostings> {
         let start = self.get_chain(START_CHAIN)?;
         for post in &mut trn.posts {
             let mut ctx = PostingContext {
                 trn: &mut trn.trn,
                 post,
+                num_posts,
             };
-            start.apply(self, &mut ctx)?;
+            start.apply(START_CHAIN, self, &mut ctx)?;
         }
         Ok(trn)
     }
 
+    /// As `update_transaction`, but returns the errors from each posting,
+    /// restoring the transaction and posting to how they were before the
+    /// failing posting was processed.
+    fn update_transaction_keep_going(
+        &self,
+        mut trn: TransactionPostings,
+        num_posts: usize,
+    ) -> (TransactionPostings, Vec<anyhow::Error>) {
+        let start = match self.get_chain(START_CHAIN) {
+            Ok(start) => start,
+            Err(err) => return (trn, vec![err]),
+        };
+        let mut errors = Vec::new();
+        for post in &mut trn.posts {
+            let (orig_trn, orig_post) = (trn.trn.clone(), post.clone());
+            let mut ctx = PostingContext {
+                trn: &mut trn.trn,
+                post,
+                num_posts,
+            };
+            if let Err(err) = start.apply(START_CHAIN, self, &mut ctx) {
+                trn.trn = orig_trn;
+                *post = orig_post;
+                errors.push(err);
+            }
+        }
+        (trn, errors)
+    }
+
     fn get_chain(&self, name: &str) -> Result<&Chain> {
         self.chains
             .get(name)
             .ok_or_else(|| anyhow!("chain {} not found", name))
     }
 
+    fn category_account(&self, category: &str) -> Option<&String> {
+        self.categories.get(&categories::normalize(category))
+    }
+
+    fn get_predicate(&self, name: &str) -> Result<&Predicate> {
+        self.predicates
+            .get(name)
+            .ok_or_else(|| anyhow!("predicate {} not found", name))
+    }
+
+    /// Matches the named defined predicate, recording statistics for it if
+    /// profiling is enabled.
+    fn is_ref_match(&self, name: &str, ctx: &PostingContext) -> bool {
+        // Validation ensures that the reference exists.
+        let Ok(pred) = self.get_predicate(name) else {
+            return false;
+        };
+        match self.profile.as_ref().and_then(|p| p.predicate(name)) {
+            Some(stats) => {
+                let start = Instant::now();
+                let matched = pred.is_match(self, ctx);
+                stats.record(matched, start.elapsed());
+                matched
+            }
+            None => pred.is_match(self, ctx),
+        }
+    }
+
     pub fn validate(&self) -> Result<()> {
         self.get_chain(START_CHAIN)?;
-        for chain in self.chains.values() {
-            chain.validate(self)?;
+        let mut rule_names = HashMap::<&str, &str>::new();
+        for (chain_name, chain) in &self.chains {
+            chain.validate(chain_name, self)?;
+            for rule_name in chain.rules.iter().filter_map(Rule::name) {
+                if let Some(other_chain) = rule_names.insert(rule_name, chain_name) {
+                    return Err(anyhow!(
+                        "rule name {:?} is used in chain {} and chain {}",
+                        rule_name,
+                        other_chain,
+                        chain_name
+                    ));
+                }
+            }
+        }
+        for (name, predicate) in &self.predicates {
+            predicate.validate(self, &mut vec![name.as_str()])?;
         }
         Ok(())
     }
+
+    /// Returns warnings for the accounts set by `SetAccount` actions that are
+    /// not under any root of `taxonomy`, e.g. from a typo such as
+    /// "expences:food".
+    pub fn check_taxonomy(&self, taxonomy: &Taxonomy) -> Vec<String> {
+        let mut chain_names: Vec<&String> = self.chains.keys().collect();
+        chain_names.sort();
+        let mut warnings = Vec::new();
+        for chain_name in chain_names {
+            for (i, rule) in self.chains[chain_name].rules.iter().enumerate() {
+                let mut accounts = Vec::new();
+                rule.action.set_accounts(&mut accounts);
+                for account in accounts {
+                    if !taxonomy.contains(account) {
+                        warnings.push(format!(
+                            "{}: account {:?} is not under any root of the taxonomy",
+                            RuleLocation::new(chain_name, i, rule.name()),
+                            account.to_string()
+                        ));
+                    }
+                }
+            }
+        }
+        warnings
+    }
 }
 
 impl TransactionProcessor for Table {
@@ -102,19 +377,73 @@ impl TransactionProcessor for Table {
     ) -> Result<Vec<TransactionPostings>> {
         Table::update_transactions(self, trns)
     }
+
+    fn update_filtered_transactions(
+        &self,
+        trns: Vec<TransactionPostings>,
+        num_posts: &[usize],
+    ) -> Result<Vec<TransactionPostings>> {
+        Table::update_filtered_transactions(self, trns, num_posts)
+    }
+
+    fn report_stats(&self) {
+        if let Some(profile) = &self.profile {
+            eprint!("{}", profile.report());
+        }
+    }
+
+    fn collected_errors(&self) -> Result<()> {
+        self.check_collected_errors()
+    }
 }
 
 #[derive(Debug)]
-pub struct Chain(Vec<Rule>);
+pub struct Chain {
+    /// If set, the rules are only applied to postings that match it.
+    guard: Option<Predicate>,
+    rules: Vec<Rule>,
+}
 
 impl Chain {
     pub fn new(rules: Vec<Rule>) -> Self {
-        Self(rules)
+        Self { guard: None, rules }
     }
 
-    fn apply(&self, table: &Table, ctx: &mut PostingContext) -> Result<()> {
-        for rule in &self.0 {
-            match rule.apply(table, ctx)? {
+    /// Only applies the rules to postings that match `guard`, which is
+    /// evaluated once each time the chain is applied.
+    pub fn with_guard(mut self, guard: Predicate) -> Self {
+        self.guard = Some(guard);
+        self
+    }
+
+    pub fn guard(&self) -> Option<&Predicate> {
+        self.guard.as_ref()
+    }
+
+    pub fn rules(&self) -> &[Rule] {
+        &self.rules
+    }
+
+    /// Applies the rules of the chain, which is named `name` in `table`.
+    fn apply(&self, name: &str, table: &Table, ctx: &mut PostingContext) -> Result<()> {
+        if let Some(guard) = &self.guard {
+            if !guard.is_match(table, ctx) {
+                return Ok(());
+            }
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            let stats = table.profile.as_ref().and_then(|p| p.rule(name, i));
+            let result = rule.apply(table, ctx, stats);
+            // Errors are only attributed to unnamed rules when they are
+            // collected, to keep them short otherwise.
+            let result = if table.errors.is_some() || rule.name.is_some() {
+                result.map_err(|err| {
+                    errors::with_location(err, RuleLocation::new(name, i, rule.name()))
+                })
+            } else {
+                result
+            };
+            match result? {
                 RuleResult::Continue => {}
                 RuleResult::Return => break,
             }
@@ -122,52 +451,194 @@ impl Chain {
         Ok(())
     }
 
-    fn validate(&self, table: &Table) -> Result<()> {
-        for r in &self.0 {
-            r.validate(table)?;
+    /// Validates the chain, which is named `name` in `table`.
+    fn validate(&self, name: &str, table: &Table) -> Result<()> {
+        if let Some(guard) = &self.guard {
+            guard
+                .validate(table, &mut Vec::new())
+                .context("in chain guard")?;
+        }
+        for (i, rule) in self.rules.iter().enumerate() {
+            rule.validate(table)
+                .with_context(|| RuleLocation::new(name, i, rule.name()))?;
         }
         Ok(())
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub struct Rule {
+    /// Identifies the rule in errors and reports, instead of its index in
+    /// its chain. Names must be unique within a table.
+    #[serde(
+        default,
+        deserialize_with = "deserialize_name",
+        serialize_with = "serialize_name",
+        skip_serializing_if = "Option::is_none"
+    )]
+    name: Option<String>,
     predicate: Predicate,
     action: Action,
     result: RuleResult,
 }
 
 impl Rule {
-    fn apply(&self, table: &Table, ctx: &mut PostingContext) -> Result<RuleResult> {
-        if self.predicate.is_match(ctx) {
+    pub fn new(predicate: Predicate, action: Action, result: RuleResult) -> Self {
+        Self {
+            name: None,
+            predicate,
+            action,
+            result,
+        }
+    }
+
+    /// Names the rule, for errors and reports.
+    pub fn with_name<S: Into<String>>(mut self, name: S) -> Self {
+        self.name = Some(name.into());
+        self
+    }
+
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// Applies the rule, recording into `stats` if given.
+    fn apply(
+        &self,
+        table: &Table,
+        ctx: &mut PostingContext,
+        stats: Option<&Stats>,
+    ) -> Result<RuleResult> {
+        let start = stats.map(|_| Instant::now());
+        let matched = self.predicate.is_match(table, ctx);
+        let result = if matched {
             self.action.apply(table, ctx)?;
-            Ok(self.result)
+            self.result
         } else {
-            Ok(RuleResult::Continue)
+            RuleResult::Continue
+        };
+        if let (Some(stats), Some(start)) = (stats, start) {
+            stats.record(matched, start.elapsed());
         }
+        Ok(result)
     }
 
     fn validate(&self, table: &Table) -> Result<()> {
+        self.predicate.validate(table, &mut Vec::new())?;
         self.action.validate(table)
     }
 }
 
-#[derive(Clone, Copy, Debug, Deserialize)]
-enum RuleResult {
+/// Allows rule names to be written without `Some(...)`.
+fn deserialize_name<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<String>, D::Error> {
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+/// Writes rule names without `Some(...)`, as read by `deserialize_name`.
+fn serialize_name<S: serde::Serializer>(
+    name: &Option<String>,
+    serializer: S,
+) -> std::result::Result<S::Ok, S::Error> {
+    match name {
+        Some(name) => serializer.serialize_str(name),
+        None => serializer.serialize_none(),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum RuleResult {
+    /// Carry on with the next rule in the chain.
     Continue,
+    /// Return from the current chain.
     Return,
 }
 
-#[derive(Debug, Deserialize)]
-enum Action {
+/// Whether a posting is real or virtual, as in `ledger_parser::Reality`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum Reality {
+    /// A normal posting, e.g. `account`.
+    Real,
+    /// A virtual posting that need not balance, e.g. `(account)`.
+    Virtual,
+    /// A virtual posting that must balance, e.g. `[account]`.
+    BalancedVirtual,
+}
+
+impl From<Reality> for ledger_parser::Reality {
+    fn from(reality: Reality) -> Self {
+        match reality {
+            Reality::Real => Self::Real,
+            Reality::Virtual => Self::UnbalancedVirtual,
+            Reality::BalancedVirtual => Self::BalancedVirtual,
+        }
+    }
+}
+
+/// How a quantity is written, as in `money::NumberFormat`.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, JsonSchema)]
+pub enum NumberFormat {
+    /// Written like "1,234.56".
+    Uk,
+    /// Written like "1.234,56".
+    CommaDecimal,
+}
+
+impl From<NumberFormat> for money::NumberFormat {
+    fn from(format: NumberFormat) -> Self {
+        match format {
+            NumberFormat::Uk => Self::UK,
+            NumberFormat::CommaDecimal => Self::COMMA_DECIMAL,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub enum Action {
+    /// Adds a flag tag to the posting.
     AddPostingFlagTag(String),
+    /// Applies each action in turn.
     All(Vec<Action>),
+    /// Stops processing with an error.
     Error(String),
+    /// Does nothing.
     Noop,
+    /// Applies the rules of the named chain, then continues.
     JumpChain(String),
-    SetAccount(String),
+    /// Sets the posting account. The account is normalized (see `Account`)
+    /// when the rules are loaded.
+    SetAccount(Account),
+    /// Sets whether the posting is real or virtual.
+    SetReality(Reality),
+    /// Removes a flag tag from the posting.
     RemovePostingFlagTag(String),
+    /// Removes a value tag from the posting.
     RemovePostingValueTag(String),
+    /// Sets the transaction's effective date from a value tag in YYYY-MM-DD
+    /// format, looked up on the posting and then on the transaction.
+    SetEffectiveDateFromTag(String),
+    /// Sets the transaction's effective date to its date plus a number of
+    /// days.
+    SetTransactionEffectiveDate(i64),
+    /// Sets the account from the category in a value tag, looked up on the
+    /// posting and then on the transaction, according to the categories
+    /// defined in the rules file. The account is left unchanged if there is
+    /// no such tag, or its category is not defined.
+    SetAccountFromCategoryTag(String),
+    /// Sets the account from a template referring to value tags (looked up on
+    /// the posting and then on the transaction), e.g.
+    /// `"expenses:{trn_type}"`. Each value is made into a single account
+    /// segment by replacing characters such as `:` with spaces. The account
+    /// is left unchanged if any of the tags is missing or empty.
+    SetAccountFromValueTag(String),
+    /// Removes the balance assertion from the posting, if it has one.
+    RemoveBalanceAssertion,
+    /// Sets the balance assertion of the posting from a value tag, looked up
+    /// on the posting and then on the transaction. The tag value is a
+    /// quantity written in the given format, in the commodity of the
+    /// posting's amount.
+    SetBalanceFromTag(String, NumberFormat),
 }
 
 impl Action {
@@ -176,7 +647,7 @@ impl Action {
 
         match self {
             AddPostingFlagTag(name) => {
-                ctx.post.comment.tags.insert(name.to_string());
+                ctx.post.comment.tags.insert(name.as_str().into());
             }
             All(actions) => {
                 for action in actions {
@@ -193,27 +664,120 @@ impl Action {
             }
             Noop => {}
             JumpChain(name) => {
-                table.get_chain(name)?.apply(table, ctx)?;
+                table.get_chain(name)?.apply(name, table, ctx)?;
             }
             SetAccount(v) => {
-                ctx.post.raw.account = v.clone();
+                ctx.post.raw.account = v.to_string();
+            }
+            SetReality(reality) => {
+                ctx.post.raw.reality = (*reality).into();
             }
             RemovePostingFlagTag(name) => {
-                ctx.post.comment.tags.remove(name);
+                ctx.post.comment.tags.remove(name.as_str());
             }
             RemovePostingValueTag(name) => {
-                ctx.post.comment.value_tags.remove(name);
+                ctx.post.comment.value_tags.remove(name.as_str());
+            }
+            SetEffectiveDateFromTag(name) => {
+                let value = ctx.value_tag(name).ok_or_else(|| {
+                        anyhow!(
+                            "value tag {:?} not found for effective date\nWhile processing posting on {}:\n{}",
+                            name,
+                            ctx.trn.raw.date,
+                            ctx.post.raw,
+                        )
+                    })?;
+                let date = NaiveDate::parse_from_str(value, "%Y-%m-%d").with_context(|| {
+                    format!("parsing value tag {:?} as an effective date", name)
+                })?;
+                ctx.trn.raw.effective_date = Some(date);
+            }
+            SetAccountFromCategoryTag(name) => {
+                let account = ctx
+                    .value_tag(name)
+                    .and_then(|category| table.category_account(category));
+                if let Some(account) = account {
+                    ctx.post.raw.account = account.clone();
+                }
+            }
+            SetAccountFromValueTag(account_template) => {
+                let account = template::expand(account_template, |name| {
+                    ctx.value_tag(name).map(String::as_str)
+                })?;
+                if let Some(account) = account {
+                    ctx.post.raw.account = account.into();
+                }
+            }
+            RemoveBalanceAssertion => {
+                ctx.post.raw.balance = None;
+            }
+            SetBalanceFromTag(name, format) => {
+                let value = ctx.value_tag(name).ok_or_else(|| {
+                    anyhow!(
+                        "value tag {:?} not found for balance assertion\nWhile processing posting on {}:\n{}",
+                        name,
+                        ctx.trn.raw.date,
+                        ctx.post.raw,
+                    )
+                })?;
+                let quantity = parse_quantity(value, (*format).into()).with_context(|| {
+                    format!("parsing value tag {:?} as a balance assertion", name)
+                })?;
+                let commodity = match (&ctx.post.raw.amount, &ctx.post.raw.balance) {
+                    (Some(amount), _) => amount.amount.commodity.clone(),
+                    (None, Some(Balance::Amount(balance))) => balance.commodity.clone(),
+                    _ => {
+                        return Err(anyhow!(
+                            "no commodity for balance assertion from value tag {:?}\nWhile processing posting on {}:\n{}",
+                            name,
+                            ctx.trn.raw.date,
+                            ctx.post.raw,
+                        ))
+                    }
+                };
+                ctx.post.raw.balance = Some(Balance::Amount(Amount {
+                    quantity,
+                    commodity,
+                }));
+            }
+            SetTransactionEffectiveDate(days) => {
+                let date = ctx
+                    .trn
+                    .raw
+                    .date
+                    .checked_add_signed(Duration::days(*days))
+                    .ok_or_else(|| {
+                        anyhow!("effective date offset of {} days is out of range", days)
+                    })?;
+                ctx.trn.raw.effective_date = Some(date);
             }
         }
 
         Ok(())
     }
 
+    /// Adds the accounts that the action sets to `accounts`.
+    fn set_accounts<'a>(&'a self, accounts: &mut Vec<&'a Account>) {
+        match self {
+            Action::SetAccount(account) => accounts.push(account),
+            Action::All(actions) => {
+                for action in actions {
+                    action.set_accounts(accounts);
+                }
+            }
+            _ => {}
+        }
+    }
+
     fn validate(&self, table: &Table) -> Result<()> {
         use Action::*;
 
         match self {
             JumpChain(name) => table.get_chain(name).map(|_| ()),
+            SetAccountFromValueTag(account_template) => template::validate(account_template),
+            SetAccountFromCategoryTag(_) if table.categories.is_empty() => Err(anyhow!(
+                "SetAccountFromCategoryTag used without any Categories or BuiltinCategories entries"
+            )),
             _ => Ok(()),
         }
     }
@@ -275,6 +839,30 @@ mod tests {
                         foo  $100.00",
                 }]),
             },
+            Test {
+                name: "set account from value tag",
+                table: r#"[
+                    Chain("start", [
+                        Rule(action: SetAccountFromValueTag("expenses:{trn_type}"), predicate: True, result: Continue),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![
+                    Case {
+                        input: r"2001/01/02 description
+                            ; trn_type: Direct Debit
+                            anything  $100.00",
+                        want: r"2001/01/02 description
+                            ; trn_type: Direct Debit
+                            expenses:Direct Debit  $100.00",
+                    },
+                    Case {
+                        input: r"2001/01/02 description
+                            anything  $100.00",
+                        want: r"2001/01/02 description
+                            anything  $100.00",
+                    },
+                ]),
+            },
             Test {
                 name: "set account in jumped chain",
                 table: r#"[
@@ -292,6 +880,81 @@ mod tests {
                         foo  $100.00",
                 }]),
             },
+            Test {
+                name: "guarded chain",
+                table: r#"[
+                    Chain("start", [
+                        Rule(action: JumpChain("paypal"), predicate: True, result: Continue),
+                        Rule(action: AddPostingFlagTag("done"), predicate: True, result: Continue),
+                    ]),
+                    GuardedChain("paypal", PostingValueTag("bank", Eq("PayPal")), [
+                        Rule(action: SetAccount("assets:paypal"), predicate: True, result: Continue),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![
+                    Case {
+                        input: r"2001/01/02 description
+                            anything  $100.00
+                            ; bank: PayPal",
+                        want: r"2001/01/02 description
+                            assets:paypal  $100.00
+                            ; :done:
+                            ; bank: PayPal",
+                    },
+                    Case {
+                        input: r"2001/01/02 description
+                            anything  $100.00
+                            ; bank: Other",
+                        want: r"2001/01/02 description
+                            anything  $100.00
+                            ; :done:
+                            ; bank: Other",
+                    },
+                ]),
+            },
+            Test {
+                name: "set account using defined predicate",
+                table: r#"[
+                    Define("is-foo", Any([Account(Eq("foo")), Account(Eq("foo2"))])),
+                    Chain("start", [
+                        Rule(action: SetAccount("assets:foo"), predicate: Ref("is-foo"), result: Continue),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        foo2  $100.00
+                        bar  $-100.00",
+                    want: r"2001/01/02 description
+                        assets:foo  $100.00
+                        bar  $-100.00",
+                }]),
+            },
+            Test {
+                name: "flat rules by priority",
+                table: r#"[
+                    Rules([
+                        Rule(action: SetAccount("expenses:other"), priority: -1),
+                        Rule(
+                            predicate: Account(Eq("shop")),
+                            action: SetAccount("expenses:shopping"),
+                            then: Stop,
+                        ),
+                        Rule(
+                            predicate: Account(Eq("shop")),
+                            action: AddPostingFlagTag("checked"),
+                            priority: 10,
+                        ),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        shop  $100.00
+                        bank  $-100.00",
+                    want: r"2001/01/02 description
+                        expenses:shopping  $100.00  ; :checked:
+                        expenses:other  $-100.00",
+                }]),
+            },
             Test {
                 name: "return before set account",
                 table: r#"[
@@ -307,6 +970,137 @@ mod tests {
                         original:value  $100.00",
                 }]),
             },
+            Test {
+                name: "set effective date from tag",
+                table: r#"[
+                    Chain("start", [
+                        Rule(
+                            action: SetEffectiveDateFromTag("edate"),
+                            predicate: PostingHasValueTag("edate"),
+                            result: Continue,
+                        ),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        card  $100.00
+                        ; edate: 2001-01-05
+                        bank  $-100.00",
+                    want: r"2001/01/02=2001/01/05 description
+                        card  $100.00
+                        ; edate: 2001-01-05
+                        bank  $-100.00",
+                }]),
+            },
+            Test {
+                name: "set and remove balance assertions",
+                table: r#"[
+                    Chain("start", [
+                        Rule(
+                            action: SetBalanceFromTag("balance", Uk),
+                            predicate: Account(Eq("bank")),
+                            result: Continue,
+                        ),
+                        Rule(
+                            action: SetBalanceFromTag("balance_eu", CommaDecimal),
+                            predicate: Account(Eq("savings")),
+                            result: Continue,
+                        ),
+                        Rule(
+                            action: RemoveBalanceAssertion,
+                            predicate: All([Account(Eq("card")), PostingHasBalance]),
+                            result: Continue,
+                        ),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        ; balance: 1,234.56
+                        ; balance_eu: 2.345,67
+                        card  $100.00 = $500.00
+                        bank  $-50.00
+                        savings  $-50.00",
+                    want: r"2001/01/02 description
+                        ; balance: 1,234.56
+                        ; balance_eu: 2.345,67
+                        card  $100.00
+                        bank  $-50.00 = $1234.56
+                        savings  $-50.00 = $2345.67",
+                }]),
+            },
+            Test {
+                name: "set account from category tag",
+                table: r#"[
+                    BuiltinCategories,
+                    Categories({"Eating out": "expenses:food:restaurants"}),
+                    Chain("start", [
+                        Rule(
+                            action: SetAccountFromCategoryTag("category"),
+                            predicate: Not(Account(Eq("bank"))),
+                            result: Continue,
+                        ),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"
+                        2001/01/02 description
+                            ; category: groceries
+                            expenses:unknown  $100.00
+                            bank  $-100.00
+
+                        2001/01/03 description
+                            expenses:unknown  $20.00
+                            ; category: eating_out
+                            other:unknown  $10.00
+                            ; category: unmapped",
+                    want: r"
+                        2001/01/02 description
+                            ; category: groceries
+                            expenses:food:groceries  $100.00
+                            bank  $-100.00
+
+                        2001/01/03 description
+                            expenses:food:restaurants  $20.00
+                            ; category: eating_out
+                            other:unknown  $10.00
+                            ; category: unmapped",
+                }]),
+            },
+            Test {
+                name: "set reality of virtual postings",
+                table: r#"[
+                    Chain("start", [
+                        Rule(action: SetReality(BalancedVirtual), predicate: IsVirtual, result: Continue),
+                        Rule(
+                            action: AddPostingFlagTag("real"),
+                            predicate: Reality(Real),
+                            result: Continue,
+                        ),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        (budget:food)  $100.00
+                        bank  $-100.00",
+                    want: r"2001/01/02 description
+                        [budget:food]  $100.00
+                        bank  $-100.00  ; :real:",
+                }]),
+            },
+            Test {
+                name: "set effective date by offset",
+                table: r#"[
+                    Chain("start", [
+                        Rule(action: SetTransactionEffectiveDate(-3), predicate: True, result: Continue),
+                    ]),
+                ]"#,
+                cases: compile_cases(vec![Case {
+                    input: r"2001/01/02 description
+                        anything  $100.00",
+                    want: r"2001/01/02=2000/12/30 description
+                        anything  $100.00",
+                }]),
+            },
             Test {
                 name: "set account based on input account",
                 table: r#"[
@@ -611,6 +1405,32 @@ mod tests {
         }
     }
 
+    #[test]
+    fn parallel_apply_keeps_order() {
+        let table = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(action: SetAccount("foo"), predicate: Account(Eq("bar")), result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect("should parse and validate");
+        let input_text: String = (0..200)
+            .map(|i| format!("2001/01/02 description {}\n    bar  $1.00\n\n", i))
+            .collect();
+        let want_text = input_text.replace("    bar", "    foo");
+
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(4)
+            .build()
+            .expect("build thread pool");
+        let got = pool
+            .install(|| table.update_transactions(parse_transaction_postings(&input_text)))
+            .expect("update_transactions");
+
+        assert_transaction_postings_eq!(parse_transaction_postings(&want_text), got);
+    }
+
     #[test]
     fn error_action() {
         let table = load_from_str(
@@ -638,6 +1458,223 @@ mod tests {
         assert!(err.to_string().contains("bad:account"));
     }
 
+    #[test]
+    fn keep_going_collects_errors() {
+        let mut table = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(action: SetAccount("good:renamed"), predicate: Account(Eq("good:account")), result: Continue),
+                    Rule(action: JumpChain("check"), predicate: True, result: Continue),
+                ]),
+                Chain("check", [
+                    Rule(action: SetAccount("bad:renamed"), predicate: Account(Eq("bad:account")), result: Continue),
+                    Rule(action: Error("MY ERROR"), predicate: Account(Eq("bad:renamed")), result: Return),
+                ]),
+            ]"#,
+        )
+        .expect("should parse and validate");
+        table.enable_keep_going();
+        let input = parse_transaction_postings(
+            r#"
+                2001/01/02 transaction
+                    bad:account   $-10.00
+                    good:account  $10.00
+
+                2001/01/03 transaction
+                    bad:account   $-5.00
+                    good:account  $5.00
+            "#,
+        );
+        let got = table
+            .update_transactions(input)
+            .expect("update_transactions");
+
+        // The failing postings are left unchanged, and the others are updated.
+        assert_eq!(
+            format_transaction_postings(got),
+            format_transaction_postings(parse_transaction_postings(
+                r#"
+                2001/01/02 transaction
+                    bad:account   $-10.00
+                    good:renamed  $10.00
+
+                2001/01/03 transaction
+                    bad:account   $-5.00
+                    good:renamed  $5.00
+                "#,
+            )),
+        );
+        let err = table
+            .check_collected_errors()
+            .expect_err("wanted collected errors");
+        let msg = err.to_string();
+        assert_eq!(msg.matches("MY ERROR").count(), 2, "{}", msg);
+        assert!(msg.contains("in rule check[1]"), "{}", msg);
+        assert!(
+            msg.ends_with("2 errors applying rules:\n  check[1]: 2"),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn filtered_transactions_count_all_postings() {
+        let table = load_from_str(
+            r#"[
+                Rules([
+                    Rule(action: SetAccount("split"), predicate: TransactionPostingCount(Eq(3))),
+                ]),
+            ]"#,
+        )
+        .expect("should parse and validate");
+        let got = table
+            .update_filtered_transactions(
+                parse_transaction_postings(
+                    "2001/01/02 transaction
+                        first  $10.00",
+                ),
+                &[3],
+            )
+            .expect("update_filtered_transactions");
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "2001/01/02 transaction
+                    split  $10.00"
+            ),
+            got
+        );
+    }
+
+    #[test]
+    fn named_rule_errors() {
+        let table = load_from_str(
+            r#"[
+                Rules([
+                    Rule(name: "check-accounts", action: JumpChain("check")),
+                ]),
+                Chain("check", [
+                    Rule(name: "no-bad-accounts", action: Error("MY ERROR"), predicate: Account(Eq("bad")), result: Return),
+                ]),
+            ]"#,
+        )
+        .expect("should parse and validate");
+        let err = table
+            .update_transactions(parse_transaction_postings(
+                "2001/01/02 transaction
+                    bad  $-10.00
+                    good  $10.00",
+            ))
+            .expect_err("should fail");
+        let msg = format!("{:#}", err);
+        assert!(
+            msg.starts_with(
+                "in rule no-bad-accounts in chain check: Rule reported error: MY ERROR"
+            ),
+            "{}",
+            msg
+        );
+    }
+
+    #[test]
+    fn rule_validation_errors_name_rule() {
+        let err = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(action: Noop, predicate: True, result: Continue),
+                    Rule(name: "shops", action: JumpChain("missing"), predicate: True, result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect_err("should fail validation");
+        assert_eq!(err.to_string(), "in rule shops in chain start");
+
+        let err = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(action: JumpChain("missing"), predicate: True, result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect_err("should fail validation");
+        assert_eq!(err.to_string(), "in rule start[0]");
+    }
+
+    #[test]
+    fn duplicate_rule_names_is_error() {
+        let err = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(name: "dup", action: Noop, predicate: True, result: Continue),
+                    Rule(action: JumpChain("other"), predicate: True, result: Continue),
+                ]),
+                Chain("other", [
+                    Rule(name: "dup", action: Noop, predicate: True, result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect_err("should fail validation");
+        assert!(
+            err.to_string()
+                .contains(r#"rule name "dup" is used in chain"#),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn check_taxonomy_warns_of_unknown_roots() {
+        let table = load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(action: SetAccount("expenses:food"), predicate: True, result: Continue),
+                    Rule(name: "typo", action: SetAccount("expences:food"), predicate: True, result: Continue),
+                    Rule(action: JumpChain("other"), predicate: True, result: Continue),
+                ]),
+                Chain("other", [
+                    Rule(action: All([Noop, SetAccount("asets:bank")]), predicate: True, result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect("load");
+        assert_eq!(
+            table.check_taxonomy(&Taxonomy::default()),
+            vec![
+                r#"in rule other[0]: account "asets:bank" is not under any root of the taxonomy"#,
+                r#"in rule typo in chain start: account "expences:food" is not under any root of the taxonomy"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn flat_rules_with_start_chain_is_error() {
+        load_from_str_unvalidated(
+            r#"[
+                Chain("start", []),
+                Rules([Rule(action: Noop)]),
+            ]"#,
+        )
+        .expect_err("should fail");
+    }
+
+    #[test]
+    fn set_account_is_normalized() {
+        let table = load_from_str_unvalidated(
+            r#"[Rules([Rule(action: SetAccount(" expenses : eating  out "))])]"#,
+        )
+        .expect("should load");
+        let got = table
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 description
+                    bar  GBP1.00",
+            ))
+            .expect("update_transactions");
+        assert_eq!(got[0].posts[0].raw.account, "expenses:eating out");
+
+        load_from_str_unvalidated(r#"[Rules([Rule(action: SetAccount(" : "))])]"#)
+            .expect_err("should fail");
+    }
+
     #[test]
     fn validate_valid_tables() {
         struct Test(&'static str, &'static str);
@@ -656,6 +1693,16 @@ mod tests {
                     Chain("foo", []),
                 ]"#,
             ),
+            Test(
+                "nested predicate references",
+                r#"[
+                    Define("a", Ref("b")),
+                    Define("b", True),
+                    Chain("start", [
+                        Rule(action: Noop, predicate: Not(Ref("a")), result: Continue),
+                    ]),
+                ]"#,
+            ),
         ];
 
         for t in &tests {
@@ -695,6 +1742,22 @@ mod tests {
                     ]),
                 ]"#,
             ),
+            Test(
+                "reference to non existing predicate",
+                r#"[
+                    Chain("start", [
+                        Rule(action: Noop, predicate: Ref("not-exist"), result: Continue),
+                    ]),
+                ]"#,
+            ),
+            Test(
+                "predicate reference cycle",
+                r#"[
+                    Define("a", All([True, Ref("b")])),
+                    Define("b", Not(Ref("a"))),
+                    Chain("start", []),
+                ]"#,
+            ),
         ];
 
         for t in &tests {
@@ -704,4 +1767,147 @@ mod tests {
                 .expect_err(&format!("{} => should fail", t.0));
         }
     }
+
+    #[test]
+    fn build_and_serialize() {
+        use predicate::{Regex, StringMatch};
+
+        let table = Table::default()
+            .with_predicate(
+                "is-shop",
+                Predicate::Account(StringMatch::Matches(Regex::new("^shop").unwrap())),
+            )
+            .with_chain(
+                START_CHAIN,
+                Chain::new(vec![Rule::new(
+                    Predicate::Ref("is-shop".to_string()),
+                    Action::JumpChain("shops".to_string()),
+                    RuleResult::Continue,
+                )]),
+            )
+            .with_category("groceries", "expenses:food:groceries")
+            .with_chain(
+                "shops",
+                Chain::new(vec![Rule::new(
+                    Predicate::True,
+                    Action::SetAccount(Account::new("expenses:shopping")),
+                    RuleResult::Return,
+                )
+                .with_name("shopping")])
+                .with_guard(Predicate::Ref("is-shop".to_string())),
+            );
+        table.validate().expect("validate built table");
+
+        let ron = table.to_ron().expect("serialize table");
+        assert!(ron.contains(r#"name: "shopping""#), "{}", ron);
+        let loaded = load_from_str(&ron).expect("load serialized table");
+        assert_eq!(loaded.to_ron().expect("reserialize table"), ron);
+
+        let trns = parse_transaction_postings(
+            r"2001/01/02 description
+                shop:tesco  $100.00
+                bank  $-100.00",
+        );
+        let got = loaded.update_transactions(trns).expect("apply rules");
+        let want = parse_transaction_postings(
+            r"2001/01/02 description
+                expenses:shopping  $100.00
+                bank  $-100.00",
+        );
+        assert_transaction_postings_eq!(want, got);
+    }
+
+    #[test]
+    fn include_from_rules_dir() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let lib_dir = dir.path().join("lib");
+        std::fs::create_dir(&lib_dir).expect("create lib dir");
+        std::fs::write(
+            lib_dir.join("common.ron"),
+            r#"[
+                Chain("common", [
+                    Rule(action: SetAccount("expenses:common"), predicate: True, result: Return),
+                ]),
+            ]"#,
+        )
+        .expect("write common rules");
+        let rules_path = dir.path().join("rules.ron");
+        std::fs::write(
+            &rules_path,
+            r#"[
+                Include("common.ron"),
+                Chain("start", [
+                    Rule(action: JumpChain("common"), predicate: Account(Eq("foo")), result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect("write rules");
+
+        let err = load_from_path(&rules_path).expect_err("should not find included file");
+        assert!(
+            format!("{:#}", err).contains("\"common.ron\" not found"),
+            "{:#}",
+            err
+        );
+
+        let table = load_from_path_with(&rules_path, &[lib_dir]).expect("load rules");
+        let got = table
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 description
+                    foo  GBP10.00
+                    bar",
+            ))
+            .expect("apply rules");
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "
+                2001/01/02 description
+                    expenses:common  GBP10.00
+                    bar"
+            ),
+            got
+        );
+    }
+
+    #[test]
+    fn include_with_windows_separators() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let lib_dir = dir.path().join("lib");
+        let rules_dir = dir.path().join("rules");
+        std::fs::create_dir(&lib_dir).expect("create lib dir");
+        std::fs::create_dir(&rules_dir).expect("create rules dir");
+        std::fs::write(
+            lib_dir.join("common.ron"),
+            r#"[
+                Chain("common", [
+                    Rule(action: SetAccount("expenses:common"), predicate: True, result: Return),
+                ]),
+            ]"#,
+        )
+        .expect("write common rules");
+        let rules_path = rules_dir.join("rules.ron");
+        std::fs::write(
+            &rules_path,
+            "[\r\n    Include(\"..\\\\lib\\\\common.ron\"),\r\n    Chain(\"start\", [Rule(action: JumpChain(\"common\"), predicate: True, result: Continue)]),\r\n]\r\n",
+        )
+        .expect("write rules");
+
+        let table = load_from_path(&rules_path).expect("load rules");
+        let got = table
+            .update_transactions(parse_transaction_postings(
+                "
+                2001/01/02 description
+                    foo  GBP10.00",
+            ))
+            .expect("apply rules");
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "
+                2001/01/02 description
+                    expenses:common  GBP10.00"
+            ),
+            got
+        );
+    }
 }
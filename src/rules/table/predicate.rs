@@ -1,56 +1,143 @@
 use std::fmt;
 
-#[cfg(test)]
-use anyhow::Result;
-use serde::de;
-use serde_derive::Deserialize;
+use anyhow::{bail, Result};
+use chrono::NaiveDate;
+use schemars::JsonSchema;
+use serde::{de, ser};
+use serde_derive::{Deserialize, Serialize};
 
+use crate::accounts::{self, Account};
 use crate::rules::table::ctx::PostingContext;
+use crate::rules::table::{Reality, Table};
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub enum Predicate {
+    /// Matches if all of the predicates match.
     All(Vec<Predicate>),
+    /// Matches if any of the predicates match.
     Any(Vec<Predicate>),
+    /// Matches the posting account.
     Account(StringMatch),
+    /// Matches the given account and its subaccounts, comparing whole
+    /// segments, so that `assets:bank` does not match `assets:bankcard`.
+    AccountUnder(Account),
+    /// Matches the commodity of the posting amount, or of its balance
+    /// assertion if it has no amount.
+    Commodity(StringMatch),
+    /// Matches virtual postings, both `(account)` and `[account]`.
+    IsVirtual,
+    /// Matches if any flag tag on the posting matches.
     PostingFlagTag(StringMatch),
+    /// Matches postings that have an amount.
+    PostingHasAmount,
+    /// Matches postings that have a balance assertion.
+    PostingHasBalance,
+    /// Matches postings that have the named flag tag.
     PostingHasFlagTag(String),
+    /// Matches postings that have the named value tag.
     PostingHasValueTag(String),
+    /// Matches the value of the named value tag on the posting.
     PostingValueTag(String, StringMatch),
+    /// Matches the date of the posting, which is the date given in its
+    /// comment as `[DATE]` if any, or otherwise the date of its transaction.
+    PostingDate(DateMatch),
+    /// Matches postings that have their own date in their comment.
+    PostingHasDate,
+    /// Matches if the predicate does not match.
     Not(Box<Predicate>),
+    /// Refers to a predicate defined with `Define` in the rules file.
+    Ref(String),
+    /// Matches postings with the given reality.
+    Reality(Reality),
+    /// Matches the transaction description.
     TransactionDescription(StringMatch),
+    /// Matches the number of postings in the transaction.
+    TransactionPostingCount(IntMatch),
+    /// Always matches.
     True,
 }
 
 impl Predicate {
-    pub fn is_match(&self, ctx: &PostingContext) -> bool {
+    pub fn is_match(&self, table: &Table, ctx: &PostingContext) -> bool {
         use Predicate::*;
         match self {
             True => true,
-            All(preds) => preds.iter().all(|p| p.is_match(ctx)),
-            Any(preds) => preds.iter().any(|p| p.is_match(ctx)),
+            All(preds) => preds.iter().all(|p| p.is_match(table, ctx)),
+            Any(preds) => preds.iter().any(|p| p.is_match(table, ctx)),
             Account(matcher) => matcher.matches_string(&ctx.post.raw.account),
-            Not(pred) => !pred.is_match(ctx),
+            AccountUnder(prefix) => {
+                accounts::Account::new(&ctx.post.raw.account).starts_with_segments(prefix)
+            }
+            Commodity(matcher) => {
+                let amount = ctx.post.raw.amount.as_ref().map(|amt| &amt.amount);
+                let amount = amount.or(match &ctx.post.raw.balance {
+                    Some(ledger_parser::Balance::Amount(balance)) => Some(balance),
+                    _ => None,
+                });
+                amount.is_some_and(|amount| matcher.matches_string(&amount.commodity.name))
+            }
+            IsVirtual => ctx.post.raw.reality != ledger_parser::Reality::Real,
+            Not(pred) => !pred.is_match(table, ctx),
+            Ref(name) => table.is_ref_match(name, ctx),
+            Reality(reality) => ctx.post.raw.reality == (*reality).into(),
             PostingFlagTag(matcher) => ctx
                 .post
                 .comment
                 .tags
                 .iter()
                 .any(|tag_name| matcher.matches_string(tag_name)),
-            PostingHasFlagTag(tag_name) => ctx.post.comment.tags.contains(tag_name),
-            PostingHasValueTag(tag_name) => ctx.post.comment.value_tags.contains_key(tag_name),
+            PostingHasAmount => ctx.post.raw.amount.is_some(),
+            PostingHasBalance => ctx.post.raw.balance.is_some(),
+            PostingHasFlagTag(tag_name) => ctx.post.comment.tags.contains(tag_name.as_str()),
+            PostingHasValueTag(tag_name) => {
+                ctx.post.comment.value_tags.contains_key(tag_name.as_str())
+            }
             PostingValueTag(tag_name, matcher) => ctx
                 .post
                 .comment
                 .value_tags
-                .get(tag_name)
+                .get(tag_name.as_str())
                 .map(|value| matcher.matches_string(value))
                 .unwrap_or(false),
             TransactionDescription(matcher) => matcher.matches_string(&ctx.trn.raw.description),
+            TransactionPostingCount(matcher) => matcher.matches_int(ctx.num_posts as u64),
+            PostingDate(matcher) => matcher.matches_date(ctx.post.date(ctx.trn.raw.date)),
+            PostingHasDate => ctx.post.comment.date.is_some(),
+        }
+    }
+
+    /// Checks that all references exist and are not cyclic. `stack` contains
+    /// the names of the defined predicates that refer to this one.
+    pub fn validate<'a>(&'a self, table: &'a Table, stack: &mut Vec<&'a str>) -> Result<()> {
+        use Predicate::*;
+        match self {
+            All(preds) | Any(preds) => {
+                for pred in preds {
+                    pred.validate(table, stack)?;
+                }
+                Ok(())
+            }
+            Not(pred) => pred.validate(table, stack),
+            Ref(name) => {
+                if stack.contains(&name.as_str()) {
+                    bail!(
+                        "predicate {:?} refers to itself via: {}",
+                        name,
+                        stack.join(" -> ")
+                    );
+                }
+                let pred = table.get_predicate(name)?;
+                stack.push(name);
+                pred.validate(table, stack)?;
+                stack.pop();
+                Ok(())
+            }
+            _ => Ok(()),
         }
     }
 
     #[cfg(test)]
-    pub fn from_str(s: &str) -> Result<Self> {
+    pub fn from_ron(s: &str) -> Result<Self> {
         ron::de::from_str(s).map_err(Into::into)
     }
 }
@@ -58,6 +145,21 @@ impl Predicate {
 #[derive(Debug)]
 pub struct Regex(regex::Regex);
 
+impl Regex {
+    pub fn new(re: &str) -> Result<Self> {
+        Ok(Self(regex::Regex::new(re)?))
+    }
+}
+
+impl ser::Serialize for Regex {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: ser::Serializer,
+    {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
 impl<'de> de::Deserialize<'de> for Regex {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -67,6 +169,21 @@ impl<'de> de::Deserialize<'de> for Regex {
     }
 }
 
+impl JsonSchema for Regex {
+    fn schema_name() -> String {
+        "Regex".to_string()
+    }
+
+    fn json_schema(_: &mut schemars::gen::SchemaGenerator) -> schemars::schema::Schema {
+        schemars::schema::SchemaObject {
+            instance_type: Some(schemars::schema::InstanceType::String.into()),
+            format: Some("regex".to_string()),
+            ..Default::default()
+        }
+        .into()
+    }
+}
+
 struct RegexVisitor;
 
 impl<'de> de::Visitor<'de> for RegexVisitor {
@@ -86,12 +203,24 @@ impl<'de> de::Visitor<'de> for RegexVisitor {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
 pub enum StringMatch {
+    /// Lowercases the string before matching it.
     AsLower(Box<StringMatch>),
+    /// Matches strings containing the given string.
     Contains(String),
+    /// Matches strings ending with the given string.
+    EndsWith(String),
+    /// Matches strings equal to the given string.
     Eq(String),
+    /// Matches strings equal to the given string, ignoring case.
+    EqIgnoreCase(String),
+    /// Matches strings that the regular expression matches anywhere in.
     Matches(Regex),
+    /// Matches strings equal to any of the given strings.
+    OneOf(Vec<String>),
+    /// Matches strings starting with the given string.
+    StartsWith(String),
 }
 
 impl StringMatch {
@@ -101,8 +230,56 @@ impl StringMatch {
         match self {
             AsLower(m) => m.matches_string(&s.to_lowercase()),
             Contains(want) => s.contains(want),
+            EndsWith(want) => s.ends_with(want),
             Eq(want) => want == s,
+            EqIgnoreCase(want) => want.to_lowercase() == s.to_lowercase(),
             Matches(regex) => regex.0.is_match(s),
+            OneOf(wants) => wants.iter().any(|want| want == s),
+            StartsWith(want) => s.starts_with(want),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub enum DateMatch {
+    /// Matches the given date, written as `"YYYY-MM-DD"`.
+    On(#[schemars(with = "String")] NaiveDate),
+    /// Matches dates before the given date.
+    Before(#[schemars(with = "String")] NaiveDate),
+    /// Matches dates on or after the given date.
+    OnOrAfter(#[schemars(with = "String")] NaiveDate),
+}
+
+impl DateMatch {
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        use DateMatch::*;
+
+        match self {
+            On(want) => date == *want,
+            Before(end) => date < *end,
+            OnOrAfter(start) => date >= *start,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Serialize, JsonSchema)]
+pub enum IntMatch {
+    /// Matches numbers equal to the given number.
+    Eq(u64),
+    /// Matches numbers greater than the given number.
+    Gt(u64),
+    /// Matches numbers less than the given number.
+    Lt(u64),
+}
+
+impl IntMatch {
+    fn matches_int(&self, n: u64) -> bool {
+        use IntMatch::*;
+
+        match self {
+            Eq(want) => n == *want,
+            Gt(bound) => n > *bound,
+            Lt(bound) => n < *bound,
         }
     }
 }
@@ -123,12 +300,47 @@ mod tests {
             ; shouty-key: SHOUTY-VALUE
     "#;
 
+    const BALANCE_ONLY_POSTING: &str = r#"
+        2000/01/01 Transaction description
+            account:name  = EUR10.00
+    "#;
+
+    const NO_AMOUNT_POSTING: &str = r#"
+        2000/01/01 Transaction description
+            account:name
+    "#;
+
+    const SPLIT_POSTING: &str = r#"
+        2000/01/01 Transaction description
+            assets:bank  $-10.00
+            expenses:food  $6.00
+            expenses:household  $4.00
+    "#;
+
+    const DATED_POSTING: &str = r#"
+        2000/01/01 Transaction description
+            account:name  $10.00  ; [2000/01/03]
+    "#;
+
     #[test_case("Account(Contains(\"name\"))", SIMPLE_POSTING => true)]
     #[test_case("Account(Contains(\"other\"))", SIMPLE_POSTING => false)]
     #[test_case("Account(Eq(\"account:name\"))", SIMPLE_POSTING => true)]
     #[test_case("Account(Eq(\"account:other\"))", SIMPLE_POSTING => false)]
     #[test_case("Account(Matches(\"name\"))", SIMPLE_POSTING => true)]
     #[test_case("Account(Matches(\"^name\"))", SIMPLE_POSTING => false)]
+    #[test_case("Account(StartsWith(\"account:\"))", SIMPLE_POSTING => true)]
+    #[test_case("Account(StartsWith(\"name\"))", SIMPLE_POSTING => false)]
+    #[test_case("Account(EndsWith(\":name\"))", SIMPLE_POSTING => true)]
+    #[test_case("Account(EndsWith(\"account\"))", SIMPLE_POSTING => false)]
+    #[test_case("Account(OneOf([\"account:other\", \"account:name\"]))", SIMPLE_POSTING => true)]
+    #[test_case("Account(OneOf([\"account:other\"]))", SIMPLE_POSTING => false)]
+    #[test_case("Account(OneOf([]))", SIMPLE_POSTING => false)]
+    #[test_case("TransactionDescription(EqIgnoreCase(\"TRANSACTION Description\"))", SIMPLE_POSTING => true)]
+    #[test_case("TransactionDescription(EqIgnoreCase(\"Transaction\"))", SIMPLE_POSTING => false)]
+    #[test_case("PostingValueTag(\"shouty-key\", EqIgnoreCase(\"shouty-value\"))", SIMPLE_POSTING => true)]
+    #[test_case("AccountUnder(\"account\")", SIMPLE_POSTING => true)]
+    #[test_case("AccountUnder(\"account:name\")", SIMPLE_POSTING => true)]
+    #[test_case("AccountUnder(\"account:na\")", SIMPLE_POSTING => false)]
     #[test_case("Not(True)", SIMPLE_POSTING => false)]
     #[test_case("PostingFlagTag(Matches(\"^flag-\"))", SIMPLE_POSTING => true)]
     #[test_case("PostingFlagTag(Matches(\"^no-such-flag\"))", SIMPLE_POSTING => false)]
@@ -142,18 +354,44 @@ mod tests {
     #[test_case("PostingValueTag(\"non-shouty-key\", AsLower(Contains(\"shouty-value\")))", SIMPLE_POSTING => true)]
     #[test_case("PostingValueTag(\"shouty-key\", AsLower(Contains(\"shouty-value\")))", SIMPLE_POSTING => true)]
     #[test_case("PostingValueTag(\"shouty-key\", AsLower(Contains(\"SHOUTY-VALUE\")))", SIMPLE_POSTING => false)]
+    #[test_case("Commodity(Eq(\"$\"))", SIMPLE_POSTING => true)]
+    #[test_case("Commodity(Eq(\"EUR\"))", SIMPLE_POSTING => false)]
+    #[test_case("Commodity(Eq(\"EUR\"))", BALANCE_ONLY_POSTING => true)]
+    #[test_case("Commodity(Eq(\"EUR\"))", NO_AMOUNT_POSTING => false)]
+    #[test_case("PostingHasAmount", SIMPLE_POSTING => true)]
+    #[test_case("PostingHasAmount", BALANCE_ONLY_POSTING => false)]
+    #[test_case("PostingHasBalance", SIMPLE_POSTING => false)]
+    #[test_case("PostingHasBalance", BALANCE_ONLY_POSTING => true)]
     #[test_case("TransactionDescription(Eq(\"Transaction description\"))", SIMPLE_POSTING => true)]
     #[test_case("TransactionDescription(Eq(\"non transaction description\"))", SIMPLE_POSTING => false)]
+    #[test_case("PostingDate(On(\"2000-01-01\"))", SIMPLE_POSTING => true)]
+    #[test_case("PostingDate(On(\"2000-01-01\"))", DATED_POSTING => false)]
+    #[test_case("PostingDate(On(\"2000-01-03\"))", DATED_POSTING => true)]
+    #[test_case("PostingDate(Before(\"2000-01-03\"))", DATED_POSTING => false)]
+    #[test_case("PostingDate(OnOrAfter(\"2000-01-03\"))", DATED_POSTING => true)]
+    #[test_case("PostingHasDate", SIMPLE_POSTING => false)]
+    #[test_case("PostingHasDate", DATED_POSTING => true)]
+    #[test_case("TransactionPostingCount(Eq(1))", SIMPLE_POSTING => true)]
+    #[test_case("TransactionPostingCount(Eq(2))", SIMPLE_POSTING => false)]
+    #[test_case("TransactionPostingCount(Eq(2))", SPLIT_POSTING => false)]
+    #[test_case("TransactionPostingCount(Gt(2))", SPLIT_POSTING => true)]
+    #[test_case("TransactionPostingCount(Gt(3))", SPLIT_POSTING => false)]
+    #[test_case("TransactionPostingCount(Lt(3))", SPLIT_POSTING => false)]
+    #[test_case("TransactionPostingCount(Lt(2))", SIMPLE_POSTING => true)]
     #[test_case("True", SIMPLE_POSTING => true)]
     fn predicate(pred: &str, trn: &str) -> bool {
         let mut trn_post_set = parse_transaction_postings(trn);
         assert_eq!(1, trn_post_set.len());
         let trn_posts = &mut trn_post_set[0];
-        assert_eq!(1, trn_posts.posts.len());
+        let num_posts = trn_posts.posts.len();
         let trn = &mut trn_posts.trn;
         let post = &mut trn_posts.posts[0];
-        let ctx = PostingContext { trn, post };
-        let predicate = Predicate::from_str(pred).expect("Predicate::from_str");
-        predicate.is_match(&ctx)
+        let ctx = PostingContext {
+            trn,
+            post,
+            num_posts,
+        };
+        let predicate = Predicate::from_ron(pred).expect("Predicate::from_ron");
+        predicate.is_match(&Table::default(), &ctx)
     }
 }
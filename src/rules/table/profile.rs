@@ -0,0 +1,203 @@
+//! Opt-in profiling of rules, to find which rules of a large table cost the
+//! most to apply.
+
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::rules::table::errors::RuleLocation;
+use crate::rules::table::predicate::Predicate;
+use crate::rules::table::Chain;
+
+/// Maximum length of the predicate shown for each rule in the report.
+const MAX_LABEL_LEN: usize = 60;
+
+/// Counters for a rule or defined predicate. These are updated concurrently
+/// when rules are applied in parallel.
+#[derive(Debug, Default)]
+pub struct Stats {
+    evaluations: AtomicU64,
+    matches: AtomicU64,
+    nanos: AtomicU64,
+}
+
+impl Stats {
+    pub fn record(&self, matched: bool, elapsed: Duration) {
+        self.evaluations.fetch_add(1, Ordering::Relaxed);
+        if matched {
+            self.matches.fetch_add(1, Ordering::Relaxed);
+        }
+        self.nanos
+            .fetch_add(elapsed.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    fn evaluations(&self) -> u64 {
+        self.evaluations.load(Ordering::Relaxed)
+    }
+
+    fn matches(&self) -> u64 {
+        self.matches.load(Ordering::Relaxed)
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::from_nanos(self.nanos.load(Ordering::Relaxed))
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    label: String,
+    stats: Stats,
+}
+
+impl Entry {
+    fn new(label: String) -> Self {
+        Self {
+            label,
+            stats: Stats::default(),
+        }
+    }
+}
+
+/// Statistics for each rule of each chain, and each defined predicate.
+#[derive(Debug, Default)]
+pub struct Profile {
+    rules: HashMap<String, Vec<Entry>>,
+    predicates: HashMap<String, Entry>,
+}
+
+impl Profile {
+    pub fn new(chains: &HashMap<String, Chain>, predicates: &HashMap<String, Predicate>) -> Self {
+        let rules = chains
+            .iter()
+            .map(|(name, chain)| {
+                let entries = chain
+                    .rules()
+                    .iter()
+                    .enumerate()
+                    .map(|(i, rule)| {
+                        let location = RuleLocation::new(name, i, rule.name());
+                        Entry::new(format!(
+                            "{} {}",
+                            location.label(),
+                            describe(&rule.predicate)
+                        ))
+                    })
+                    .collect();
+                (name.clone(), entries)
+            })
+            .collect();
+        let predicates = predicates
+            .keys()
+            .map(|name| (name.clone(), Entry::new(name.clone())))
+            .collect();
+        Self { rules, predicates }
+    }
+
+    /// Returns the stats for the rule at `index` of the named chain.
+    pub fn rule(&self, chain: &str, index: usize) -> Option<&Stats> {
+        self.rules
+            .get(chain)
+            .and_then(|entries| entries.get(index))
+            .map(|entry| &entry.stats)
+    }
+
+    /// Returns the stats for the named defined predicate.
+    pub fn predicate(&self, name: &str) -> Option<&Stats> {
+        self.predicates.get(name).map(|entry| &entry.stats)
+    }
+
+    /// Formats a report of the rules and defined predicates, each ordered by
+    /// descending cumulative time. The time of a rule includes that of its
+    /// action, so includes the rules of any chain that it jumps to.
+    pub fn report(&self) -> String {
+        let mut out = String::new();
+        let rules: Vec<&Entry> = self.rules.values().flatten().collect();
+        write_section(&mut out, "rule", rules);
+        if !self.predicates.is_empty() {
+            out.push('\n');
+            write_section(&mut out, "predicate", self.predicates.values().collect());
+        }
+        out
+    }
+}
+
+fn write_section(out: &mut String, kind: &str, mut entries: Vec<&Entry>) {
+    entries.sort_by(|a, b| {
+        b.stats
+            .elapsed()
+            .cmp(&a.stats.elapsed())
+            .then_with(|| a.label.cmp(&b.label))
+    });
+    writeln!(
+        out,
+        "{:>12} {:>10} {:>10} {:>7}  {}",
+        "time", "evals", "matches", "rate", kind
+    )
+    .unwrap();
+    for entry in entries {
+        let evaluations = entry.stats.evaluations();
+        let matches = entry.stats.matches();
+        let rate = if evaluations == 0 {
+            0.0
+        } else {
+            100.0 * matches as f64 / evaluations as f64
+        };
+        writeln!(
+            out,
+            "{:>12} {:>10} {:>10} {:>6.1}%  {}",
+            format!("{:.3?}", entry.stats.elapsed()),
+            evaluations,
+            matches,
+            rate,
+            entry.label
+        )
+        .unwrap();
+    }
+}
+
+/// Describes a predicate in RON, truncated for display.
+fn describe(predicate: &Predicate) -> String {
+    let mut ron = ron::ser::to_string(predicate).unwrap_or_else(|_| "?".to_string());
+    if ron.chars().count() > MAX_LABEL_LEN {
+        ron = ron.chars().take(MAX_LABEL_LEN - 3).collect();
+        ron.push_str("...");
+    }
+    ron
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::rules::table::load_from_str;
+    use crate::testutil::parse_transaction_postings;
+
+    #[test]
+    fn counts_evaluations_and_matches() {
+        let mut table = load_from_str(
+            r#"[
+                Define("is-foo", Account(Eq("foo"))),
+                Chain("start", [
+                    Rule(action: SetAccount("assets:foo"), predicate: Ref("is-foo"), result: Return),
+                    Rule(action: Noop, predicate: True, result: Continue),
+                ]),
+            ]"#,
+        )
+        .expect("should parse and validate");
+        table.enable_profiling();
+        table
+            .update_transactions(parse_transaction_postings(
+                "2001/01/02 description
+                    foo  $100.00
+                    bar  $-100.00",
+            ))
+            .expect("update_transactions");
+
+        let profile = table.profile().expect("profiling enabled");
+        let counts = |stats: &super::Stats| (stats.evaluations(), stats.matches());
+        assert_eq!(counts(profile.rule("start", 0).unwrap()), (2, 1));
+        assert_eq!(counts(profile.rule("start", 1).unwrap()), (1, 1));
+        assert_eq!(counts(profile.predicate("is-foo").unwrap()), (2, 1));
+        assert!(profile.report().contains(r#"start[0] Ref("is-foo")"#));
+    }
+}
@@ -0,0 +1,202 @@
+//! Description of the rules file format, generated from the types that rules
+//! files are parsed into so that it stays in sync with them.
+
+use std::fmt::Write;
+
+use anyhow::Result;
+use clap::Args;
+use schemars::schema::RootSchema;
+use serde_json::Value;
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// Write the schema as JSON Schema, instead of as text.
+    #[arg(long = "json")]
+    json: bool,
+}
+
+impl Command {
+    pub fn run(&self) -> Result<()> {
+        let schema = super::schema();
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&schema)?);
+        } else {
+            print!("{}", describe(&schema)?);
+        }
+        Ok(())
+    }
+}
+
+/// Formats `schema` as text, listing the variants or fields of each type in
+/// a rules file along with their arguments and documentation.
+pub fn describe(schema: &RootSchema) -> Result<String> {
+    let schema = serde_json::to_value(schema)?;
+    let mut out = String::new();
+    writeln!(out, "A rules file is a list of Entry.")?;
+    if let Some(definitions) = schema["definitions"].as_object() {
+        for (name, def) in definitions {
+            writeln!(out, "\n{}", name)?;
+            if let Some(desc) = description(def) {
+                writeln!(out, "  {}", desc)?;
+            }
+            let items = items(def);
+            if items.is_empty() {
+                writeln!(out, "  = {}", type_name(def))?;
+            }
+            for (sig, desc) in items {
+                writeln!(out, "  {}", sig)?;
+                if let Some(desc) = desc {
+                    writeln!(out, "      {}", desc)?;
+                }
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Returns the signatures and descriptions of the variants of an enum, or the
+/// fields of a struct, ordered by name.
+fn items(def: &Value) -> Vec<(String, Option<&str>)> {
+    let mut items = Vec::new();
+    let alternatives = match def["oneOf"].as_array() {
+        Some(alternatives) => alternatives.iter().collect(),
+        None => vec![def],
+    };
+    for alt in alternatives {
+        if let Some(values) = alt["enum"].as_array() {
+            // Variants without arguments.
+            for value in values.iter().filter_map(Value::as_str) {
+                items.push((value.to_string(), description(alt)));
+            }
+        } else if let Some(properties) = alt["properties"].as_object() {
+            if def["oneOf"].is_array() {
+                // A variant with arguments.
+                for (variant, args) in properties {
+                    items.push((format!("{}{}", variant, arguments(args)), description(alt)));
+                }
+            } else {
+                for (field, field_def) in properties {
+                    let mut sig = format!("{}: {}", field, type_name(field_def));
+                    let required = alt["required"]
+                        .as_array()
+                        .is_some_and(|req| req.iter().any(|r| r == field));
+                    if !required {
+                        match &field_def["default"] {
+                            Value::Null => sig.push_str(" (optional)"),
+                            default => write!(sig, " (default {})", default).unwrap(),
+                        }
+                    }
+                    items.push((sig, description(field_def)));
+                }
+            }
+        }
+    }
+    items.sort();
+    items
+}
+
+fn description(def: &Value) -> Option<&str> {
+    def["description"].as_str()
+}
+
+/// Formats the arguments of a variant, e.g. `(String, Predicate)`.
+fn arguments(def: &Value) -> String {
+    match (def["type"].as_str(), def["items"].as_array()) {
+        (Some("array"), Some(items)) => tuple(items),
+        _ => format!("({})", type_name(def)),
+    }
+}
+
+fn tuple(items: &[Value]) -> String {
+    let names: Vec<String> = items.iter().map(type_name).collect();
+    format!("({})", names.join(", "))
+}
+
+fn type_name(def: &Value) -> String {
+    if let Some(reference) = def["$ref"].as_str() {
+        return reference
+            .rsplit('/')
+            .next()
+            .unwrap_or(reference)
+            .to_string();
+    }
+    if let Some([inner]) = def["allOf"].as_array().map(Vec::as_slice) {
+        return type_name(inner);
+    }
+    match def["type"].as_str() {
+        Some("string") => "String".to_string(),
+        Some("integer") => "Integer".to_string(),
+        Some("number") => "Number".to_string(),
+        Some("boolean") => "Bool".to_string(),
+        Some("array") => match &def["items"] {
+            Value::Array(items) => tuple(items),
+            items => format!("[{}]", type_name(items)),
+        },
+        Some("object") => format!("{{String: {}}}", type_name(&def["additionalProperties"])),
+        _ => "Value".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::rules::table::FORMAT_MAN_PAGE;
+
+    #[test]
+    fn describes_rules_types() {
+        let text = describe(&super::super::schema()).expect("describe");
+        for want in [
+            "\nAction\n",
+            "  SetBalanceFromTag(String, NumberFormat)\n      Sets the balance assertion",
+            "  Noop\n      Does nothing.",
+            "  PostingValueTag(String, StringMatch)\n",
+            "  Define(String, Predicate)\n",
+            "  Categories({String: String})\n",
+            "  Rules([FlatRule])\n",
+            "  priority: Integer (default 0)\n",
+            "  predicate: Predicate (default \"True\")\n",
+            "\nRegex\n  = String\n",
+        ] {
+            assert!(text.contains(want), "missing {:?} in:\n{}", want, text);
+        }
+    }
+
+    #[test]
+    fn json_has_definitions() {
+        let schema = serde_json::to_value(super::super::schema()).expect("to_value");
+        for name in ["Entry", "Action", "Predicate", "StringMatch"] {
+            assert!(
+                schema["definitions"][name]["oneOf"].is_array(),
+                "{} in {}",
+                name,
+                schema
+            );
+        }
+    }
+
+    /// The man page should describe every variant in the schema.
+    #[test]
+    fn man_page_is_complete() {
+        let schema = serde_json::to_value(super::super::schema()).expect("to_value");
+        for name in [
+            "Entry",
+            "Action",
+            "Predicate",
+            "StringMatch",
+            "DateMatch",
+            "IntMatch",
+            "RuleResult",
+        ] {
+            for (sig, _) in items(&schema["definitions"][name]) {
+                let variant = sig.split('(').next().unwrap();
+                assert!(
+                    FORMAT_MAN_PAGE.contains(&format!("\\fB{}", variant)),
+                    "{}::{} is not in the man page",
+                    name,
+                    variant
+                );
+            }
+        }
+    }
+}
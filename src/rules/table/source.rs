@@ -1,11 +1,15 @@
-use std::collections::HashMap;
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::path::{Path, PathBuf};
 
 use anyhow::{anyhow, bail, Context, Result};
-use serde_derive::Deserialize;
+use schemars::schema::RootSchema;
+use schemars::JsonSchema;
+use serde_derive::{Deserialize, Serialize};
 
-use crate::rules::table::{Chain, Rule, Table};
+use crate::filespec;
+use crate::rules::table::categories;
+use crate::rules::table::predicate::Predicate;
+use crate::rules::table::{Action, Chain, Rule, RuleResult, Table, START_CHAIN};
 
 #[derive(Debug)]
 pub struct File {
@@ -34,22 +38,58 @@ impl File {
         })
     }
 
-    pub fn load(self) -> Result<Table> {
+    /// Loads the table, looking for included files that are not found
+    /// relative to the including file in `search_dirs`.
+    pub fn load(self, search_dirs: &[PathBuf]) -> Result<Table> {
         let mut chains = HashMap::<String, Chain>::new();
+        let mut predicates = HashMap::<String, Predicate>::new();
+        let mut flat_rules = Vec::<FlatRule>::new();
         let mut seen_paths = HashSet::new();
-        self.load_into(&mut chains, &mut seen_paths)?;
-        Ok(Table::new(chains))
+        let mut cats = Categories::default();
+        self.load_into(
+            search_dirs,
+            &mut chains,
+            &mut predicates,
+            &mut flat_rules,
+            &mut cats,
+            &mut seen_paths,
+        )?;
+        if cats.builtin {
+            categories::add_builtin(&mut cats.defined);
+        }
+
+        if !flat_rules.is_empty() {
+            if chains.contains_key(START_CHAIN) {
+                bail!(
+                    "a {:?} chain cannot be defined in addition to a Rules list",
+                    START_CHAIN
+                );
+            }
+            // Stable sort, so rules of equal priority keep the order that
+            // they were read in.
+            flat_rules.sort_by_key(|rule| std::cmp::Reverse(rule.priority));
+            let rules = flat_rules.into_iter().map(FlatRule::into_rule).collect();
+            chains.insert(START_CHAIN.to_string(), Chain::new(rules));
+        }
+
+        let mut table = Table::new(chains, predicates);
+        table.categories = cats.defined;
+        Ok(table)
     }
 
     fn load_into(
         self,
+        search_dirs: &[PathBuf],
         chains: &mut HashMap<String, Chain>,
+        predicates: &mut HashMap<String, Predicate>,
+        flat_rules: &mut Vec<FlatRule>,
+        cats: &mut Categories,
         seen_paths: &mut HashSet<Option<PathBuf>>,
     ) -> Result<()> {
         let self_path = self
             .source
             .as_ref()
-            .map(std::fs::canonicalize)
+            .map(|path| filespec::canonicalize(path))
             .transpose()
             .with_context(|| format!("canonicalizing path {:?}", self.source))?;
         if !seen_paths.insert(self_path.clone()) {
@@ -60,35 +100,53 @@ impl File {
         for entry in self.entries {
             match entry {
                 Entry::Include(include_path) => {
-                    let include_path = match self_path {
-                        Some(ref self_path) => {
-                            let parent_dir = self_path.parent().ok_or_else(|| {
-                                anyhow!(
-                                    "unexpected missing parent directory for path {:?}",
-                                    self_path
-                                )
-                            })?;
-                            parent_dir.join(include_path)
-                        }
-                        None => include_path,
-                    };
+                    let include_path =
+                        resolve_include(self_path.as_deref(), &include_path, search_dirs)?;
 
                     let included_file = Self::from_path(&include_path)?;
                     included_file
-                        .load_into(chains, seen_paths)
+                        .load_into(
+                            search_dirs,
+                            chains,
+                            predicates,
+                            flat_rules,
+                            cats,
+                            seen_paths,
+                        )
                         .with_context(|| format!("when including from {:?}", include_path))?;
                 }
                 Entry::Chain(name, rules) => {
+                    insert_chain(chains, name, Chain::new(rules))?;
+                }
+                Entry::GuardedChain(name, guard, rules) => {
+                    insert_chain(chains, name, Chain::new(rules).with_guard(guard))?;
+                }
+                Entry::Rules(rules) => {
+                    flat_rules.extend(rules);
+                }
+                Entry::Categories(mapping) => {
+                    for (category, account) in mapping {
+                        let category = categories::normalize(&category);
+                        if cats.defined.contains_key(&category) {
+                            bail!("found duplicate definition for category {:?}", category);
+                        }
+                        cats.defined.insert(category, account);
+                    }
+                }
+                Entry::BuiltinCategories => {
+                    cats.builtin = true;
+                }
+                Entry::Define(name, predicate) => {
                     use std::collections::hash_map::Entry::*;
-                    match chains.entry(name) {
+                    match predicates.entry(name) {
                         Occupied(entry) => {
                             bail!(
-                                "found duplicate definition for chain named {:?}",
+                                "found duplicate definition for predicate named {:?}",
                                 entry.key()
                             );
                         }
                         Vacant(entry) => {
-                            entry.insert(Chain::new(rules));
+                            entry.insert(predicate);
                         }
                     }
                 }
@@ -99,8 +157,174 @@ impl File {
     }
 }
 
-#[derive(Debug, Deserialize)]
+fn insert_chain(chains: &mut HashMap<String, Chain>, name: String, chain: Chain) -> Result<()> {
+    use std::collections::hash_map::Entry::*;
+    match chains.entry(name) {
+        Occupied(entry) => {
+            bail!(
+                "found duplicate definition for chain named {:?}",
+                entry.key()
+            );
+        }
+        Vacant(entry) => {
+            entry.insert(chain);
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
 enum Entry {
+    /// Includes the entries of another rules file, relative to the including
+    /// file or else to one of the directories given by `--rules-dir`. Either
+    /// `/` or `\\` may separate directories.
     Include(PathBuf),
+    /// Defines a named chain of rules.
     Chain(String, Vec<Rule>),
+    /// Defines a named chain of rules that only applies to postings matching
+    /// the predicate, which is evaluated once before any of the rules.
+    GuardedChain(String, Predicate, Vec<Rule>),
+    /// Defines a named predicate, for `Ref`.
+    Define(String, Predicate),
+    /// A flat list of rules, as a simpler alternative to chains. The rules
+    /// from all `Rules` entries are combined into the start chain, ordered by
+    /// descending priority.
+    Rules(Vec<FlatRule>),
+    /// Maps categories to accounts, for `SetAccountFromCategoryTag`.
+    Categories(HashMap<String, String>),
+    /// Uses the built-in categories, for any categories not defined by
+    /// `Categories` entries.
+    BuiltinCategories,
+}
+
+/// Finds an included rules file, relative to the including file (if any), or
+/// failing that in the first of `search_dirs` that contains it.
+fn resolve_include(
+    including: Option<&Path>,
+    include_path: &Path,
+    search_dirs: &[PathBuf],
+) -> Result<PathBuf> {
+    let include_path = &*filespec::portable_path(include_path);
+    let relative = match including {
+        Some(including) => including
+            .parent()
+            .ok_or_else(|| {
+                anyhow!(
+                    "unexpected missing parent directory for path {:?}",
+                    including
+                )
+            })?
+            .join(include_path),
+        None => include_path.to_owned(),
+    };
+    let mut searched = vec![relative];
+    if !include_path.is_absolute() {
+        searched.extend(search_dirs.iter().map(|dir| dir.join(include_path)));
+    }
+    match searched.iter().find(|path| path.exists()) {
+        Some(path) => Ok(path.clone()),
+        None => bail!(
+            "included rules file {:?} not found, looked for: {}",
+            include_path,
+            itertools::join(searched.iter().map(|path| format!("{:?}", path)), ", ")
+        ),
+    }
+}
+
+/// Returns the JSON schema of a rules file.
+pub fn schema() -> RootSchema {
+    schemars::schema_for!(Vec<Entry>)
+}
+
+/// Categories read from `Categories` and `BuiltinCategories` entries.
+#[derive(Default)]
+struct Categories {
+    defined: HashMap<String, String>,
+    builtin: bool,
+}
+
+/// Borrowed form of the `Entry` variants that a `Table` serializes to.
+#[derive(Serialize)]
+#[serde(rename = "Entry")]
+enum EntryRef<'a> {
+    Categories(BTreeMap<&'a str, &'a str>),
+    Chain(&'a str, &'a [Rule]),
+    Define(&'a str, &'a Predicate),
+    GuardedChain(&'a str, &'a Predicate, &'a [Rule]),
+}
+
+pub fn to_ron(table: &Table) -> Result<String> {
+    let mut entries = Vec::<EntryRef>::new();
+
+    if !table.categories.is_empty() {
+        entries.push(EntryRef::Categories(
+            table
+                .categories
+                .iter()
+                .map(|(category, account)| (category.as_str(), account.as_str()))
+                .collect(),
+        ));
+    }
+
+    let mut predicate_names: Vec<&String> = table.predicates.keys().collect();
+    predicate_names.sort();
+    for name in predicate_names {
+        entries.push(EntryRef::Define(name, &table.predicates[name]));
+    }
+
+    let mut chain_names: Vec<&String> = table.chains.keys().collect();
+    chain_names.sort_by_key(|name| (name.as_str() != START_CHAIN, name.as_str()));
+    for name in chain_names {
+        let chain = &table.chains[name];
+        entries.push(match chain.guard() {
+            Some(guard) => EntryRef::GuardedChain(name, guard, chain.rules()),
+            None => EntryRef::Chain(name, chain.rules()),
+        });
+    }
+
+    let config = ron::ser::PrettyConfig::default().struct_names(true);
+    Ok(ron::ser::to_string_pretty(&entries, config)?)
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+#[serde(deny_unknown_fields, rename = "Rule")]
+#[schemars(rename = "FlatRule")]
+struct FlatRule {
+    #[serde(default, deserialize_with = "super::deserialize_name")]
+    name: Option<String>,
+    #[serde(default)]
+    priority: i32,
+    #[serde(default = "default_flat_predicate")]
+    predicate: Predicate,
+    action: Action,
+    #[serde(default)]
+    then: Then,
+}
+
+fn default_flat_predicate() -> Predicate {
+    Predicate::True
+}
+
+impl FlatRule {
+    fn into_rule(self) -> Rule {
+        Rule {
+            name: self.name,
+            predicate: self.predicate,
+            action: self.action,
+            result: match self.then {
+                Then::Continue => RuleResult::Continue,
+                Then::Stop => RuleResult::Return,
+            },
+        }
+    }
+}
+
+/// What to do after a flat rule matches.
+#[derive(Debug, Default, Deserialize, JsonSchema)]
+enum Then {
+    /// Carry on checking the following rules.
+    #[default]
+    Continue,
+    /// Do not check any further rules for the posting.
+    Stop,
 }
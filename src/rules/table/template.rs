@@ -0,0 +1,110 @@
+//! Account templates, which build an account from value tags for
+//! `Action::SetAccountFromValueTag`, e.g. `"expenses:{trn_type}"`.
+
+use anyhow::{bail, Result};
+
+use crate::accounts::{self, Account};
+
+/// A part of a parsed template.
+enum Part<'a> {
+    Text(&'a str),
+    /// The name of a value tag, written as `{name}`.
+    Tag(&'a str),
+}
+
+fn parse(template: &str) -> Result<Vec<Part<'_>>> {
+    let mut parts = Vec::new();
+    let mut rest = template;
+    while let Some(start) = rest.find(['{', '}']) {
+        if rest[start..].starts_with('}') {
+            bail!("unmatched '}}' in account template {:?}", template);
+        }
+        let end = match rest[start..].find('}') {
+            Some(end) => start + end,
+            None => bail!("unmatched '{{' in account template {:?}", template),
+        };
+        let name = &rest[start + 1..end];
+        if name.is_empty() || name.contains('{') {
+            bail!(
+                "invalid value tag name {:?} in account template {:?}",
+                name,
+                template
+            );
+        }
+        if start > 0 {
+            parts.push(Part::Text(&rest[..start]));
+        }
+        parts.push(Part::Tag(name));
+        rest = &rest[end + 1..];
+    }
+    if !rest.is_empty() {
+        parts.push(Part::Text(rest));
+    }
+    Ok(parts)
+}
+
+/// Checks that `template` is a valid account template.
+pub fn validate(template: &str) -> Result<()> {
+    let parts = parse(template)?;
+    if !parts.iter().any(|part| matches!(part, Part::Tag(_))) {
+        bail!("account template {:?} refers to no value tags", template);
+    }
+    Ok(())
+}
+
+/// Expands `template`, replacing each `{name}` with the value of the named
+/// value tag, as sanitized by `accounts::sanitize_segment`. Returns `None` if
+/// any of the value tags is missing or has no usable value.
+pub fn expand<'a, F>(template: &str, value_tag: F) -> Result<Option<Account>>
+where
+    F: Fn(&str) -> Option<&'a str>,
+{
+    let mut account = String::new();
+    for part in parse(template)? {
+        match part {
+            Part::Text(text) => account.push_str(text),
+            Part::Tag(name) => {
+                let segment = match value_tag(name).map(accounts::sanitize_segment) {
+                    Some(segment) if !segment.is_empty() => segment,
+                    _ => return Ok(None),
+                };
+                account.push_str(&segment);
+            }
+        }
+    }
+    account.parse().map(Some)
+}
+
+#[cfg(test)]
+mod tests {
+    use test_case::test_case;
+
+    fn value_tag(name: &str) -> Option<&'static str> {
+        match name {
+            "type" => Some("Direct Debit"),
+            "category" => Some(" Bills: Utilities (gas) "),
+            "blank" => Some(" ; "),
+            _ => None,
+        }
+    }
+
+    #[test_case("expenses:{type}" => Some("expenses:Direct Debit".to_string()); "one_tag")]
+    #[test_case("expenses:{category}:{type}" => Some("expenses:Bills Utilities gas:Direct Debit".to_string()); "sanitized")]
+    #[test_case("expenses:{missing}" => None; "missing_tag")]
+    #[test_case("expenses:{blank}" => None; "blank_value")]
+    fn expand(template: &str) -> Option<String> {
+        super::expand(template, value_tag)
+            .expect("expand")
+            .map(String::from)
+    }
+
+    #[test_case("expenses:{type}" => true; "valid")]
+    #[test_case("expenses:type" => false; "no_tags")]
+    #[test_case("expenses:{type" => false; "unclosed")]
+    #[test_case("expenses:type}" => false; "unopened")]
+    #[test_case("expenses:{}" => false; "empty_name")]
+    #[test_case("expenses:{{type}}" => false; "nested")]
+    fn validate(template: &str) -> bool {
+        super::validate(template).is_ok()
+    }
+}
@@ -0,0 +1,275 @@
+//! Restricting the postings that rules are applied to by their value tags,
+//! e.g. to apply a bank's rules to an already merged journal without
+//! touching the postings of other banks.
+
+use std::str::FromStr;
+
+use anyhow::{anyhow, bail, Error, Result};
+use clap::Args;
+
+use crate::internal::{PostingInternal, TransactionInternal, TransactionPostings};
+
+/// A value tag that a posting must have.
+#[derive(Clone, Debug)]
+pub struct TagMatch {
+    key: String,
+    value: String,
+}
+
+impl FromStr for TagMatch {
+    type Err = Error;
+
+    /// Parses from the form `KEY=VALUE`, e.g. `bank=Nationwide`.
+    fn from_str(s: &str) -> Result<Self> {
+        let (key, value) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("expected KEY=VALUE, got {:?}", s))?;
+        if key.is_empty() {
+            bail!("expected KEY=VALUE, got {:?}", s);
+        }
+        Ok(Self {
+            key: key.to_string(),
+            value: value.to_string(),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default, Args)]
+pub struct TagFilter {
+    /// Rules are only applied to postings with this value tag, given as
+    /// KEY=VALUE (e.g. bank=Nationwide), either on the posting or on its
+    /// transaction. Other postings are written unchanged. If given multiple
+    /// times, postings with any of the value tags are included.
+    #[arg(long = "only-tagged", value_name = "KEY=VALUE")]
+    only_tagged: Vec<TagMatch>,
+}
+
+/// Where the postings of an input transaction went.
+enum Slot {
+    /// No postings were included, so the transaction is passed through.
+    Untouched(Box<TransactionPostings>),
+    /// Some postings were included. The excluded postings are kept along
+    /// with their original positions.
+    Filtered {
+        included: usize,
+        excluded: Vec<(usize, PostingInternal)>,
+    },
+}
+
+impl TagFilter {
+    fn contains(&self, trn: &TransactionInternal, post: &PostingInternal) -> bool {
+        self.only_tagged.iter().any(|tag| {
+            post.comment
+                .value_tags
+                .get(tag.key.as_str())
+                .or_else(|| trn.comment.value_tags.get(tag.key.as_str()))
+                .is_some_and(|value| *value == tag.value)
+        })
+    }
+
+    /// Calls `update` with only the postings that the filter contains, and
+    /// restores the other postings to their original positions in the
+    /// updated transactions. Transactions without any such postings are
+    /// not passed to `update`. `update` is also given the number of postings
+    /// that each transaction had before filtering.
+    pub fn update<F>(
+        &self,
+        trns: Vec<TransactionPostings>,
+        update: F,
+    ) -> Result<Vec<TransactionPostings>>
+    where
+        F: FnOnce(Vec<TransactionPostings>, &[usize]) -> Result<Vec<TransactionPostings>>,
+    {
+        if self.only_tagged.is_empty() {
+            let num_posts: Vec<usize> = trns.iter().map(|trn| trn.posts.len()).collect();
+            return update(trns, &num_posts);
+        }
+
+        let mut slots = Vec::with_capacity(trns.len());
+        let mut filtered = Vec::new();
+        let mut num_posts = Vec::new();
+        for mut trn in trns {
+            let trn_num_posts = trn.posts.len();
+            let (included, excluded): (Vec<_>, Vec<_>) = std::mem::take(&mut trn.posts)
+                .into_iter()
+                .enumerate()
+                .partition(|(_, post)| self.contains(&trn.trn, post));
+            if included.is_empty() {
+                trn.posts = excluded.into_iter().map(|(_, post)| post).collect();
+                slots.push(Slot::Untouched(Box::new(trn)));
+            } else {
+                slots.push(Slot::Filtered {
+                    included: included.len(),
+                    excluded,
+                });
+                trn.posts = included.into_iter().map(|(_, post)| post).collect();
+                filtered.push(trn);
+                num_posts.push(trn_num_posts);
+            }
+        }
+
+        let filtered_len = filtered.len();
+        let updated = update(filtered, &num_posts)?;
+        if updated.len() != filtered_len {
+            bail!(
+                "--only-tagged requires the rules to keep the transactions, but {} became {}",
+                filtered_len,
+                updated.len()
+            );
+        }
+
+        let mut updated = updated.into_iter();
+        let mut trns = Vec::with_capacity(slots.len());
+        for slot in slots {
+            match slot {
+                Slot::Untouched(trn) => trns.push(*trn),
+                Slot::Filtered { included, excluded } => {
+                    let mut trn = updated.next().expect("checked length");
+                    if trn.posts.len() != included {
+                        bail!(
+                            "--only-tagged requires the rules to keep the postings, but transaction on {} ({:?}) has {} postings instead of {}",
+                            trn.trn.raw.date,
+                            trn.trn.raw.description,
+                            trn.posts.len(),
+                            included
+                        );
+                    }
+                    for (i, post) in excluded {
+                        trn.posts.insert(i, post);
+                    }
+                    trns.push(trn);
+                }
+            }
+        }
+        Ok(trns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assert_transaction_postings_eq;
+    use crate::testutil::parse_transaction_postings;
+
+    fn filter(tags: &[&str]) -> TagFilter {
+        TagFilter {
+            only_tagged: tags.iter().map(|tag| tag.parse().unwrap()).collect(),
+        }
+    }
+
+    fn set_account(
+        trns: Vec<TransactionPostings>,
+        _num_posts: &[usize],
+    ) -> Result<Vec<TransactionPostings>> {
+        Ok(trns
+            .into_iter()
+            .map(|mut trn| {
+                for post in &mut trn.posts {
+                    post.raw.account = "changed".to_string();
+                }
+                trn
+            })
+            .collect())
+    }
+
+    #[test]
+    fn only_updates_tagged_postings() {
+        let trns = parse_transaction_postings(
+            "
+            2001/01/01 mixed
+                first  GBP1.00
+                ; bank: Other
+                second  GBP2.00
+                ; bank: Nationwide
+                third  GBP-3.00
+
+            2001/01/02 other bank
+                first  GBP1.00
+                ; bank: Other
+                second  GBP-1.00
+
+            2001/01/03 transaction tag
+                ; bank: Nationwide
+                first  GBP1.00
+                second  GBP-1.00
+                ; bank: Other",
+        );
+        let got = filter(&["bank=Nationwide"])
+            .update(trns, set_account)
+            .expect("update");
+        assert_transaction_postings_eq!(
+            parse_transaction_postings(
+                "
+                2001/01/01 mixed
+                    first  GBP1.00
+                    ; bank: Other
+                    changed  GBP2.00
+                    ; bank: Nationwide
+                    third  GBP-3.00
+
+                2001/01/02 other bank
+                    first  GBP1.00
+                    ; bank: Other
+                    second  GBP-1.00
+
+                2001/01/03 transaction tag
+                    ; bank: Nationwide
+                    changed  GBP1.00
+                    second  GBP-1.00
+                    ; bank: Other"
+            ),
+            got
+        );
+    }
+
+    #[test]
+    fn passes_original_posting_counts() {
+        let trns = parse_transaction_postings(
+            "
+            2001/01/01 mixed
+                first  GBP1.00
+                ; bank: Nationwide
+                second  GBP2.00
+                third  GBP-3.00
+
+            2001/01/02 other bank
+                first  GBP1.00
+                second  GBP-1.00
+
+            2001/01/03 tagged
+                first  GBP1.00
+                ; bank: Nationwide
+                second  GBP-1.00",
+        );
+        filter(&["bank=Nationwide"])
+            .update(trns, |trns, num_posts| {
+                let filtered: Vec<usize> = trns.iter().map(|trn| trn.posts.len()).collect();
+                assert_eq!(filtered, vec![1, 1]);
+                assert_eq!(num_posts, &[3, 2]);
+                Ok(trns)
+            })
+            .expect("update");
+    }
+
+    #[test]
+    fn rejects_removed_postings() {
+        let trns = parse_transaction_postings(
+            "
+            2001/01/01 description
+                first  GBP1.00
+                ; bank: Nationwide
+                second  GBP-1.00",
+        );
+        let err = filter(&["bank=Nationwide"])
+            .update(trns, |mut trns, _| {
+                trns[0].posts.clear();
+                Ok(trns)
+            })
+            .expect_err("should fail");
+        assert!(
+            err.to_string().contains("has 0 postings instead of 1"),
+            "{}",
+            err
+        );
+    }
+}
@@ -0,0 +1,299 @@
+//! Test cases for rules files, so that a rules file can be checked without
+//! writing Rust tests.
+//!
+//! Test cases are read from a RON file, by default next to the rules file
+//! with the extension `.tests.ron` (e.g. `rules.tests.ron` for `rules.ron`):
+//!
+//! ```ron
+//! [
+//!     Case(
+//!         name: "groceries",
+//!         input: "
+//!             2001/01/01 TESCO STORES
+//!                 assets:current  GBP-10.00
+//!                 expenses:unknown  GBP10.00
+//!         ",
+//!         expect: [
+//!             Posting(account: "assets:current"),
+//!             Posting(account: "expenses:food", tags: ["groceries"]),
+//!         ],
+//!     ),
+//! ]
+//! ```
+//!
+//! Each case contains a single transaction, and an expectation for each of its
+//! postings in order. Only the fields given in an expectation are checked.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde_derive::Deserialize;
+
+use crate::internal::{JournalTransactions, PostingInternal};
+use crate::ledgerutil;
+use crate::rules::table::{self, Table};
+
+#[derive(Debug, Args)]
+pub struct Command {
+    /// The `.ron` file containing the rules to test.
+    rules: PathBuf,
+    /// The `.ron` file containing the test cases. Defaults to the rules file
+    /// with the extension `.tests.ron`.
+    #[arg(long = "cases")]
+    cases: Option<PathBuf>,
+    /// Directory to look for files named by `Include` entries in, as for
+    /// `apply-rules table`.
+    #[arg(long = "rules-dir", value_name = "DIR")]
+    rules_dirs: Vec<PathBuf>,
+}
+
+impl Command {
+    pub fn run(&self) -> Result<()> {
+        let table = table::load_from_path_with(&self.rules, &self.rules_dirs)?;
+        let cases_path = self
+            .cases
+            .clone()
+            .unwrap_or_else(|| self.rules.with_extension("tests.ron"));
+        let cases = read_cases(&cases_path)?;
+
+        let mut failures = 0;
+        for case in &cases {
+            match case.run(&table) {
+                Ok(diffs) if diffs.is_empty() => println!("PASS {}", case.name),
+                Ok(diffs) => {
+                    failures += 1;
+                    println!("FAIL {}", case.name);
+                    for line in diffs {
+                        println!("  {}", line);
+                    }
+                }
+                Err(e) => {
+                    failures += 1;
+                    println!("FAIL {}", case.name);
+                    println!("  error: {:#}", e);
+                }
+            }
+        }
+
+        if failures > 0 {
+            bail!("{} of {} test cases failed", failures, cases.len());
+        }
+        Ok(())
+    }
+}
+
+fn read_cases(path: &Path) -> Result<Vec<Case>> {
+    ron::de::from_reader(
+        std::fs::File::open(path).with_context(|| format!("opening {:?} for reading", path))?,
+    )
+    .with_context(|| format!("parsing {:?}", path))
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Case {
+    name: String,
+    /// A journal snippet containing a single transaction.
+    input: String,
+    /// Expectations for each posting of the transaction after the rules are
+    /// applied.
+    expect: Vec<ExpectPosting>,
+}
+
+impl Case {
+    /// Applies the rules to the input, and returns lines describing
+    /// differences from the expectations. No lines are returned if the case
+    /// passes.
+    fn run(&self, table: &Table) -> Result<Vec<String>> {
+        let ledger = ledgerutil::parse(&dedent(&self.input)).context("parsing input")?;
+        let mut trns = JournalTransactions::from(ledger).trns;
+        if trns.len() != 1 {
+            bail!("input must contain 1 transaction, found {}", trns.len());
+        }
+        let trn = table.update_transaction(trns.remove(0))?;
+
+        let mut diffs = Vec::new();
+        if trn.posts.len() != self.expect.len() {
+            diffs.push(format!(
+                "expected {} postings, got {}",
+                self.expect.len(),
+                trn.posts.len()
+            ));
+        }
+        for (i, (expect, post)) in self.expect.iter().zip(&trn.posts).enumerate() {
+            let post_diffs = expect.diff(post);
+            if !post_diffs.is_empty() {
+                diffs.push(format!("posting {}:", i + 1));
+                diffs.extend(post_diffs);
+            }
+        }
+        Ok(diffs)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename = "Posting")]
+struct ExpectPosting {
+    #[serde(default, deserialize_with = "some")]
+    account: Option<String>,
+    /// The exact set of flag tags expected on the posting.
+    #[serde(default, deserialize_with = "some")]
+    tags: Option<BTreeSet<String>>,
+    /// The exact set of value tags expected on the posting.
+    #[serde(default, deserialize_with = "some")]
+    value_tags: Option<BTreeMap<String, String>>,
+}
+
+/// Allows optional expectations to be written without `Some(...)`.
+fn some<'de, D, T>(deserializer: D) -> std::result::Result<Option<T>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    T::deserialize(deserializer).map(Some)
+}
+
+impl ExpectPosting {
+    fn diff(&self, post: &PostingInternal) -> Vec<String> {
+        let mut diffs = Vec::new();
+        if let Some(account) = &self.account {
+            diff_field(&mut diffs, "account", account, &post.raw.account);
+        }
+        if let Some(tags) = &self.tags {
+            let got: BTreeSet<String> = post.comment.tags.iter().map(|t| t.to_string()).collect();
+            diff_field(&mut diffs, "tags", &format_tags(tags), &format_tags(&got));
+        }
+        if let Some(value_tags) = &self.value_tags {
+            let got: BTreeMap<String, String> = post
+                .comment
+                .value_tags
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect();
+            diff_field(
+                &mut diffs,
+                "value_tags",
+                &format_value_tags(value_tags),
+                &format_value_tags(&got),
+            );
+        }
+        diffs
+    }
+}
+
+fn diff_field(diffs: &mut Vec<String>, name: &str, want: &str, got: &str) {
+    if want != got {
+        diffs.push(format!("- {}: {}", name, want));
+        diffs.push(format!("+ {}: {}", name, got));
+    }
+}
+
+fn format_tags(tags: &BTreeSet<String>) -> String {
+    tags.iter().cloned().collect::<Vec<_>>().join(", ")
+}
+
+fn format_value_tags(tags: &BTreeMap<String, String>) -> String {
+    tags.iter()
+        .map(|(k, v)| format!("{}: {}", k, v))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Removes indentation common to all non-blank lines, so that inputs can be
+/// indented within the test cases file.
+fn dedent(s: &str) -> String {
+    let mut lines = s.lines().filter(|line| !line.trim().is_empty());
+    let first = lines.next().unwrap_or("");
+    let mut indent = &first[..first.len() - first.trim_start().len()];
+    for line in lines {
+        while !line.starts_with(indent) {
+            let mut chars = indent.chars();
+            chars.next_back();
+            indent = chars.as_str();
+        }
+    }
+    let mut result = String::new();
+    for line in s.lines() {
+        if !line.trim().is_empty() {
+            result.push_str(line.strip_prefix(indent).unwrap_or(line));
+        }
+        result.push('\n');
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use test_case::test_case;
+
+    fn run_case(case: &str) -> Vec<String> {
+        let table = table::load_from_str(
+            r#"[
+                Chain("start", [
+                    Rule(
+                        predicate: TransactionDescription(Eq("TESCO STORES")),
+                        action: All([
+                            SetAccount("expenses:food"),
+                            AddPostingFlagTag("groceries"),
+                        ]),
+                        result: Return,
+                    ),
+                ]),
+            ]"#,
+        )
+        .expect("load rules");
+        let case: Case = ron::de::from_str(case).expect("parse case");
+        case.run(&table).expect("run case")
+    }
+
+    #[test]
+    fn passing_case() {
+        let diffs = run_case(
+            r#"Case(
+                name: "groceries",
+                input: "
+                    2001/01/01 TESCO STORES
+                        expenses:unknown  GBP10.00
+                ",
+                expect: [Posting(account: "expenses:food", tags: ["groceries"])],
+            )"#,
+        );
+        assert_eq!(diffs, Vec::<String>::new());
+    }
+
+    #[test]
+    fn failing_case() {
+        let diffs = run_case(
+            r#"Case(
+                name: "not groceries",
+                input: "
+                    2001/01/01 TESCO STORES
+                        expenses:unknown  GBP10.00
+                ",
+                expect: [Posting(account: "expenses:unknown", value_tags: {"shop": "tesco"})],
+            )"#,
+        );
+        assert_eq!(
+            diffs,
+            vec![
+                "posting 1:",
+                "- account: expenses:unknown",
+                "+ account: expenses:food",
+                "- value_tags: shop: tesco",
+                "+ value_tags: ",
+            ]
+        );
+    }
+
+    #[test_case("  a\n    b\n" => "a\n  b\n"; "spaces")]
+    #[test_case("\t a\n\t\tb\n" => " a\n\tb\n"; "mixed whitespace")]
+    #[test_case("\u{3000}a\n  b\n" => "\u{3000}a\n  b\n"; "no common indent")]
+    #[test_case("  a\n\n  b\n" => "a\n\nb\n"; "blank line")]
+    fn dedent_removes_common_indent(s: &str) -> String {
+        dedent(s)
+    }
+}
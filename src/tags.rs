@@ -2,6 +2,9 @@
 pub const ACCOUNT: &str = "account";
 /// Bank identifier/name, provided by the importer.
 pub const BANK: &str = "bank";
+/// Value tag on a posting identifying the merge run that added or last
+/// modified it.
+pub const MERGED: &str = "merged";
 /// Date-specific sequence number, provided by the importer on the import-self posting.
 pub const SEQ: &str = "seq";
 /// Tag indicating that an importer has marked the posting as *not* being of the
@@ -11,16 +14,60 @@ pub const IMPORT_PEER: &str = "import-peer";
 /// Tag indicating that an importer has marked the posting as being of the
 /// account whose data is being imported.
 pub const IMPORT_SELF: &str = "import-self";
+/// Stable identifier for a posting provided by the bank (e.g. a transaction
+/// reference), which is the same across different export formats. Postings
+/// with the same match key for the same account are matched when merging,
+/// regardless of their dates.
+pub const MATCH_KEY: &str = "match-key";
+/// Value tag on a transaction naming the statement file that it was imported
+/// from. Unlike `TRANSACTION_SOURCE_KEY`, this is kept in the journal.
+pub const SOURCE: &str = "source";
+/// Value tag on a transaction referencing where it came from in the imported
+/// statement, to help find the original when checking an import. This is the
+/// line number of a CSV row (or rows, separated by commas), or the page and
+/// line number (as `PAGE:LINE`) of the first line of a PDF transaction.
+pub const SOURCE_LINE: &str = "src_line";
+/// Value tag on a transaction giving its time of day as `HH:MM:SS`, provided
+/// by importers whose statements include times. Merging orders transactions
+/// on the same day by this tag, where they have it.
+pub const TIME: &str = "time";
+/// Value tag on a transaction naming the timezone of its `time` tag.
+pub const TIMEZONE: &str = "tz";
 /// Indicates that the posting's account name is unknown.
 pub const UNKNOWN_ACCOUNT: &str = "unknown-account";
+/// Tag added by merging to a new transaction whose postings all have unknown
+/// accounts, so that they can be classified after merging.
+pub const NEEDS_CLASSIFICATION: &str = "needs-classification";
+/// Tag that rules can add to a posting to flag it for a human to check,
+/// e.g. when a rule's classification is only a guess. Listed by `review`.
+pub const NEEDS_REVIEW: &str = "needs-review";
+/// Tag added by `merge --check-commodities tag` to an input posting whose
+/// commodity is not one that its account has in the journal.
+pub const COMMODITY_MISMATCH: &str = "commodity-mismatch";
 
 /// Prefix for a fingerprint tag applied by merging for postings that are
 /// candidates for merging from another source.
 pub const CANDIDATE_FP_PREFIX: &str = "candidate-";
+/// Tag on an unmerged posting whose candidates were all rejected by a human,
+/// so that the next merge adds it as a new posting instead of soft matching
+/// it. Merging removes the tag.
+pub const MERGE_AS_NEW: &str = "merge-as-new";
+/// Key for a value tag applied by merging alongside candidate tags, which
+/// describes the best candidate to help a human resolve the ambiguity.
+pub const CANDIDATE_HINT: &str = "candidate-hint";
 /// Prefix for a tag key of a fingerprint hash/identifier produced by the
 /// importer. The key and value for this must be consistent upon each re-import
 /// for any given posting that has it.
 pub const FINGERPRINT_PREFIX: &str = "fp-";
+/// Prefix for a tag on a transaction of a hash of its date, description and
+/// postings, added by `generate-fingerprints --transaction-fingerprints`.
+/// Merging matches transactions by it when none of their postings match.
+pub const TRANSACTION_FINGERPRINT_PREFIX: &str = "trn-fp-";
 
 /// Key for a key-value tag on a transaction that specifies where it came from.
 pub const TRANSACTION_SOURCE_KEY: &str = "source-file";
+
+/// Key for a key-value tag on a transaction that records which file of a
+/// journal split by `include` directives it was read from. This is only used
+/// in memory and is removed before writing.
+pub const TRANSACTION_ORIGIN_KEY: &str = "origin-file";
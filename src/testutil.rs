@@ -1,12 +1,13 @@
 use ledger_parser::{LedgerItem, Posting, Transaction};
 
 use crate::comment::Comment;
-use crate::internal::{PostingInternal, TransactionPostings};
+use crate::internal::{JournalTransactions, PostingInternal, TransactionPostings};
+use crate::ledgerutil;
 
 pub fn parse_transaction_postings(s: &str) -> Vec<TransactionPostings> {
-    let ledger =
-        ledger_parser::parse(textwrap::dedent(s).as_ref()).expect("test input did not parse");
-    TransactionPostings::from_ledger(ledger).expect("expected success")
+    let journal =
+        ledgerutil::parse(textwrap::dedent(s).as_ref()).expect("test input did not parse");
+    JournalTransactions::from(journal).trns
 }
 
 pub fn format_transaction_postings(transactions: Vec<TransactionPostings>) -> String {
@@ -26,16 +27,16 @@ pub fn normalize_comment(text: &mut Option<String>) {
 #[macro_export]
 macro_rules! assert_transactions_eq {
     ($want:expr, $got:expr, $($context_arg:expr),*) => {
-        let want_str = crate::testutil::format_transactions(&$want);
-        let got_str = crate::testutil::format_transactions(&$got);
+        let want_str = $crate::testutil::format_transactions(&$want);
+        let got_str = $crate::testutil::format_transactions(&$got);
         if want_str != got_str {
             eprintln!($($context_arg,)*);
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
     };
     ($want:expr, $got:expr) => {
-        let want_str = crate::testutil::format_transactions(&$want);
-        let got_str = crate::testutil::format_transactions(&$got);
+        let want_str = $crate::testutil::format_transactions(&$want);
+        let got_str = $crate::testutil::format_transactions(&$got);
         if want_str != got_str {
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
@@ -45,16 +46,16 @@ macro_rules! assert_transactions_eq {
 #[macro_export]
 macro_rules! assert_transaction_postings_eq {
     ($want:expr, $got:expr, $($context_arg:expr),*) => {
-        let want_str = crate::testutil::format_transaction_postings($want);
-        let got_str = crate::testutil::format_transaction_postings($got);
+        let want_str = $crate::testutil::format_transaction_postings($want);
+        let got_str = $crate::testutil::format_transaction_postings($got);
         if want_str != got_str {
             eprintln!($($context_arg,)*);
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
     };
     ($want:expr, $got:expr) => {
-        let want_str = crate::testutil::format_transaction_postings($want);
-        let got_str = crate::testutil::format_transaction_postings($got);
+        let want_str = $crate::testutil::format_transaction_postings($want);
+        let got_str = $crate::testutil::format_transaction_postings($got);
         if want_str != got_str {
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
@@ -85,16 +86,16 @@ pub fn format_posting_internal(post: PostingInternal) -> String {
 #[macro_export]
 macro_rules! assert_posting_internal_eq {
     ($want:expr, $got:expr, $($context_arg:expr),*) => {
-        let want_str = crate::testutil::format_posting_internal($want);
-        let got_str = crate::testutil::format_posting_internal($got);
+        let want_str = $crate::testutil::format_posting_internal($want);
+        let got_str = $crate::testutil::format_posting_internal($got);
         if want_str != got_str {
             eprintln!($($context_arg,)*);
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
     };
     ($want:expr, $got:expr) => {
-        let want_str = crate::testutil::format_posting_internal($want);
-        let got_str = crate::testutil::format_posting_internal($got);
+        let want_str = $crate::testutil::format_posting_internal($want);
+        let got_str = $crate::testutil::format_posting_internal($got);
         if want_str != got_str {
             text_diff::assert_diff(&want_str, &got_str, "\n", 0);
         }
@@ -1,8 +1,10 @@
 use std::collections::HashMap;
+use std::fmt;
 use std::io::Read;
 
-use anyhow::{bail, format_err, Result};
-use chrono::FixedOffset;
+use anyhow::{anyhow, bail, format_err, Result};
+use chrono::{FixedOffset, LocalResult, NaiveDateTime, Offset, TimeZone};
+use chrono_tz::{OffsetName, Tz};
 use lazy_static::lazy_static;
 use regex::Regex;
 use serde_derive::Deserialize;
@@ -11,44 +13,156 @@ use serde_derive::Deserialize;
 #[derive(Debug, Deserialize)]
 struct TzRecord {
     abbreviation: String,
-    utc_offset: String,
+    #[serde(default)]
+    utc_offset: Option<String>,
+    /// IANA timezone that the abbreviation is used in, e.g. "Europe/London".
+    #[serde(default)]
+    zone: Option<String>,
 }
 
-/// Provides a mapping from timezone abbreviations to fixed UTC offsets.
+/// What an abbreviation means in one timezone.
+#[derive(Debug)]
+struct Definition {
+    /// The fixed offset, or `None` to use the offset of `zone` in effect at
+    /// the time, taking daylight saving time into account.
+    offset: Option<FixedOffset>,
+    zone: Option<Tz>,
+}
+
+impl Definition {
+    fn from_record(record: &TzRecord) -> Result<Self> {
+        let offset = record
+            .utc_offset
+            .as_deref()
+            .map(parse_utc_offset)
+            .transpose()?;
+        let zone = record
+            .zone
+            .as_deref()
+            .map(|zone| zone.parse::<Tz>().map_err(|e| anyhow!("{}", e)))
+            .transpose()?;
+        if offset.is_none() && zone.is_none() {
+            bail!(
+                "timezone abbreviation {} needs a utc_offset or a zone",
+                record.abbreviation
+            );
+        }
+        Ok(Self { offset, zone })
+    }
+
+    /// Returns the offset for the abbreviation at the local time.
+    fn resolve(&self, abbr: &str, local: &NaiveDateTime) -> Result<FixedOffset> {
+        let zone = match (self.offset, self.zone) {
+            (Some(offset), _) => return Ok(offset),
+            (None, Some(zone)) => zone,
+            (None, None) => unreachable!("checked in from_record"),
+        };
+        // At a transition out of daylight saving time, the abbreviation tells
+        // which of the repeated local times is meant.
+        let offset = match zone.offset_from_local_datetime(local) {
+            LocalResult::Single(offset) => Some(offset),
+            LocalResult::Ambiguous(a, b) => [a, b].into_iter().find(|o| o.abbreviation() == abbr),
+            LocalResult::None => None,
+        };
+        match offset {
+            Some(offset) if offset.abbreviation() == abbr => Ok(offset.fix()),
+            _ => bail!(
+                "timezone abbreviation {} is not in use in {} at {}",
+                abbr,
+                zone,
+                local
+            ),
+        }
+    }
+}
+
+impl fmt::Display for Definition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.offset, self.zone) {
+            (Some(offset), Some(zone)) => write!(f, "{} in {}", offset, zone),
+            (Some(offset), None) => write!(f, "{}", offset),
+            (None, Some(zone)) => write!(f, "{}", zone),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+/// Provides a mapping from timezone abbreviations to UTC offsets.
+///
+/// An abbreviation can be defined by a fixed UTC offset, or by the timezone
+/// that it is used in, in which case its offset is that of the timezone at
+/// the time (e.g. "BST" and "GMT" both in "Europe/London"). An abbreviation
+/// can be defined in several timezones (e.g. "CST" in "America/Chicago" and
+/// "Asia/Shanghai"), in which case the preferred regions choose between them.
 pub struct TzAbbrDB {
-    map: HashMap<String, FixedOffset>,
+    map: HashMap<String, Vec<Definition>>,
+    preferred_regions: Vec<String>,
 }
 
 impl TzAbbrDB {
     pub fn from_reader<R: Read>(r: R) -> Result<Self> {
-        use std::collections::hash_map::Entry::*;
-
-        let mut map = HashMap::new();
+        let mut map = HashMap::<String, Vec<Definition>>::new();
         let mut rdr = csv::Reader::from_reader(r);
         for result in rdr.deserialize() {
             let record: TzRecord = result?;
-            let offset: FixedOffset = parse_utc_offset(&record.utc_offset)?;
-            match map.entry(record.abbreviation) {
-                Occupied(entry) => {
-                    bail!(
-                        "found multiple definitions of timezone abbreviation {}: {} and {}",
-                        entry.key(),
-                        entry.get(),
-                        offset,
-                    );
-                }
-                Vacant(entry) => {
-                    entry.insert(offset);
-                }
+            let def = Definition::from_record(&record)?;
+            let defs = map.entry(record.abbreviation.clone()).or_default();
+            // Multiple definitions are only allowed in distinct timezones.
+            if let Some(existing) = defs.iter().find(|existing| {
+                def.zone.is_none() || existing.zone.is_none() || existing.zone == def.zone
+            }) {
+                bail!(
+                    "found multiple definitions of timezone abbreviation {}: {} and {}",
+                    record.abbreviation,
+                    existing,
+                    def,
+                );
             }
+            defs.push(def);
         }
-        Ok(Self { map })
+        Ok(Self {
+            map,
+            preferred_regions: Vec::new(),
+        })
+    }
+
+    /// Sets the regions that choose between the timezones of an abbreviation
+    /// that is defined in more than one, in order of preference. A region is
+    /// a timezone name or a prefix of one, e.g. "America/Chicago" or
+    /// "America/".
+    pub fn with_preferred_regions(mut self, regions: Vec<String>) -> Self {
+        self.preferred_regions = regions;
+        self
+    }
+
+    /// Returns the UTC offset for the named timezone abbreviation at the
+    /// local time.
+    pub fn resolve(&self, abbr: &str, local: &NaiveDateTime) -> Result<FixedOffset> {
+        let defs = self
+            .map
+            .get(abbr)
+            .ok_or_else(|| anyhow!("unknown timezone {:?}", abbr))?;
+        let def = match defs.as_slice() {
+            [def] => def,
+            _ => self.preferred(defs).ok_or_else(|| {
+                anyhow!(
+                    "timezone abbreviation {} is ambiguous between {}; choose with a preferred region",
+                    abbr,
+                    itertools::join(defs, ", ")
+                )
+            })?,
+        };
+        def.resolve(abbr, local)
     }
 
-    /// Returns the fixed UTC offset for the named timezone abbreviation, if
-    /// known.
-    pub fn abbr_to_tz(&self, abbr: &str) -> Option<FixedOffset> {
-        self.map.get(abbr).copied()
+    /// Returns the definition in the most preferred region, if any.
+    fn preferred<'a>(&self, defs: &'a [Definition]) -> Option<&'a Definition> {
+        self.preferred_regions.iter().find_map(|region| {
+            defs.iter().find(|def| {
+                def.zone
+                    .is_some_and(|zone| zone.name().starts_with(region.as_str()))
+            })
+        })
     }
 }
 
@@ -82,9 +196,16 @@ fn parse_utc_offset(s: &str) -> Result<FixedOffset> {
 mod tests {
     use super::{parse_utc_offset, TzAbbrDB};
     use anyhow::Result;
-    use chrono::FixedOffset;
+    use chrono::{FixedOffset, NaiveDate, NaiveDateTime};
     use test_case::test_case;
 
+    fn datetime(y: i32, m: u32, d: u32, h: u32, min: u32) -> NaiveDateTime {
+        NaiveDate::from_ymd_opt(y, m, d)
+            .unwrap()
+            .and_hms_opt(h, min, 0)
+            .unwrap()
+    }
+
     #[test_case("BST" => Some(FixedOffset::east_opt(3600).unwrap()))]
     #[test_case("GMT" => Some(FixedOffset::east_opt(0).unwrap()))]
     #[test_case("ZZZ" => None)]
@@ -98,7 +219,50 @@ mod tests {
         )
         .unwrap();
 
-        db.abbr_to_tz(abbr)
+        db.resolve(abbr, &datetime(2001, 1, 2, 12, 0)).ok()
+    }
+
+    const ZONES_DB: &str = r#"
+        abbreviation,utc_offset,zone
+        BST,,Europe/London
+        GMT,,Europe/London
+        CST,,America/Chicago
+        CST,UTC+08,Asia/Shanghai
+    "#;
+
+    #[test_case("GMT", datetime(2001, 1, 2, 12, 0) => 0; "winter")]
+    #[test_case("BST", datetime(2001, 7, 2, 12, 0) => 3600; "summer")]
+    // 01:30 occurs twice as the clocks go back.
+    #[test_case("BST", datetime(2001, 10, 28, 1, 30) => 3600; "repeated hour in summer time")]
+    #[test_case("GMT", datetime(2001, 10, 28, 1, 30) => 0; "repeated hour in winter time")]
+    fn zone_lookup(abbr: &str, local: NaiveDateTime) -> i32 {
+        parse_string_db(ZONES_DB)
+            .unwrap()
+            .resolve(abbr, &local)
+            .expect("should resolve")
+            .local_minus_utc()
+    }
+
+    #[test]
+    fn zone_lookup_abbreviation_not_in_use() {
+        let err = parse_string_db(ZONES_DB)
+            .unwrap()
+            .resolve("BST", &datetime(2001, 1, 2, 12, 0))
+            .expect_err("BST is not used in winter");
+        assert!(err.to_string().contains("not in use"), "{}", err);
+    }
+
+    #[test_case(&[] => None; "no preference")]
+    #[test_case(&["Europe/"] => None; "no matching preference")]
+    #[test_case(&["America/"] => Some(-6 * 3600); "america")]
+    #[test_case(&["Asia/Shanghai", "America/"] => Some(8 * 3600); "first preference")]
+    fn ambiguous_lookup(regions: &[&str]) -> Option<i32> {
+        parse_string_db(ZONES_DB)
+            .unwrap()
+            .with_preferred_regions(regions.iter().map(|r| r.to_string()).collect())
+            .resolve("CST", &datetime(2001, 1, 2, 12, 0))
+            .ok()
+            .map(|offset| offset.local_minus_utc())
     }
 
     #[test_case(
@@ -133,6 +297,21 @@ mod tests {
     "#,
         "multiple definitions of timezone abbreviation AAA: +01:00 and -01:00"
     )]
+    #[test_case(
+        r#"
+        abbreviation,utc_offset,zone
+        AAA,,Europe/London
+        AAA,UTC+01,Europe/London
+    "#,
+        "multiple definitions of timezone abbreviation AAA: Europe/London and +01:00 in Europe/London"
+    )]
+    #[test_case(
+        r#"
+        abbreviation,zone
+        AAA,Nowhere/Special
+    "#,
+        "not a valid timezone"
+    )]
     fn bad_csv_file(content: &str, want_err_containing: &str) {
         match parse_string_db(content) {
             Ok(_) => panic!("expected an error"),
@@ -0,0 +1,317 @@
+//! Watches a drop folder for statement files, and imports, applies rules to,
+//! and merges each into a journal.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use glob::Pattern;
+use serde_derive::Deserialize;
+
+use crate::accounts::Taxonomy;
+use crate::filespec::{self, FileSpec};
+use crate::importers::cmd::import_file;
+use crate::importers::registry::Registry;
+use crate::internal::{JournalEnds, JournalTransactions, TransactionPostings};
+use crate::merge::{merger, sources};
+use crate::prices::Prices;
+use crate::rules::table;
+
+#[derive(Debug, Args)]
+pub struct Cmd {
+    /// The directory to watch for new statement files.
+    dir: PathBuf,
+    /// RON file configuring the importers and rules to use for statement
+    /// files, and where to write the results.
+    #[arg(long = "config")]
+    config: PathBuf,
+    /// Seconds to wait between checks for new files.
+    #[arg(long = "interval", default_value = "10")]
+    interval: u64,
+    /// Seconds since a file was last modified before it is processed, to
+    /// avoid reading files that are still being written.
+    #[arg(long = "settle", default_value = "5")]
+    settle: u64,
+    /// Process the files currently in the directory and exit, instead of
+    /// watching for new files. Exits with an error if any file failed.
+    #[arg(long = "once")]
+    once: bool,
+}
+
+/// Configuration for `Cmd`, as read from its config file. Relative paths are
+/// relative to the config file.
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    /// Journal to merge imported transactions into. Created if it does not
+    /// exist.
+    journal: PathBuf,
+    /// Directory to write transactions that could not be merged into, as a
+    /// journal named after the statement file.
+    unmerged_dir: PathBuf,
+    /// Directory to move statement files to once they have been processed.
+    processed_dir: PathBuf,
+    /// Accounts registry to use when importing (as for `import
+    /// --accounts-file`).
+    #[serde(default)]
+    accounts_file: Option<PathBuf>,
+    /// Root accounts to put imported unknown accounts under (as for `import
+    /// --taxonomy`).
+    #[serde(default)]
+    taxonomy: Option<PathBuf>,
+    /// Statement file patterns, checked in order. The first pattern matching
+    /// a file's name determines how it is imported. Files that match no
+    /// pattern are ignored.
+    inputs: Vec<Input>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Input {
+    /// Glob pattern matched against the file name.
+    pattern: String,
+    /// Importer subcommand and its arguments, as they would be given on the
+    /// command line, except for the statement file, which is given as the
+    /// importer's first positional argument.
+    importer: Vec<String>,
+    /// Rules file to apply to the imported transactions before merging.
+    #[serde(default)]
+    rules: Option<PathBuf>,
+}
+
+impl Config {
+    fn from_path(path: &Path) -> Result<Self> {
+        let reader = std::fs::File::open(path)
+            .with_context(|| format!("opening watch config {:?}", path))?;
+        let mut config: Config = ron::de::from_reader(reader)
+            .with_context(|| format!("parsing watch config {:?}", path))?;
+        if let Some(dir) = path.parent() {
+            config.journal = dir.join(&config.journal);
+            config.unmerged_dir = dir.join(&config.unmerged_dir);
+            config.processed_dir = dir.join(&config.processed_dir);
+            if let Some(accounts_file) = config.accounts_file.as_mut() {
+                *accounts_file = dir.join(&accounts_file);
+            }
+            if let Some(taxonomy) = config.taxonomy.as_mut() {
+                *taxonomy = dir.join(&taxonomy);
+            }
+            for input in &mut config.inputs {
+                if let Some(rules) = input.rules.as_mut() {
+                    *rules = dir.join(&rules);
+                }
+            }
+        }
+        Ok(config)
+    }
+
+    fn input_for(&self, path: &Path) -> Result<Option<&Input>> {
+        let name = match path.file_name().and_then(|name| name.to_str()) {
+            Some(name) if !name.starts_with('.') => name,
+            _ => return Ok(None),
+        };
+        for input in &self.inputs {
+            let pattern = Pattern::new(&input.pattern)
+                .with_context(|| format!("parsing pattern {:?}", input.pattern))?;
+            if pattern.matches(name) {
+                return Ok(Some(input));
+            }
+        }
+        Ok(None)
+    }
+}
+
+impl Cmd {
+    pub fn run(&self) -> Result<()> {
+        let config = Config::from_path(&self.config)?;
+        let mut accounts = match &config.accounts_file {
+            Some(path) => Registry::from_path(path)?,
+            None => Registry::default(),
+        };
+        if let Some(path) = &config.taxonomy {
+            accounts = accounts.with_taxonomy(Taxonomy::from_path(path)?);
+        }
+
+        // Files that failed to process are not retried until restarted.
+        let mut failed = HashSet::<PathBuf>::new();
+        loop {
+            for (path, input) in self.ready_files(&config, &failed)? {
+                match process_file(&config, &accounts, &path, input) {
+                    Ok(summary) => eprintln!("{}: {}", path.display(), summary),
+                    Err(e) => {
+                        eprintln!("error: {}: {:#}", path.display(), e);
+                        failed.insert(path);
+                    }
+                }
+            }
+
+            if self.once {
+                if !failed.is_empty() {
+                    bail!("{} files failed to process", failed.len());
+                }
+                return Ok(());
+            }
+            std::thread::sleep(Duration::from_secs(self.interval));
+        }
+    }
+
+    /// Returns the files in the watched directory that are ready to be
+    /// processed, sorted by name.
+    fn ready_files<'c>(
+        &self,
+        config: &'c Config,
+        failed: &HashSet<PathBuf>,
+    ) -> Result<Vec<(PathBuf, &'c Input)>> {
+        let settle = Duration::from_secs(self.settle);
+        let now = SystemTime::now();
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(&self.dir)
+            .with_context(|| format!("reading directory {:?}", self.dir))?
+        {
+            let entry = entry?;
+            let path = entry.path();
+            let metadata = entry.metadata()?;
+            if !metadata.is_file() || failed.contains(&path) {
+                continue;
+            }
+            let age = now.duration_since(metadata.modified()?).unwrap_or_default();
+            if age < settle {
+                continue;
+            }
+            if let Some(input) = config.input_for(&path)? {
+                files.push((path, input));
+            }
+        }
+        files.sort_by(|lhs, rhs| lhs.0.cmp(&rhs.0));
+        Ok(files)
+    }
+}
+
+/// Imports, applies rules to, and merges a single statement file, then moves
+/// it to the processed directory. Returns a summary of the outcome.
+fn process_file(
+    config: &Config,
+    accounts: &Registry,
+    path: &Path,
+    input: &Input,
+) -> Result<String> {
+    let file_name = path
+        .file_name()
+        .expect("files in the watched directory have names");
+
+    let (mut trns, import_prices) = import_file(&input.importer, path, accounts)?;
+    let imported = trns.len();
+    if let Some(rules) = &input.rules {
+        let table = table::load_from_path(rules)
+            .with_context(|| format!("loading rules file {:?}", rules))?;
+        trns = table.update_transactions(trns)?;
+    }
+
+    let journal = FileSpec::Path(config.journal.clone());
+    let mut merger = merger::Merger::new();
+    let mut unmerged = Vec::<TransactionPostings>::new();
+    let mut prices = Prices::new();
+    let mut journal_ends = JournalEnds::default();
+    if config.journal.exists() {
+        let (ends, journal_trns) =
+            sources::read_ledger_file(&journal, &mut prices, Default::default())?;
+        journal_ends = ends;
+        for trns in journal_trns {
+            unmerged.append(&mut merger.merge(trns)?.0);
+        }
+    }
+    let stats_before = merger.stats();
+    merger.set_tag_unclassified(true);
+    for src_trns in sources::group_by_source(trns, &FileSpec::Path(path.to_path_buf())) {
+        unmerged.append(&mut merger.merge(src_trns)?.0);
+    }
+    let stats = merger.stats().since(&stats_before);
+    prices.extend(import_prices);
+
+    if !unmerged.is_empty() {
+        std::fs::create_dir_all(&config.unmerged_dir)?;
+        let mut unmerged_name = file_name.to_owned();
+        unmerged_name.push(".journal");
+        let unmerged_path = config.unmerged_dir.join(unmerged_name);
+        filespec::write_ledger_file(
+            &FileSpec::Path(unmerged_path),
+            &TransactionPostings::into_journal(unmerged),
+        )?;
+    }
+
+    let mut merged_trns = merger.build();
+    sources::strip_sources(&mut merged_trns);
+    filespec::write_ledger_file(
+        &journal,
+        &JournalTransactions {
+            trns: merged_trns,
+            prices: prices.into_sorted(),
+            ends: journal_ends,
+        }
+        .into_journal(),
+    )?;
+
+    std::fs::create_dir_all(&config.processed_dir)?;
+    std::fs::rename(path, config.processed_dir.join(file_name))
+        .with_context(|| format!("moving {:?} to {:?}", path, config.processed_dir))?;
+
+    Ok(format!(
+        "imported {}, {} merged, {} new ({} needing classification), {} unmerged",
+        imported, stats.merged, stats.new, stats.needs_classification, stats.unmerged
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn process_and_reprocess_statement() {
+        let dir = tempfile::tempdir().expect("create temp dir");
+        let drop_dir = dir.path().join("drop");
+        std::fs::create_dir(&drop_dir).expect("create drop dir");
+        let config_path = dir.path().join("watch.ron");
+        std::fs::write(
+            &config_path,
+            r#"Config(
+                journal: "main.journal",
+                unmerged_dir: "unmerged",
+                processed_dir: "processed",
+                inputs: [
+                    Input(
+                        pattern: "*.csv",
+                        importer: ["nationwide-csv", "--fp-namespace=fixed:current"],
+                    ),
+                ],
+            )"#,
+        )
+        .expect("write config");
+        let statement = include_bytes!("../testdata/importers/nationwide_csv_6.csv");
+        std::fs::write(drop_dir.join("statement.csv"), statement).expect("write statement");
+        std::fs::write(drop_dir.join("notes.txt"), "ignored").expect("write other file");
+
+        let cmd = Cmd {
+            dir: drop_dir.clone(),
+            config: config_path,
+            interval: 0,
+            settle: 0,
+            once: true,
+        };
+        cmd.run().expect("first run");
+
+        assert!(!drop_dir.join("statement.csv").exists());
+        assert!(drop_dir.join("notes.txt").exists());
+        assert!(dir.path().join("processed/statement.csv").exists());
+        let journal = std::fs::read_to_string(dir.path().join("main.journal")).expect("journal");
+        assert_eq!(journal.matches("ATM Withdrawal").count(), 1);
+
+        // Dropping the same statement again merges into the existing
+        // transactions.
+        std::fs::write(drop_dir.join("statement.csv"), statement).expect("write statement");
+        cmd.run().expect("second run");
+        let journal = std::fs::read_to_string(dir.path().join("main.journal")).expect("journal");
+        assert_eq!(journal.matches("ATM Withdrawal").count(), 1);
+        assert!(!dir.path().join("unmerged").exists());
+    }
+}
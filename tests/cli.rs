@@ -0,0 +1,961 @@
+//! End-to-end tests that run the accountmerge binary, to catch regressions in
+//! how the subcommands are wired together, and in reading and writing files.
+//!
+//! Outputs are compared against golden files in testdata/cli. Run with
+//! `UPDATE_GOLDENFILES=1` to update them after an intended change.
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use assert_cmd::Command;
+use goldenfile::Mint;
+use tempfile::TempDir;
+
+const TESTDATA: &str = "testdata/cli";
+
+fn accountmerge() -> Command {
+    Command::cargo_bin("accountmerge").expect("find binary")
+}
+
+fn read(path: &Path) -> String {
+    std::fs::read_to_string(path).unwrap_or_else(|e| panic!("reading {:?}: {}", path, e))
+}
+
+/// Checks that the file at `path` matches the golden file `name`.
+fn check_golden(mint: &mut Mint, name: &str, path: &Path) {
+    let mut golden = mint
+        .new_goldenfile_with_differ(name, Box::new(goldenfile::differs::text_diff))
+        .expect("new goldenfile");
+    golden
+        .write_all(read(path).as_bytes())
+        .expect("write output");
+}
+
+struct Workspace {
+    dir: TempDir,
+}
+
+impl Workspace {
+    fn new() -> Self {
+        Self {
+            dir: tempfile::tempdir().expect("create temp dir"),
+        }
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.dir.path().join(name)
+    }
+
+    /// Writes `content` to the file `name`, with the common indentation
+    /// removed from each line.
+    fn write(&self, name: &str, content: &str) {
+        std::fs::write(self.path(name), textwrap::dedent(content).trim_start())
+            .expect("write file");
+    }
+
+    /// Returns a command that runs in the workspace, so that file names in
+    /// its output do not depend on where the workspace is.
+    fn accountmerge(&self) -> Command {
+        let mut cmd = accountmerge();
+        cmd.current_dir(self.dir.path());
+        cmd
+    }
+}
+
+#[test]
+fn import_apply_rules_and_merge() {
+    let ws = Workspace::new();
+    let mut mint = Mint::new(TESTDATA);
+
+    let imported = ws.path("imported.journal");
+    accountmerge()
+        .arg("import")
+        .arg("--output")
+        .arg(&imported)
+        .args([
+            "nationwide-csv",
+            "--fp-namespace",
+            "lookup:examples/accounts.ron",
+        ])
+        .arg("examples/statement1.csv")
+        .assert()
+        .success();
+    check_golden(&mut mint, "imported.golden.journal", &imported);
+
+    let ruled = ws.path("ruled.journal");
+    accountmerge()
+        .arg("apply-rules")
+        .arg("--output")
+        .arg(&ruled)
+        .arg(&imported)
+        .args(["table", "examples/rules.ron"])
+        .assert()
+        .success();
+    check_golden(&mut mint, "ruled.golden.journal", &ruled);
+
+    let merged = ws.path("merged.journal");
+    accountmerge()
+        .arg("merge")
+        .arg("--output")
+        .arg(&merged)
+        .arg(Path::new(TESTDATA).join("initial.journal"))
+        .arg(&ruled)
+        .assert()
+        .success();
+    check_golden(&mut mint, "merged.golden.journal", &merged);
+
+    // Merging the same statement again changes nothing.
+    let remerged = ws.path("remerged.journal");
+    accountmerge()
+        .arg("merge")
+        .arg("--output")
+        .arg(&remerged)
+        .arg(&merged)
+        .arg(&ruled)
+        .assert()
+        .success();
+    assert_eq!(read(&merged), read(&remerged));
+}
+
+#[test]
+fn merge_import_matches_import_then_merge() {
+    let ws = Workspace::new();
+    ws.write("journal.journal", "");
+    std::fs::copy("examples/statement1.csv", ws.path("my statement.csv")).expect("copy");
+
+    ws.accountmerge()
+        .args(["import", "-o", "imported.journal"])
+        .args(["nationwide-csv", "my statement.csv"])
+        .assert()
+        .success();
+    let merged = ws
+        .accountmerge()
+        .args(["merge", "journal.journal", "imported.journal"])
+        .assert()
+        .success()
+        .get_output()
+        .stdout
+        .clone();
+
+    ws.accountmerge()
+        .args(["merge", "journal.journal"])
+        .args(["--import", "nationwide-csv 'my statement.csv'"])
+        .assert()
+        .success()
+        .stdout(String::from_utf8(merged).expect("UTF-8 output"));
+}
+
+#[test]
+fn merge_import_records_history() {
+    let ws = Workspace::new();
+    ws.write("journal.journal", "");
+    std::fs::copy("examples/statement1.csv", ws.path("statement.csv")).expect("copy");
+    std::fs::copy("examples/statement1.csv", ws.path("copy.csv")).expect("copy");
+
+    ws.accountmerge()
+        .args(["merge", "journal.journal", "-o", "merged.journal"])
+        .args(["--import", "nationwide-csv statement.csv"])
+        .args(["--import-history", "history.json", "--reject-reimport"])
+        .assert()
+        .success();
+    assert!(ws.path("history.json").exists(), "history not written");
+
+    ws.accountmerge()
+        .args(["merge", "merged.journal", "-o", "merged.journal"])
+        .args(["--import", "nationwide-csv copy.csv"])
+        .args(["--import-history", "history.json", "--reject-reimport"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "already imported to \"merged.journal\"",
+        ));
+}
+
+#[test]
+fn merge_import_with_positional_after_statement() {
+    // The statement is given before the timezone abbreviations file.
+    accountmerge()
+        .args(["merge", "-"])
+        .arg("--import")
+        .arg(
+            "paypal-csv testdata/importers/paypal_csv.csv \
+             testdata/importers/paypal_csv_tz_abbrs.csv \
+             --output-timezone UTC",
+        )
+        .write_stdin("")
+        .assert()
+        .success()
+        .stdout(predicates::str::contains("2019-01-02 Othercompany Ltd."));
+}
+
+#[test]
+fn merge_to_stdout() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+        ",
+    );
+    ws.write(
+        "input.journal",
+        "
+        2000/01/02 Other shop
+            assets:checking  GBP-5.00  ; :fp-3:
+            expenses:food  GBP5.00  ; :fp-4:
+        ",
+    );
+    ws.accountmerge()
+        .args(["merge", "journal.journal", "input.journal"])
+        .assert()
+        .success()
+        .stdout(
+            "2000-01-01 Shop\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-1:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-2:\n\
+             \n\
+             2000-01-02 Other shop\n  \
+             assets:checking  GBP-5.00\n  \
+             ; :fp-3:\n  \
+             expenses:food  GBP5.00\n  \
+             ; :fp-4:\n",
+        );
+}
+
+#[test]
+fn merge_conflict() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-3:
+            expenses:food  GBP10.00  ; :fp-4:
+        ",
+    );
+    // The fingerprints of the first posting match postings of both existing
+    // transactions.
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:fp-3:
+            expenses:food  GBP10.00  ; :fp-5:
+        ",
+    );
+    let merge = [
+        "merge",
+        "--output",
+        "output.journal",
+        "journal.journal",
+        "input.journal",
+    ];
+
+    ws.accountmerge()
+        .args(merge)
+        .arg("--dry-run")
+        .assert()
+        .failure()
+        .stdout(
+            "\"journal.journal\": 0 would merge, 2 new (0 needing classification), 0 unmerged, 0 conflicts\n\
+             \"input.journal\": 0 would merge, 0 new (0 needing classification), 0 unmerged, 1 conflicts\n",
+        )
+        .stderr(predicates::str::contains("dry run found 1 conflicts"))
+        .stderr(predicates::str::contains("later inputs were not checked"));
+    assert!(!ws.path("output.journal").exists(), "dry run wrote output");
+
+    ws.accountmerge()
+        .args(merge)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "input posting matches multiple same destination postings by fingerprints",
+        ));
+    assert!(
+        !ws.path("output.journal").exists(),
+        "failed merge wrote output"
+    );
+}
+
+#[test]
+fn merge_unmerged() {
+    let ws = Workspace::new();
+    let mut mint = Mint::new(TESTDATA);
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-3:
+            expenses:food  GBP10.00  ; :fp-4:
+        ",
+    );
+    // Soft matches both of the existing transactions.
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-5:
+            expenses:unknown  GBP10.00  ; :fp-6:unknown-account:
+
+        2000/01/02 Other shop
+            assets:checking  GBP-5.00  ; :fp-7:
+            expenses:food  GBP5.00  ; :fp-8:
+        ",
+    );
+    let merge = [
+        "merge",
+        "--output",
+        "output.journal",
+        "journal.journal",
+        "input.journal",
+    ];
+
+    ws.accountmerge()
+        .args(merge)
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "1 input transactions have gone unmerged and no --unmerged output file was specified",
+        ));
+    assert!(
+        !ws.path("output.journal").exists(),
+        "failed merge wrote output"
+    );
+
+    ws.accountmerge()
+        .args(merge)
+        .args(["--unmerged", "unmerged.journal"])
+        .assert()
+        .success();
+    check_golden(
+        &mut mint,
+        "unmerged_output.golden.journal",
+        &ws.path("output.journal"),
+    );
+    check_golden(
+        &mut mint,
+        "unmerged.golden.journal",
+        &ws.path("unmerged.journal"),
+    );
+}
+
+#[test]
+fn merge_unmerged_rules() {
+    let ws = Workspace::new();
+    let mut mint = Mint::new(TESTDATA);
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-3:
+            expenses:food  GBP10.00  ; :fp-4:
+        ",
+    );
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-5:
+            expenses:unknown  GBP10.00  ; :fp-6:unknown-account:
+        ",
+    );
+    ws.write(
+        "unmerged.ron",
+        r#"
+        [
+            Chain("start", [
+                Rule(
+                    action: AddPostingFlagTag("review"),
+                    predicate: PostingFlagTag(Eq("unknown-account")),
+                    result: Continue,
+                ),
+            ]),
+        ]
+        "#,
+    );
+
+    ws.accountmerge()
+        .args([
+            "merge",
+            "--output",
+            "output.journal",
+            "--unmerged",
+            "unmerged.journal",
+            "--unmerged-rules",
+            "unmerged.ron",
+            "journal.journal",
+            "input.journal",
+        ])
+        .assert()
+        .success();
+    check_golden(
+        &mut mint,
+        "unmerged_ruled.golden.journal",
+        &ws.path("unmerged.journal"),
+    );
+}
+
+#[test]
+fn resolve_candidates_and_merge_again() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-3:
+            expenses:food  GBP10.00  ; :fp-4:
+        ",
+    );
+    ws.write(
+        "unmerged.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-5:candidate-fp-1:candidate-fp-3:
+            expenses:food  GBP10.00  ; :fp-6:candidate-fp-2:candidate-fp-4:
+        ",
+    );
+
+    ws.accountmerge()
+        .args(["resolve-candidates", "unmerged.journal"])
+        .args(["--accept", "fp-5=fp-3", "--reject", "fp-7"])
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "no posting with candidates has the fingerprint(s): fp-7",
+        ));
+
+    ws.accountmerge()
+        .args(["resolve-candidates", "unmerged.journal"])
+        .args(["--accept", "fp-5=fp-3", "--accept", "fp-6=candidate-fp-4"])
+        .assert()
+        .success()
+        .stderr("\"unmerged.journal\": resolved 2 postings, 0 still have candidates\n");
+
+    ws.accountmerge()
+        .args(["merge", "journal.journal", "unmerged.journal"])
+        .assert()
+        .success()
+        .stdout(
+            "2000-01-01 Shop\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-1:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-2:\n\
+             \n\
+             2000-01-01 Shop\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-3:fp-5:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-4:fp-6:\n",
+        );
+}
+
+#[test]
+fn reject_candidates_and_merge_again() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop A
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/01 Shop B
+            assets:checking  GBP-10.00  ; :fp-3:
+            expenses:food  GBP10.00  ; :fp-4:
+        ",
+    );
+    // Only one posting has candidates, so its peer would soft match on its
+    // own if the rejection were not applied to the whole transaction.
+    ws.write(
+        "unmerged.journal",
+        "
+        2000/01/01 Shop C
+            assets:checking  GBP-10.00  ; :fp-5:candidate-fp-1:candidate-fp-3:
+            expenses:food  GBP10.00  ; :fp-6:
+        ",
+    );
+
+    ws.accountmerge()
+        .args(["resolve-candidates", "unmerged.journal", "--reject", "fp-5"])
+        .assert()
+        .success()
+        .stderr("\"unmerged.journal\": resolved 1 postings, 0 still have candidates\n");
+
+    ws.accountmerge()
+        .args(["merge", "journal.journal", "unmerged.journal"])
+        .assert()
+        .success()
+        .stdout(
+            "2000-01-01 Shop A\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-1:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-2:\n\
+             \n\
+             2000-01-01 Shop B\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-3:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-4:\n\
+             \n\
+             2000-01-01 Shop C\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-5:\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-6:\n",
+        );
+}
+
+#[test]
+fn merged_tag_marks_only_changed_postings() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            ; merged: run-1
+            expenses:unknown  GBP10.00  ; :fp-2:unknown-account:
+            ; merged: run-1
+        ",
+    );
+    // Re-merging the same postings, except for the now known account.
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/02 Cafe
+            assets:checking  GBP-3.00  ; :fp-3:
+            expenses:food  GBP3.00  ; :fp-4:
+        ",
+    );
+
+    ws.accountmerge()
+        .args([
+            "merge",
+            "--merged-tag=run-2",
+            "journal.journal",
+            "input.journal",
+        ])
+        .assert()
+        .success()
+        .stdout(
+            "2000-01-01 Shop\n  \
+             assets:checking  GBP-10.00\n  \
+             ; :fp-1:\n  \
+             ; merged: run-1\n  \
+             expenses:food  GBP10.00\n  \
+             ; :fp-2:\n  \
+             ; merged: run-2\n\
+             \n\
+             2000-01-02 Cafe\n  \
+             assets:checking  GBP-3.00\n  \
+             ; :fp-3:\n  \
+             ; merged: run-2\n  \
+             expenses:food  GBP3.00\n  \
+             ; :fp-4:\n  \
+             ; merged: run-2\n",
+        );
+}
+
+#[test]
+fn fingerprint_index_matches_full_merge() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 ! Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+        ",
+    );
+    // Clears the existing transaction, which the index cannot skip.
+    ws.write(
+        "update.journal",
+        "
+        2000/01/01 * Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            ; receipt: 123
+            expenses:food  GBP10.00  ; :fp-2:
+        ",
+    );
+    // Only repeats already merged postings and adds new ones.
+    ws.write(
+        "append.journal",
+        "
+        2000/01/01 * Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        2000/01/03 Cafe
+            assets:checking  GBP-3.00  ; :fp-5:
+            expenses:food  GBP3.00  ; :fp-6:
+
+        2000/01/02 Bakery
+            assets:checking  GBP-2.00  ; :fp-3:
+            expenses:food  GBP2.00  ; :fp-4:
+
+        2000/01/04 Unknown
+            assets:unknown  GBP-1.00  ; :fp-7:unknown-account:
+            expenses:unknown  GBP1.00  ; :fp-8:unknown-account:
+        ",
+    );
+    std::fs::copy(ws.path("journal.journal"), ws.path("full.journal")).expect("copy");
+    std::fs::copy(ws.path("journal.journal"), ws.path("indexed.journal")).expect("copy");
+
+    for (input, uses_index) in [
+        // Builds the index.
+        ("journal.journal", false),
+        ("update.journal", false),
+        ("append.journal", true),
+    ] {
+        ws.accountmerge()
+            .args(["merge", "full.journal", input, "-o", "full.journal"])
+            .assert()
+            .success();
+        let output = ws
+            .accountmerge()
+            .args([
+                "merge",
+                "--fingerprint-index",
+                "indexed.index",
+                "indexed.journal",
+                input,
+                "-o",
+                "indexed.journal",
+            ])
+            .assert()
+            .success()
+            .get_output()
+            .clone();
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert_eq!(
+            stderr.contains("merged using fingerprint index"),
+            uses_index,
+            "merging {}: {}",
+            input,
+            stderr
+        );
+        assert_eq!(
+            stderr.contains("new transaction needs classification"),
+            input == "append.journal",
+            "merging {}: {}",
+            input,
+            stderr
+        );
+        assert_eq!(
+            read(&ws.path("indexed.journal")),
+            read(&ws.path("full.journal")),
+            "merging {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn fingerprint_index_applies_account_policy() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+        ",
+    );
+    // Reclassified by changed rules.
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:groceries  GBP10.00  ; :fp-2:
+        ",
+    );
+    let merge = |input: &str| {
+        let mut cmd = ws.accountmerge();
+        cmd.args([
+            "merge",
+            "--account-policy=update",
+            "--fingerprint-index",
+            "journal.index",
+            "journal.journal",
+            input,
+            "-o",
+            "journal.journal",
+        ]);
+        cmd
+    };
+    // Builds the index.
+    merge("journal.journal").assert().success();
+
+    merge("input.journal")
+        .assert()
+        .success()
+        .stderr(predicates::str::contains(
+            "in account \"expenses:food\" was merged with an input posting in account \"expenses:groceries\", updated",
+        ));
+    assert!(read(&ws.path("journal.journal")).contains("expenses:groceries"));
+}
+
+#[test]
+fn fingerprint_index_checks_namespaces() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-nwcsv6.1.ns1-1:
+            expenses:food  GBP10.00  ; :fp-nwcsv6.1.ns1-2:
+
+        2000/01/10 Shop
+            assets:checking  GBP-20.00  ; :fp-nwcsv6.1.ns1-3:
+            expenses:food  GBP20.00  ; :fp-nwcsv6.1.ns1-4:
+        ",
+    );
+    // Imported into the same namespace with inconsistent options.
+    ws.write(
+        "input.journal",
+        "
+        2000/01/10 Cafe
+            assets:savings  GBP-3.00  ; :fp-nwcsv6.1.ns1-5:
+            expenses:food  GBP3.00  ; :fp-nwcsv6.1.ns1-6:
+        ",
+    );
+    let merge = |input: &str| {
+        let mut cmd = ws.accountmerge();
+        cmd.args([
+            "merge",
+            "--fingerprint-index",
+            "journal.index",
+            "journal.journal",
+            input,
+            "-o",
+            "journal.journal",
+        ]);
+        cmd
+    };
+    // Builds the index.
+    merge("journal.journal").assert().success();
+
+    merge("input.journal")
+        .assert()
+        .failure()
+        .stderr(predicates::str::contains(
+            "none of their fingerprints match",
+        ));
+}
+
+#[test]
+fn pipeline_script_keeps_state() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal",
+        "
+        2001/01/02 Shop
+            assets:checking  GBP -10.00
+            expenses:unknown  GBP 10.00
+        ",
+    );
+    ws.write(
+        "seen.rhai",
+        r#"
+        fn update(posting) {
+            this.seen = this.seen ?? #{};
+            if posting.description in this.seen {
+                posting.tags.push("repeat");
+            }
+            this.seen[posting.description] = posting.date;
+            posting
+        }
+        "#,
+    );
+    ws.write("pipeline.ron", r#"[Script(script: "seen.rhai")]"#);
+
+    let run = || {
+        ws.accountmerge()
+            .args(["apply-rules", "--output", "out.journal", "journal"])
+            .args(["pipeline", "--state", "state.json", "pipeline.ron"])
+            .assert()
+            .success();
+        read(&ws.path("out.journal"))
+    };
+
+    // The second posting repeats the first one's description.
+    let first = run();
+    assert_eq!(first.matches(":repeat:").count(), 1, "{}", first);
+    // Both postings have been seen by the second run.
+    let second = run();
+    assert_eq!(second.matches(":repeat:").count(), 2, "{}", second);
+    assert!(read(&ws.path("state.json")).contains("\"Shop\": \"2001-01-02\""));
+}
+
+#[test]
+fn reconcile_days_out_of_range() {
+    for days in ["-1", "367", "100000000000000000"] {
+        accountmerge()
+            .args(["merge", &format!("--reconcile-days={}", days), "-"])
+            .write_stdin("")
+            .assert()
+            .failure()
+            .stderr(predicates::str::contains("--reconcile-days"));
+    }
+}
+
+#[test]
+fn merge_keeps_directives_and_comments() {
+    let ws = Workspace::new();
+    let mut mint = Mint::new(TESTDATA);
+    ws.write(
+        "journal.journal",
+        "
+        ; Personal journal.
+        account assets:checking
+            note Current account
+        commodity GBP
+            format GBP1,000.00
+
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+
+        year 2000
+        tag receipt
+        include other.journal
+
+        2000/01/05 Shop
+            assets:checking  GBP-3.00  ; :fp-5:
+            expenses:food  GBP3.00  ; :fp-6:
+
+        ; End of journal.
+        ",
+    );
+    // The input's own directives and comments are not merged, and the
+    // journal's header stays at the top above an earlier transaction.
+    ws.write(
+        "input.journal",
+        "
+        ; Input journal.
+        include input-other.journal
+
+        1999/12/31 Earlier shop
+            assets:checking  GBP-1.00  ; :fp-7:
+            expenses:food  GBP1.00  ; :fp-8:
+
+        2000/01/02 Other shop
+            assets:checking  GBP-5.00  ; :fp-3:
+            expenses:food  GBP5.00  ; :fp-4:
+
+        ; End of input.
+        ",
+    );
+    ws.accountmerge()
+        .args([
+            "merge",
+            "--output",
+            "output.journal",
+            "journal.journal",
+            "input.journal",
+        ])
+        .assert()
+        .success();
+    check_golden(
+        &mut mint,
+        "directives.golden.journal",
+        &ws.path("output.journal"),
+    );
+}
+
+#[test]
+fn merge_into_journal_without_transactions() {
+    let ws = Workspace::new();
+    ws.write("journal.journal", "; My journal.\n");
+    ws.write(
+        "input.journal",
+        "
+        2000/01/01 Shop
+            assets:checking  GBP-10.00  ; :fp-1:
+            expenses:food  GBP10.00  ; :fp-2:
+        ",
+    );
+    ws.accountmerge()
+        .args([
+            "merge",
+            "--output",
+            "journal.journal",
+            "journal.journal",
+            "input.journal",
+        ])
+        .assert()
+        .success();
+    let journal = read(&ws.path("journal.journal"));
+    assert!(journal.starts_with("; My journal.\n"), "{}", journal);
+    assert!(journal.contains("2000-01-01 Shop"), "{}", journal);
+}
+
+#[test]
+fn export_csv_skips_prices() {
+    accountmerge()
+        .args(["export", "csv", "-"])
+        .write_stdin(
+            "P 2001/01/01 00:00:00 EUR GBP0.90\n\n\
+             2001/01/02 Shop\n  assets:bank  GBP-1.00\n  expenses:food  GBP1.00\n",
+        )
+        .assert()
+        .success()
+        .stdout(
+            "date,description,account,amount,commodity,tags\n\
+             2001-01-02,Shop,assets:bank,-1.00,GBP,\n\
+             2001-01-02,Shop,expenses:food,1.00,GBP,\n",
+        );
+}
+
+#[test]
+fn generate_fingerprints_keeps_prices() {
+    let ws = Workspace::new();
+    ws.write(
+        "journal.journal",
+        "
+        P 2001/01/01 00:00:00 EUR GBP0.90
+
+        2001/01/02 Shop
+            assets:bank  GBP-1.00
+            expenses:food  GBP1.00
+        ",
+    );
+    ws.accountmerge()
+        .args(["generate-fingerprints", "journal.journal"])
+        .assert()
+        .success();
+    let journal = read(&ws.path("journal.journal"));
+    assert!(
+        journal.starts_with("P 2001-01-01 00:00:00 EUR GBP0.90\n"),
+        "{}",
+        journal
+    );
+    assert!(journal.contains(":fp-"), "{}", journal);
+}